@@ -1,12 +1,16 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, FileSystem};
-use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easy_fs::{fsck, BlockDevice, FileSystem, Inode};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use easy_fs::BLOCK_SZ;
 
+mod pack;
+use pack::{parse_size, unix_nanos, Geometry, Plan};
+
 struct BlockFile(Mutex<File>);
 
 impl BlockDevice for BlockFile {
@@ -25,11 +29,17 @@ impl BlockDevice for BlockFile {
   }
 }
 
-pub fn main() {
-  fs_pack().expect("Error when creating fs.img");
+/// Subcommand taking an image and the given positional arguments
+fn image_command<'a, 'b>(name: &'a str, about: &'a str, args: &[(&'a str, bool, &'a str)]) -> App<'a, 'b> {
+  args.iter().fold(
+    SubCommand::with_name(name)
+      .about(about)
+      .arg(Arg::with_name("image").required(true).help("easy-fs image")),
+    |command, &(arg, required, help)| command.arg(Arg::with_name(arg).required(required).help(help)),
+  )
 }
 
-fn fs_pack() -> std::io::Result<()> {
+pub fn main() {
   let matches = App::new("FileSystem packer")
     .arg(
       Arg::with_name("source")
@@ -44,46 +54,289 @@ fn fs_pack() -> std::io::Result<()> {
         .long("target")
         .takes_value(true)
         .help("Executable target dir(with backslash)")
-    ).get_matches();
-  
-  let src = matches.value_of("source").unwrap();
-  let dst = matches.value_of("target").unwrap();
+    )
+    .arg(
+      Arg::with_name("tree")
+        .long("tree")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Host directory to pack into / with its subdirectories")
+    )
+    .arg(
+      Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .takes_value(true)
+        .help("Image to write, fs.img in the target dir by default")
+    )
+    .arg(Arg::with_name("size").long("size").default_value("32M").help("Image size in bytes, K, M and G suffixes allowed; blocks are always 512 bytes"))
+    .arg(Arg::with_name("inodes").long("inodes").default_value("4096").help("Least number of inodes"))
+    .subcommand(
+      image_command("fsck", "efs-fsck: check the consistency of an image", &[])
+        .arg(Arg::with_name("repair").short("r").long("repair").help("Fix the problems found"))
+    )
+    .subcommand(image_command("info", "Print the super block and the free space", &[]))
+    .subcommand(image_command("ls", "List a directory", &[("path", false, "Directory or file, / by default")]))
+    .subcommand(image_command("cat", "Print a file", &[("path", true, "File in the image")]))
+    .subcommand(image_command("get", "Copy a file out of the image", &[
+      ("path", true, "File in the image"),
+      ("dest", false, "Host file, named like the file by default"),
+    ]))
+    .subcommand(image_command("put", "Copy a host file into the image, replacing what is there", &[
+      ("file", true, "Host file"),
+      ("path", false, "Path or directory in the image, / by default"),
+    ]))
+    .subcommand(image_command("rm", "Remove a file or an empty directory", &[("path", true, "Path in the image")]))
+    .subcommand(image_command("mkdir", "Create a directory", &[("path", true, "Path in the image")]))
+    .get_matches();
 
-  println!("src = {}, dst = {}", src, dst);
-  let file_size = 32 * 2048 * 512; // 32 MB allocated
-  let file = Arc::new(BlockFile(Mutex::new({
-    let f = OpenOptions::new()
-      .read(true)
-      .write(true)
-      .create(true)
-      .open(format!("{}{}", dst, "fs.img")).unwrap();
-    f.set_len(file_size).unwrap(); 
-    f
-  })));
+  let result = match matches.subcommand() {
+    ("fsck", Some(args)) => {
+      let code = efs_fsck(args.value_of("image").unwrap(), args.is_present("repair"))
+        .expect("Error when checking the image");
+      std::process::exit(code);
+    }
+    ("info", Some(args)) => efs_info(args.value_of("image").unwrap()),
+    (command, Some(args)) => efs_edit(command, args),
+    _ => fs_pack(&matches),
+  };
+  if let Err(err) = result {
+    eprintln!("easy-fs-fuse: {}", err);
+    std::process::exit(1);
+  }
+}
 
-  let fs = FileSystem::create(file.clone(), file_size as u32 / 512, 1);
-  let root_inode = Arc::new(FileSystem::root_inode(&fs));
+/// Open an existing image
+fn open_image(image: &str, writable: bool) -> std::io::Result<Arc<dyn BlockDevice>> {
+  let file = OpenOptions::new().read(true).write(writable).open(image)?;
+  let too_short = file.metadata()?.len() < BLOCK_SZ as u64;
+  let file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(file)));
+  if too_short || !FileSystem::probe(&file) {
+    return Err(Error::new(ErrorKind::InvalidData, format!("{}: not an easy-fs image", image)));
+  }
+  Ok(file)
+}
 
-  let apps:Vec<_> = read_dir(src)?
-    .into_iter()
-    .map(|direntry| {
-      let mut elf_name = direntry.unwrap().file_name().into_string().unwrap();
-      elf_name.drain(elf_name.find('.').unwrap()..elf_name.len());
-      elf_name
-    }).collect();
-  
-  for app in apps {
-    // println!("app path = {}", format!("{}{}", dst, app));
-    let mut app_file = File::open(format!("{}{}", dst, app)).unwrap();
-    let mut app_data = Vec::new();
-    app_file.read_to_end(&mut app_data).unwrap();
-    let app_inode = root_inode.create(app.as_str()).unwrap();
-    app_inode.write_at(0, &app_data);
+/// Check an image, returning the exit status `fsck` uses: 0 if clean,
+/// 1 if problems were repaired, 4 if problems were left, 8 if it is no easy-fs image
+fn efs_fsck(image: &str, repair: bool) -> std::io::Result<i32> {
+  let file = match open_image(image, repair) {
+    Err(err) if err.kind() == ErrorKind::InvalidData => {
+      eprintln!("{}", err);
+      return Ok(8);
+    }
+    file => file?,
+  };
+  let efs = FileSystem::open(file);
+  let report = fsck(&efs, repair);
+  for problem in &report.problems {
+    println!("{}", problem);
+  }
+  println!("{}: {} inodes, {} blocks in use, {} problems", image, report.inodes, report.blocks, report.problems.len());
+  Ok(match (report.is_clean(), report.repaired) {
+    (true, _) => 0,
+    (false, true) => 1,
+    (false, false) => 4,
+  })
+}
+
+fn efs_info(image: &str) -> std::io::Result<()> {
+  let efs = FileSystem::open(open_image(image, false)?);
+  let fs = efs.lock();
+  let super_blk = fs.super_block();
+  let (used_inodes, used_blocks) = (fs.used_inodes(), fs.used_data_blocks());
+  let data_blocks = super_blk.data_area_blocks as usize;
+  println!("total blocks:        {}", super_blk.total_blocks);
+  println!("journal:             {} blocks from block {}", super_blk.journal_blocks, super_blk.journal_start);
+  println!("checksum table:      {} blocks", super_blk.checksum_blocks);
+  println!("inode bitmap:        {} blocks", super_blk.inode_bitmap_blocks);
+  println!("inode area:          {} blocks", super_blk.inode_area_blocks);
+  println!("data bitmap:         {} blocks", super_blk.data_bitmap_blocks);
+  println!("data area:           {} blocks", data_blocks);
+  println!("inodes:              {} used, {} free", used_inodes, fs.free_inodes());
+  println!("data blocks:         {} used, {} free", used_blocks, fs.free_data_blocks());
+  println!("free space:          {} bytes", fs.free_data_blocks() * BLOCK_SZ);
+  Ok(())
+}
+
+/// Names along `path`, which is relative to `/` of the image
+fn components(path: &str) -> Vec<&str> {
+  path.split('/').filter(|name| !name.is_empty()).collect()
+}
+
+/// Inode at `path`
+fn resolve(root: &Arc<Inode>, path: &str) -> std::io::Result<Arc<Inode>> {
+  components(path).into_iter().try_fold(root.clone(), |dir, name| {
+    if !dir.is_dir() {
+      return Err(Error::new(ErrorKind::NotFound, format!("{}: not a directory on the way", path)));
+    }
+    dir.find_name(name).ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}: no such file or directory", path)))
+  })
+}
+
+/// Directory holding `path`, and the last name in it
+fn resolve_parent<'a>(root: &Arc<Inode>, path: &'a str) -> std::io::Result<(Arc<Inode>, &'a str)> {
+  let mut names = components(path);
+  let name = names.pop().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "the root has no parent"))?;
+  let dir = resolve(root, &names.join("/"))?;
+  if !dir.is_dir() {
+    return Err(Error::new(ErrorKind::NotFound, format!("{}: not a directory on the way", path)));
+  }
+  Ok((dir, name))
+}
+
+/// Last component of a host path
+fn base_name(path: &str) -> std::io::Result<String> {
+  std::path::Path::new(path)
+    .file_name()
+    .and_then(|name| name.to_str())
+    .map(str::to_string)
+    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{}: no file name", path)))
+}
+
+fn read_all(inode: &Inode) -> Vec<u8> {
+  let mut data = vec![0u8; inode.size()];
+  let len = inode.read_at(0, &mut data);
+  data.truncate(len);
+  data
+}
+
+/// Run one of the subcommands that look at or change the files of an image
+fn efs_edit(command: &str, args: &ArgMatches) -> std::io::Result<()> {
+  let writable = matches!(command, "put" | "rm" | "mkdir");
+  let efs = FileSystem::open(open_image(args.value_of("image").unwrap(), writable)?);
+  // reads leave the times alone, so that read-only images are never written
+  if writable {
+    efs.lock().set_clock(|| unix_nanos(std::time::SystemTime::now()));
+  }
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  let path = args.value_of("path").unwrap_or("/");
+  match command {
+    "ls" => {
+      let inode = resolve(&root, path)?;
+      if !inode.is_dir() {
+        println!("{} {:>10} {}", mode_string(&inode), inode.size(), path);
+        return Ok(());
+      }
+      for name in inode.ls() {
+        let child = inode.find_name(&name).unwrap();
+        println!("{} {:>10} {}", mode_string(&child), child.size(), name);
+      }
+    }
+    "cat" | "get" => {
+      let inode = resolve(&root, path)?;
+      if inode.is_dir() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{}: is a directory", path)));
+      }
+      let data = read_all(&inode);
+      match command {
+        "cat" => std::io::stdout().write_all(&data)?,
+        _ => {
+          let dest = match args.value_of("dest") {
+            Some(dest) => dest.to_string(),
+            None => base_name(path)?,
+          };
+          std::fs::write(dest, data)?;
+        }
+      }
+    }
+    "put" => {
+      let file = args.value_of("file").unwrap();
+      let data = std::fs::read(file)?;
+      // into a directory, under the host name
+      let path = match resolve(&root, path) {
+        Ok(inode) if inode.is_dir() => format!("{}/{}", path, base_name(file)?),
+        _ => path.to_string(),
+      };
+      let (dir, name) = resolve_parent(&root, &path)?;
+      let inode = match dir.find_name(name) {
+        Some(inode) if inode.is_dir() => {
+          return Err(Error::new(ErrorKind::AlreadyExists, format!("{}: is a directory", path)));
+        }
+        // checked before the old contents go
+        Some(inode) if data.len() > inode.max_size() => {
+          return Err(Error::new(ErrorKind::InvalidInput, format!("{}: holds at most {} bytes, {} has {}", path, inode.max_size(), file, data.len())));
+        }
+        Some(inode) => {
+          if !inode.shrink(0) {
+            return Err(Error::other(format!("{}: could not empty it", path)));
+          }
+          inode
+        }
+        None => dir.create(name).ok_or_else(|| invalid_name(&dir, name))?,
+      };
+      let written = inode.write_at(0, &data);
+      if written != data.len() {
+        return Err(Error::other(format!("{}: wrote {} of {} bytes", path, written, data.len())));
+      }
+    }
+    "rm" => {
+      let (dir, name) = resolve_parent(&root, path)?;
+      resolve(&root, path)?;
+      if !dir.unlink(name) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{}: directory not empty", path)));
+      }
+    }
+    "mkdir" => {
+      let (dir, name) = resolve_parent(&root, path)?;
+      if dir.find_name(name).is_some() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{}: already exists", path)));
+      }
+      dir.create_dir(name).ok_or_else(|| invalid_name(&dir, name))?;
+    }
+    _ => unreachable!(),
   }
+  root.sync();
   Ok(())
 }
 
+/// Type and permissions like `ls -l` shows them, e.g. `drwxr-xr-x`
+fn mode_string(inode: &Inode) -> String {
+  let mode = inode.metadata().mode;
+  let kind = if inode.is_dir() { 'd' } else { '-' };
+  let perms = (0..9).rev().map(|bit| match mode >> bit & 1 {
+    0 => '-',
+    _ => ['x', 'w', 'r'][bit % 3],
+  });
+  std::iter::once(kind).chain(perms).collect()
+}
+
+fn invalid_name(dir: &Inode, name: &str) -> Error {
+  Error::new(ErrorKind::InvalidInput, format!("{}: invalid name, at most {} bytes", name, dir.name_max()))
+}
 
+fn fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
+  let number = |arg: &str| {
+    let text = matches.value_of(arg).unwrap();
+    text.parse::<u64>().map_err(|_| Error::new(ErrorKind::InvalidInput, format!("--{} {}: not a number", arg, text)))
+  };
+  let geometry = Geometry::new(parse_size(matches.value_of("size").unwrap())?, number("inodes")?)?;
+
+  let mut plan = Plan::default();
+  match (matches.value_of("source"), matches.value_of("target")) {
+    (Some(src), Some(dst)) => {
+      println!("src = {}, dst = {}", src, dst);
+      plan.add_apps(Path::new(src), Path::new(dst))?;
+    }
+    (None, None) => {}
+    _ => return Err(Error::new(ErrorKind::InvalidInput, "--source and --target go together")),
+  }
+  for tree in matches.values_of("tree").into_iter().flatten() {
+    plan.add_tree(Path::new(tree))?;
+  }
+  let output = match (matches.value_of("output"), matches.value_of("target")) {
+    (Some(output), _) => PathBuf::from(output),
+    (None, Some(dst)) => Path::new(dst).join("fs.img"),
+    (None, None) => return Err(Error::new(ErrorKind::InvalidInput, "no image to write, give --output")),
+  };
+  // fail before truncating an existing image
+  plan.check(&geometry)?;
+
+  let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&output)?;
+  file.set_len(geometry.total_blocks as u64 * BLOCK_SZ as u64)?;
+  plan.pack(Arc::new(BlockFile(Mutex::new(file))), &geometry)
+}
 
 #[test]
 fn efs_test() -> std::io::Result<()> {
@@ -92,11 +345,12 @@ fn efs_test() -> std::io::Result<()> {
       .read(true)
       .write(true)
       .create(true)
+      .truncate(true)
       .open("target/fs.img")?;
     f.set_len(8192 * 512).unwrap();
     f
   })));
-  FileSystem::create(block_file.clone(), 4096, 1);
+  FileSystem::create(block_file.clone(), 8192, 1);
   let efs = FileSystem::open(block_file.clone());
   let root_inode = FileSystem::root_inode(&efs);
   
@@ -124,7 +378,7 @@ fn efs_test() -> std::io::Result<()> {
     use rand;
     // random digit
     for _ in 0..len {
-      str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+      str.push(char::from(b'0' + rand::random::<u8>() % 10));
     }
     filea.write_at(0, str.as_bytes());
     let mut read_buffer = [0u8; 127];
@@ -157,3 +411,965 @@ fn efs_test() -> std::io::Result<()> {
 
   Ok(())
 }
+
+#[test]
+fn efs_unlink_test() -> std::io::Result<()> {
+  let block_file = Arc::new(BlockFile(Mutex::new({
+    let f = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open("target/fs-unlink.img")?;
+    f.set_len(8192 * 512).unwrap();
+    f
+  })));
+  let efs = FileSystem::create(block_file.clone(), 4096, 1);
+  let root_inode = FileSystem::root_inode(&efs);
+  let filea = root_inode.create("filea").unwrap();
+  root_inode.create("fileb").unwrap();
+  filea.write_at(0, &[b'a'; 20 * BLOCK_SZ]);
+  let filea_id = filea.inode_id();
+  assert!(Arc::ptr_eq(&filea, &root_inode.find_name("filea").unwrap()));
+  let free_inodes = efs.lock().free_inodes();
+
+  assert!(root_inode.unlink("filea"));
+  assert!(!root_inode.unlink("filea"));
+  assert!(root_inode.find_name("filea").is_none());
+  assert_eq!(root_inode.ls(), vec!["fileb"]);
+
+  // the open file stays usable until its last handle drops
+  assert!(filea.is_unlinked());
+  assert_eq!(filea.write_at(20 * BLOCK_SZ, b"more"), 4);
+  assert_eq!(read_all(&filea).len(), 20 * BLOCK_SZ + 4);
+  assert_eq!(efs.lock().free_inodes(), free_inodes);
+  let dir = root_inode.create_dir("dir").unwrap();
+  assert!(root_inode.unlink("dir"));
+  assert!(dir.create("f").is_none());
+  let free_inodes = efs.lock().free_inodes();
+  let free_blocks = efs.lock().free_data_blocks();
+  let handle = filea.clone();
+  drop(filea);
+  assert_eq!(efs.lock().free_inodes(), free_inodes);
+  drop((handle, dir));
+  assert_eq!(efs.lock().free_inodes(), free_inodes + 2);
+  assert_eq!(efs.lock().free_data_blocks(), free_blocks + 21);
+  assert!(fsck(&efs, false).is_clean());
+
+  // the freed slot and inode are reused
+  let filec = root_inode.create("filec").unwrap();
+  assert_eq!(filec.inode_id(), filea_id);
+  assert_eq!(filec.size(), 0);
+  assert_eq!(root_inode.ls(), vec!["filec", "fileb"]);
+  assert!(root_inode.create(&"x".repeat(easy_fs::NAME_LENGTH_LIMIT + 1)).is_none());
+  Ok(())
+}
+
+/// A block id and what was written there
+#[cfg(test)]
+type BlockWrite = (usize, [u8; BLOCK_SZ]);
+
+/// Block device in memory that counts writes, and records them on demand
+#[cfg(test)]
+struct MemDevice {
+  blocks: Mutex<Vec<[u8; BLOCK_SZ]>>,
+  writes: std::sync::atomic::AtomicUsize,
+  recorded: Mutex<Option<Vec<BlockWrite>>>,
+}
+
+#[cfg(test)]
+impl MemDevice {
+  fn new(blocks: usize) -> Arc<Self> {
+    Self::with_blocks(vec![[0u8; BLOCK_SZ]; blocks])
+  }
+
+  fn with_blocks(blocks: Vec<[u8; BLOCK_SZ]>) -> Arc<Self> {
+    Arc::new(Self { blocks: Mutex::new(blocks), writes: Default::default(), recorded: Mutex::new(None) })
+  }
+}
+
+#[cfg(test)]
+impl BlockDevice for MemDevice {
+  fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    buf.copy_from_slice(&self.blocks.lock().unwrap()[block_id]);
+  }
+
+  fn write_block(&self, block_id: usize, buf: &[u8]) {
+    self.blocks.lock().unwrap()[block_id].copy_from_slice(buf);
+    self.writes.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    if let Some(recorded) = self.recorded.lock().unwrap().as_mut() {
+      recorded.push((block_id, buf.try_into().unwrap()));
+    }
+  }
+}
+
+#[test]
+fn block_cache_test() {
+  use easy_fs::{BlockCacheManager, WritePolicy};
+  use std::sync::atomic::Ordering;
+  let mem = MemDevice::new(8);
+  let mut cache = BlockCacheManager::new(mem.clone(), 2, WritePolicy::WriteBack);
+
+  // least recently used goes first
+  cache.get_block_cache(0);
+  cache.get_block_cache(1);
+  cache.get_block_cache(0);
+  cache.get_block_cache(2);
+  cache.get_block_cache(0);
+  let stats = cache.stats();
+  assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 3, 1));
+  cache.get_block_cache(1);
+  assert_eq!(cache.stats().misses, 4);
+
+  // modified blocks reach the device on sync or eviction only
+  cache.get_block_cache(1).lock().modify(0, |byte: &mut u8| *byte = 7);
+  assert_eq!(mem.writes.load(Ordering::Relaxed), 0);
+  cache.sync();
+  assert_eq!(mem.writes.load(Ordering::Relaxed), 1);
+  assert_eq!(mem.blocks.lock().unwrap()[1][0], 7);
+  cache.get_block_cache(1).lock().modify(0, |byte: &mut u8| *byte = 8);
+  cache.get_block_cache(3);
+  cache.get_block_cache(4);
+  assert_eq!(mem.blocks.lock().unwrap()[1][0], 8);
+
+  // entries in use are never evicted: grow, then shrink back
+  let pinned: Vec<_> = (5..8).map(|id| cache.get_block_cache(id)).collect();
+  assert_eq!(cache.len(), 3);
+  assert_eq!(cache.stats().overflows, 1);
+  drop(pinned);
+  cache.get_block_cache(0);
+  assert_eq!(cache.len(), 2);
+
+  cache.configure(2, WritePolicy::WriteThrough);
+  let writes = mem.writes.load(Ordering::Relaxed);
+  cache.get_block_cache(2).lock().modify(0, |byte: &mut u8| *byte = 9);
+  assert_eq!(mem.writes.load(Ordering::Relaxed), writes + 1);
+
+  // entries in use grow the cache by at most a transaction the journal holds; blocks
+  // past that bypass it
+  let last = 2 + easy_fs::JOURNAL_MAX_BLOCKS;
+  let mem = MemDevice::new(last + 1);
+  let mut bounded = BlockCacheManager::new(mem.clone(), 2, WritePolicy::WriteBack);
+  let pinned: Vec<_> = (0..last).map(|id| bounded.get_block_cache(id)).collect();
+  bounded.get_block_cache(last).lock().modify(0, |byte: &mut u8| *byte = 1);
+  assert_eq!((bounded.len(), bounded.stats().uncached), (pinned.len(), 1));
+  assert_eq!(mem.blocks.lock().unwrap()[last][0], 1);
+}
+
+#[test]
+fn two_volumes_test() {
+  let (dev_a, dev_b) = (MemDevice::new(4096), MemDevice::new(4096));
+  let efs_a = FileSystem::create(dev_a.clone(), 4096, 1);
+  let efs_b = FileSystem::create(dev_b.clone(), 4096, 1);
+  let (root_a, root_b) = (FileSystem::root_inode(&efs_a), FileSystem::root_inode(&efs_b));
+
+  // same names and block ids on both, different contents
+  root_a.create("file").unwrap().write_at(0, b"volume a");
+  root_b.create("file").unwrap().write_at(0, b"volume b");
+  root_b.create("only_b").unwrap();
+  let mut buf = [0u8; 8];
+  root_a.find_name("file").unwrap().read_at(0, &mut buf);
+  assert_eq!(&buf, b"volume a");
+  root_b.find_name("file").unwrap().read_at(0, &mut buf);
+  assert_eq!(&buf, b"volume b");
+  assert_eq!(root_a.ls(), vec!["file"]);
+
+  // each device has a cache of its own
+  let dev_a: Arc<dyn BlockDevice> = dev_a;
+  let misses = easy_fs::block_cache_stats(&dev_a).misses;
+  root_b.find_name("only_b").unwrap();
+  assert_eq!(easy_fs::block_cache_stats(&dev_a).misses, misses);
+
+  // releasing the cache writes it back; reopening reads the device again
+  easy_fs::block_cache_release(&dev_a);
+  let efs_a = FileSystem::open(dev_a.clone());
+  FileSystem::root_inode(&efs_a).find_name("file").unwrap().read_at(0, &mut buf);
+  assert_eq!(&buf, b"volume a");
+
+  // caches do not keep their devices alive
+  assert!(easy_fs::block_cache_exists(&(dev_b.clone() as Arc<dyn BlockDevice>)));
+  let weak_b = Arc::downgrade(&dev_b);
+  drop((root_b, efs_b, dev_b));
+  assert!(weak_b.upgrade().is_none());
+}
+
+/// Name and contents of every file in `root`, sorted by name
+#[cfg(test)]
+fn snapshot(root: &easy_fs::Inode) -> Vec<(String, Vec<u8>)> {
+  let mut files: Vec<_> = root.ls().into_iter().map(|name| {
+    let file = root.find_name(&name).unwrap();
+    let mut data = vec![0u8; file.size()];
+    assert_eq!(file.read_at(0, &mut data), data.len());
+    (name, data)
+  }).collect();
+  files.sort();
+  files
+}
+
+/// Run `op` on `image`, then pretend the machine died after each prefix of the writes it
+/// issued: the recovered filesystem must be as it was either before or after `op`
+#[cfg(test)]
+fn crash_at_every_write(image: &[[u8; BLOCK_SZ]], op: impl Fn(&easy_fs::Inode)) {
+  let dev = MemDevice::with_blocks(image.to_vec());
+  let efs = FileSystem::open(dev.clone());
+  let root = FileSystem::root_inode(&efs);
+  let before = snapshot(&root);
+  *dev.recorded.lock().unwrap() = Some(Vec::new());
+  op(&root);
+  root.sync();
+  let writes = dev.recorded.lock().unwrap().take().unwrap();
+  let after = snapshot(&root);
+  assert_ne!(before, after);
+  easy_fs::block_cache_release(&(dev as Arc<dyn BlockDevice>));
+
+  for crash in 0..=writes.len() {
+    let mut blocks = image.to_vec();
+    for (block_id, data) in &writes[..crash] {
+      blocks[*block_id] = *data;
+    }
+    let dev: Arc<dyn BlockDevice> = MemDevice::with_blocks(blocks);
+    let efs = FileSystem::open(dev.clone());
+    let root = FileSystem::root_inode(&efs);
+    let state = snapshot(&root);
+    assert!(state == before || state == after, "inconsistent after {} of {} writes", crash, writes.len());
+    let report = fsck(&efs, false);
+    assert!(report.is_clean(), "after {} of {} writes: {:?}", crash, writes.len(), report.problems);
+    // the bitmaps agree with the inodes: new blocks must not overwrite existing files
+    root.create("probe").unwrap().write_at(0, &[0xaa; 10 * BLOCK_SZ]);
+    root.unlink("probe");
+    assert_eq!(snapshot(&root), state);
+    easy_fs::block_cache_release(&dev);
+  }
+}
+
+#[test]
+fn journal_crash_test() {
+  let dev = MemDevice::new(4096);
+  let efs = FileSystem::create(dev.clone(), 4096, 1);
+  let root = FileSystem::root_inode(&efs);
+  root.create("a").unwrap().write_at(0, &[b'a'; 3 * BLOCK_SZ]);
+  root.create("b").unwrap().write_at(0, b"bbb");
+  easy_fs::block_cache_release(&(dev.clone() as Arc<dyn BlockDevice>));
+  let image = dev.blocks.lock().unwrap().clone();
+
+  crash_at_every_write(&image, |root| {
+    root.create("c").unwrap();
+  });
+  crash_at_every_write(&image, |root| {
+    root.find_name("a").unwrap().write_at(1000, &[b'x'; 20 * BLOCK_SZ]);
+  });
+  crash_at_every_write(&image, |root| {
+    assert!(root.unlink("a"));
+  });
+  crash_at_every_write(&image, |root| {
+    root.find_name("a").unwrap().clear();
+  });
+
+  // a transaction too large for the journal is dropped, not half written
+  let efs = FileSystem::open(MemDevice::with_blocks(image));
+  let free = efs.lock().free_data_blocks();
+  let mut fs = efs.lock();
+  fs.begin();
+  fs.alloc_data_blocks(200);
+  assert!(!fs.commit());
+  assert_eq!(fs.free_data_blocks(), free);
+}
+
+#[test]
+fn fsck_test() {
+  use easy_fs::FsckProblem;
+  let dev = MemDevice::new(4096);
+  let block_dev: Arc<dyn BlockDevice> = dev.clone();
+  let efs = FileSystem::create(dev.clone(), 4096, 1);
+  let root = FileSystem::root_inode(&efs);
+  root.create("a").unwrap().write_at(0, &[b'a'; 200 * BLOCK_SZ]);
+  let b = root.create("b").unwrap();
+  b.write_at(0, b"bbb");
+  let b_id = b.inode_id();
+  let report = fsck(&efs, false);
+  assert!(report.is_clean(), "{:?}", report.problems);
+  // `/`, a and b; a uses an indirect2 block with one child besides indirect1
+  assert_eq!((report.inodes, report.blocks), (3, 1 + 200 + 3 + 1));
+
+  // a's first data block is marked free, so c gets it too
+  efs.lock().data_bitmap.dealloc(&block_dev, 1);
+  let c = root.create("c").unwrap();
+  c.write_at(0, b"c");
+  {
+    let mut fs = efs.lock();
+    // a leaked inode and block
+    assert_eq!(fs.inode_bitmap.alloc(&block_dev), Some(4));
+    fs.data_bitmap.alloc(&block_dev).unwrap();
+    // b's entry now dangles
+    fs.inode_bitmap.dealloc(&block_dev, b_id as usize);
+  }
+  let problems = fsck(&efs, false).problems;
+  assert!(problems.contains(&FsckProblem::DanglingEntry { dir: 0, name: "b".to_string(), inode: b_id }));
+  let c_id = c.inode_id();
+  assert!(problems.iter().any(|problem| matches!(problem, FsckProblem::DoubleAllocated { inode, .. } if *inode == c_id)));
+  assert!(problems.contains(&FsckProblem::LeakedInode { inode: 4 }));
+  assert!(problems.iter().any(|problem| matches!(problem, FsckProblem::LeakedBlock { .. })));
+  // unrepaired checks change nothing
+  assert_eq!(fsck(&efs, false).problems, problems);
+
+  let report = fsck(&efs, true);
+  assert!(report.repaired);
+  assert!(fsck(&efs, false).is_clean());
+  // what survives is readable
+  let mut buf = [0u8; BLOCK_SZ];
+  assert_eq!(root.find_name("a").unwrap().read_at(100 * BLOCK_SZ, &mut buf), BLOCK_SZ);
+  assert_eq!(buf, [b'a'; BLOCK_SZ]);
+  assert!(root.find_name("b").is_none());
+  assert_eq!(c.size(), 0);
+
+  // the free counts in the super block are checked against the bitmaps
+  let free = efs.lock().free_data_blocks() as u32;
+  easy_fs::block_cache_release(&block_dev);
+  patch_super_block(&mut dev.blocks.lock().unwrap()[0], |super_blk| super_blk.free_data_blocks = free + 7);
+  let efs = FileSystem::open(block_dev.clone());
+  assert_eq!(efs.lock().free_data_blocks() as u32, free + 7);
+  assert_eq!(fsck(&efs, false).problems, vec![FsckProblem::WrongFreeBlocks { stored: free + 7, counted: free }]);
+  assert!(fsck(&efs, true).repaired);
+  assert_eq!(efs.lock().free_data_blocks() as u32, free);
+  assert!(fsck(&efs, false).is_clean());
+}
+
+/// Block cache lookups `op` makes on `dev`
+#[cfg(test)]
+fn lookups(dev: &Arc<dyn BlockDevice>, op: impl FnOnce()) -> usize {
+  let before = easy_fs::block_cache_stats(dev);
+  op();
+  let after = easy_fs::block_cache_stats(dev);
+  after.hits + after.misses - before.hits - before.misses
+}
+
+#[test]
+fn alloc_bench_test() {
+  use std::time::Instant;
+  let dev = MemDevice::new(65536);
+  let block_dev: Arc<dyn BlockDevice> = dev.clone();
+  let efs = FileSystem::create(dev.clone(), 65536, 1);
+  let root = FileSystem::root_inode(&efs);
+  let data_bitmap_blocks = efs.lock().super_block().data_bitmap_blocks as usize;
+  let (early, late) = (root.create("early").unwrap(), root.create("late").unwrap());
+  let early_lookups = lookups(&block_dev, || { early.write_at(0, &[1; BLOCK_SZ]); });
+
+  // fill most of the disk with 8 MiB files, written in one go
+  let file = vec![b'f'; 8 << 20];
+  let start = Instant::now();
+  for name in ["f0", "f1", "f2"] {
+    root.create(name).unwrap().write_at(0, &file);
+  }
+  let elapsed = start.elapsed();
+  println!(
+    "wrote {} MiB in {:?}, {:.1} MiB/s",
+    (3 * file.len()) >> 20, elapsed, (3 * file.len()) as f64 / (1 << 20) as f64 / elapsed.as_secs_f64(),
+  );
+  assert!(efs.lock().free_data_blocks() < 65536 / 4);
+
+  // with most of the bitmap full, the next block is found as fast as the first one was;
+  // a search from the start would read every full bitmap block first
+  let late_lookups = lookups(&block_dev, || { late.write_at(0, &[1; BLOCK_SZ]); });
+  let full_bitmap_blocks = efs.lock().used_data_blocks() / (BLOCK_SZ * 8);
+  println!(
+    "one block allocated with {} block cache lookups on an empty disk, {} on a full one, {} searching from the start",
+    early_lookups, late_lookups, late_lookups + full_bitmap_blocks,
+  );
+  assert!(data_bitmap_blocks > 8 && full_bitmap_blocks > 8);
+  assert_eq!(late_lookups, early_lookups);
+
+  // appending block by block costs the same early and late too
+  let appends = |file: &Arc<Inode>| {
+    let start = Instant::now();
+    for i in 1..100 {
+      file.write_at(i * BLOCK_SZ, &[1; BLOCK_SZ]);
+    }
+    start.elapsed()
+  };
+  println!("99 appends: {:?} on the first file, {:?} on the last", appends(&early), appends(&late));
+
+  // nothing was freed, so everything went into one run from the start of the data area
+  {
+    let fs = efs.lock();
+    let used = fs.used_data_blocks();
+    assert!((0..used).all(|bit| fs.data_bitmap.get(&block_dev, bit)));
+    assert!(!fs.data_bitmap.get(&block_dev, used));
+  }
+
+  // the free counts survive reopening
+  let free = efs.lock().free_data_blocks();
+  root.create("more").unwrap().write_at(0, &[b'm'; 64 * BLOCK_SZ]);
+  easy_fs::block_cache_release(&block_dev);
+  let efs = FileSystem::open(block_dev);
+  assert_eq!(efs.lock().free_data_blocks(), free - 65);
+  assert!(fsck(&efs, false).is_clean());
+}
+
+#[test]
+fn efs_dir_test() {
+  let dev = MemDevice::new(4096);
+  let efs = FileSystem::create(dev, 4096, 1);
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  let dir = root.create_dir("dir").unwrap();
+  assert!(dir.is_dir() && dir.ls().is_empty());
+  assert!(root.create_dir("dir").is_none());
+  dir.create_dir("sub").unwrap().create("file").unwrap().write_at(0, b"nested");
+  assert_eq!(read_all(&resolve(&root, "/dir/sub/file").unwrap()), b"nested");
+
+  // directories go only once empty
+  assert!(!root.unlink("dir"));
+  let sub = dir.find_name("sub").unwrap();
+  assert!(sub.unlink("file"));
+  assert!(dir.unlink("sub"));
+  assert!(root.unlink("dir"));
+  assert!(root.ls().is_empty());
+  assert!(fsck(&efs, false).is_clean());
+}
+
+#[test]
+fn efs_rename_test() {
+  use easy_fs::RenameError;
+  let dev = MemDevice::new(4096);
+  let efs = FileSystem::create(dev, 4096, 1);
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  let a = root.create_dir("a").unwrap();
+  let b = a.create_dir("b").unwrap();
+  let file = root.create("f").unwrap();
+  file.write_at(0, b"moved");
+  let file_id = file.inode_id();
+
+  // within a directory, then into another one
+  Inode::rename(&root, "f", &root, "g").unwrap();
+  assert_eq!(root.ls(), ["a", "g"]);
+  Inode::rename(&root, "g", &b, "h").unwrap();
+  assert_eq!(root.ls(), ["a"]);
+  let moved = resolve(&root, "/a/b/h").unwrap();
+  assert_eq!((moved.inode_id(), read_all(&moved)), (file_id, b"moved".to_vec()));
+
+  // a file replaces a file, whose inode and blocks are freed
+  let old = b.create("old").unwrap();
+  old.write_at(0, &[b'o'; 20 * BLOCK_SZ]);
+  Inode::rename(&b, "h", &b, "old").unwrap();
+  assert_eq!(b.ls(), ["old"]);
+  assert_eq!(read_all(&b.find_name("old").unwrap()), b"moved");
+  assert!(fsck(&efs, false).is_clean());
+  // the replaced file stays readable until its last handle drops
+  assert!(old.is_unlinked());
+  assert_eq!(read_all(&old), [b'o'; 20 * BLOCK_SZ]);
+  let free = efs.lock().free_data_blocks();
+  drop(old);
+  assert_eq!(efs.lock().free_data_blocks(), free + 20);
+
+  assert_eq!(Inode::rename(&root, "missing", &root, "x"), Err(RenameError::NotFound));
+  assert_eq!(Inode::rename(&b, "old", &root, ""), Err(RenameError::InvalidName));
+  assert_eq!(Inode::rename(&b, "old", &root, "a"), Err(RenameError::IsDir));
+  root.create("file").unwrap();
+  assert_eq!(Inode::rename(&root, "a", &root, "file"), Err(RenameError::NotDir));
+  let c = root.create_dir("c").unwrap();
+  assert_eq!(Inode::rename(&c, "a", &root, "x"), Err(RenameError::NotFound));
+  // directories never move below themselves
+  assert_eq!(Inode::rename(&root, "a", &a, "a2"), Err(RenameError::IntoItself));
+  assert_eq!(Inode::rename(&root, "a", &b, "a2"), Err(RenameError::IntoItself));
+  // a directory replaces only an empty one
+  assert_eq!(Inode::rename(&root, "c", &root, "a"), Err(RenameError::NotEmpty));
+  Inode::rename(&root, "a", &root, "c").unwrap();
+  assert_eq!(root.ls(), ["file", "c"]);
+  assert_eq!(read_all(&resolve(&root, "/c/b/old").unwrap()), b"moved");
+  assert!(fsck(&efs, false).is_clean());
+  // nothing moves into the replaced directory
+  assert!(c.is_unlinked());
+  assert_eq!(Inode::rename(&root, "file", &c, "file"), Err(RenameError::NotFound));
+
+  let other = FileSystem::create(MemDevice::new(4096), 4096, 1);
+  let other_root = FileSystem::root_inode(&other);
+  assert_eq!(Inode::rename(&root, "file", &other_root, "file"), Err(RenameError::CrossDevice));
+}
+
+#[test]
+fn pack_test() -> std::io::Result<()> {
+  let tree = Path::new("target/pack_test");
+  let _ = std::fs::remove_dir_all(tree);
+  std::fs::create_dir_all(tree.join("etc/init.d"))?;
+  std::fs::write(tree.join("etc/init.d/rc"), b"#!/bin/sh")?;
+  std::fs::write(tree.join("hello.txt"), vec![b'h'; 3 * BLOCK_SZ + 7])?;
+  std::fs::create_dir(tree.join("empty"))?;
+  std::fs::set_permissions(tree.join("hello.txt"), std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+
+  let geometry = Geometry::new(parse_size("2M")?, 100)?;
+  let mut plan = Plan::default();
+  plan.add_tree(tree)?;
+  let images: Vec<_> = (0..2)
+    .map(|_| {
+      let dev = MemDevice::new(geometry.total_blocks as usize);
+      plan.pack(dev.clone(), &geometry).unwrap();
+      let blocks = dev.blocks.lock().unwrap().clone();
+      blocks
+    })
+    .collect();
+  // same inputs, same bytes
+  assert!(images[0] == images[1]);
+
+  let efs = FileSystem::open(MemDevice::with_blocks(images[0].clone()));
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  assert_eq!(root.ls(), ["empty", "etc", "hello.txt"]);
+  assert!(resolve(&root, "/empty")?.is_dir());
+  assert_eq!(read_all(&resolve(&root, "/etc/init.d/rc").unwrap()), b"#!/bin/sh");
+  assert_eq!(read_all(&resolve(&root, "/hello.txt").unwrap()).len(), 3 * BLOCK_SZ + 7);
+  // host modes and mtimes are kept
+  for path in ["/hello.txt", "/etc/init.d", "/etc/init.d/rc"] {
+    let host = std::fs::metadata(tree.join(&path[1..]))?;
+    let meta = resolve(&root, path).unwrap().metadata();
+    assert_eq!(meta.mode, std::os::unix::fs::PermissionsExt::mode(&host.permissions()) & 0o7777);
+    assert_eq!(meta.mtime(), unix_nanos(host.modified()?));
+  }
+  assert_eq!(resolve(&root, "/hello.txt").unwrap().metadata().mode, 0o600);
+  assert!(fsck(&efs, false).is_clean());
+
+  // what does not fit is refused before anything is written
+  assert!(Geometry::new(parse_size("64K")?, 4096).is_err());
+  assert!(Geometry::new(1000, 100).is_err());
+  let tiny = Geometry::new(parse_size("1100K")?, 1)?;
+  assert!(plan.check(&tiny).is_err());
+  // the same names twice
+  assert!(plan.add_tree(tree).is_err());
+  Ok(())
+}
+
+#[test]
+fn dir_format_test() {
+  use easy_fs::{dir_size, FsckProblem, NAME_LENGTH_LIMIT};
+  let dev = MemDevice::new(4096);
+  let efs = FileSystem::create(dev.clone(), 4096, 1);
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  assert_eq!(root.name_max(), NAME_LENGTH_LIMIT);
+  let long = "l".repeat(NAME_LENGTH_LIMIT);
+  assert!(root.create(&(long.clone() + "l")).is_none());
+  let names: Vec<String> = (0..40).map(|i| format!("{}{}", "n".repeat(i * 6), i)).chain([long.clone()]).collect();
+  for name in &names {
+    root.create(name).unwrap().write_at(0, name.as_bytes());
+  }
+  assert_eq!(root.size(), dir_size(names.iter().map(String::as_str)));
+  assert_eq!(read_all(&root.find_name(&long).unwrap()), long.as_bytes());
+  // freed space goes to the next entry that fits
+  let size = root.size();
+  for name in names.iter().step_by(3) {
+    assert!(root.unlink(name));
+  }
+  for name in names.iter().step_by(3) {
+    root.create(name).unwrap();
+  }
+  assert_eq!(root.size(), size);
+  assert_eq!(root.ls().len(), names.len());
+  assert!(fsck(&efs, false).is_clean());
+
+  // a record pointing past its block
+  // `/` got the first block of the data area, which ends the image
+  let super_blk = efs.lock().super_block();
+  let dir_block = (super_blk.total_blocks - super_blk.data_area_blocks) as usize;
+  dev.blocks.lock().unwrap()[dir_block][4] = 0xff;
+  easy_fs::block_cache_release(&(dev.clone() as Arc<dyn BlockDevice>));
+  let problems = fsck(&efs, true).problems;
+  assert!(problems.contains(&FsckProblem::BadEntry { dir: 0, offset: 0 }), "{:?}", problems);
+  assert!(fsck(&efs, false).is_clean());
+  assert!(root.ls().len() < names.len());
+}
+
+/// Make a fresh image look like one of an older `version`
+#[cfg(test)]
+fn as_version(blocks: &mut [[u8; BLOCK_SZ]], version: u32) {
+  let super_blk = patch_super_block(&mut blocks[0], |super_blk| super_blk.version = version);
+  // the flags of the root inode, after its size, 30 block ids and type
+  if version < 4 {
+    let inode_area = super_blk.journal_start + super_blk.journal_blocks + super_blk.checksum_blocks + super_blk.inode_bitmap_blocks;
+    blocks[inode_area as usize][4 + 30 * 4 + 1] = 0;
+  }
+}
+
+/// Change the super block in `block` with `f`, keeping its checksum right
+#[cfg(test)]
+fn patch_super_block(block: &mut [u8; BLOCK_SZ], f: impl FnOnce(&mut easy_fs::SuperBlock)) -> easy_fs::SuperBlock {
+  let super_blk = block.as_mut_ptr() as *mut easy_fs::SuperBlock;
+  let mut copy = unsafe { super_blk.read_unaligned() };
+  f(&mut copy);
+  copy.seal();
+  unsafe { super_blk.write_unaligned(copy) };
+  copy
+}
+
+#[test]
+fn old_dir_format_test() {
+  let dev = MemDevice::new(4096);
+  FileSystem::create(dev.clone(), 4096, 1);
+  let mut blocks = dev.blocks.lock().unwrap().clone();
+  // version 0 images have 32 byte slots
+  as_version(&mut blocks, 0);
+  let dev = MemDevice::with_blocks(blocks);
+  let efs = FileSystem::open(dev.clone());
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  assert_eq!(root.name_max(), 27);
+  assert!(root.create(&"o".repeat(28)).is_none());
+  root.create(&"o".repeat(27)).unwrap();
+  root.create_dir("d").unwrap().create("f").unwrap().write_at(0, b"old");
+  assert_eq!(root.size(), 64);
+  assert!(root.unlink(&"o".repeat(27)));
+  root.create("e").unwrap().write_at(0, b"e");
+  assert_eq!(root.size(), 64);
+  root.create("g").unwrap();
+  Inode::rename(&root, "e", &root, "g").unwrap();
+  root.sync();
+
+  let efs = FileSystem::open(MemDevice::with_blocks(dev.blocks.lock().unwrap().clone()));
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  assert_eq!(root.ls(), ["d", "g"]);
+  assert_eq!(read_all(&root.find_name("g").unwrap()), b"e");
+  assert_eq!(read_all(&resolve(&root, "/d/f").unwrap()), b"old");
+  assert!(fsck(&efs, false).is_clean());
+}
+
+/// Images made before the journal: magic 0x3b800001, a super block of six fields, and
+/// the inode bitmap right after it
+#[test]
+fn baseline_image_test() {
+  let put = |block: &mut [u8; BLOCK_SZ], offset: usize, words: &[u32]| {
+    for (i, word) in words.iter().enumerate() {
+      block[offset + 4 * i..offset + 4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+  };
+  // 4096 blocks: super block, 1 inode bitmap block, 1024 inode blocks, 1 data bitmap block
+  let (inode_area, data_bitmap, data_area) = (2, 1026, 1027);
+  let mut blocks = vec![[0u8; BLOCK_SZ]; 4096];
+  put(&mut blocks[0], 0, &[0x3b800001, 4096, 1, 1024, 1, 3069]);
+  // the rest of the block was never part of the super block
+  blocks[0][24..64].fill(0xff);
+  blocks[1][0] = 0b11;
+  blocks[data_bitmap][0] = 0b11;
+  // `/` holds one 32 byte entry naming inode 1, a file of 5 bytes
+  let inode = |size: u32, block: u32, dir: bool| {
+    let mut bytes = [0u8; 128];
+    bytes[..8].copy_from_slice(&[size.to_le_bytes(), block.to_le_bytes()].concat());
+    bytes[124] = dir as u8;
+    bytes
+  };
+  blocks[inode_area][..128].copy_from_slice(&inode(32, data_area as u32, true));
+  blocks[inode_area][128..256].copy_from_slice(&inode(5, data_area as u32 + 1, false));
+  blocks[data_area][..5].copy_from_slice(b"hello");
+  put(&mut blocks[data_area], 28, &[1]);
+  blocks[data_area + 1][..5].copy_from_slice(b"world");
+
+  let dev = MemDevice::with_blocks(blocks.clone());
+  let block_dev: Arc<dyn BlockDevice> = dev.clone();
+  assert!(FileSystem::probe(&block_dev));
+  let efs = FileSystem::open(block_dev.clone());
+  let super_blk = efs.lock().super_block();
+  assert_eq!((super_blk.version, super_blk.journal_blocks, super_blk.checksum_blocks), (0, 0, 0));
+  let report = fsck(&efs, false);
+  assert!(report.is_clean(), "{:?}", report.problems);
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  assert_eq!(root.name_max(), 27);
+  assert_eq!(root.ls(), ["hello"]);
+  assert_eq!(read_all(&root.find_name("hello").unwrap()), b"world");
+
+  // changes go straight home, the super block stays as it was
+  root.create("new").unwrap().write_at(0, &[b'n'; 3 * BLOCK_SZ]);
+  assert!(root.unlink("hello"));
+  root.sync();
+  assert_eq!(dev.blocks.lock().unwrap()[0], blocks[0]);
+  easy_fs::block_cache_release(&block_dev);
+  let efs = FileSystem::open(block_dev);
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  assert_eq!(root.ls(), ["new"]);
+  assert_eq!(read_all(&root.find_name("new").unwrap()), [b'n'; 3 * BLOCK_SZ]);
+  assert_eq!(root.size(), 64);
+  let report = fsck(&efs, false);
+  assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[cfg(test)]
+static TEST_CLOCK: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+#[test]
+fn inode_meta_test() {
+  use std::sync::atomic::Ordering;
+  let tick = |to: u64| TEST_CLOCK.store(to, Ordering::Relaxed);
+  let dev = MemDevice::new(4096);
+  let efs = FileSystem::create(dev.clone(), 4096, 1);
+  efs.lock().set_clock(|| TEST_CLOCK.load(Ordering::Relaxed));
+  let root = Arc::new(FileSystem::root_inode(&efs));
+  tick(10);
+  let file = root.create("f").unwrap();
+  let meta = file.metadata();
+  assert_eq!((meta.mode, meta.uid, meta.gid), (0o644, 0, 0));
+  assert_eq!((meta.atime(), meta.mtime(), meta.ctime()), (10, 10, 10));
+  assert_eq!(root.metadata().mtime(), 10);
+  assert_eq!(root.create_dir("d").unwrap().metadata().mode, 0o755);
+
+  tick(20);
+  file.write_at(0, b"data");
+  let meta = file.metadata();
+  assert_eq!((meta.atime(), meta.mtime(), meta.ctime()), (10, 20, 20));
+  // the first read after a change is recorded, later ones are not
+  tick(30);
+  read_all(&file);
+  assert_eq!(file.metadata().atime(), 30);
+  tick(40);
+  read_all(&file);
+  assert_eq!(file.metadata().atime(), 30);
+
+  tick(50);
+  assert!(file.set_mode(0o4711));
+  assert!(file.set_times(Some(5), None));
+  let meta = file.metadata();
+  assert_eq!((meta.mode, meta.atime(), meta.mtime(), meta.ctime()), (0o4711, 5, 20, 50));
+  tick(60);
+  assert!(root.unlink("f"));
+  assert_eq!(root.metadata().mtime(), 60);
+  assert!(fsck(&efs, false).is_clean());
+
+  // images from before `InodeMeta` report the defaults and refuse changes
+  let mut blocks = dev.blocks.lock().unwrap().clone();
+  as_version(&mut blocks, 1);
+  let efs = FileSystem::open(MemDevice::with_blocks(blocks));
+  let root = FileSystem::root_inode(&efs);
+  assert_eq!((root.metadata().mode, root.metadata().mtime()), (0o755, 0));
+  assert!(!root.set_mode(0o700));
+}
+
+#[test]
+fn large_file_test() {
+  use easy_fs::{MAX_FILE_SIZE, SMALL_MAX_FILE_SIZE};
+  let dev = MemDevice::new(65536);
+  let block_dev: Arc<dyn BlockDevice> = dev.clone();
+  let efs = FileSystem::create(dev.clone(), 65536, 1);
+  let root = FileSystem::root_inode(&efs);
+  let file = root.create("large").unwrap();
+  let free = efs.lock().free_data_blocks();
+
+  // 20 MiB reach well into the triple indirect block; every block is numbered
+  let size: usize = 20 << 20;
+  assert!(size as u64 > SMALL_MAX_FILE_SIZE && (size as u64) < MAX_FILE_SIZE);
+  let data: Vec<u8> = (0..size as u32 / 4).flat_map(|i| (i / 128).to_le_bytes()).collect();
+  assert_eq!(file.write_at(0, &data), size);
+  assert_eq!(file.size(), size);
+  assert_eq!(read_all(&file), data);
+  assert_eq!(free - efs.lock().free_data_blocks(), easy_fs::blocks_for_size(size as u64) as usize);
+  let report = fsck(&efs, false);
+  assert!(report.is_clean(), "{:?}", report.problems);
+
+  // rewriting across the boundary of the double and triple indirect blocks
+  let boundary = SMALL_MAX_FILE_SIZE as usize - BLOCK_SZ;
+  file.write_at(boundary - 100, &[b'x'; 2 * BLOCK_SZ]);
+  let mut buf = vec![0u8; 2 * BLOCK_SZ + 200];
+  assert_eq!(file.read_at(boundary - 200, &mut buf), buf.len());
+  assert_eq!(&buf[..100], &data[boundary - 200..boundary - 100]);
+  assert!(buf[100..100 + 2 * BLOCK_SZ].iter().all(|&byte| byte == b'x'));
+  assert_eq!(&buf[100 + 2 * BLOCK_SZ..], &data[boundary + 2 * BLOCK_SZ - 100..boundary + 2 * BLOCK_SZ]);
+
+  // all of it survives reopening, and goes when cleared
+  easy_fs::block_cache_release(&block_dev);
+  let efs = FileSystem::open(block_dev.clone());
+  let file = FileSystem::root_inode(&efs).find_name("large").unwrap();
+  assert_eq!(file.size(), size);
+  let mut buf = [0u8; 4];
+  file.read_at(size - 4, &mut buf);
+  assert_eq!(u32::from_le_bytes(buf) as usize, (size - 4) / BLOCK_SZ);
+  file.clear();
+  assert_eq!(efs.lock().free_data_blocks(), free);
+  assert!(fsck(&efs, false).is_clean());
+  drop(file);
+  assert!(FileSystem::root_inode(&efs).unlink("large"));
+  easy_fs::block_cache_sync(&block_dev);
+
+  // files of older images stop at the double indirect block
+  let mut blocks = dev.blocks.lock().unwrap().clone();
+  as_version(&mut blocks, 3);
+  let efs = FileSystem::open(MemDevice::with_blocks(blocks));
+  let file = FileSystem::root_inode(&efs).create("small").unwrap();
+  assert_eq!(file.write_at(0, &data[..SMALL_MAX_FILE_SIZE as usize + 1000]), SMALL_MAX_FILE_SIZE as usize);
+  assert_eq!(file.write_at(SMALL_MAX_FILE_SIZE as usize, b"more"), 0);
+  assert_eq!(read_all(&file), &data[..SMALL_MAX_FILE_SIZE as usize]);
+  let report = fsck(&efs, false);
+  assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn shrink_test() {
+  let dev = MemDevice::new(65536);
+  let efs = FileSystem::create(dev.clone(), 65536, 1);
+  let root = FileSystem::root_inode(&efs);
+  let file = root.create("shrink").unwrap();
+  let free = efs.lock().free_data_blocks();
+  let used = || free - efs.lock().free_data_blocks();
+
+  // just past the triple indirect block; every block is numbered
+  let direct = 27;
+  let boundaries = [direct, direct + 128, easy_fs::SMALL_MAX_FILE_SIZE as usize / BLOCK_SZ - 1];
+  let size = (boundaries[2] + 200) * BLOCK_SZ;
+  let data: Vec<u8> = (0..size as u32 / 4).flat_map(|i| (i / 128).to_le_bytes()).collect();
+  assert_eq!(file.write_at(0, &data), size);
+
+  // down across every boundary, up to the middle of the block before it
+  for &boundary in boundaries.iter().rev() {
+    let size = boundary * BLOCK_SZ - 100;
+    assert!(file.shrink(size as u64));
+    assert_eq!(file.size(), size);
+    assert_eq!(read_all(&file), &data[..size]);
+    assert_eq!(used(), easy_fs::blocks_for_size(size as u64) as usize);
+    let report = fsck(&efs, false);
+    assert!(report.is_clean(), "{:?}", report.problems);
+  }
+  // growing shows zeroes where the cut block held data, and crosses all boundaries again
+  let size = boundaries[0] * BLOCK_SZ - 100;
+  let end = (boundaries[2] + 3) * BLOCK_SZ;
+  assert_eq!(file.write_at(end - 4, b"tail"), 4);
+  let grown = read_all(&file);
+  assert_eq!(grown.len(), end);
+  assert_eq!(&grown[..size], &data[..size]);
+  assert!(grown[size..end - 4].iter().all(|&byte| byte == 0));
+  assert_eq!(&grown[end - 4..], b"tail");
+  assert_eq!(used(), easy_fs::blocks_for_size(end as u64) as usize);
+  assert!(file.shrink(end as u64 + 1) && file.size() == end);
+  file.clear();
+  assert_eq!((file.size(), used()), (0, 0));
+  assert!(fsck(&efs, false).is_clean());
+}
+
+/// Files too large to free in one transaction are freed in several
+#[test]
+fn huge_file_test() {
+  let blocks = 560_000;
+  let dev = MemDevice::new(blocks);
+  let efs = FileSystem::create(dev.clone(), blocks as u32, 1);
+  let root = FileSystem::root_inode(&efs);
+  let files = ["cleared", "unlinked"].map(|name| (name, root.create(name).unwrap()));
+  let free = efs.lock().free_data_blocks();
+  // the journal holds 125 blocks, each data bitmap block covers 2 MiB
+  let size = 250 << 20;
+  let chunk = vec![0x5au8; 1 << 20];
+  for (name, file) in files {
+    for offset in (0..size).step_by(chunk.len()) {
+      assert_eq!(file.write_at(offset, &chunk), chunk.len());
+    }
+    assert_eq!(file.size(), size);
+    if name == "cleared" {
+      file.clear();
+      assert_eq!(file.size(), 0);
+    }
+    drop(file);
+    assert!(root.unlink(name));
+    assert_eq!(efs.lock().free_data_blocks(), free);
+    assert!(root.find_name(name).is_none());
+    let report = fsck(&efs, false);
+    assert!(report.is_clean(), "{:?}", report.problems);
+  }
+}
+
+/// A device on a copy of `image` with the byte at `offset` flipped, and that copy
+#[cfg(test)]
+fn corrupt_copy(image: &[u8], offset: usize) -> std::io::Result<(Arc<dyn BlockDevice>, Vec<u8>)> {
+  let mut image = image.to_vec();
+  image[offset] ^= 0x10;
+  let path = "target/checksum-corrupt.img";
+  std::fs::write(path, &image)?;
+  let file = OpenOptions::new().read(true).write(true).open(path)?;
+  Ok((Arc::new(BlockFile(Mutex::new(file))), image))
+}
+
+#[test]
+fn checksum_test() -> std::io::Result<()> {
+  use easy_fs::{ChecksumPolicy, FsckProblem};
+  let image = "target/checksum.img";
+  let dev: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new({
+    let f = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(image)?;
+    f.set_len(8192 * BLOCK_SZ as u64)?;
+    f
+  })));
+  let efs = FileSystem::create(dev.clone(), 8192, 1);
+  let root = FileSystem::root_inode(&efs);
+  let small = root.create("small").unwrap();
+  small.write_at(0, b"small");
+  let big = root.create("big").unwrap();
+  let big_data = vec![b'b'; 100 * BLOCK_SZ];
+  big.write_at(0, &big_data);
+  let big_id = big.inode_id() as usize;
+  root.sync();
+  easy_fs::block_cache_release(&dev);
+  let pristine = std::fs::read(image)?;
+
+  let super_blk = unsafe { (pristine.as_ptr() as *const easy_fs::SuperBlock).read_unaligned() };
+  let inode_bitmap = (super_blk.journal_start + super_blk.journal_blocks + super_blk.checksum_blocks) as usize;
+  let inode_area = inode_bitmap + super_blk.inode_bitmap_blocks as usize;
+  let data_bitmap = inode_area + super_blk.inode_area_blocks as usize;
+  // two 256 byte inodes a block; the indirect block id follows the size and 28 block ids
+  let big_inode = (inode_area + big_id / 2) * BLOCK_SZ + big_id % 2 * 256;
+  let indirect = u32::from_ne_bytes(pristine[big_inode + 116..big_inode + 120].try_into().unwrap()) as usize;
+  assert!(indirect > data_bitmap);
+
+  // a corrupt super block is no easy-fs image, until fsck fixes it; here the free data
+  // block count got the flip
+  let (dev, _) = corrupt_copy(&pristine, 41)?;
+  assert!(!FileSystem::probe(&dev));
+  let report = fsck(&FileSystem::open(dev.clone()), true);
+  assert_eq!(report.problems[0], FsckProblem::BadChecksum { block: 0 });
+  assert!(matches!(report.problems[1..], [FsckProblem::WrongFreeBlocks { .. }]));
+  easy_fs::block_cache_release(&dev);
+  assert!(FileSystem::probe(&dev));
+  easy_fs::block_cache_release(&dev);
+
+  // panic on a corrupt inode, before anything is written
+  let (dev, corrupt) = corrupt_copy(&pristine, big_inode)?;
+  let efs = FileSystem::open(dev.clone());
+  efs.lock().set_checksum_policy(ChecksumPolicy::Panic);
+  let root = FileSystem::root_inode(&efs);
+  let big = root.find_name("big").unwrap();
+  assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| big.size())).is_err());
+  easy_fs::block_cache_release(&dev);
+  assert!(std::fs::read("target/checksum-corrupt.img")? == corrupt);
+
+  // a corrupt indirect block fails what uses it, the rest of the image still works
+  let (dev, _) = corrupt_copy(&pristine, indirect * BLOCK_SZ + 3)?;
+  let efs = FileSystem::open(dev.clone());
+  efs.lock().set_checksum_policy(ChecksumPolicy::Error);
+  let root = FileSystem::root_inode(&efs);
+  let big = root.find_name("big").unwrap();
+  let data = read_all(&big);
+  // the last direct block id is the triple indirect one
+  assert_eq!(data[..27 * BLOCK_SZ], big_data[..27 * BLOCK_SZ]);
+  assert!(data[27 * BLOCK_SZ..].iter().all(|&byte| byte == 0));
+  assert_eq!(big.take_corrupt_blocks(), [indirect]);
+  assert_eq!(big.write_at(50 * BLOCK_SZ, b"lost"), 0);
+  assert_eq!(big.take_corrupt_blocks(), [indirect]);
+  assert_eq!(root.find_name("small").unwrap().write_at(0, b"SMALL"), 5);
+  assert!(big.take_corrupt_blocks().is_empty() && !big.is_read_only());
+  root.sync();
+  easy_fs::block_cache_release(&dev);
+  let efs = FileSystem::open(dev.clone());
+  let root = FileSystem::root_inode(&efs);
+  assert_eq!(read_all(&root.find_name("small").unwrap()), b"SMALL");
+  assert_eq!(fsck(&efs, false).problems, [FsckProblem::BadChecksum { block: indirect as u32 }]);
+  // repairing trusts the block again, and then drops the file it breaks
+  let report = fsck(&efs, true);
+  assert!(report.repaired && report.problems.contains(&FsckProblem::BadChecksum { block: indirect as u32 }));
+  assert!(fsck(&efs, false).is_clean());
+  assert_eq!(read_all(&root.find_name("small").unwrap()), b"SMALL");
+  easy_fs::block_cache_release(&dev);
+
+  // a corrupt bitmap makes the image read-only: nothing reaches the file from then on
+  let (dev, corrupt) = corrupt_copy(&pristine, data_bitmap * BLOCK_SZ + 1)?;
+  let efs = FileSystem::open(dev.clone());
+  let root = FileSystem::root_inode(&efs);
+  let small = root.find_name("small").unwrap();
+  assert_eq!(small.write_at(0, &[b's'; 3 * BLOCK_SZ]), 0);
+  assert!(small.is_read_only());
+  assert_eq!(small.take_corrupt_blocks(), [data_bitmap]);
+  assert!(root.create("new").is_none());
+  assert_eq!(small.write_at(0, b"x"), 0);
+  assert_eq!(read_all(&small), b"small");
+  // a rename whose transaction is dropped says so, and moves nothing
+  assert_eq!(Inode::rename(&root, "small", &root, "moved"), Err(easy_fs::RenameError::Dropped));
+  assert!(root.find_name("moved").is_none());
+  assert_eq!(read_all(&root.find_name("small").unwrap()), b"small");
+  root.sync();
+  easy_fs::block_cache_release(&dev);
+  assert!(std::fs::read("target/checksum-corrupt.img")? == corrupt);
+  Ok(())
+}
@@ -0,0 +1,235 @@
+//! Packing host files into a fresh image
+//!
+//! Everything is collected and checked against the image geometry before the image is
+//! touched, and directories are packed in name order, so the same inputs always give
+//! byte-identical images.
+
+use easy_fs::{blocks_for_size, dir_size, BlockDevice, FileSystem, Inode, BLOCK_SZ, MAX_FILE_SIZE, NAME_LENGTH_LIMIT};
+use std::collections::{btree_map::Entry, BTreeMap};
+use std::fs::{metadata, read_dir, Metadata};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Inodes one block of inode bitmap tracks
+const INODES_PER_BITMAP_BLOCK: u64 = (BLOCK_SZ * 8) as u64;
+
+fn invalid(msg: String) -> Error {
+  Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/// `time` in nanoseconds since the Unix epoch, 0 if it is earlier
+pub fn unix_nanos(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// Parse `32M`, `512K`, `1G` or a plain number of bytes
+pub fn parse_size(text: &str) -> Result<u64> {
+  let (digits, unit) = match text.char_indices().find(|(_, ch)| !ch.is_ascii_digit()) {
+    Some((idx, _)) => text.split_at(idx),
+    None => (text, ""),
+  };
+  let shift = match unit {
+    "" => 0,
+    "K" | "k" => 10,
+    "M" | "m" => 20,
+    "G" | "g" => 30,
+    _ => return Err(invalid(format!("{}: unknown size unit, use K, M or G", text))),
+  };
+  digits
+    .parse::<u64>()
+    .ok()
+    .and_then(|number| number.checked_mul(1 << shift))
+    .ok_or_else(|| invalid(format!("{}: not a size", text)))
+}
+
+/// Number of blocks of an image and of its inode bitmap
+#[derive(Clone, Copy, Debug)]
+pub struct Geometry {
+  pub total_blocks: u32,
+  pub inode_bitmap_blocks: u32,
+}
+
+impl Geometry {
+  /// An image of `size` bytes with room for at least `inodes` inodes. Blocks are always
+  /// `BLOCK_SZ` bytes, the size easy-fs is built for.
+  pub fn new(size: u64, inodes: u64) -> Result<Self> {
+    let total_blocks = u32::try_from(size / BLOCK_SZ as u64)
+      .map_err(|_| invalid(format!("image size {} is too large", size)))?;
+    if total_blocks as u64 * BLOCK_SZ as u64 != size {
+      return Err(invalid(format!("image size {} is not a multiple of the block size", size)));
+    }
+    if inodes == 0 {
+      return Err(invalid("an image needs at least the inode of /".to_string()));
+    }
+    let inode_bitmap_blocks = u32::try_from((inodes - 1) / INODES_PER_BITMAP_BLOCK + 1)
+      .map_err(|_| invalid(format!("{} inodes are too many", inodes)))?;
+    FileSystem::layout(total_blocks, inode_bitmap_blocks).ok_or_else(|| invalid(format!(
+      "an image of {} blocks is too small for the metadata of {} inodes",
+      total_blocks, inode_bitmap_blocks as u64 * INODES_PER_BITMAP_BLOCK
+    )))?;
+    Ok(Self { total_blocks, inode_bitmap_blocks })
+  }
+
+  pub fn inodes(&self) -> u64 {
+    self.inode_bitmap_blocks as u64 * INODES_PER_BITMAP_BLOCK
+  }
+
+  pub fn data_blocks(&self) -> u64 {
+    FileSystem::layout(self.total_blocks, self.inode_bitmap_blocks).unwrap().data_area_blocks as u64
+  }
+}
+
+/// What is kept of the host metadata
+#[derive(Clone, Copy)]
+struct HostMeta {
+  mode: u32,
+  mtime: u64,
+}
+
+impl HostMeta {
+  fn of(meta: &Metadata) -> Result<Self> {
+    Ok(Self { mode: meta.permissions().mode() & 0o7777, mtime: unix_nanos(meta.modified()?) })
+  }
+}
+
+enum Node {
+  Dir { children: BTreeMap<String, Node>, meta: HostMeta },
+  File { host: PathBuf, size: u64, meta: HostMeta },
+}
+
+/// The files and directories to put in an image, by name
+#[derive(Default)]
+pub struct Plan {
+  root: BTreeMap<String, Node>,
+}
+
+impl Plan {
+  fn insert(dir: &mut BTreeMap<String, Node>, name: String, node: Node, host: &Path) -> Result<()> {
+    if name.len() > NAME_LENGTH_LIMIT {
+      return Err(invalid(format!("{}: the name is longer than {} bytes", host.display(), NAME_LENGTH_LIMIT)));
+    }
+    if let Node::File { size, .. } = node {
+      if size > MAX_FILE_SIZE {
+        return Err(invalid(format!("{}: {} bytes, easy-fs files hold at most {}", host.display(), size, MAX_FILE_SIZE)));
+      }
+    }
+    match dir.entry(name) {
+      Entry::Vacant(entry) => {
+        entry.insert(node);
+        Ok(())
+      }
+      Entry::Occupied(entry) => Err(invalid(format!("{}: {} is in the image already", host.display(), entry.key()))),
+    }
+  }
+
+  /// Add, for every app source in `src_dir`, the ELF named like it without the extension
+  /// from `elf_dir`
+  pub fn add_apps(&mut self, src_dir: &Path, elf_dir: &Path) -> Result<()> {
+    for entry in read_dir(src_dir)? {
+      let src = entry?.path();
+      let name = src
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| invalid(format!("{}: not a valid name", src.display())))?;
+      let host = elf_dir.join(name);
+      let meta = metadata(&host).map_err(|err| Error::new(err.kind(), format!("{}: {}", host.display(), err)))?;
+      let node = Node::File { host: host.clone(), size: meta.len(), meta: HostMeta::of(&meta)? };
+      Self::insert(&mut self.root, name.to_string(), node, &host)?;
+    }
+    Ok(())
+  }
+
+  /// Add everything under `dir` to `/`, keeping its directories
+  pub fn add_tree(&mut self, dir: &Path) -> Result<()> {
+    Self::add_dir(&mut self.root, dir)
+  }
+
+  fn add_dir(into: &mut BTreeMap<String, Node>, dir: &Path) -> Result<()> {
+    for entry in read_dir(dir)? {
+      let entry = entry?;
+      let host = entry.path();
+      let name = entry
+        .file_name()
+        .into_string()
+        .map_err(|_| invalid(format!("{}: not a valid name", host.display())))?;
+      // follows symlinks
+      let meta = metadata(&host)?;
+      let node = if meta.is_dir() {
+        let mut children = BTreeMap::new();
+        Self::add_dir(&mut children, &host)?;
+        Node::Dir { children, meta: HostMeta::of(&meta)? }
+      } else if meta.is_file() {
+        Node::File { host: host.clone(), size: meta.len(), meta: HostMeta::of(&meta)? }
+      } else {
+        return Err(invalid(format!("{}: neither a file nor a directory", host.display())));
+      };
+      Self::insert(into, name, node, &host)?;
+    }
+    Ok(())
+  }
+
+  /// Inodes and data blocks of the entries of a directory, and of the directory itself
+  fn usage(dir: &BTreeMap<String, Node>) -> (u64, u64) {
+    let own_blocks = blocks_for_size(dir_size(dir.keys().map(String::as_str)) as u64);
+    dir.values().fold((1, own_blocks), |(inodes, blocks), node| {
+      let (node_inodes, node_blocks) = match node {
+        Node::Dir { children, .. } => Self::usage(children),
+        Node::File { size, .. } => (1, blocks_for_size(*size)),
+      };
+      (inodes + node_inodes, blocks + node_blocks)
+    })
+  }
+
+  /// Check everything fits in an image of `geometry`
+  pub fn check(&self, geometry: &Geometry) -> Result<()> {
+    let (inodes, blocks) = Self::usage(&self.root);
+    if inodes > geometry.inodes() {
+      return Err(invalid(format!("{} files and directories need {} inodes, the image has {}", inodes - 1, inodes, geometry.inodes())));
+    }
+    if blocks > geometry.data_blocks() {
+      return Err(invalid(format!("the files need {} data blocks, the image has {}", blocks, geometry.data_blocks())));
+    }
+    Ok(())
+  }
+
+  /// Create a filesystem of `geometry` on `block_dev` and copy everything in
+  pub fn pack(&self, block_dev: Arc<dyn BlockDevice>, geometry: &Geometry) -> Result<()> {
+    self.check(geometry)?;
+    let efs = FileSystem::create(block_dev, geometry.total_blocks, geometry.inode_bitmap_blocks);
+    let root = FileSystem::root_inode(&efs);
+    Self::pack_dir(&root, &self.root)?;
+    root.sync();
+    Ok(())
+  }
+
+  fn pack_dir(dir: &Inode, children: &BTreeMap<String, Node>) -> Result<()> {
+    for (name, node) in children {
+      let (inode, meta) = match node {
+        Node::Dir { children: grandchildren, meta } => {
+          let inode = dir.create_dir(name).unwrap();
+          Self::pack_dir(&inode, grandchildren)?;
+          (inode, meta)
+        }
+        Node::File { host, size, meta } => {
+          let data = std::fs::read(host)?;
+          if data.len() as u64 != *size {
+            return Err(invalid(format!("{}: changed while packing", host.display())));
+          }
+          let inode = dir.create(name).unwrap();
+          if inode.write_at(0, &data) != data.len() {
+            return Err(Error::other(format!("{}: could not write it all to the image", host.display())));
+          }
+          (inode, meta)
+        }
+      };
+      // last, as filling the inode changes its times
+      if !inode.set_mode(meta.mode) || !inode.set_times(Some(meta.mtime), Some(meta.mtime)) {
+        return Err(Error::other(format!("{}: could not set its mode and times", name)));
+      }
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,424 @@
+//! Mount an easy-fs image on the host through FUSE:
+//!
+//! ```text
+//! easy-fs-mount fs.img /mnt/efs
+//! cp kernel-output.txt /mnt/efs/ && ls -l /mnt/efs
+//! fusermount -u /mnt/efs
+//! ```
+//!
+//! FUSE inode numbers are easy-fs inode ids plus one, since FUSE reserves 1 for the root.
+//! Modes and times come from the image; owners do not, as peaCore has no users, so every
+//! file shows up as owned by the user who mounted it. Images from before easy-fs kept
+//! times show the time of the mount.
+
+use clap::{App, Arg};
+use easy_fs::{BlockDevice, FileSystem, Inode, RenameError, BLOCK_SZ};
+use fuser::{
+  FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+  ReplyEmpty, ReplyEntry, ReplyWrite, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+use std::collections::{hash_map::Entry, HashMap};
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the kernel may cache attributes and entries; nothing else changes the image
+const TTL: Duration = Duration::from_secs(1);
+
+struct BlockFile(Mutex<File>);
+
+impl BlockDevice for BlockFile {
+  fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    let mut file = self.0.lock().unwrap();
+    file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+        .expect("Error occurred when seeking");
+    assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+  }
+
+  fn write_block(&self, block_id: usize, buf: &[u8]) {
+    let mut file = self.0.lock().unwrap();
+    file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+        .expect("Error when seeking!");
+    assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+  }
+}
+
+struct EfsFuse {
+  /// every inode the kernel has been told about and not forgotten yet, by FUSE inode
+  /// number, with the number of lookups the kernel holds on it. Unlinked inodes stay, so
+  /// files open on the host keep working, until the kernel forgets them.
+  inodes: HashMap<u64, (Arc<Inode>, u64)>,
+  mounted_at: SystemTime,
+  uid: u32,
+  gid: u32,
+}
+
+impl EfsFuse {
+  fn new(root: Inode) -> Self {
+    let root = Arc::new(root);
+    Self {
+      inodes: HashMap::from([(FUSE_ROOT_ID, (root, 0))]),
+      mounted_at: SystemTime::now(),
+      uid: unsafe { libc::getuid() },
+      gid: unsafe { libc::getgid() },
+    }
+  }
+
+  fn inode(&self, ino: u64) -> Result<Arc<Inode>, libc::c_int> {
+    self.inodes.get(&ino).map(|(inode, _)| inode.clone()).ok_or(libc::ENOENT)
+  }
+
+  fn dir(&self, ino: u64) -> Result<Arc<Inode>, libc::c_int> {
+    let inode = self.inode(ino)?;
+    if inode.is_dir() { Ok(inode) } else { Err(libc::ENOTDIR) }
+  }
+
+  /// Remember `inode`, counting one more lookup of it, and describe it
+  fn remember(&mut self, inode: Arc<Inode>) -> FileAttr {
+    let attr = self.attr(&inode);
+    self.inodes.entry(attr.ino).or_insert((inode, 0)).1 += 1;
+    attr
+  }
+
+  /// An easy-fs time, 0 if the image keeps none
+  fn time(&self, nanos: u64) -> SystemTime {
+    match nanos {
+      0 => self.mounted_at,
+      nanos => UNIX_EPOCH + Duration::from_nanos(nanos),
+    }
+  }
+
+  fn attr(&self, inode: &Inode) -> FileAttr {
+    let size = inode.size() as u64;
+    let meta = inode.metadata();
+    let kind = if inode.is_dir() { FileType::Directory } else { FileType::RegularFile };
+    FileAttr {
+      ino: inode.inode_id() as u64 + 1,
+      size,
+      blocks: size.div_ceil(BLOCK_SZ as u64),
+      atime: self.time(meta.atime()),
+      mtime: self.time(meta.mtime()),
+      ctime: self.time(meta.ctime()),
+      crtime: self.time(meta.ctime()),
+      kind,
+      perm: meta.mode as u16,
+      nlink: if inode.is_dir() { 2 } else { 1 },
+      uid: self.uid,
+      gid: self.gid,
+      rdev: 0,
+      blksize: BLOCK_SZ as u32,
+      flags: 0,
+    }
+  }
+
+  /// Check `name` can be created in `parent`
+  fn new_name<'a>(&self, parent: u64, name: &'a OsStr) -> Result<(Arc<Inode>, &'a str), libc::c_int> {
+    let dir = self.dir(parent)?;
+    let name = name.to_str().ok_or(libc::EINVAL)?;
+    if name.len() > dir.name_max() {
+      return Err(libc::ENAMETOOLONG);
+    }
+    if dir.find_name(name).is_some() {
+      return Err(libc::EEXIST);
+    }
+    Ok((dir, name))
+  }
+
+  fn remove(&mut self, parent: u64, name: &OsStr, want_dir: bool) -> Result<(), libc::c_int> {
+    let dir = self.dir(parent)?;
+    let name = name.to_str().ok_or(libc::ENOENT)?;
+    let inode = dir.find_name(name).ok_or(libc::ENOENT)?;
+    match (inode.is_dir(), want_dir) {
+      (true, false) => return Err(libc::EISDIR),
+      (false, true) => return Err(libc::ENOTDIR),
+      _ => {}
+    }
+    if !dir.unlink(name) {
+      return Err(libc::ENOTEMPTY);
+    }
+    Ok(())
+  }
+
+  /// Move `name` of `parent` to `new_name` of `new_parent`
+  fn move_entry(&mut self, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr, flags: u32) -> Result<(), libc::c_int> {
+    let (dir, new_dir) = (self.dir(parent)?, self.dir(new_parent)?);
+    let name = name.to_str().ok_or(libc::ENOENT)?;
+    let new_name = new_name.to_str().ok_or(libc::EINVAL)?;
+    if flags & !libc::RENAME_NOREPLACE != 0 {
+      return Err(libc::EINVAL);
+    }
+    if new_name.len() > new_dir.name_max() {
+      return Err(libc::ENAMETOOLONG);
+    }
+    let moved = dir.find_name(name).ok_or(libc::ENOENT)?;
+    let replaced = new_dir.find_name(new_name).filter(|replaced| replaced.inode_id() != moved.inode_id());
+    if replaced.is_some() && flags & libc::RENAME_NOREPLACE != 0 {
+      return Err(libc::EEXIST);
+    }
+    Inode::rename(&dir, name, &new_dir, new_name).map_err(|err| match err {
+      RenameError::NotFound => libc::ENOENT,
+      RenameError::InvalidName | RenameError::IntoItself => libc::EINVAL,
+      RenameError::CrossDevice => libc::EXDEV,
+      RenameError::NotDir => libc::ENOTDIR,
+      RenameError::IsDir => libc::EISDIR,
+      RenameError::NotEmpty => libc::ENOTEMPTY,
+      RenameError::Dropped => libc::EIO,
+    })?;
+    Ok(())
+  }
+
+  /// Grow with zeroes or shrink to `size`. Fails with EIO if a transaction was dropped.
+  fn truncate(inode: &Inode, size: usize) -> Result<(), i32> {
+    let old_size = inode.size();
+    let done = if size > old_size {
+      inode.write_at(old_size, &vec![0u8; size - old_size]) == size - old_size
+    } else {
+      inode.shrink(size as u64)
+    };
+    if done { Ok(()) } else { Err(libc::EIO) }
+  }
+}
+
+impl Filesystem for EfsFuse {
+  fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    let found = self.dir(parent).and_then(|dir| {
+      name.to_str().and_then(|name| dir.find_name(name)).ok_or(libc::ENOENT)
+    });
+    match found {
+      Ok(inode) => reply.entry(&TTL, &self.remember(inode), 0),
+      Err(err) => reply.error(err),
+    }
+  }
+
+  /// `batch_forget` comes here too, once per inode
+  fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+    if let Entry::Occupied(mut entry) = self.inodes.entry(ino) {
+      let lookups = &mut entry.get_mut().1;
+      *lookups = lookups.saturating_sub(nlookup);
+      if *lookups == 0 && ino != FUSE_ROOT_ID {
+        entry.remove();
+      }
+    }
+  }
+
+  fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+    match self.inode(ino) {
+      Ok(inode) => reply.attr(&TTL, &self.attr(&inode)),
+      Err(err) => reply.error(err),
+    }
+  }
+
+  /// The size, permission bits and times can change; owners and the other times are
+  /// accepted and ignored
+  fn setattr(
+    &mut self,
+    _req: &Request<'_>,
+    ino: u64,
+    mode: Option<u32>,
+    _uid: Option<u32>,
+    _gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<TimeOrNow>,
+    mtime: Option<TimeOrNow>,
+    _ctime: Option<SystemTime>,
+    _fh: Option<u64>,
+    _crtime: Option<SystemTime>,
+    _chgtime: Option<SystemTime>,
+    _bkuptime: Option<SystemTime>,
+    _flags: Option<u32>,
+    reply: ReplyAttr,
+  ) {
+    let inode = match self.inode(ino) {
+      Ok(inode) => inode,
+      Err(err) => return reply.error(err),
+    };
+    if let Some(size) = size {
+      if inode.is_dir() {
+        return reply.error(libc::EISDIR);
+      }
+      if let Err(err) = Self::truncate(&inode, size as usize) {
+        return reply.error(err);
+      }
+    }
+    if let Some(mode) = mode {
+      if !inode.set_mode(mode) {
+        return reply.error(libc::EOPNOTSUPP);
+      }
+    }
+    if atime.is_some() || mtime.is_some() {
+      let nanos = |time: TimeOrNow| match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+      }
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |since| since.as_nanos() as u64);
+      if !inode.set_times(atime.map(nanos), mtime.map(nanos)) {
+        return reply.error(libc::EOPNOTSUPP);
+      }
+    }
+    reply.attr(&TTL, &self.attr(&inode));
+  }
+
+  fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+    let dir = match self.dir(ino) {
+      Ok(dir) => dir,
+      Err(err) => return reply.error(err),
+    };
+    // easy-fs has no `.` and `..` entries; the parent is not known here, and the kernel
+    // resolves `..` itself anyway
+    let mut entries = vec![(ino, FileType::Directory, ".".to_string()), (ino, FileType::Directory, "..".to_string())];
+    for name in dir.ls() {
+      if let Some(child) = dir.find_name(&name) {
+        let kind = if child.is_dir() { FileType::Directory } else { FileType::RegularFile };
+        entries.push((child.inode_id() as u64 + 1, kind, name));
+      }
+    }
+    for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+      // the offset passed back is that of the next entry
+      if reply.add(ino, i as i64 + 1, kind, name) {
+        break;
+      }
+    }
+    reply.ok();
+  }
+
+  fn read(
+    &mut self,
+    _req: &Request<'_>,
+    ino: u64,
+    _fh: u64,
+    offset: i64,
+    size: u32,
+    _flags: i32,
+    _lock_owner: Option<u64>,
+    reply: ReplyData,
+  ) {
+    match self.inode(ino) {
+      Ok(inode) if inode.is_dir() => reply.error(libc::EISDIR),
+      Ok(inode) => {
+        let mut buf = vec![0u8; size as usize];
+        let len = inode.read_at(offset as usize, &mut buf);
+        reply.data(&buf[..len]);
+      }
+      Err(err) => reply.error(err),
+    }
+  }
+
+  fn write(
+    &mut self,
+    _req: &Request<'_>,
+    ino: u64,
+    _fh: u64,
+    offset: i64,
+    data: &[u8],
+    _write_flags: u32,
+    _flags: i32,
+    _lock_owner: Option<u64>,
+    reply: ReplyWrite,
+  ) {
+    match self.inode(ino) {
+      Ok(inode) if inode.is_dir() => reply.error(libc::EISDIR),
+      Ok(inode) => reply.written(inode.write_at(offset as usize, data) as u32),
+      Err(err) => reply.error(err),
+    }
+  }
+
+  fn create(
+    &mut self,
+    _req: &Request<'_>,
+    parent: u64,
+    name: &OsStr,
+    _mode: u32,
+    _umask: u32,
+    flags: i32,
+    reply: ReplyCreate,
+  ) {
+    let created = self.new_name(parent, name).and_then(|(dir, name)| dir.create(name).ok_or(libc::EINVAL));
+    match created {
+      Ok(inode) => reply.created(&TTL, &self.remember(inode), 0, 0, flags as u32),
+      Err(err) => reply.error(err),
+    }
+  }
+
+  fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
+    let created = self.new_name(parent, name).and_then(|(dir, name)| dir.create_dir(name).ok_or(libc::EINVAL));
+    match created {
+      Ok(inode) => reply.entry(&TTL, &self.remember(inode), 0),
+      Err(err) => reply.error(err),
+    }
+  }
+
+  fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    match self.remove(parent, name, false) {
+      Ok(()) => reply.ok(),
+      Err(err) => reply.error(err),
+    }
+  }
+
+  fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    match self.remove(parent, name, true) {
+      Ok(()) => reply.ok(),
+      Err(err) => reply.error(err),
+    }
+  }
+
+  fn rename(
+    &mut self,
+    _req: &Request<'_>,
+    parent: u64,
+    name: &OsStr,
+    newparent: u64,
+    newname: &OsStr,
+    flags: u32,
+    reply: ReplyEmpty,
+  ) {
+    match self.move_entry(parent, name, newparent, newname, flags) {
+      Ok(()) => reply.ok(),
+      Err(err) => reply.error(err),
+    }
+  }
+
+  fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+    match self.inode(ino) {
+      Ok(inode) => {
+        inode.sync();
+        reply.ok();
+      }
+      Err(err) => reply.error(err),
+    }
+  }
+
+  /// Unmounted: write back whatever is still cached
+  fn destroy(&mut self) {
+    self.inodes[&FUSE_ROOT_ID].0.sync();
+  }
+}
+
+fn main() {
+  let matches = App::new("easy-fs FUSE mount")
+    .arg(Arg::with_name("image").required(true).help("easy-fs image"))
+    .arg(Arg::with_name("mountpoint").required(true).help("Directory to mount it on"))
+    .arg(Arg::with_name("read-only").short("r").long("read-only").help("Mount read-only"))
+    .get_matches();
+  let image = matches.value_of("image").unwrap();
+  let read_only = matches.is_present("read-only");
+
+  let file = OpenOptions::new()
+    .read(true)
+    .write(!read_only)
+    .open(image)
+    .unwrap_or_else(|err| panic!("{}: {}", image, err));
+  let block_dev: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(file)));
+  assert!(FileSystem::probe(&block_dev), "{}: not an easy-fs image", image);
+  let efs = FileSystem::open(block_dev);
+  if !read_only {
+    efs.lock().set_clock(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64));
+  }
+
+  let mut options = vec![MountOption::FSName("easy-fs".to_string()), MountOption::DefaultPermissions];
+  options.push(if read_only { MountOption::RO } else { MountOption::RW });
+  fuser::mount2(EfsFuse::new(FileSystem::root_inode(&efs)), matches.value_of("mountpoint").unwrap(), &options)
+    .expect("Error when mounting");
+}
@@ -1,4 +1,4 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 
 use crate::{BLOCK_SZ, block_dev::BlockDevice, block_cache::get_block_cache};
 
@@ -8,8 +8,15 @@ type BitmapBlock = [u64; 64];
 const BLOCK_BITS: usize = BLOCK_SZ * 8;
 
 pub struct Bitmap {
-  start_block_id: usize, 
+  start_block_id: usize,
   blocks: usize, // total block number
+  /// bits that stand for an inode or block; the last bitmap block may have more
+  bits: usize,
+  /// bits below `bits` that are free
+  free: usize,
+  /// where searches start: allocation goes on where the last one ended, or at the
+  /// lowest bit freed since
+  hint: usize,
 }
 
 fn decompose(mut bit: usize) -> (usize, usize, usize) {
@@ -19,53 +26,196 @@ fn decompose(mut bit: usize) -> (usize, usize, usize) {
 }
 
 impl Bitmap {
-  /// A new bitmap from start block id and number of blocks
-  pub fn new(start_block_id: usize, blocks: usize) -> Self {
-    Self { 
-      start_block_id, 
-      blocks 
+  /// A new bitmap of `bits` bits from start block id and number of blocks. Until
+  /// `set_free` or `recount` it counts no bit free.
+  pub fn new(start_block_id: usize, blocks: usize, bits: usize) -> Self {
+    assert!(bits <= blocks * BLOCK_BITS);
+    Self {
+      start_block_id,
+      blocks,
+      bits,
+      free: 0,
+      hint: 0,
     }
-  }    
+  }
 
-  /// returns index of an avaliable empty block
-  pub fn alloc(&self, block_dev: &Arc<dyn BlockDevice>) -> Option<usize> {
-    for block_id in 0..self.blocks {
-      let blk_pos = get_block_cache(
-        block_id + self.start_block_id, 
-        block_dev.clone()
-      )
-      .lock()
-      .modify(0, |bitmap_blk: &mut BitmapBlock| {
-        if let Some((bits64_pos, inner_pos)) = 
-          bitmap_blk.iter()
-          .enumerate()
-          .find(|(_, num)| **num != u64::MAX)
-          .map(|(idx, num)| (idx, num.trailing_ones())) {
-            bitmap_blk[bits64_pos] |= 1u64 << inner_pos;
-            Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-        } else {
-          None
+  /// Walk the bits in `[from, to)`, a bitmap block at a time. `f` gets a bit, the u64
+  /// holding it and whether that whole u64 is in the range, and returns how many bits
+  /// to move on, 0 to stop.
+  fn scan(&self, block_dev: &Arc<dyn BlockDevice>, from: usize, to: usize, mut f: impl FnMut(usize, u64, bool) -> usize) {
+    let mut bit = from;
+    while bit < to {
+      let (blk_id, _, _) = decompose(bit);
+      let block_end = ((blk_id + 1) * BLOCK_BITS).min(to);
+      let done = get_block_cache(blk_id + self.start_block_id, block_dev.clone())
+        .lock()
+        .read(0, |bitmap_blk: &BitmapBlock| {
+          while bit < block_end {
+            let (_, bits64_pos, inner_pos) = decompose(bit);
+            let whole = inner_pos == 0 && bit + 64 <= block_end;
+            match f(bit, bitmap_blk[bits64_pos], whole) {
+              0 => return true,
+              step => bit += step,
+            }
+          }
+          false
+        });
+      if done {
+        return;
+      }
+    }
+  }
+
+  /// First bit of a run of `len` free bits in `[from, to)`
+  fn find_run(&self, block_dev: &Arc<dyn BlockDevice>, from: usize, to: usize, len: usize) -> Option<usize> {
+    let (mut run_start, mut run_len) = (from, 0);
+    let mut found = false;
+    self.scan(block_dev, from, to, |bit, bits64, whole| {
+      let step = match (whole, bits64) {
+        (true, 0) => {
+          run_len += 64;
+          64
         }
-      });
-      if blk_pos.is_some() {
-        return blk_pos;
+        (true, u64::MAX) => {
+          (run_start, run_len) = (bit + 64, 0);
+          64
+        }
+        _ if bits64 >> (bit % 64) & 1 == 0 => {
+          run_len += 1;
+          1
+        }
+        _ => {
+          (run_start, run_len) = (bit + 1, 0);
+          1
+        }
+      };
+      found = run_len >= len;
+      if found { 0 } else { step }
+    });
+    found.then_some(run_start)
+  }
+
+  /// Up to `count` free bits from `from` on, wrapping around to the start
+  fn find_free(&self, block_dev: &Arc<dyn BlockDevice>, from: usize, count: usize) -> Vec<usize> {
+    let mut free = Vec::with_capacity(count);
+    for (from, to) in [(from, self.bits), (0, from)] {
+      if free.len() == count {
+        break;
       }
+      self.scan(block_dev, from, to, |bit, bits64, whole| {
+        if whole && bits64 == u64::MAX {
+          return 64;
+        }
+        if bits64 >> (bit % 64) & 1 == 0 {
+          free.push(bit);
+        }
+        if free.len() == count { 0 } else { 1 }
+      });
+    }
+    free
+  }
+
+  /// Allocate `count` bits, fewer if not that many are free. They are one run if a long
+  /// enough one is free, the first one from the hint on.
+  pub fn alloc_many(&mut self, block_dev: &Arc<dyn BlockDevice>, count: usize) -> Vec<usize> {
+    let count = count.min(self.free);
+    if count == 0 {
+      return Vec::new();
+    }
+    let hint = if self.hint < self.bits { self.hint } else { 0 };
+    let run = self
+      .find_run(block_dev, hint, self.bits, count)
+      .or_else(|| self.find_run(block_dev, 0, (hint + count - 1).min(self.bits), count));
+    let bits = match run {
+      Some(start) => (start..start + count).collect(),
+      None => self.find_free(block_dev, hint, count),
+    };
+    for &bit in &bits {
+      let (blk_id, bits64_pos, inner_pos) = decompose(bit);
+      get_block_cache(blk_id + self.start_block_id, block_dev.clone())
+        .lock()
+        .modify(0, |bitmap_blk: &mut BitmapBlock| {
+          assert_eq!(0, bitmap_blk[bits64_pos] >> inner_pos & 1);
+          bitmap_blk[bits64_pos] |= 1u64 << inner_pos;
+        });
     }
-    None
+    self.free -= bits.len();
+    self.hint = bits.last().map_or(hint, |bit| bit + 1);
+    bits
+  }
+
+  /// returns index of an avaliable empty block
+  pub fn alloc(&mut self, block_dev: &Arc<dyn BlockDevice>) -> Option<usize> {
+    self.alloc_many(block_dev, 1).pop()
   }
 
   /// Deallocate a block's index
-  pub fn dealloc(&self, block_dev: &Arc<dyn BlockDevice>, bit: usize) {
+  pub fn dealloc(&mut self, block_dev: &Arc<dyn BlockDevice>, bit: usize) {
+    let (blk_id, bits64_pos, inner_pos) = decompose(bit);
+    let bitmap_blk = get_block_cache(blk_id + self.start_block_id, block_dev.clone());
+    let mut bitmap_blk = bitmap_blk.lock();
+    // a corrupt block reads as all free; the transaction is dropped anyway
+    if bitmap_blk.is_corrupt() {
+      return;
+    }
+    bitmap_blk.modify(0, |bitmap_blk: &mut BitmapBlock| {
+      assert_eq!(1, bitmap_blk[bits64_pos] >> inner_pos & 1);
+      bitmap_blk[bits64_pos] ^= 1u64 << inner_pos;
+    });
+    self.free += 1;
+    self.hint = self.hint.min(bit);
+  }
+  /// Is `bit` allocated?
+  pub fn get(&self, block_dev: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+    let (blk_id, bits64_pos, inner_pos) = decompose(bit);
+    get_block_cache(blk_id + self.start_block_id, block_dev.clone())
+    .lock()
+    .read(0, |bitmap_blk: &BitmapBlock| bitmap_blk[bits64_pos] >> inner_pos & 1 == 1)
+  }
+
+  /// Mark `bit` allocated or free, whatever it was before. The free count is not changed,
+  /// `recount` once done.
+  pub fn set(&self, block_dev: &Arc<dyn BlockDevice>, bit: usize, allocated: bool) {
     let (blk_id, bits64_pos, inner_pos) = decompose(bit);
     get_block_cache(blk_id + self.start_block_id, block_dev.clone())
     .lock()
     .modify(0, |bitmap_blk: &mut BitmapBlock| {
-      assert_eq!(1, bitmap_blk[bits64_pos] >> inner_pos & 1);
-      bitmap_blk[bits64_pos] ^= 1u64 << inner_pos;
+      bitmap_blk[bits64_pos] &= !(1u64 << inner_pos);
+      bitmap_blk[bits64_pos] |= (allocated as u64) << inner_pos;
     });
   }
+
+  /// Number of allocated bits
+  pub fn count_allocated(&self, block_dev: &Arc<dyn BlockDevice>) -> usize {
+    (0..self.blocks).map(|block_id| {
+      get_block_cache(block_id + self.start_block_id, block_dev.clone())
+      .lock()
+      .read(0, |bitmap_blk: &BitmapBlock| bitmap_blk.iter().map(|bits| bits.count_ones() as usize).sum::<usize>())
+    }).sum()
+  }
+
+  /// Number of free bits, as counted along
+  pub fn free(&self) -> usize {
+    self.free
+  }
+
+  /// Take the number of free bits from an image that keeps it
+  pub fn set_free(&mut self, free: usize) {
+    self.free = free.min(self.bits);
+  }
+
+  /// Count the free bits on the device
+  pub fn recount(&mut self, block_dev: &Arc<dyn BlockDevice>) {
+    self.free = self.bits.saturating_sub(self.count_allocated(block_dev));
+  }
+
+  /// Number of blocks of the bitmap
+  pub fn blocks(&self) -> usize {
+    self.blocks
+  }
+
   /// Get the max number of allocatable blocks
   pub fn maximum(&self) -> usize {
-    self.blocks * BLOCK_BITS
+    self.bits
   }
-}
\ No newline at end of file
+}
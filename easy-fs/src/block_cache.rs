@@ -1,20 +1,41 @@
-use alloc::{sync::Arc, collections::VecDeque};
+use alloc::{sync::{Arc, Weak}, vec::Vec};
+use core::hash::{BuildHasherDefault, Hasher};
+use hashbrown::HashMap;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
-use crate::{BLOCK_SZ, block_dev::BlockDevice};
+use crate::{BLOCK_SZ, block_dev::BlockDevice, checksum::{Checksums, ChecksumPolicy}, journal::{Journal, JOURNAL_MAX_BLOCKS}, DataBlock};
 
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Number of blocks cached unless configured otherwise
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 16;
+
+/// When modified blocks reach the device. Blocks modified in a transaction reach it when
+/// it commits, through the journal, under either policy.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WritePolicy {
+  /// on eviction or an explicit sync
+  WriteBack,
+  /// as soon as `BlockCache::modify` returns
+  WriteThrough,
+}
 
 pub struct BlockCache {
   /// cached block data
   cache: [u8; BLOCK_SZ],
   /// corresponding block id
   block_id: usize, 
-  /// corresponding block device
-  block_device: Arc<dyn BlockDevice>,
+  /// corresponding block device, held weakly so that the cache does not keep it alive
+  block_device: Weak<dyn BlockDevice>,
   /// dirty bit 
   modified: bool,
+  /// sync after every `modify`
+  write_through: bool,
+  /// part of the running transaction, so it must not reach its home location before the commit
+  in_txn: bool,
+  /// checksum table of the device, if it has one
+  checksums: Option<Arc<Mutex<Checksums>>>,
+  /// failed its checksum and was zeroed, so it must never be written back
+  corrupt: bool,
 }
 
 impl BlockCache {
@@ -25,11 +46,20 @@ impl BlockCache {
     Self {
       cache,
       block_id,
-      block_device,
+      block_device: Arc::downgrade(&block_device),
       modified: false,
+      write_through: false,
+      in_txn: false,
+      checksums: None,
+      corrupt: false,
     }
   }
 
+  /// Did the block fail its checksum? Its contents are zeroes then.
+  pub fn is_corrupt(&self) -> bool {
+    self.corrupt
+  }
+
   /// Get the address of an offset inside the cached block data
   pub fn addr_of_offset(&self, offset: usize) -> usize {
       &self.cache[offset] as *const u8 as usize
@@ -56,14 +86,33 @@ impl BlockCache {
   }
 
   pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
-    f(self.get_mut(offset))
+    let ret = f(self.get_mut(offset));
+    if self.write_through {
+      self.sync();
+    }
+    ret
   }
 
-  /// if dirty, write back to block device
+  /// if dirty, write back to block device, unless the running transaction holds it back,
+  /// the block is corrupt or the device read-only
   pub fn sync(&mut self) {
-    if self.modified {
-      self.block_device.write_block(self.block_id, &self.cache);
-      self.modified = false;
+    if !self.modified || self.in_txn {
+      return;
+    }
+    self.modified = false;
+    if self.corrupt {
+      return;
+    }
+    if let Some(checksums) = &self.checksums {
+      let mut checksums = checksums.lock();
+      if checksums.is_read_only() {
+        return;
+      }
+      checksums.update(self.block_id, &self.cache);
+    }
+    // blocks left dirty on a device everyone dropped go with it
+    if let Some(block_device) = self.block_device.upgrade() {
+      block_device.write_block(self.block_id, &self.cache);
     }
   }
 }
@@ -74,58 +123,557 @@ impl Drop for BlockCache {
   }
 }
 
+/// FxHash-style multiplicative hash; block ids are small integers that need no DoS resistance
+#[derive(Default)]
+struct BlockIdHasher(u64);
+
+impl BlockIdHasher {
+  fn add(&mut self, word: u64) {
+    self.0 = (self.0.rotate_left(5) ^ word).wrapping_mul(0x517c_c1b7_2722_0a95);
+  }
+}
+
+impl Hasher for BlockIdHasher {
+  fn finish(&self) -> u64 {
+    self.0
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    bytes.iter().for_each(|&byte| self.add(byte as u64));
+  }
+
+  fn write_usize(&mut self, n: usize) {
+    self.add(n as u64);
+  }
+}
+
+/// Block cache counters
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BlockCacheStats {
+  pub hits: usize,
+  pub misses: usize,
+  /// entries dropped to make room, written back if dirty
+  pub evictions: usize,
+  /// misses that found every entry in use and grew the cache past its capacity
+  pub overflows: usize,
+  /// misses that found the cache at its hard bound, `capacity` + `JOURNAL_MAX_BLOCKS`
+  /// entries all in use, and were served a block outside it
+  pub uncached: usize,
+}
+
+impl core::ops::AddAssign for BlockCacheStats {
+  fn add_assign(&mut self, other: Self) {
+    self.hits += other.hits;
+    self.misses += other.misses;
+    self.evictions += other.evictions;
+    self.overflows += other.overflows;
+    self.uncached += other.uncached;
+  }
+}
+
+/// End marker of the recency list
+const NIL: usize = usize::MAX;
+
+struct Entry {
+  block_id: usize,
+  cache: Arc<Mutex<BlockCache>>,
+  /// neighbour towards the most recently used end
+  newer: usize,
+  /// neighbour towards the least recently used end
+  older: usize,
+  /// already recorded in `BlockCacheManager::txn`
+  in_txn: bool,
+  /// failed its checksum when loaded
+  corrupt: bool,
+}
+
+/// LRU cache of the blocks of one device.
+///
+/// Entries live in a slab linked in recency order and are found through a hash map,
+/// so lookups and updates are O(1). An entry still referenced outside the cache cannot
+/// be evicted; if all of them are, the cache grows past its capacity for a while
+/// instead of failing, and shrinks back on later misses.
+///
+/// During a transaction every block handed out is also held by the manager until the
+/// commit, which keeps it in the cache and its modifications off the device meanwhile.
+///
+/// If the device has a checksum table, blocks are checked against it when loaded, and a
+/// transaction that came across a corrupt block is dropped instead of committed.
 pub struct BlockCacheManager {
-  queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>
+  /// held weakly, so that the device is dropped with its last user and the cache with it
+  block_device: Weak<dyn BlockDevice>,
+  capacity: usize,
+  policy: WritePolicy,
+  map: HashMap<usize, usize, BuildHasherDefault<BlockIdHasher>>,
+  slab: Vec<Option<Entry>>,
+  /// vacant slots of `slab`
+  free: Vec<usize>,
+  newest: usize,
+  oldest: usize,
+  stats: BlockCacheStats,
+  /// slots of the blocks used by the running transaction, if any
+  txn: Option<Vec<(usize, Arc<Mutex<BlockCache>>)>>,
+  /// the running transaction handed out a corrupt block
+  txn_failed: bool,
+  checksums: Option<Arc<Mutex<Checksums>>>,
 }
 
 impl BlockCacheManager {
-  pub fn new() -> Self {
+  pub fn new(block_device: Arc<dyn BlockDevice>, capacity: usize, policy: WritePolicy) -> Self {
+    assert!(capacity > 0, "the block cache needs at least one entry");
     Self {
-      queue: VecDeque::new()
-    }
-  }
-
-  /// find `block_id`-th block on `block_device`
-  /// and place it on cache
-  pub fn get_block_cache(
-    &mut self, 
-    block_id: usize, 
-    block_device: Arc<dyn BlockDevice>
-  ) -> Arc<Mutex<BlockCache>> {
-    if let Some((_, entry)) = self.queue.iter().find(|entry| entry.0 == block_id)  {
-      return entry.clone();
-    } else {
-      if self.queue.len() == BLOCK_CACHE_SIZE {
-        if let Some((idx, _)) =  
-          self.queue
-          .iter()
-          .enumerate()
-          .find(|(_, entry)| Arc::strong_count(&entry.1) == 1) {
-          self.queue.drain(idx..=idx);
-        } else {
-          panic!("Run out of Block Cache entries");
-        } 
-      } 
-      let cache_entry = Arc::new(Mutex::new(BlockCache::new(block_id, block_device)));
-      self.queue.push_back((block_id, cache_entry.clone()));
-      cache_entry
+      block_device: Arc::downgrade(&block_device),
+      capacity,
+      policy,
+      map: HashMap::default(),
+      slab: Vec::new(),
+      free: Vec::new(),
+      newest: NIL,
+      oldest: NIL,
+      stats: BlockCacheStats::default(),
+      txn: None,
+      txn_failed: false,
+      checksums: None,
+    }
+  }
+
+  /// The device, which whoever uses the cache holds
+  fn block_device(&self) -> Arc<dyn BlockDevice> {
+    self.block_device.upgrade().expect("the block device of a cache in use was dropped")
+  }
+
+  fn entry(&mut self, idx: usize) -> &mut Entry {
+    self.slab[idx].as_mut().unwrap()
+  }
+
+  /// Take slot `idx` out of the recency list
+  fn unlink(&mut self, idx: usize) {
+    let (newer, older) = {
+      let entry = self.entry(idx);
+      (entry.newer, entry.older)
+    };
+    match newer {
+      NIL => self.newest = older,
+      newer => self.entry(newer).older = older,
+    }
+    match older {
+      NIL => self.oldest = newer,
+      older => self.entry(older).newer = newer,
+    }
+  }
+
+  /// Put slot `idx` at the most recently used end
+  fn push_newest(&mut self, idx: usize) {
+    let newest = self.newest;
+    {
+      let entry = self.entry(idx);
+      entry.newer = NIL;
+      entry.older = newest;
+    }
+    match newest {
+      NIL => self.oldest = idx,
+      newest => self.entry(newest).newer = idx,
+    }
+    self.newest = idx;
+  }
+
+  /// Take slot `idx` out of the cache
+  fn remove(&mut self, idx: usize) {
+    self.unlink(idx);
+    let entry = self.slab[idx].take().unwrap();
+    self.map.remove(&entry.block_id);
+    self.free.push(idx);
+  }
+
+  /// Drop the least recently used entry nobody else holds. Returns false if there is none.
+  fn evict(&mut self) -> bool {
+    let mut idx = self.oldest;
+    while idx != NIL {
+      let entry = self.slab[idx].as_ref().unwrap();
+      if Arc::strong_count(&entry.cache) == 1 {
+        // dropping the last reference writes the block back if dirty
+        self.remove(idx);
+        self.stats.evictions += 1;
+        return true;
+      }
+      idx = entry.newer;
+    }
+    false
+  }
+
+  /// find `block_id`-th block and place it on cache
+  pub fn get_block_cache(&mut self, block_id: usize) -> Arc<Mutex<BlockCache>> {
+    let idx = match self.map.get(&block_id) {
+      Some(&idx) => {
+        self.stats.hits += 1;
+        self.unlink(idx);
+        self.push_newest(idx);
+        idx
+      }
+      None => match self.insert(block_id) {
+        Some(idx) => idx,
+        None => return self.uncached(block_id),
+      },
+    };
+    if self.entry(idx).corrupt {
+      // reported on every use, as every operation that makes one fails
+      if let Some(checksums) = &self.checksums {
+        checksums.lock().report(block_id);
+      }
+      self.txn_failed |= self.txn.is_some();
+    }
+    if self.txn.is_some() && !self.entry(idx).in_txn {
+      let entry = self.entry(idx);
+      entry.in_txn = true;
+      let cache = entry.cache.clone();
+      cache.lock().in_txn = true;
+      self.txn.as_mut().unwrap().push((idx, cache));
+    }
+    self.entry(idx).cache.clone()
+  }
+
+  /// Load `block_id` into a free slot, returning the slot. Entries in use let the cache
+  /// grow past `capacity`, by up to a transaction as large as the journal holds; `None`
+  /// if it is that large already.
+  fn insert(&mut self, block_id: usize) -> Option<usize> {
+    self.stats.misses += 1;
+    while self.map.len() >= self.capacity {
+      if self.evict() {
+        continue;
+      }
+      if self.map.len() >= self.capacity + JOURNAL_MAX_BLOCKS {
+        return None;
+      }
+      self.stats.overflows += 1;
+      break;
+    }
+    let block_cache = self.load(block_id);
+    let corrupt = block_cache.corrupt;
+    let cache = Arc::new(Mutex::new(block_cache));
+    let entry = Entry { block_id, cache, newer: NIL, older: NIL, in_txn: false, corrupt };
+    let idx = match self.free.pop() {
+      Some(idx) => {
+        self.slab[idx] = Some(entry);
+        idx
+      }
+      None => {
+        self.slab.push(Some(entry));
+        self.slab.len() - 1
+      }
+    };
+    self.map.insert(block_id, idx);
+    self.push_newest(idx);
+    Some(idx)
+  }
+
+  /// `block_id` outside the cache, which is at its hard bound. A running transaction is
+  /// dropped and the block never written; outside one, it is written back when dropped.
+  fn uncached(&mut self, block_id: usize) -> Arc<Mutex<BlockCache>> {
+    self.stats.uncached += 1;
+    let mut block_cache = self.load(block_id);
+    if block_cache.corrupt {
+      if let Some(checksums) = &self.checksums {
+        checksums.lock().report(block_id);
+      }
+    }
+    if self.txn.is_some() {
+      self.txn_failed = true;
+      block_cache.in_txn = true;
+    }
+    Arc::new(Mutex::new(block_cache))
+  }
+
+  /// Read `block_id` from the device, zeroed and marked corrupt if it fails its checksum
+  fn load(&self, block_id: usize) -> BlockCache {
+    let mut block_cache = BlockCache::new(block_id, self.block_device());
+    block_cache.write_through = self.policy == WritePolicy::WriteThrough;
+    if let Some(checksums) = &self.checksums {
+      let checksums_guard = checksums.lock();
+      if !checksums_guard.matches(block_id, &block_cache.cache) {
+        if checksums_guard.policy() == ChecksumPolicy::Panic {
+          panic!("block {} does not match its checksum", block_id);
+        }
+        block_cache.cache = [0; BLOCK_SZ];
+        block_cache.corrupt = true;
+      }
+      block_cache.checksums = Some(checksums.clone());
+    }
+    block_cache
+  }
+
+  /// Start recording the blocks handed out, for `commit`
+  pub fn begin(&mut self) {
+    assert!(self.txn.is_none(), "transactions do not nest");
+    self.txn = Some(Vec::new());
+  }
+
+  /// End the running transaction: log the blocks it modified in `journal`, with the
+  /// checksum table blocks that changed along, then write them to their home locations
+  /// and empty the journal again. Returns false if the transaction handed out a corrupt
+  /// block, overflows the journal or the device is read-only; it is dropped then, as if
+  /// it never ran.
+  pub fn commit(&mut self, journal: &mut Journal) -> bool {
+    let Some(txn) = self.txn.take() else {
+      return true;
+    };
+    let read_only = self.checksums.as_ref().is_some_and(|checksums| checksums.lock().is_read_only());
+    if core::mem::take(&mut self.txn_failed) || read_only {
+      self.discard(txn);
+      return false;
+    }
+    let dirty: Vec<(usize, DataBlock)> = txn
+      .iter()
+      .filter_map(|(_, cache)| {
+        let cache = cache.lock();
+        cache.modified.then_some((cache.block_id, cache.cache))
+      })
+      .collect();
+    let table_blocks = self.checksums.as_ref().map_or(0, |checksums| {
+      checksums.lock().table_blocks_after(dirty.iter().map(|(block_id, _)| *block_id))
+    });
+    if dirty.len() + table_blocks > journal.capacity() {
+      self.discard(txn);
+      return false;
+    }
+    let table = match &self.checksums {
+      Some(checksums) => {
+        let mut checksums = checksums.lock();
+        dirty.iter().for_each(|(block_id, data)| checksums.update(*block_id, data));
+        checksums.take_dirty()
+      }
+      None => Vec::new(),
+    };
+    let logged = [dirty, table.clone()].concat();
+    let block_device = self.block_device();
+    if !logged.is_empty() && !journal.commit(&block_device, &logged) {
+      unreachable!("the transaction was checked against the journal capacity");
     }
+    for (idx, cache) in txn {
+      self.entry(idx).in_txn = false;
+      let mut cache = cache.lock();
+      cache.in_txn = false;
+      cache.sync();
+    }
+    for (block_id, data) in &table {
+      block_device.write_block(*block_id, data);
+    }
+    if !logged.is_empty() {
+      journal.clear(&block_device);
+    }
+    true
+  }
+
+  /// Throw away the blocks of a transaction, so they are read again from the device
+  fn discard(&mut self, txn: Vec<(usize, Arc<Mutex<BlockCache>>)>) {
+    for (idx, cache) in txn {
+      {
+        let mut cache = cache.lock();
+        cache.modified = false;
+        cache.in_txn = false;
+      }
+      self.remove(idx);
+    }
+  }
+
+  /// Check the blocks loaded from now on against `checksums`
+  pub(crate) fn set_checksums(&mut self, checksums: Option<Arc<Mutex<Checksums>>>) {
+    for entry in self.slab.iter().flatten() {
+      entry.cache.lock().checksums = checksums.clone();
+    }
+    self.checksums = checksums;
+  }
+
+  /// Drop the blocks that failed their checksum from the cache, so they are checked again
+  /// when next used
+  pub(crate) fn forget_corrupt(&mut self) {
+    let corrupt: Vec<usize> = (0..self.slab.len())
+      .filter(|&idx| self.slab[idx].as_ref().is_some_and(|entry| entry.corrupt && !entry.in_txn))
+      .collect();
+    for idx in corrupt {
+      self.remove(idx);
+    }
+  }
+
+  /// Change the size and write policy, evicting what no longer fits
+  pub fn configure(&mut self, capacity: usize, policy: WritePolicy) {
+    assert!(capacity > 0, "the block cache needs at least one entry");
+    self.capacity = capacity;
+    self.policy = policy;
+    while self.map.len() > self.capacity && self.evict() {}
+    for entry in self.slab.iter().flatten() {
+      let mut cache = entry.cache.lock();
+      cache.write_through = policy == WritePolicy::WriteThrough;
+      if cache.write_through {
+        cache.sync();
+      }
+    }
+  }
+
+  pub fn stats(&self) -> BlockCacheStats {
+    self.stats
+  }
+
+  /// Number of blocks currently cached
+  pub fn len(&self) -> usize {
+    self.map.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.map.is_empty()
+  }
+
+  /// Write back every dirty block, and the checksum table
+  pub fn sync(&self) {
+    self.slab.iter().flatten().for_each(|entry| entry.cache.lock().sync());
+    if let (Some(checksums), Some(block_device)) = (&self.checksums, self.block_device.upgrade()) {
+      checksums.lock().flush(&block_device);
+    }
+  }
+}
+
+type SharedManager = Arc<Mutex<BlockCacheManager>>;
+
+/// Is `weak` a handle to the same device as `block_device`? A dropped device matches none,
+/// its address may have been reused.
+fn same_device(weak: &Weak<dyn BlockDevice>, block_device: &Arc<dyn BlockDevice>) -> bool {
+  weak.strong_count() > 0 && weak.as_ptr() as *const () == Arc::as_ptr(block_device) as *const ()
+}
+
+type WeakChecksums = Weak<Mutex<Checksums>>;
+
+/// The caches of all devices in use, and the settings new caches start with
+struct BlockCaches {
+  /// caches hold their devices weakly, entries whose device is gone are dropped when the
+  /// next cache is created
+  devices: Vec<(Weak<dyn BlockDevice>, SharedManager)>,
+  capacity: usize,
+  policy: WritePolicy,
+  /// the checksum tables of the filesystems open on devices, for the caches created after
+  /// `block_cache_release`
+  checksums: Vec<(Weak<dyn BlockDevice>, WeakChecksums)>,
+}
+
+impl BlockCaches {
+  fn of(&mut self, block_device: &Arc<dyn BlockDevice>) -> SharedManager {
+    if let Some((_, manager)) = self.devices.iter().find(|(dev, _)| same_device(dev, block_device)) {
+      return manager.clone();
+    }
+    // forget the caches of dropped devices
+    self.devices.retain(|(dev, _)| dev.strong_count() > 0);
+    let mut manager = BlockCacheManager::new(block_device.clone(), self.capacity, self.policy);
+    let checksums = self.checksums.iter().find(|(dev, _)| same_device(dev, block_device));
+    manager.set_checksums(checksums.and_then(|(_, checksums)| checksums.upgrade()));
+    let manager = Arc::new(Mutex::new(manager));
+    self.devices.push((Arc::downgrade(block_device), manager.clone()));
+    manager
+  }
+
+  fn set_checksums(&mut self, block_device: &Arc<dyn BlockDevice>, checksums: Option<&Arc<Mutex<Checksums>>>) {
+    self.checksums.retain(|(dev, checksums)| {
+      dev.strong_count() > 0 && checksums.strong_count() > 0 && !same_device(dev, block_device)
+    });
+    if let Some(checksums) = checksums {
+      self.checksums.push((Arc::downgrade(block_device), Arc::downgrade(checksums)));
+    }
+  }
+
+  fn managers(&self) -> Vec<SharedManager> {
+    self.devices.iter().filter(|(dev, _)| dev.strong_count() > 0).map(|(_, manager)| manager.clone()).collect()
   }
 }
 
 lazy_static! {
-  pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> = Mutex::new(BlockCacheManager::new());
+  static ref BLOCK_CACHES: Mutex<BlockCaches> = Mutex::new(BlockCaches {
+    devices: Vec::new(),
+    capacity: DEFAULT_BLOCK_CACHE_SIZE,
+    policy: WritePolicy::WriteBack,
+    checksums: Vec::new(),
+  });
+}
+
+/// The cache of the blocks of `block_device`
+fn manager_of(block_device: &Arc<dyn BlockDevice>) -> SharedManager {
+  BLOCK_CACHES.lock().of(block_device)
 }
 
 /// Get the block cache corresponding to the given block id and block device
 pub fn get_block_cache(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<BlockCache>> {
-  BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device)
+  manager_of(&block_device).lock().get_block_cache(block_id)
+}
+
+/// Start a transaction on `block_device`, see `BlockCacheManager::begin`
+pub fn block_cache_begin(block_device: &Arc<dyn BlockDevice>) {
+  manager_of(block_device).lock().begin();
+}
+
+/// Commit the transaction running on `block_device` through `journal`, false if it was dropped
+pub fn block_cache_commit(block_device: &Arc<dyn BlockDevice>, journal: &mut Journal) -> bool {
+  manager_of(block_device).lock().commit(journal)
+}
+
+/// Check the blocks of `block_device` against `checksums` from now on
+pub(crate) fn block_cache_set_checksums(block_device: &Arc<dyn BlockDevice>, checksums: Option<Arc<Mutex<Checksums>>>) {
+  let manager = {
+    let mut caches = BLOCK_CACHES.lock();
+    caches.set_checksums(block_device, checksums.as_ref());
+    caches.of(block_device)
+  };
+  manager.lock().set_checksums(checksums);
+}
+
+/// See `BlockCacheManager::forget_corrupt`
+pub(crate) fn block_cache_forget_corrupt(block_device: &Arc<dyn BlockDevice>) {
+  manager_of(block_device).lock().forget_corrupt();
+}
+
+/// Set the size and write policy of the cache of every device
+pub fn configure_block_cache(capacity: usize, policy: WritePolicy) {
+  let managers = {
+    let mut caches = BLOCK_CACHES.lock();
+    caches.capacity = capacity;
+    caches.policy = policy;
+    caches.managers()
+  };
+  managers.iter().for_each(|manager| manager.lock().configure(capacity, policy));
+}
+
+/// Counters of the cache of `block_device`
+pub fn block_cache_stats(block_device: &Arc<dyn BlockDevice>) -> BlockCacheStats {
+  manager_of(block_device).lock().stats()
+}
+
+/// Counters summed over all devices
+pub fn block_cache_stats_all() -> BlockCacheStats {
+  let managers = BLOCK_CACHES.lock().managers();
+  let mut stats = BlockCacheStats::default();
+  managers.iter().for_each(|manager| stats += manager.lock().stats());
+  stats
 }
 
 /// Sync all block cache to block device
 pub fn block_cache_sync_all() {
-  let manager = BLOCK_CACHE_MANAGER.lock();
-  manager.queue.iter().for_each(|(_, cache)| {
-    cache.lock().sync();
-  });
-}
\ No newline at end of file
+  let managers = BLOCK_CACHES.lock().managers();
+  managers.iter().for_each(|manager| manager.lock().sync());
+}
+
+/// Write back the dirty cached blocks of `block_device`
+pub fn block_cache_sync(block_device: &Arc<dyn BlockDevice>) {
+  manager_of(block_device).lock().sync();
+}
+
+/// Does `block_device` have a cache, i.e. has it been used since it was last released?
+pub fn block_cache_exists(block_device: &Arc<dyn BlockDevice>) -> bool {
+  BLOCK_CACHES.lock().devices.iter().any(|(dev, _)| same_device(dev, block_device))
+}
+
+/// Write back and drop the cache of `block_device`, e.g. once it is unmounted
+pub fn block_cache_release(block_device: &Arc<dyn BlockDevice>) {
+  let manager = {
+    let mut caches = BLOCK_CACHES.lock();
+    let idx = caches.devices.iter().position(|(dev, _)| same_device(dev, block_device));
+    idx.map(|idx| caches.devices.remove(idx).1)
+  };
+  if let Some(manager) = manager {
+    manager.lock().sync();
+  }
+}
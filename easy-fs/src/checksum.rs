@@ -0,0 +1,194 @@
+//! CRC32 checksums of the blocks of an image
+//!
+//! From version 5 on a table after the journal holds the CRC32 of every block from the
+//! inode bitmap to the end of the data area, a `u32` each: bitmap, inode, index and data
+//! blocks alike, as the block cache cannot tell them apart. The cache checks a block
+//! against its entry when loading it and updates the entry when writing the block back;
+//! the table blocks a transaction changes are logged with it. The table is accessed
+//! directly, never through the block cache. The super block has a checksum of its own.
+
+use alloc::{collections::BTreeSet, sync::Arc, vec::Vec};
+
+use crate::{BLOCK_SZ, block_dev::BlockDevice, DataBlock};
+
+/// Table entries per block
+const SUMS_PER_BLOCK: usize = BLOCK_SZ / 4;
+
+/// What to do about a block that does not match its checksum
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChecksumPolicy {
+  /// panic, naming the block
+  Panic,
+  /// hand the block out zeroed and drop the transaction that used it, so nothing built on
+  /// it is written; `FileSystem::take_corrupt_blocks` tells which blocks it was
+  Error,
+  /// like `Error`, and write nothing to the device from then on
+  ReadOnly,
+}
+
+const fn crc32_table() -> [u32; 256] {
+  let mut table = [0u32; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 == 1 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 of `bytes`, the IEEE 802.3 one zlib and PNG use
+pub fn crc32(bytes: &[u8]) -> u32 {
+  !bytes.iter().fold(!0u32, |crc, &byte| crc >> 8 ^ CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize])
+}
+
+/// The checksum table of a device in memory, shared by its block cache and filesystem
+pub struct Checksums {
+  /// first block of the table
+  table_start: usize,
+  /// first block the table covers
+  first: usize,
+  sums: Vec<u32>,
+  /// table blocks changed since they were last written
+  dirty: BTreeSet<usize>,
+  policy: ChecksumPolicy,
+  /// blocks found corrupt and not reported yet
+  corrupt: Vec<usize>,
+  /// has `ChecksumPolicy::ReadOnly` kicked in?
+  read_only: bool,
+}
+
+impl Checksums {
+  /// A table of `covered` blocks from `first` on, kept in the blocks from `table_start` on,
+  /// every entry `sum`. All of it is written on the next `flush`.
+  pub fn new(table_start: usize, first: usize, covered: usize, sum: u32) -> Self {
+    let table_blocks = (covered + SUMS_PER_BLOCK - 1) / SUMS_PER_BLOCK;
+    Self {
+      table_start,
+      first,
+      sums: alloc::vec![sum; covered],
+      dirty: (0..table_blocks).collect(),
+      policy: ChecksumPolicy::ReadOnly,
+      corrupt: Vec::new(),
+      read_only: false,
+    }
+  }
+
+  /// Read the table of `covered` blocks from `first` on from the blocks from `table_start` on
+  pub fn load(block_dev: &Arc<dyn BlockDevice>, table_start: usize, first: usize, covered: usize) -> Self {
+    let mut checksums = Self::new(table_start, first, covered, 0);
+    let mut block = [0u8; BLOCK_SZ];
+    for (i, sums) in checksums.sums.chunks_mut(SUMS_PER_BLOCK).enumerate() {
+      block_dev.read_block(table_start + i, &mut block);
+      for (sum, bytes) in sums.iter_mut().zip(block.chunks_exact(4)) {
+        *sum = u32::from_ne_bytes(bytes.try_into().unwrap());
+      }
+    }
+    checksums.dirty.clear();
+    checksums
+  }
+
+  pub fn policy(&self) -> ChecksumPolicy {
+    self.policy
+  }
+
+  pub fn set_policy(&mut self, policy: ChecksumPolicy) {
+    self.policy = policy;
+  }
+
+  /// Has a corrupt block made the device read-only?
+  pub fn is_read_only(&self) -> bool {
+    self.read_only
+  }
+
+  fn index(&self, block_id: usize) -> Option<usize> {
+    block_id.checked_sub(self.first).filter(|&i| i < self.sums.len())
+  }
+
+  /// Does `data` match the entry of `block_id`? Blocks the table does not cover always do.
+  pub fn matches(&self, block_id: usize, data: &DataBlock) -> bool {
+    match self.index(block_id) {
+      Some(i) => self.sums[i] == crc32(data),
+      None => true,
+    }
+  }
+
+  /// Take note that `block_id` did not match, and make the device read-only if that is
+  /// the policy
+  pub fn report(&mut self, block_id: usize) {
+    if !self.corrupt.contains(&block_id) {
+      self.corrupt.push(block_id);
+    }
+    if self.policy == ChecksumPolicy::ReadOnly {
+      self.read_only = true;
+    }
+  }
+
+  /// Forget the blocks reported corrupt and make the device writable again, once they
+  /// were dealt with
+  pub fn reset(&mut self) {
+    self.corrupt.clear();
+    self.read_only = false;
+  }
+
+  /// Blocks the table covers
+  pub fn covered(&self) -> core::ops::Range<usize> {
+    self.first..self.first + self.sums.len()
+  }
+
+  /// The blocks reported corrupt since the last call
+  pub fn take_corrupt(&mut self) -> Vec<usize> {
+    core::mem::take(&mut self.corrupt)
+  }
+
+  /// Set the entry of `block_id` to the checksum of `data`
+  pub fn update(&mut self, block_id: usize, data: &DataBlock) {
+    if let Some(i) = self.index(block_id) {
+      let sum = crc32(data);
+      if self.sums[i] != sum {
+        self.sums[i] = sum;
+        self.dirty.insert(i / SUMS_PER_BLOCK);
+      }
+    }
+  }
+
+  /// Most table blocks dirty once the entries of `block_ids` are updated
+  pub fn table_blocks_after(&self, block_ids: impl Iterator<Item = usize>) -> usize {
+    let mut dirty = self.dirty.clone();
+    dirty.extend(block_ids.filter_map(|block_id| self.index(block_id)).map(|i| i / SUMS_PER_BLOCK));
+    dirty.len()
+  }
+
+  fn table_block(&self, i: usize) -> (usize, DataBlock) {
+    let mut block = [0u8; BLOCK_SZ];
+    let sums = &self.sums[i * SUMS_PER_BLOCK..((i + 1) * SUMS_PER_BLOCK).min(self.sums.len())];
+    for (bytes, sum) in block.chunks_exact_mut(4).zip(sums) {
+      bytes.copy_from_slice(&sum.to_ne_bytes());
+    }
+    (self.table_start + i, block)
+  }
+
+  /// The table blocks changed since they were last written, with their block ids, as
+  /// written from now on
+  pub fn take_dirty(&mut self) -> Vec<(usize, DataBlock)> {
+    let dirty = core::mem::take(&mut self.dirty);
+    dirty.into_iter().map(|i| self.table_block(i)).collect()
+  }
+
+  /// Write the changed table blocks, unless the device is read-only
+  pub fn flush(&mut self, block_dev: &Arc<dyn BlockDevice>) {
+    if self.read_only {
+      return;
+    }
+    for (block_id, block) in self.take_dirty() {
+      block_dev.write_block(block_id, &block);
+    }
+  }
+}
@@ -0,0 +1,211 @@
+//! Directory contents in either on-disk format
+//!
+//! Version 0 images store a directory as 32 byte `DirEntry` slots holding names of at most
+//! 27 bytes. Version 1 images store it as whole blocks, each tiled by records made of a
+//! `DirRecord` header and the name, padded to 4 bytes, so that names can be up to
+//! `NAME_LENGTH_LIMIT` bytes. A record spans its entry and any free space after it, up to
+//! the next record. In both formats an empty name marks free space.
+
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
+
+use crate::{
+  block_dev::BlockDevice, layout::{DirEntry, DirRecord, DiskInode, SuperBlock, DIRENT_SZ, DIR_RECORD_SZ, FIXED_NAME_LENGTH_LIMIT, NAME_LENGTH_LIMIT},
+  BLOCK_SZ,
+};
+
+/// How the directories of an image are stored, from `SuperBlock::version`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DirFormat {
+  /// version 0: fixed-size `DirEntry` slots
+  Fixed,
+  /// version 1: variable-length records
+  Records,
+}
+
+impl DirFormat {
+  pub fn of(super_blk: &SuperBlock) -> Self {
+    match super_blk.version {
+      0 => Self::Fixed,
+      _ => Self::Records,
+    }
+  }
+
+  /// Longest name, in bytes
+  pub fn name_max(self) -> usize {
+    match self {
+      Self::Fixed => FIXED_NAME_LENGTH_LIMIT,
+      Self::Records => NAME_LENGTH_LIMIT,
+    }
+  }
+
+  /// Bytes a directory grows by once it is full
+  pub fn grow(self) -> usize {
+    match self {
+      Self::Fixed => DIRENT_SZ,
+      Self::Records => BLOCK_SZ,
+    }
+  }
+
+  /// Can a directory be `size` bytes long?
+  pub fn valid_size(self, size: usize) -> bool {
+    size % self.grow() == 0
+  }
+
+  /// Bytes the entry of a `name_len` bytes long name needs
+  fn entry_len(self, name_len: usize) -> usize {
+    match self {
+      Self::Fixed => DIRENT_SZ,
+      Self::Records => (DIR_RECORD_SZ + name_len + 3) & !3,
+    }
+  }
+}
+
+/// One record of a directory
+#[derive(Clone, Debug)]
+pub struct Record {
+  /// position in the directory, in bytes
+  pub offset: usize,
+  /// bytes up to the next record
+  pub len: usize,
+  pub inode: u32,
+  /// empty for free space, `None` if the record is corrupt
+  pub name: Option<String>,
+}
+
+impl Record {
+  /// Does the record hold an entry?
+  pub fn is_entry(&self) -> bool {
+    self.name.as_deref().is_some_and(|name| !name.is_empty())
+  }
+
+  /// Bytes its entry takes, 0 if there is none
+  fn used(&self, format: DirFormat) -> usize {
+    match &self.name {
+      Some(name) if !name.is_empty() => format.entry_len(name.len()),
+      _ => 0,
+    }
+  }
+}
+
+/// Every record of `dir`, in order
+pub fn records(dir: &DiskInode, block_dev: &Arc<dyn BlockDevice>, format: DirFormat) -> Vec<Record> {
+  let mut records = Vec::new();
+  let mut block = [0u8; BLOCK_SZ];
+  match format {
+    DirFormat::Fixed => {
+      let mut dirent = DirEntry::empty();
+      for offset in (0..dir.size() as usize).step_by(DIRENT_SZ) {
+        dir.read_at(offset, dirent.as_bytes_mut(), block_dev);
+        let name = dirent.try_name().map(|name| name.to_string());
+        records.push(Record { offset, len: DIRENT_SZ, inode: dirent.inode_number(), name });
+      }
+    }
+    DirFormat::Records => {
+      for start in (0..dir.size() as usize).step_by(BLOCK_SZ) {
+        dir.read_at(start, &mut block, block_dev);
+        let mut pos = 0;
+        while pos < BLOCK_SZ {
+          let fits = |header: &DirRecord| {
+            let len = header.rec_len as usize;
+            len >= DIR_RECORD_SZ && len % 4 == 0 && pos + len <= BLOCK_SZ && DIR_RECORD_SZ + header.name_len as usize <= len
+          };
+          let Some(header) = DirRecord::read(&block[pos..]).filter(fits) else {
+            // the rest of the block cannot be trusted
+            records.push(Record { offset: start + pos, len: BLOCK_SZ - pos, inode: 0, name: None });
+            break;
+          };
+          let (len, name_len) = (header.rec_len as usize, header.name_len as usize);
+          let name = &block[pos + DIR_RECORD_SZ..pos + DIR_RECORD_SZ + name_len];
+          let name = core::str::from_utf8(name).ok().map(|name| name.to_string());
+          records.push(Record { offset: start + pos, len, inode: header.inode, name });
+          pos += len;
+        }
+      }
+    }
+  }
+  records
+}
+
+/// The first record with room for an entry named `name`
+pub fn find_room<'a>(records: &'a [Record], format: DirFormat, name: &str) -> Option<&'a Record> {
+  let need = format.entry_len(name.len());
+  records
+    .iter()
+    .find(|record| record.name.is_some() && record.len - record.used(format) >= need)
+}
+
+/// Turn the `format.grow()` bytes at `offset`, just added to `dir`, into free space
+pub fn add_free(dir: &mut DiskInode, block_dev: &Arc<dyn BlockDevice>, format: DirFormat, offset: usize) -> Record {
+  let record = Record { offset, len: format.grow(), inode: 0, name: Some(String::new()) };
+  free(dir, block_dev, format, &record);
+  record
+}
+
+/// Put the entry `name` → `inode` in the room `find_room` found in `record`
+pub fn insert(dir: &mut DiskInode, block_dev: &Arc<dyn BlockDevice>, format: DirFormat, record: &Record, name: &str, inode: u32) {
+  match format {
+    DirFormat::Fixed => {
+      dir.write_at(record.offset, DirEntry::new(name, inode).as_bytes(), block_dev);
+    }
+    DirFormat::Records => {
+      let (offset, len) = match record.used(format) {
+        0 => (record.offset, record.len),
+        used => {
+          // split the free space off the record that has it
+          let header = DirRecord::new(record.inode, used, record.name.as_ref().unwrap().len());
+          dir.write_at(record.offset, header.as_bytes(), block_dev);
+          (record.offset + used, record.len - used)
+        }
+      };
+      dir.write_at(offset, DirRecord::new(inode, len, name.len()).as_bytes(), block_dev);
+      dir.write_at(offset + DIR_RECORD_SZ, name.as_bytes(), block_dev);
+    }
+  }
+}
+
+/// Point the entry of `record` at `inode`
+pub fn set_inode(dir: &mut DiskInode, block_dev: &Arc<dyn BlockDevice>, format: DirFormat, record: &Record, inode: u32) {
+  let name = record.name.as_deref().unwrap();
+  match format {
+    DirFormat::Fixed => dir.write_at(record.offset, DirEntry::new(name, inode).as_bytes(), block_dev),
+    DirFormat::Records => dir.write_at(record.offset, DirRecord::new(inode, record.len, name.len()).as_bytes(), block_dev),
+  };
+}
+
+/// Mark `record` free space, keeping its length
+pub fn free(dir: &mut DiskInode, block_dev: &Arc<dyn BlockDevice>, format: DirFormat, record: &Record) {
+  match format {
+    DirFormat::Fixed => dir.write_at(record.offset, DirEntry::empty().as_bytes(), block_dev),
+    DirFormat::Records => dir.write_at(record.offset, DirRecord::new(0, record.len, 0).as_bytes(), block_dev),
+  };
+}
+
+/// Remove the entry of `records[idx]`, giving its space to the record before it if that
+/// is in the same block
+pub fn remove(dir: &mut DiskInode, block_dev: &Arc<dyn BlockDevice>, format: DirFormat, records: &[Record], idx: usize) {
+  let record = &records[idx];
+  let prev = idx.checked_sub(1).map(|prev| &records[prev]);
+  match prev {
+    Some(prev) if format == DirFormat::Records && prev.name.is_some() && prev.offset / BLOCK_SZ == record.offset / BLOCK_SZ => {
+      let name_len = prev.name.as_ref().unwrap().len();
+      let header = DirRecord::new(prev.inode, prev.len + record.len, name_len);
+      dir.write_at(prev.offset, header.as_bytes(), block_dev);
+    }
+    _ => free(dir, block_dev, format, record),
+  }
+}
+
+/// Size of a version 1 directory after creating entries with these names in this order
+pub fn dir_size<'a>(names: impl IntoIterator<Item = &'a str>) -> usize {
+  let format = DirFormat::Records;
+  // free bytes at the end of every block
+  let mut room: Vec<usize> = Vec::new();
+  for name in names {
+    let need = format.entry_len(name.len());
+    match room.iter_mut().find(|room| **room >= need) {
+      Some(room) => *room -= need,
+      None => room.push(BLOCK_SZ - need),
+    }
+  }
+  room.len() * BLOCK_SZ
+}
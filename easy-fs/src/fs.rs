@@ -1,17 +1,40 @@
 //! implentation of a easy FileSystem
-use alloc::sync::Arc;
+use alloc::{collections::{BTreeMap, BTreeSet}, sync::{Arc, Weak}, vec::Vec};
 use spin::Mutex;
 
-use crate::{block_dev::BlockDevice, bitmap::Bitmap, layout::{DiskInode, SuperBlock, DiskInodeType}, BLOCK_SZ, block_cache::{get_block_cache, block_cache_sync_all}, DataBlock, vfs::Inode};
+use crate::{
+  block_dev::BlockDevice, bitmap::Bitmap, BLOCK_SZ,
+  layout::{inode_size, DiskInode, DiskInodeType, InodeMeta, SuperBlock, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE, FS_VERSION},
+  block_cache::{get_block_cache, block_cache_sync, block_cache_release, block_cache_begin, block_cache_commit, block_cache_set_checksums},
+  checksum::{crc32, Checksums, ChecksumPolicy},
+  journal::{Journal, JOURNAL_BLOCKS}, DataBlock, vfs::Inode, dir::DirFormat,
+};
 
 /// FS on memory
 pub struct FileSystem {
   pub block_dev: Arc<dyn BlockDevice>,
   pub inode_bitmap: Bitmap,
   pub data_bitmap: Bitmap,
+  journal: Journal,
+  pub(crate) dir_format: DirFormat,
+  /// bytes every inode takes, `InodeMeta` included if the image has them
+  inode_size: usize,
+  /// current time in nanoseconds since the Unix epoch
+  clock: fn() -> u64,
+
+  /// does the super block keep the free counts?
+  pub(crate) stores_free_counts: bool,
+  /// can inodes have triple indirect blocks?
+  pub(crate) triple_indirect: bool,
+  /// checksum table, shared with the block cache, if the image has one
+  pub(crate) checksums: Option<Arc<Mutex<Checksums>>>,
+  /// the `Inode`s handed out, one per inode, so that unlinking can tell which are open
+  pub(crate) handles: BTreeMap<u32, Weak<Inode>>,
+  /// inodes unlinked while open, freed when their last handle drops
+  pub(crate) orphans: BTreeSet<u32>,
 
   inode_area_start_block: u32,
-  data_area_start_block: u32,
+  pub(crate) data_area_start_block: u32,
 }
 
 impl FileSystem {
@@ -20,103 +43,297 @@ impl FileSystem {
     total_blks: u32,
     inode_bitmap_blks: u32,
   ) -> Arc<Mutex<Self>> {
-    let inode_bitmap = Bitmap::new(1, inode_bitmap_blks as usize);
-    let inode_num = inode_bitmap.maximum();  // number of total inodes
-    let inode_area_blks = (inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ;
-
-    let data_tot_blks = total_blks - 1 - inode_bitmap_blks - inode_area_blks as u32;
-    let data_bitmap_blks = (data_tot_blks as usize + (BLOCK_SZ * 8) - 1) / (BLOCK_SZ * 8);
-    let data_area_blks = data_tot_blks as usize - data_bitmap_blks;
-    let data_bitmap = Bitmap::new(1 + inode_bitmap_blks as usize + inode_area_blks, data_bitmap_blks);
-
-    let mut fs = Self {
-      block_dev: block_dev.clone(),
-      inode_bitmap,
-      data_bitmap,
-
-      inode_area_start_block: 1 + inode_bitmap_blks,
-      data_area_start_block: 1 + inode_bitmap_blks + inode_area_blks as u32 + data_bitmap_blks as u32,
-    };
-
-    // initialize with zero
-    for i in 0..total_blks {
+    let super_blk = Self::layout(total_blks, inode_bitmap_blks)
+      .expect("the device is too small for the filesystem metadata");
+    let mut fs = Self::from_super_block(block_dev.clone(), &super_blk);
+    let table = (super_blk.journal_start + super_blk.journal_blocks) as usize..fs.inode_bitmap_start();
+    // whatever the device held before is not checked
+    block_cache_set_checksums(&block_dev, None);
+
+    // initialize with zero, which also leaves the journal empty; the checksum table
+    // is written on its own
+    for i in (0..total_blks).filter(|i| !table.contains(&(*i as usize))) {
       get_block_cache(i as usize, block_dev.clone())
         .lock()
         .modify(0, |blk: &mut DataBlock|{
           blk.iter_mut().for_each(|byte| *byte = 0);
         }) 
     }
+    fs.load_checksums(&super_blk, |table_start, first, covered| {
+      Checksums::new(table_start, first, covered, crc32(&[0; BLOCK_SZ]))
+    });
 
     get_block_cache(0, block_dev.clone()) 
       .lock()
-      .modify(0, |super_blk: &mut SuperBlock| {
-        *super_blk = SuperBlock::new(
-          total_blks, 
-          inode_bitmap_blks, 
-          inode_area_blks as u32, 
-          data_bitmap_blks as u32, 
-          data_area_blks as u32
-        );
-      });
+      .modify(0, |disk_super_blk: &mut SuperBlock| *disk_super_blk = super_blk);
+    fs.load_free_counts();
 
     assert_eq!(0, fs.alloc_inode());
     // initiliaze `/`
-    let (root_inode_blk_id, root_inode_offset) = fs.get_disk_inode_pos(0);
-    assert_eq!(0, root_inode_offset);
-    get_block_cache(root_inode_blk_id, block_dev.clone())
-      .lock()
-      .modify(root_inode_offset, |root_inode: &mut DiskInode| {
-        root_inode.initialize(DiskInodeType::Directory);
-      });
-    block_cache_sync_all();
+    assert_eq!((fs.inode_area_start_block as usize, 0), fs.initialize_inode(0, DiskInodeType::Directory));
+    block_cache_sync(&block_dev);
     Arc::new(Mutex::new(fs))
   }
 
-  /// Open a block device as a filesystem
+  /// Layout of a filesystem of `total_blks` blocks with `inode_bitmap_blks` blocks of inode
+  /// bitmap, `None` if that leaves no room for data
+  pub fn layout(total_blks: u32, inode_bitmap_blks: u32) -> Option<SuperBlock> {
+    // super block and journal
+    let meta_blks = 1 + JOURNAL_BLOCKS;
+    // a checksum for every block after them
+    let checksum_blks = ((total_blks.checked_sub(meta_blks)? as usize * 4 + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+    let inode_num = inode_bitmap_blks as usize * BLOCK_SZ * 8;
+    let inode_area_blks = ((inode_num * inode_size(FS_VERSION) + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+
+    let data_tot_blks = total_blks.checked_sub(meta_blks + checksum_blks + inode_bitmap_blks + inode_area_blks)?;
+    let data_bitmap_blks = (data_tot_blks + (BLOCK_SZ * 8) as u32 - 1) / (BLOCK_SZ * 8) as u32;
+    let data_area_blks = data_tot_blks - data_bitmap_blks;
+    if inode_bitmap_blks == 0 || data_area_blks == 0 {
+      return None;
+    }
+    Some(SuperBlock::new(
+      total_blks,
+      inode_bitmap_blks,
+      inode_area_blks,
+      data_bitmap_blks,
+      data_area_blks,
+      JOURNAL_BLOCKS,
+      checksum_blks,
+    ))
+  }
+
+  fn from_super_block(block_dev: Arc<dyn BlockDevice>, super_blk: &SuperBlock) -> Self {
+    let meta_blks = super_blk.journal_start + super_blk.journal_blocks + super_blk.checksum_blocks;
+    Self {
+      block_dev,
+      inode_bitmap: Bitmap::new(
+        meta_blks as usize,
+        super_blk.inode_bitmap_blocks as usize,
+        super_blk.inode_bitmap_blocks as usize * BLOCK_SZ * 8,
+      ),
+      data_bitmap: Bitmap::new(
+        (meta_blks + super_blk.inode_bitmap_blocks + super_blk.inode_area_blocks) as usize,
+        super_blk.data_bitmap_blocks as usize,
+        super_blk.data_area_blocks as usize,
+      ),
+      journal: Journal::new(super_blk.journal_start as usize, super_blk.journal_blocks as usize),
+      dir_format: DirFormat::of(super_blk),
+      inode_size: inode_size(super_blk.version),
+      clock: || 0,
+      stores_free_counts: super_blk.version >= 3,
+      triple_indirect: super_blk.version >= 4,
+      checksums: None,
+      handles: BTreeMap::new(),
+      orphans: BTreeSet::new(),
+      inode_area_start_block: meta_blks + super_blk.inode_bitmap_blocks,
+      data_area_start_block: meta_blks + super_blk.inode_bitmap_blocks + super_blk.inode_area_blocks + super_blk.data_bitmap_blocks, 
+    }
+  }
+
+  /// Does `block_dev` hold an easy-fs image?
+  pub fn probe(block_dev: &Arc<dyn BlockDevice>) -> bool {
+    SuperBlock::read(block_dev).is_valid()
+  }
+
+  /// Open a block device as a filesystem, first finishing a transaction a crash interrupted
   pub fn open(block_dev: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
-    let fs = get_block_cache(0, block_dev.clone()) 
+    let mut fs = Self::from_super_block(block_dev.clone(), &SuperBlock::read(&block_dev));
+    // replay writes the device directly, drop whatever was cached before
+    if fs.journal.replay(&block_dev) > 0 {
+      block_cache_release(&block_dev);
+    }
+    let super_blk = fs.super_block();
+    fs.load_checksums(&super_blk, |table_start, first, covered| Checksums::load(&block_dev, table_start, first, covered));
+    fs.load_free_counts();
+    Arc::new(Mutex::new(fs))
+  }
+
+  /// First block of the inode bitmap, which is the first one the checksum table covers
+  fn inode_bitmap_start(&self) -> usize {
+    self.inode_area_start_block as usize - self.inode_bitmap.blocks()
+  }
+
+  /// Set up the checksum table `table` makes from its first block, the first block it
+  /// covers and how many, and have the block cache check against it, if the image has one
+  fn load_checksums(&mut self, super_blk: &SuperBlock, table: impl FnOnce(usize, usize, usize) -> Checksums) {
+    self.checksums = (super_blk.version >= 5).then(|| {
+      let first = self.inode_bitmap_start();
+      let table_start = first - super_blk.checksum_blocks as usize;
+      Arc::new(Mutex::new(table(table_start, first, super_blk.total_blocks as usize - first)))
+    });
+    block_cache_set_checksums(&self.block_dev, self.checksums.clone());
+  }
+
+  /// What to do about blocks that fail their checksum, `ChecksumPolicy::ReadOnly` unless
+  /// set otherwise. Images from before version 5 have no checksums.
+  pub fn set_checksum_policy(&self, policy: ChecksumPolicy) {
+    if let Some(checksums) = &self.checksums {
+      checksums.lock().set_policy(policy);
+    }
+  }
+
+  /// The blocks found corrupt since the last call. The operations that came across them
+  /// failed, unless the policy is `ChecksumPolicy::Panic`.
+  pub fn take_corrupt_blocks(&self) -> Vec<usize> {
+    self.checksums.as_ref().map_or(Vec::new(), |checksums| checksums.lock().take_corrupt())
+  }
+
+  /// Has a corrupt block made the filesystem read-only?
+  pub fn is_read_only(&self) -> bool {
+    self.checksums.as_ref().is_some_and(|checksums| checksums.lock().is_read_only())
+  }
+
+  /// Copy of the super block
+  pub fn super_block(&self) -> SuperBlock {
+    SuperBlock::read(&self.block_dev)
+  }
+
+  /// Longest name the directories can hold, in bytes
+  pub fn name_max(&self) -> usize {
+    self.dir_format.name_max()
+  }
+
+  /// Take the time for new and changed inodes from `clock`, in nanoseconds since the Unix
+  /// epoch. Until this is called every time is 0.
+  pub fn set_clock(&mut self, clock: fn() -> u64) {
+    self.clock = clock;
+  }
+
+  pub(crate) fn now(&self) -> u64 {
+    (self.clock)()
+  }
+
+  /// Does the image store an `InodeMeta` for every inode?
+  pub(crate) fn has_meta(&self) -> bool {
+    self.inode_size > core::mem::size_of::<DiskInode>()
+  }
+
+  /// Make the inode `inode_id` an empty `type_`, returning its position like `get_disk_inode_pos`
+  pub(crate) fn initialize_inode(&self, inode_id: u32, type_: DiskInodeType) -> (usize, usize) {
+    let (block_id, block_offset) = self.get_disk_inode_pos(inode_id as usize);
+    let mode = if type_ == DiskInodeType::Directory { DEFAULT_DIR_MODE } else { DEFAULT_FILE_MODE };
+    let block = get_block_cache(block_id, self.block_dev.clone());
+    let mut block = block.lock();
+    block.modify(block_offset, |disk_inode: &mut DiskInode| disk_inode.initialize(type_, self.triple_indirect));
+    if self.has_meta() {
+      let now = self.now();
+      block.modify(block_offset + core::mem::size_of::<DiskInode>(), |meta: &mut InodeMeta| *meta = InodeMeta::new(mode, now));
+    }
+    (block_id, block_offset)
+  }
+
+  /// Take the free counts from the super block, or count the bitmaps of images from
+  /// before it kept them
+  fn load_free_counts(&mut self) {
+    if self.stores_free_counts {
+      let super_blk = self.super_block();
+      self.inode_bitmap.set_free(super_blk.free_inodes as usize);
+      self.data_bitmap.set_free(super_blk.free_data_blocks as usize);
+    } else {
+      self.recount_free();
+    }
+  }
+
+  /// Write the free counts back to the super block, if the image keeps them
+  fn store_free_counts(&self) {
+    if !self.stores_free_counts {
+      return;
+    }
+    let (free_inodes, free_data_blocks) = (self.inode_bitmap.free() as u32, self.data_bitmap.free() as u32);
+    get_block_cache(0, self.block_dev.clone())
       .lock()
-      .read(0, |super_blk: &SuperBlock| {
-        Self {
-          block_dev: block_dev.clone(),
-          inode_bitmap: Bitmap::new(1, super_blk.inode_bitmap_blocks as usize),
-          data_bitmap: Bitmap::new(
-            1 + super_blk.inode_bitmap_blocks as usize + super_blk.inode_area_blocks as usize, 
-            super_blk.data_bitmap_blocks as usize
-          ),
-          inode_area_start_block: 1 + super_blk.inode_bitmap_blocks,
-          data_area_start_block: 1 + super_blk.inode_bitmap_blocks + super_blk.inode_area_blocks + super_blk.data_bitmap_blocks, 
-        }
+      .modify(0, |super_blk: &mut SuperBlock| {
+        super_blk.free_inodes = free_inodes;
+        super_blk.free_data_blocks = free_data_blocks;
+        super_blk.seal();
       });
-    Arc::new(Mutex::new(fs))
+  }
+
+  /// Count both bitmaps again and store the result, after they were rewritten by hand
+  pub(crate) fn recount_free(&mut self) {
+    self.inode_bitmap.recount(&self.block_dev);
+    self.data_bitmap.recount(&self.block_dev);
+    self.store_free_counts();
+  }
+
+  /// Number of inodes in use
+  pub fn used_inodes(&self) -> usize {
+    self.inode_bitmap.maximum() - self.inode_bitmap.free()
+  }
+
+  /// Number of blocks of the data area in use, index blocks included
+  pub fn used_data_blocks(&self) -> usize {
+    self.data_bitmap.maximum() - self.data_bitmap.free()
+  }
+
+  /// Number of inodes left
+  pub fn free_inodes(&self) -> usize {
+    self.inode_bitmap.free()
+  }
+
+  /// Number of blocks of the data area left
+  pub fn free_data_blocks(&self) -> usize {
+    self.data_bitmap.free()
+  }
+
+  /// Start a transaction: the blocks modified until `commit` reach their home locations
+  /// all together or not at all
+  pub fn begin(&self) {
+    block_cache_begin(&self.block_dev);
+  }
+
+  /// Log the blocks modified since `begin`, then write them home. Returns false if a
+  /// corrupt block or a read-only filesystem got the transaction dropped instead.
+  pub fn commit(&mut self) -> bool {
+    let committed = block_cache_commit(&self.block_dev, &mut self.journal);
+    if !committed {
+      self.load_free_counts();
+    }
+    committed
   }
 
   pub fn alloc_inode(&mut self) -> u32 {
-    self.inode_bitmap.alloc(&self.block_dev).unwrap() as u32
+    let inode_id = self.inode_bitmap.alloc(&self.block_dev).expect("no free inode") as u32;
+    self.store_free_counts();
+    inode_id
   }
 
   pub fn dealloc_inode(&mut self, inode_id: usize) {
-    self.inode_bitmap.dealloc(&self.block_dev, inode_id)
+    self.inode_bitmap.dealloc(&self.block_dev, inode_id);
+    self.store_free_counts();
   }
 
-  /// available data_block's id in the device's layout
+  /// available data_block's id in the device's layout, zeroed
   pub fn alloc_data(&mut self) -> u32 {
-    self.data_bitmap.alloc(&self.block_dev).unwrap() as u32 + self.data_area_start_block
+    self.alloc_data_blocks(1)[0]
+  }
+
+  /// `count` available data blocks' ids in the device's layout, zeroed. They are
+  /// contiguous if a long enough run of free blocks is left.
+  pub fn alloc_data_blocks(&mut self, count: usize) -> Vec<u32> {
+    let bits = self.data_bitmap.alloc_many(&self.block_dev, count);
+    assert_eq!(bits.len(), count, "no free data block");
+    let data_ids: Vec<u32> = bits.into_iter().map(|bit| bit as u32 + self.data_area_start_block).collect();
+    for &data_id in &data_ids {
+      get_block_cache(data_id as usize, self.block_dev.clone())
+        .lock()
+        .modify(0, |data: &mut DataBlock| {
+          data.iter_mut().for_each(|byte| *byte = 0);
+        });
+    }
+    self.store_free_counts();
+    data_ids
   }
 
   pub fn dealloc_data(&mut self, data_id: usize) {
-    get_block_cache(data_id, self.block_dev.clone())
-      .lock()
-      .modify(0, |data: &mut DataBlock| {
-        data.iter_mut().for_each(|byte| *byte = 0);
-      });
     assert!(data_id >= self.data_area_start_block as usize, "{} {}", data_id, self.data_area_start_block);
     self.data_bitmap.dealloc(&self.block_dev, data_id - self.data_area_start_block as usize);
+    self.store_free_counts();
   }
 
   /// returns (block_id, inner_block_offset)
   pub fn get_disk_inode_pos(&self, inode_id: usize) -> (usize, usize) {
-    let inode_size = core::mem::size_of::<DiskInode>();
+    let inode_size = self.inode_size;
     let inodes_per_block = BLOCK_SZ / inode_size;
     let block_id = self.inode_area_start_block as usize + inode_id / inodes_per_block;
     (
@@ -125,6 +342,13 @@ impl FileSystem {
     )
   }
 
+  /// inverse of `get_disk_inode_pos`
+  pub fn get_inode_id(&self, block_id: usize, block_offset: usize) -> u32 {
+    let inode_size = self.inode_size;
+    let inodes_per_block = BLOCK_SZ / inode_size;
+    ((block_id - self.inode_area_start_block as usize) * inodes_per_block + block_offset / inode_size) as u32
+  }
+
   pub fn root_inode(fs: &Arc<Mutex<FileSystem>>) -> Inode {
     let block_dev = fs.lock().block_dev.clone();
     let (root_inode_blk_id, root_inode_offset) = fs.lock().get_disk_inode_pos(0);
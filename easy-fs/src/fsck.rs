@@ -0,0 +1,318 @@
+//! Consistency check of an image
+//!
+//! Walks the tree from `/`, collecting the inodes and blocks actually in use, and compares
+//! them against `inode_bitmap` and `data_bitmap`. Repairing drops what cannot be trusted
+//! (entries naming free inodes, inodes with bad or shared blocks) and then rewrites both
+//! bitmaps from what is left. Images that keep free counts in the super block get them
+//! checked against the bitmaps and recounted.
+//!
+//! Images with checksums get those checked first, against the device. If any is wrong
+//! nothing else is checked, as it would be built on corrupt blocks, unless repairing:
+//! then the checksums are made to match again and the rest is checked as usual.
+
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec, vec::Vec};
+use core::fmt::{self, Display, Formatter};
+use spin::Mutex;
+
+use crate::{
+  block_cache::{get_block_cache, block_cache_sync, block_cache_forget_corrupt}, block_dev::BlockDevice,
+  fs::FileSystem, layout::{DiskInode, DiskInodeType, SuperBlock}, dir::{self, Record}, BLOCK_SZ,
+};
+
+/// Something `fsck` found wrong
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FsckProblem {
+  /// an entry names an inode that is free or does not exist
+  DanglingEntry { dir: u32, name: String, inode: u32 },
+  /// an entry names an inode already reached through another one; easy-fs has no hard links
+  DuplicateEntry { dir: u32, name: String, inode: u32 },
+  /// a directory record without a valid name or length, `offset` bytes into the directory
+  BadEntry { dir: u32, offset: usize },
+  /// an inode of impossible size
+  BadInode { inode: u32 },
+  /// an inode pointing outside the data area
+  BadBlock { inode: u32, block: u32 },
+  /// a block `inode` uses although an inode reached earlier uses it too
+  DoubleAllocated { inode: u32, block: u32 },
+  /// an allocated inode no entry leads to
+  LeakedInode { inode: u32 },
+  /// an inode in use but marked free. Only `/` can be: an entry naming any other one is dangling.
+  UnmarkedInode { inode: u32 },
+  /// an allocated block no inode uses
+  LeakedBlock { block: u32 },
+  /// a block in use but marked free
+  UnmarkedBlock { block: u32 },
+  /// the super block counts `stored` free inodes, the inode bitmap `counted`
+  WrongFreeInodes { stored: u32, counted: u32 },
+  /// the super block counts `stored` free data blocks, the data bitmap `counted`
+  WrongFreeBlocks { stored: u32, counted: u32 },
+  /// a block, the super block if 0, that does not match its checksum
+  BadChecksum { block: u32 },
+}
+
+impl Display for FsckProblem {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::DanglingEntry { dir, name, inode } => write!(f, "entry {:?} in directory {} names free inode {}", name, dir, inode),
+      Self::DuplicateEntry { dir, name, inode } => write!(f, "entry {:?} in directory {} names inode {} again", name, dir, inode),
+      Self::BadEntry { dir, offset } => write!(f, "record at byte {} of directory {} is corrupt", offset, dir),
+      Self::BadInode { inode } => write!(f, "inode {} has an impossible size", inode),
+      Self::BadBlock { inode, block } => write!(f, "inode {} points to block {} outside the data area", inode, block),
+      Self::DoubleAllocated { inode, block } => write!(f, "block {} of inode {} is used by another inode too", block, inode),
+      Self::LeakedInode { inode } => write!(f, "inode {} is allocated but unreachable", inode),
+      Self::UnmarkedInode { inode } => write!(f, "inode {} is in use but marked free", inode),
+      Self::LeakedBlock { block } => write!(f, "block {} is allocated but unused", block),
+      Self::UnmarkedBlock { block } => write!(f, "block {} is in use but marked free", block),
+      Self::WrongFreeInodes { stored, counted } => write!(f, "super block counts {} free inodes, the bitmap {}", stored, counted),
+      Self::WrongFreeBlocks { stored, counted } => write!(f, "super block counts {} free data blocks, the bitmap {}", stored, counted),
+      Self::BadChecksum { block: 0 } => write!(f, "super block does not match its checksum"),
+      Self::BadChecksum { block } => write!(f, "block {} does not match its checksum", block),
+    }
+  }
+}
+
+/// Outcome of `fsck`
+#[derive(Default, Debug)]
+pub struct FsckReport {
+  pub problems: Vec<FsckProblem>,
+  /// inodes reached from `/`
+  pub inodes: usize,
+  /// blocks they use, index blocks included
+  pub blocks: usize,
+  /// the problems were fixed on the image
+  pub repaired: bool,
+}
+
+impl FsckReport {
+  pub fn is_clean(&self) -> bool {
+    self.problems.is_empty()
+  }
+}
+
+/// Check `fs`, and fix what is wrong if `repair` is set
+pub fn fsck(fs: &Arc<Mutex<FileSystem>>, repair: bool) -> FsckReport {
+  let mut fs = fs.lock();
+  let block_dev = fs.block_dev.clone();
+  let bad_checksums = check_checksums(&fs, repair);
+  if !bad_checksums.is_empty() && !repair {
+    return FsckReport { problems: bad_checksums, ..FsckReport::default() };
+  }
+  let super_blk = fs.super_block();
+  let data_area_blocks = super_blk.data_area_blocks as usize;
+  let mut checker = Checker {
+    fs: &fs,
+    block_dev: block_dev.clone(),
+    repair,
+    data_area_blocks,
+    reached: vec![false; fs.inode_bitmap.maximum()],
+    used: vec![false; data_area_blocks],
+    queue: VecDeque::new(),
+    report: FsckReport { problems: bad_checksums, ..FsckReport::default() },
+  };
+  // open inodes that were unlinked are still in use
+  for inode in core::iter::once(0).chain(fs.orphans.iter().copied()) {
+    checker.reached[inode as usize] = true;
+    checker.queue.push_back(inode);
+  }
+  while let Some(inode) = checker.queue.pop_front() {
+    checker.visit(inode);
+  }
+  if fs.stores_free_counts {
+    checker.check_free_counts(&super_blk);
+  }
+  checker.check_bitmaps();
+  let mut report = checker.report;
+  if repair && !report.is_clean() {
+    fs.recount_free();
+    block_cache_sync(&block_dev);
+    report.repaired = true;
+  }
+  report
+}
+
+/// Compare the super block and every block the checksum table covers on the device with
+/// their checksums, and make the checksums match if `repair` is set
+fn check_checksums(fs: &FileSystem, repair: bool) -> Vec<FsckProblem> {
+  let Some(checksums) = &fs.checksums else {
+    return Vec::new();
+  };
+  let block_dev = &fs.block_dev;
+  block_cache_sync(block_dev);
+  let mut problems = Vec::new();
+  let mut block = [0u8; BLOCK_SZ];
+  block_dev.read_block(0, &mut block);
+  let mut super_blk = unsafe { core::ptr::read_unaligned(block.as_ptr() as *const SuperBlock) };
+  if !super_blk.checksum_ok() {
+    problems.push(FsckProblem::BadChecksum { block: 0 });
+    if repair {
+      super_blk.seal();
+      get_block_cache(0, block_dev.clone())
+        .lock()
+        .modify(0, |disk_super_blk: &mut SuperBlock| *disk_super_blk = super_blk);
+    }
+  }
+  let mut checksums = checksums.lock();
+  for block_id in checksums.covered() {
+    block_dev.read_block(block_id, &mut block);
+    if !checksums.matches(block_id, &block) {
+      problems.push(FsckProblem::BadChecksum { block: block_id as u32 });
+      if repair {
+        checksums.update(block_id, &block);
+      }
+    }
+  }
+  if repair && !problems.is_empty() {
+    // the blocks are trusted as they are now: load them again, and write again
+    checksums.reset();
+    drop(checksums);
+    block_cache_forget_corrupt(block_dev);
+    block_cache_sync(block_dev);
+  }
+  problems
+}
+
+struct Checker<'a> {
+  fs: &'a FileSystem,
+  block_dev: Arc<dyn BlockDevice>,
+  repair: bool,
+  data_area_blocks: usize,
+  /// inodes reached so far, by inode id
+  reached: Vec<bool>,
+  /// blocks claimed so far, by position in the data area
+  used: Vec<bool>,
+  /// inodes reached but not visited yet
+  queue: VecDeque<u32>,
+  report: FsckReport,
+}
+
+impl Checker<'_> {
+  fn in_data_area(&self, block_id: u32) -> bool {
+    let start = self.fs.data_area_start_block;
+    block_id >= start && ((block_id - start) as usize) < self.data_area_blocks
+  }
+
+  /// Claim the blocks of `inode`, all of them or none
+  fn claim_blocks(&mut self, inode: u32, disk_inode: &DiskInode) -> Result<(), FsckProblem> {
+    let dir_size_ok = !disk_inode.is_dir() || self.fs.dir_format.valid_size(disk_inode.size() as usize);
+    let index_ok = !disk_inode.has_indirect3() || self.fs.triple_indirect;
+    if disk_inode.data_blocks() as usize > disk_inode.max_data_blocks() || !dir_size_ok || !index_ok {
+      return Err(FsckProblem::BadInode { inode });
+    }
+    let mut blocks = disk_inode
+      .blocks(&self.block_dev, |block_id| self.in_data_area(block_id))
+      .map_err(|block| FsckProblem::BadBlock { inode, block })?;
+    let start = self.fs.data_area_start_block;
+    blocks.sort_unstable();
+    let shared = blocks
+      .windows(2)
+      .find(|pair| pair[0] == pair[1])
+      .map(|pair| pair[0])
+      .or_else(|| blocks.iter().copied().find(|&block| self.used[(block - start) as usize]));
+    if let Some(block) = shared {
+      return Err(FsckProblem::DoubleAllocated { inode, block });
+    }
+    for &block in &blocks {
+      self.used[(block - start) as usize] = true;
+    }
+    self.report.blocks += blocks.len();
+    Ok(())
+  }
+
+  fn visit(&mut self, inode: u32) {
+    self.report.inodes += 1;
+    let (block_id, offset) = self.fs.get_disk_inode_pos(inode as usize);
+    let inode_block = get_block_cache(block_id, self.block_dev.clone());
+    let (claimed, is_dir) = inode_block
+      .lock()
+      .read(offset, |disk_inode: &DiskInode| (self.claim_blocks(inode, disk_inode), disk_inode.is_dir()));
+    if let Err(problem) = claimed {
+      self.report.problems.push(problem);
+      if self.repair {
+        let triple_indirect = self.fs.triple_indirect;
+        inode_block.lock().modify(offset, |disk_inode: &mut DiskInode| {
+          let type_ = if disk_inode.is_dir() { DiskInodeType::Directory } else { DiskInodeType::File };
+          disk_inode.initialize(type_, triple_indirect);
+        });
+      }
+      return;
+    }
+    if !is_dir {
+      return;
+    }
+    let format = self.fs.dir_format;
+    let records = inode_block
+      .lock()
+      .read(offset, |dir: &DiskInode| dir::records(dir, &self.block_dev, format));
+    for record in &records {
+      let Some(problem) = self.check_entry(inode, record) else {
+        continue;
+      };
+      self.report.problems.push(problem);
+      if self.repair {
+        inode_block.lock().modify(offset, |dir: &mut DiskInode| dir::free(dir, &self.block_dev, format, record));
+      }
+    }
+  }
+
+  /// Queue the inode `record` names, or say why the entry is wrong
+  fn check_entry(&mut self, dir: u32, record: &Record) -> Option<FsckProblem> {
+    let name = match &record.name {
+      None => return Some(FsckProblem::BadEntry { dir, offset: record.offset }),
+      // free space
+      Some(name) if name.is_empty() => return None,
+      Some(name) => name.clone(),
+    };
+    let inode = record.inode;
+    if inode as usize >= self.reached.len() || !self.fs.inode_bitmap.get(&self.block_dev, inode as usize) {
+      return Some(FsckProblem::DanglingEntry { dir, name, inode });
+    }
+    if self.reached[inode as usize] {
+      return Some(FsckProblem::DuplicateEntry { dir, name, inode });
+    }
+    self.reached[inode as usize] = true;
+    self.queue.push_back(inode);
+    None
+  }
+
+  /// Compare the free counts of the super block with the bitmaps as they are
+  fn check_free_counts(&mut self, super_blk: &SuperBlock) {
+    let free_inodes = self.fs.inode_bitmap.maximum().saturating_sub(self.fs.inode_bitmap.count_allocated(&self.block_dev)) as u32;
+    if super_blk.free_inodes != free_inodes {
+      self.report.problems.push(FsckProblem::WrongFreeInodes { stored: super_blk.free_inodes, counted: free_inodes });
+    }
+    let free_blocks = self.data_area_blocks.saturating_sub(self.fs.data_bitmap.count_allocated(&self.block_dev)) as u32;
+    if super_blk.free_data_blocks != free_blocks {
+      self.report.problems.push(FsckProblem::WrongFreeBlocks { stored: super_blk.free_data_blocks, counted: free_blocks });
+    }
+  }
+
+  /// Compare both bitmaps with what the walk found, rewriting the bits that differ if repairing
+  fn check_bitmaps(&mut self) {
+    for inode in 0..self.reached.len() {
+      let (allocated, reached) = (self.fs.inode_bitmap.get(&self.block_dev, inode), self.reached[inode]);
+      if allocated != reached {
+        let inode = inode as u32;
+        self.report.problems.push(match reached {
+          false => FsckProblem::LeakedInode { inode },
+          true => FsckProblem::UnmarkedInode { inode },
+        });
+        if self.repair {
+          self.fs.inode_bitmap.set(&self.block_dev, inode as usize, reached);
+        }
+      }
+    }
+    for bit in 0..self.data_area_blocks {
+      let (allocated, used) = (self.fs.data_bitmap.get(&self.block_dev, bit), self.used[bit]);
+      if allocated != used {
+        let block = self.fs.data_area_start_block + bit as u32;
+        self.report.problems.push(match used {
+          false => FsckProblem::LeakedBlock { block },
+          true => FsckProblem::UnmarkedBlock { block },
+        });
+        if self.repair {
+          self.fs.data_bitmap.set(&self.block_dev, bit, used);
+        }
+      }
+    }
+  }
+}
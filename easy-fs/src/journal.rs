@@ -0,0 +1,144 @@
+//! Write-ahead log making each filesystem operation atomic
+//!
+//! The journal region (right after the super block) holds one transaction at a time:
+//!
+//! ```text
+//! +--------+---------+---------+-----
+//! | header | copy 0  | copy 1  | ...
+//! +--------+---------+---------+-----
+//! ```
+//!
+//! Committing writes the new contents of every block the transaction modified into the
+//! copies, then the header listing their home block ids; the header write is the commit
+//! point. Then the blocks go to their home locations and the header is cleared. Replaying
+//! a header that is still set redoes the copy home, which is idempotent.
+//!
+//! All of this happens synchronously in the commit, whatever the `WritePolicy` of the block
+//! cache: write-back only delays blocks modified outside transactions.
+//!
+//! Images from before the journal have none: their transactions go straight to the home
+//! locations, so a crash may leave one half written.
+//!
+//! A transaction must fit in the journal. Operations that may touch many blocks (writing,
+//! freeing a file) split their work into transactions of bounded size; one that does not
+//! fit anyway is refused and dropped, never half written.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{BLOCK_SZ, block_dev::BlockDevice, DataBlock};
+
+const JOURNAL_MAGIC: u32 = 0x6a6e_6c31;
+
+/// Most blocks a header can list, hence a transaction can modify
+pub const JOURNAL_MAX_BLOCKS: usize = (BLOCK_SZ - 12) / 4;
+
+/// Size of the journal region, header included, of a newly created filesystem
+pub const JOURNAL_BLOCKS: u32 = 1 + JOURNAL_MAX_BLOCKS as u32;
+
+#[repr(C)]
+struct JournalHeader {
+  magic: u32,
+  /// number of the transaction, for diagnostics
+  seq: u32,
+  /// number of logged blocks, 0 if the journal is empty
+  count: u32,
+  /// home block id of each copy
+  blocks: [u32; JOURNAL_MAX_BLOCKS],
+}
+
+impl JournalHeader {
+  fn read(block_dev: &Arc<dyn BlockDevice>, block_id: usize) -> Self {
+    let mut header = core::mem::MaybeUninit::<Self>::uninit();
+    let bytes = unsafe { core::slice::from_raw_parts_mut(header.as_mut_ptr() as *mut u8, BLOCK_SZ) };
+    block_dev.read_block(block_id, bytes);
+    unsafe { header.assume_init() }
+  }
+
+  fn write(&self, block_dev: &Arc<dyn BlockDevice>, block_id: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, BLOCK_SZ) };
+    block_dev.write_block(block_id, bytes);
+  }
+
+  fn is_committed(&self) -> bool {
+    self.magic == JOURNAL_MAGIC && self.count > 0 && self.count as usize <= JOURNAL_MAX_BLOCKS
+  }
+}
+
+/// The journal region of a filesystem. It is accessed directly, never through the block cache.
+pub struct Journal {
+  start: usize,
+  blocks: usize,
+  seq: u32,
+}
+
+impl Journal {
+  /// The journal region of `blocks` blocks from `start` on, none if `blocks` is 0
+  pub fn new(start: usize, blocks: usize) -> Self {
+    assert!(blocks == 0 || blocks >= 2, "the journal needs a header and at least one copy");
+    Self { start, blocks, seq: 0 }
+  }
+
+  fn exists(&self) -> bool {
+    self.blocks > 0
+  }
+
+  /// Most blocks one transaction may modify, any number without a journal
+  pub fn capacity(&self) -> usize {
+    if !self.exists() {
+      return usize::MAX;
+    }
+    (self.blocks - 1).min(JOURNAL_MAX_BLOCKS)
+  }
+
+  /// Log `blocks`, returning once the transaction is committed on the device. Returns
+  /// false, having written nothing, if they overflow the journal.
+  pub fn commit(&mut self, block_dev: &Arc<dyn BlockDevice>, blocks: &[(usize, DataBlock)]) -> bool {
+    if blocks.len() > self.capacity() {
+      return false;
+    }
+    if !self.exists() {
+      return true;
+    }
+    let mut header = JournalHeader { magic: JOURNAL_MAGIC, seq: self.seq, count: blocks.len() as u32, blocks: [0; JOURNAL_MAX_BLOCKS] };
+    for (i, (block_id, data)) in blocks.iter().enumerate() {
+      block_dev.write_block(self.start + 1 + i, data);
+      header.blocks[i] = *block_id as u32;
+    }
+    header.write(block_dev, self.start);
+    self.seq = self.seq.wrapping_add(1);
+    true
+  }
+
+  /// Mark the journal empty, once the committed blocks reached their home locations
+  pub fn clear(&self, block_dev: &Arc<dyn BlockDevice>) {
+    if !self.exists() {
+      return;
+    }
+    let header = JournalHeader { magic: JOURNAL_MAGIC, seq: self.seq, count: 0, blocks: [0; JOURNAL_MAX_BLOCKS] };
+    header.write(block_dev, self.start);
+  }
+
+  /// Finish a transaction that was committed but maybe not written home before a crash.
+  /// Returns the number of blocks copied home.
+  pub fn replay(&mut self, block_dev: &Arc<dyn BlockDevice>) -> usize {
+    if !self.exists() {
+      return 0;
+    }
+    let header = JournalHeader::read(block_dev, self.start);
+    if header.magic == JOURNAL_MAGIC {
+      self.seq = header.seq.wrapping_add(1);
+    }
+    if !header.is_committed() {
+      return 0;
+    }
+    let count = header.count as usize;
+    let mut data: DataBlock = [0; BLOCK_SZ];
+    let homes: Vec<usize> = header.blocks[..count].iter().map(|&id| id as usize).collect();
+    for (i, home) in homes.iter().enumerate() {
+      block_dev.read_block(self.start + 1 + i, &mut data);
+      block_dev.write_block(*home, &data);
+    }
+    self.clear(block_dev);
+    count
+  }
+}
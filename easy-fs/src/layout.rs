@@ -5,25 +5,46 @@ use core::{fmt::{Debug, Result, Formatter}, mem::size_of, cmp::min};
 
 use alloc::{sync::Arc, vec::Vec};
 
-use crate::{BLOCK_SZ, block_dev::BlockDevice, block_cache::get_block_cache, DataBlock};
-
-/// FileSystem Magic Number
-const FS_MAGIC: u32 = 0x3b800001;
+use crate::{BLOCK_SZ, block_dev::BlockDevice, block_cache::get_block_cache, checksum::crc32, DataBlock};
+
+/// FileSystem Magic Number, bumped whenever the on-disk layout changes
+const FS_MAGIC: u32 = 0x3b800002;
+/// Magic of the images from before the journal: a super block of the fields up to
+/// `data_area_blocks`, the inode bitmap right after it, and version 0 otherwise
+const BASELINE_MAGIC: u32 = 0x3b800001;
+/// Version of the on-disk format within `FS_MAGIC`, bumped for changes older images can
+/// still be read after: 1 brought variable-length directory records, 2 an `InodeMeta`
+/// after every `DiskInode`, 3 the free counts in the super block, 4 triple indirect blocks
+/// and the high bits of the size, 5 a checksum table after the journal and a checksum of
+/// the super block
+pub const FS_VERSION: u32 = 5;
 /// Inode direct index
 const INODE_DIRECT_COUNT: usize = 28;
 /// indirect index range
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
-const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * BLOCK_SZ / 4;
-const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
-const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
-
-const NAME_LENGTH_LIMIT: usize = 27;
+const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+const INODE_INDIRECT3_COUNT: usize = INODE_INDIRECT2_COUNT * INODE_INDIRECT1_COUNT;
+/// most data blocks a file can have on images from before version 4, which have no
+/// triple indirect blocks
+pub const SMALL_MAX_DATA_BLOCKS: usize = INODE_DIRECT_COUNT + INODE_INDIRECT1_COUNT + INODE_INDIRECT2_COUNT;
+/// most data blocks a file can have
+pub const MAX_DATA_BLOCKS: usize = SMALL_MAX_DATA_BLOCKS - 1 + INODE_INDIRECT3_COUNT;
+/// largest file, in bytes
+pub const MAX_FILE_SIZE: u64 = MAX_DATA_BLOCKS as u64 * BLOCK_SZ as u64;
+/// largest file on images from before version 4, in bytes
+pub const SMALL_MAX_FILE_SIZE: u64 = SMALL_MAX_DATA_BLOCKS as u64 * BLOCK_SZ as u64;
+
+/// longest name, in bytes
+pub const NAME_LENGTH_LIMIT: usize = 255;
+/// longest name of a version 0 image
+pub const FIXED_NAME_LENGTH_LIMIT: usize = 27;
 
 /// Block that stores indirect block's indexes
 type IndirectBlock = [u32; BLOCK_SZ / size_of::<u32>()];
 
 /// Super block of a filesystem
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SuperBlock {
   magic: u32,
   pub total_blocks: u32,
@@ -31,6 +52,18 @@ pub struct SuperBlock {
   pub inode_area_blocks: u32,
   pub data_bitmap_blocks: u32,
   pub data_area_blocks: u32,
+  /// the write-ahead log sits between the super block and the inode bitmap
+  pub journal_start: u32,
+  pub journal_blocks: u32,
+  /// `FS_VERSION` of the image, 0 for images from before there was one
+  pub version: u32,
+  /// free entries of the bitmaps, kept up to date from version 3 on
+  pub free_inodes: u32,
+  pub free_data_blocks: u32,
+  /// the checksum table sits between the journal and the inode bitmap, from version 5 on
+  pub checksum_blocks: u32,
+  /// CRC32 of the super block with this field 0, from version 5 on
+  checksum: u32,
 }
 
 impl Debug for SuperBlock {
@@ -41,6 +74,13 @@ impl Debug for SuperBlock {
       .field("inode_area_blocks", &self.inode_area_blocks)
       .field("data_bitmap_blocks", &self.data_bitmap_blocks)
       .field("data_area_blocks", &self.data_area_blocks)
+      .field("journal_start", &self.journal_start)
+      .field("journal_blocks", &self.journal_blocks)
+      .field("version", &self.version)
+      .field("free_inodes", &self.free_inodes)
+      .field("free_data_blocks", &self.free_data_blocks)
+      .field("checksum_blocks", &self.checksum_blocks)
+      .field("checksum", &self.checksum)
       .finish()
   }
 }
@@ -52,23 +92,86 @@ impl SuperBlock {
     inode_area_blocks: u32,
     data_bitmap_blocks: u32,
     data_area_blocks: u32,
+    journal_blocks: u32,
+    checksum_blocks: u32,
   ) -> Self {
-    Self {
+    let mut super_blk = Self {
       magic: FS_MAGIC,
       total_blocks,
       inode_bitmap_blocks,
       inode_area_blocks,
       data_bitmap_blocks,
       data_area_blocks,
+      journal_start: 1,
+      journal_blocks,
+      version: FS_VERSION,
+      free_inodes: inode_bitmap_blocks * (BLOCK_SZ * 8) as u32,
+      free_data_blocks: data_area_blocks,
+      checksum_blocks,
+      checksum: 0,
+    };
+    super_blk.seal();
+    super_blk
+  }
+
+  fn compute_checksum(&self) -> u32 {
+    let unsealed = Self { checksum: 0, ..*self };
+    crc32(unsafe { core::slice::from_raw_parts(&unsealed as *const _ as *const u8, size_of::<Self>()) })
+  }
+
+  /// Update the checksum after changing fields; a no-op before version 5
+  pub fn seal(&mut self) {
+    if self.version >= 5 {
+      self.checksum = self.compute_checksum();
     }
   }
 
-  #[allow(unused)]
+  /// Does the checksum match, or is the image too old to have one?
+  pub fn checksum_ok(&self) -> bool {
+    self.version < 5 || self.checksum == self.compute_checksum()
+  }
+
+  /// Is this the super block of an image this code can read?
   pub fn is_valid(&self) -> bool {
-    self.magic == FS_MAGIC
+    (self.magic == FS_MAGIC && self.version <= FS_VERSION && self.checksum_ok()) || self.magic == BASELINE_MAGIC
+  }
+
+  /// The super block of `block_dev`. On images from before the journal the bytes after
+  /// `data_area_blocks` are not part of it, so they read as a version 0 image without
+  /// journal has them.
+  pub fn read(block_dev: &Arc<dyn BlockDevice>) -> Self {
+    let super_blk = get_block_cache(0, block_dev.clone())
+      .lock()
+      .read(0, |super_blk: &Self| *super_blk);
+    if super_blk.magic != BASELINE_MAGIC {
+      return super_blk;
+    }
+    Self {
+      journal_start: 1,
+      journal_blocks: 0,
+      version: 0,
+      free_inodes: 0,
+      free_data_blocks: 0,
+      checksum_blocks: 0,
+      checksum: 0,
+      ..super_blk
+    }
+  }
+}
+
+
+/// Bytes every inode takes in the inode area of an image of `version`
+pub fn inode_size(version: u32) -> usize {
+  match version {
+    0 | 1 => size_of::<DiskInode>(),
+    _ => size_of::<DiskInode>() + size_of::<InodeMeta>(),
   }
 }
 
+/// Blocks a file or directory of `size` bytes occupies on a current image, index blocks included
+pub fn blocks_for_size(size: u64) -> u64 {
+  DiskInode::blocks_for(size, INODE_DIRECT_COUNT - 1) as u64
+}
 
 #[derive(PartialEq)]
 pub enum DiskInodeType {
@@ -76,23 +179,44 @@ pub enum DiskInodeType {
   Directory,
 }
 
+/// `DiskInode::flags`: the last direct pointer is the triple indirect block instead
+const FLAG_INDIRECT3: u8 = 1;
+
+/// A pointer held by the `DiskInode` itself
+#[derive(Clone, Copy)]
+enum Root {
+  Direct(usize),
+  Indirect1,
+  Indirect2,
+  Indirect3,
+}
+
 #[repr(C)]
 pub struct DiskInode {
-  /// file's total bytes, not includes `indirects`
-  pub size: u32, 
+  /// low half of the file's bytes, not includes `indirects`
+  size: u32, 
   direct: [u32; INODE_DIRECT_COUNT],
   indirect1: u32,
   indirect2: u32,
   type_: DiskInodeType,
+  /// `FLAG_INDIRECT3` on inodes of version 4 images, the padding older ones left zero
+  flags: u8,
+  /// bits 32 to 47 of the size, all the inode has room for: no file can be larger, as
+  /// `MAX_FILE_SIZE` is checked to fit, and neither can a device with `u32` block ids
+  size_hi: u16,
 }
 
+const _: () = assert!(MAX_FILE_SIZE < 1 << 48 && ((u32::MAX as u64 + 1) * BLOCK_SZ as u64) <= 1 << 48);
+
 impl DiskInode {
-  pub fn initialize(&mut self, type_: DiskInodeType) {
-    self.size = 0;
+  /// `indirect3` inodes give up their last direct block for a triple indirect one
+  pub fn initialize(&mut self, type_: DiskInodeType, indirect3: bool) {
+    self.set_size(0);
     self.direct.iter_mut().for_each(|a| *a = 0);
     self.indirect1 = 0;
     self.indirect2 = 0;
     self.type_ = type_;
+    self.flags = if indirect3 { FLAG_INDIRECT3 } else { 0 };
   }
 
   pub fn is_dir(&self) -> bool {
@@ -104,218 +228,231 @@ impl DiskInode {
     self.type_ == DiskInodeType::File
   }
 
+  /// file's total bytes, not includes `indirects`
+  pub fn size(&self) -> u64 {
+    (self.size_hi as u64) << 32 | self.size as u64
+  }
+
+  fn set_size(&mut self, size: u64) {
+    assert!(size < 1 << 48, "size {} does not fit in an inode", size);
+    self.size = size as u32;
+    self.size_hi = (size >> 32) as u16;
+  }
+
+  /// Can the inode have a triple indirect block?
+  pub fn has_indirect3(&self) -> bool {
+    self.flags & FLAG_INDIRECT3 != 0
+  }
+
+  fn direct_count(&self) -> usize {
+    if self.has_indirect3() { INODE_DIRECT_COUNT - 1 } else { INODE_DIRECT_COUNT }
+  }
+
+  /// Most data blocks the inode can index
+  pub fn max_data_blocks(&self) -> usize {
+    if self.has_indirect3() { MAX_DATA_BLOCKS } else { SMALL_MAX_DATA_BLOCKS }
+  }
+
+  /// Largest size the inode can grow to, in bytes
+  pub fn max_size(&self) -> u64 {
+    self.max_data_blocks() as u64 * BLOCK_SZ as u64
+  }
+
   /// blocks number for storing data (dispite of inodes)
   pub fn data_blocks(&self) -> u32 { 
-    DiskInode::_data_blocks(self.size)
+    DiskInode::_data_blocks(self.size())
   }
-  pub fn _data_blocks(size: u32) -> u32 {
-    (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+  pub fn _data_blocks(size: u64) -> u32 {
+    ((size + BLOCK_SZ as u64 - 1) / BLOCK_SZ as u64) as u32
+  }
+
+  /// Return number of blocks in use, index blocks included.
+  pub fn total_blocks(&self) -> u32 {
+    Self::blocks_for(self.size(), self.direct_count())
   }
 
-  /// Return number of blocks needed include indirect1/2.
-  pub fn total_blocks(size: u32) -> u32 {
+  /// Blocks of `size` bytes of an inode with `direct` direct blocks, index blocks included
+  fn blocks_for(size: u64, direct: usize) -> u32 {
     let data_blocks = Self::_data_blocks(size) as usize;
-    let mut total = data_blocks as usize;
-    // TODO: total += 1 ?
-    if data_blocks > INODE_DIRECT_COUNT {
-      total += 1;
-    }
-    if data_blocks > INDIRECT1_BOUND {
-      total += 1;
-      total += (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+    let mut total = data_blocks;
+    let mut rest = data_blocks.saturating_sub(direct);
+    for depth in 1..=3 {
+      let below = min(rest, INODE_INDIRECT1_COUNT.pow(depth));
+      // index blocks of a tree `depth` levels deep over `below` data blocks
+      for level in 1..=depth {
+        let per = INODE_INDIRECT1_COUNT.pow(level);
+        total += (below + per - 1) / per;
+      }
+      rest -= below;
     }
     total as u32
   }
 
-  pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-    assert!(new_size >= self.size);
-    Self::total_blocks(new_size) - Self::total_blocks(self.size)
+  pub fn blocks_num_needed(&self, new_size: u64) -> u32 {
+    assert!(new_size >= self.size());
+    Self::blocks_for(new_size, self.direct_count()) - self.total_blocks()
+  }
+
+  fn root(&self, root: Root) -> u32 {
+    match root {
+      Root::Direct(i) => self.direct[i],
+      Root::Indirect1 => self.indirect1,
+      Root::Indirect2 => self.indirect2,
+      Root::Indirect3 => self.direct[INODE_DIRECT_COUNT - 1],
+    }
+  }
+
+  fn root_mut(&mut self, root: Root) -> &mut u32 {
+    match root {
+      Root::Direct(i) => &mut self.direct[i],
+      Root::Indirect1 => &mut self.indirect1,
+      Root::Indirect2 => &mut self.indirect2,
+      Root::Indirect3 => &mut self.direct[INODE_DIRECT_COUNT - 1],
+    }
+  }
+
+  /// Where data block `inner_id` hangs: the pointer of the inode it is found through, how
+  /// many index blocks lie between, and the entry taken in each of them
+  fn locate(&self, inner_id: usize) -> (Root, usize, [usize; 3]) {
+    const P: usize = INODE_INDIRECT1_COUNT;
+    let direct = self.direct_count();
+    if inner_id < direct {
+      return (Root::Direct(inner_id), 0, [0; 3]);
+    }
+    let n = inner_id - direct;
+    if n < P {
+      return (Root::Indirect1, 1, [n, 0, 0]);
+    }
+    let n = n - P;
+    if n < INODE_INDIRECT2_COUNT {
+      return (Root::Indirect2, 2, [n / P, n % P, 0]);
+    }
+    let n = n - INODE_INDIRECT2_COUNT;
+    assert!(self.has_indirect3() && n < INODE_INDIRECT3_COUNT, "block {} is past the end of the index", inner_id);
+    (Root::Indirect3, 3, [n / INODE_INDIRECT2_COUNT, n / P % P, n % P])
   }
 
   /// get block's id of the DiskInode
   pub fn get_block_id(&self, inner_id: u32, block_dev: &Arc<dyn BlockDevice>) -> u32 {
-    let inner_id = inner_id as usize;
-    if inner_id < DIRECT_BOUND {
-      self.direct[inner_id]
-    } else if inner_id < INDIRECT1_BOUND {
-      get_block_cache(self.indirect1 as usize, block_dev.clone())
+    let (root, depth, path) = self.locate(inner_id as usize);
+    path[..depth].iter().fold(self.root(root), |block_id, &entry| {
+      get_block_cache(block_id as usize, block_dev.clone())
         .lock()
-        .read(0, |indirect1: &IndirectBlock| {
-          indirect1[inner_id - DIRECT_BOUND]
-        }) 
-    } else {
-      let indirect1 = get_block_cache(self.indirect2 as usize, block_dev.clone())
-        .lock()
-        .read(0, |indirect_blks: &IndirectBlock| {
-          indirect_blks[(inner_id - INDIRECT1_BOUND) / INODE_INDIRECT1_COUNT]
-        });
-      get_block_cache(indirect1 as usize, block_dev.clone())
-        .lock()
-        .read(0, |indirect2: &IndirectBlock| {
-          indirect2[(inner_id - INDIRECT1_BOUND) % INODE_INDIRECT1_COUNT]
-        })
-    }
+        .read(0, |indirect_blks: &IndirectBlock| indirect_blks[entry])
+    })
   }
 
   /// increase current file's size to `new_size`
   pub fn increase_size(
     &mut self,
-    new_size: u32,
+    new_size: u64,
     new_blocks: Vec<u32>,
     block_dev: &Arc<dyn BlockDevice>,
   ) {
-    assert!(new_size >= self.size);
-    let mut cur_data_blks = self.data_blocks() as usize;
-    self.size = new_size;
-    let mut tot_data_blks = self.data_blocks() as usize;
-    
+    assert!(new_size >= self.size());
+    let cur_data_blks = self.data_blocks() as usize;
+    self.set_size(new_size);
+    let tot_data_blks = self.data_blocks() as usize;
+    assert!(tot_data_blks <= self.max_data_blocks());
+
     let mut blk_iter = new_blocks.into_iter();
-    while cur_data_blks < min(DIRECT_BOUND, tot_data_blks) {
-      self.direct[cur_data_blks] = blk_iter.next().unwrap();
-      cur_data_blks += 1;
+    for inner_id in cur_data_blks..tot_data_blks {
+      let (root, depth, path) = self.locate(inner_id);
+      // an index block is new when the first entry below it gets used
+      let fresh = |level: usize| path[level..depth].iter().all(|&entry| entry == 0);
+      if fresh(0) {
+        *self.root_mut(root) = blk_iter.next().unwrap();
+      }
+      let mut block_id = self.root(root);
+      for (level, &entry) in path[..depth].iter().enumerate() {
+        let new = fresh(level + 1).then(|| blk_iter.next().unwrap());
+        block_id = get_block_cache(block_id as usize, block_dev.clone())
+          .lock()
+          .modify(0, |indirect_blks: &mut IndirectBlock| {
+            if let Some(new) = new {
+              indirect_blks[entry] = new;
+            }
+            indirect_blks[entry]
+          });
+      }
     }
+    assert!(blk_iter.next().is_none());
+  }
 
-    if tot_data_blks <= DIRECT_BOUND {
-      return ;
-    } 
-
-    assert!(cur_data_blks >= DIRECT_BOUND);
-    if cur_data_blks == INODE_DIRECT_COUNT { // expand indirect1
-      self.indirect1 = blk_iter.next().unwrap();
+  /// Shrink to `new_size` and return the blocks past it, index blocks included, that
+  /// should be deallocated. The rest of the last block kept is zeroed, so growing again
+  /// reads zeroes there. The entries of the index blocks kept are left as they are:
+  /// `FileSystem::alloc_data` zeroes blocks on reuse.
+  pub fn decrease_size(&mut self, new_size: u64, block_dev: &Arc<dyn BlockDevice>) -> Vec<u32> {
+    assert!(new_size <= self.size());
+    let new_data_blks = Self::_data_blocks(new_size) as usize;
+    let mut vec = Vec::new();
+    for inner_id in (new_data_blks..self.data_blocks() as usize).rev() {
+      let (root, depth, path) = self.locate(inner_id);
+      // an index block is unused once the first entry below it is
+      let emptied = |level: usize| path[level..depth].iter().all(|&entry| entry == 0);
+      let mut block_id = self.root(root);
+      if emptied(0) {
+        vec.push(block_id);
+        *self.root_mut(root) = 0;
+      }
+      for (level, &entry) in path[..depth].iter().enumerate() {
+        block_id = get_block_cache(block_id as usize, block_dev.clone())
+          .lock()
+          .read(0, |indirect_blks: &IndirectBlock| indirect_blks[entry]);
+        if emptied(level + 1) {
+          vec.push(block_id);
+        }
+      }
     }
-    cur_data_blks -= DIRECT_BOUND;
-    tot_data_blks -= DIRECT_BOUND;
-
-    if cur_data_blks < min(INODE_INDIRECT1_COUNT, tot_data_blks) {
-      get_block_cache(self.indirect1 as usize, block_dev.clone())
+    let tail = new_size as usize % BLOCK_SZ;
+    if tail != 0 && new_size < self.size() {
+      let block_id = self.get_block_id(new_data_blks as u32 - 1, block_dev) as usize;
+      get_block_cache(block_id, block_dev.clone())
         .lock()
-        .modify(0, |indirect_blks: &mut IndirectBlock| {
-          while cur_data_blks < min(INODE_INDIRECT1_COUNT, tot_data_blks) {
-            indirect_blks[cur_data_blks] = blk_iter.next().unwrap();
-            cur_data_blks += 1;
-          }
-        });
-    }
-    
-    if tot_data_blks <= INODE_INDIRECT1_COUNT {
-      return;
-    }
-
-    assert!(cur_data_blks >= INODE_INDIRECT1_COUNT);
-    if cur_data_blks == INODE_INDIRECT1_COUNT {
-      self.indirect2 = blk_iter.next().unwrap();
+        .modify(0, |data: &mut DataBlock| data[tail..].fill(0));
     }
-    cur_data_blks -= INODE_INDIRECT1_COUNT;
-    tot_data_blks -= INODE_INDIRECT1_COUNT;
-
-    let mut a0 = cur_data_blks / INODE_INDIRECT1_COUNT;
-    let mut b0 = cur_data_blks % INODE_INDIRECT1_COUNT;
-    let a1 = tot_data_blks / INODE_INDIRECT1_COUNT;
-    let b1 = tot_data_blks % INODE_INDIRECT1_COUNT;
-    get_block_cache(self.indirect2 as usize, block_dev.clone())
-      .lock()
-      .modify(0, |indirect1: &mut IndirectBlock| {
-        while (a0 < a1) || (a0 == a1 && b0 < b1) {
-          if b0 == 0 {
-            indirect1[a0] = blk_iter.next().unwrap();
-          }
-          // modify (a0, b0)
-          get_block_cache(indirect1[a0] as usize, block_dev.clone())
-            .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-              indirect2[b0] = blk_iter.next().unwrap();
-            }); 
-          
-          b0 += 1;
-          if b0 == INODE_INDIRECT1_COUNT {
-            b0 = 0;
-            a0 += 1;
-          }
-        }
-      });
+    self.set_size(new_size);
+    // only a corrupt index block leaves a hole
+    vec.retain(|&block_id| block_id != 0);
+    vec
   }
 
-  /// Clear size to zero and return blocks that should be deallocated.
-  /// We will clear the block contents to zero later.
-  /// TODO: debug
-  pub fn clear_size(&mut self, block_dev: &Arc<dyn BlockDevice>) -> Vec<u32> {
+  /// Every block in use, index blocks included, without changing anything.
+  /// Stops at the first block for which `valid` fails, which is returned as the error,
+  /// so a corrupt index block is never followed. `size` must be at most `max_data_blocks` blocks.
+  pub fn blocks(&self, block_dev: &Arc<dyn BlockDevice>, valid: impl Fn(u32) -> bool) -> core::result::Result<Vec<u32>, u32> {
+    let data_blks = self.data_blocks() as usize;
+    assert!(data_blks <= self.max_data_blocks());
     let mut vec: Vec<u32> = Vec::new();
-    let mut tot_data_blks = self.data_blocks() as usize;
-    let mut cur_data_blks = 0usize;
-
-    self.size = 0;
-
-    while cur_data_blks < min(DIRECT_BOUND, tot_data_blks){
-      vec.push(self.direct[cur_data_blks]);
-      self.direct[cur_data_blks] = 0;
-      cur_data_blks += 1;
-    } 
+    let mut take = |block_id: u32| if valid(block_id) {
+      vec.push(block_id);
+      Ok(())
+    } else {
+      Err(block_id)
+    };
 
-    if tot_data_blks <= DIRECT_BOUND {
-      return vec;
+    let direct = self.direct_count();
+    for &block_id in &self.direct[..min(direct, data_blks)] {
+      take(block_id)?;
     }
-
-    vec.push(self.indirect1);
-    tot_data_blks -= DIRECT_BOUND;
-    cur_data_blks -= DIRECT_BOUND;
-    
-    get_block_cache(self.indirect1 as usize, block_dev.clone())
-      .lock()
-      .modify(0, |indirect_blks: &mut IndirectBlock| {
-        while cur_data_blks < min(INODE_INDIRECT1_COUNT, tot_data_blks) {
-          vec.push(indirect_blks[cur_data_blks]);
-          indirect_blks[cur_data_blks] = 0;
-          cur_data_blks += 1;
-        }
-      });
-    self.indirect1 = 0;
-
-    if tot_data_blks <= INODE_INDIRECT1_COUNT {
-      return vec;
+    let mut rest = data_blks.saturating_sub(direct);
+    for (depth, root) in [(1, Root::Indirect1), (2, Root::Indirect2), (3, Root::Indirect3)] {
+      let below = min(rest, INODE_INDIRECT1_COUNT.pow(depth));
+      if below == 0 {
+        break;
+      }
+      walk_index(self.root(root), depth, below, block_dev, &mut take)?;
+      rest -= below;
     }
-    vec.push(self.indirect2);
-    tot_data_blks -= INODE_INDIRECT1_COUNT;
-    cur_data_blks -= INODE_INDIRECT1_COUNT;
-
-    assert_eq!(cur_data_blks, 0);
-    assert!(tot_data_blks <= INODE_INDIRECT2_COUNT);
-    get_block_cache(self.indirect2 as usize, block_dev.clone())
-      .lock()
-      .modify(0, |indirect1: &mut IndirectBlock| {
-        let mut retrived: usize = 0;
-        while retrived * INODE_INDIRECT1_COUNT < tot_data_blks {
-          get_block_cache(indirect1[retrived] as usize, block_dev.clone())
-            .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-              let mut i: usize = 0;
-              while cur_data_blks < tot_data_blks && i < INODE_INDIRECT1_COUNT {
-                assert_ne!(0, indirect2[i], 
-                  "Error message: i = {} 
-                  retrived = {} 
-                  cur_data_blks = {} 
-                  tot_data_blks = {} 
-                  indirect1 = {}", 
-                  i, retrived, cur_data_blks, tot_data_blks, indirect1[retrived]);
-                vec.push(indirect2[i]);
-                i += 1;
-                cur_data_blks += 1;
-              }
-            });
-            assert_ne!(0, indirect1[retrived]);
-          vec.push(indirect1[retrived]);
-          retrived += 1;
-        }
-      });
-    self.indirect2 = 0;
-
-    vec
+    Ok(vec)
   }
 
   /// read data from disk inode to `buf`
   pub fn read_at(&self, offset: usize, buf: &mut [u8], block_dev: &Arc<dyn BlockDevice>) -> usize {
     // [start, end)
     let mut start = offset;
-    let end = min(self.size as usize, start + buf.len());
+    let end = min(self.size() as usize, start + buf.len());
     if start >= end {
       return 0;
     }
@@ -325,16 +462,20 @@ impl DiskInode {
       let cur_block_end = min(end, (start / BLOCK_SZ + 1) * BLOCK_SZ);
       let block_read_size = cur_block_end - start;
       let dst = &mut buf[read_size..read_size + block_read_size];
-      get_block_cache(
-        self.get_block_id(start_block as u32, block_dev) as usize, 
-        block_dev.clone()
-      ) .lock()
-        .read(0, |data: &DataBlock| {
-          assert!(start % BLOCK_SZ + block_read_size <= BLOCK_SZ, 
-            "start: {}, end: {}, read size: {}", start, cur_block_end, start % BLOCK_SZ + block_read_size);
-          let src = &data[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-          dst.copy_from_slice(src);
-        });
+      let block_id = self.get_block_id(start_block as u32, block_dev) as usize;
+      if block_id == 0 {
+        // only a corrupt index block leaves a hole, read as zeroes rather than the super block
+        dst.fill(0);
+      } else {
+        get_block_cache(block_id, block_dev.clone())
+          .lock()
+          .read(0, |data: &DataBlock| {
+            assert!(start % BLOCK_SZ + block_read_size <= BLOCK_SZ, 
+              "start: {}, end: {}, read size: {}", start, cur_block_end, start % BLOCK_SZ + block_read_size);
+            let src = &data[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+            dst.copy_from_slice(src);
+          });
+      }
 
       read_size += block_read_size;
       start += block_read_size;
@@ -349,7 +490,7 @@ impl DiskInode {
   /// write data into disk inode from `buf`
   pub fn write_at(&mut self, offset: usize, buf: &[u8], block_dev: &Arc<dyn BlockDevice>) -> usize {
     let mut start = offset;
-    let end = min(self.size as usize, start + buf.len());
+    let end = min(self.size() as usize, start + buf.len());
     if start >= end {
       return 0;
     }
@@ -382,9 +523,104 @@ impl DiskInode {
 
 }
 
+/// Take `block_id` and, if it is an index block `depth` levels above the data, the index
+/// and data blocks below it that hold the first `data_blks` data blocks
+fn walk_index(
+  block_id: u32,
+  depth: u32,
+  data_blks: usize,
+  block_dev: &Arc<dyn BlockDevice>,
+  take: &mut impl FnMut(u32) -> core::result::Result<(), u32>,
+) -> core::result::Result<(), u32> {
+  take(block_id)?;
+  if depth == 0 {
+    return Ok(());
+  }
+  let entries = get_block_cache(block_id as usize, block_dev.clone())
+    .lock()
+    .read(0, |indirect_blks: &IndirectBlock| *indirect_blks);
+  let per = INODE_INDIRECT1_COUNT.pow(depth - 1);
+  for (i, &child) in entries[..(data_blks + per - 1) / per].iter().enumerate() {
+    walk_index(child, depth - 1, min(per, data_blks - i * per), block_dev, take)?;
+  }
+  Ok(())
+}
+
+/// Permission bits of a new directory
+pub const DEFAULT_DIR_MODE: u32 = 0o755;
+/// Permission bits of a new file
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Owner, permissions and times of an inode, stored right after its `DiskInode` on
+/// version 2 images. Older images get `InodeMeta::new` with the default mode and no times.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InodeMeta {
+  /// permission bits, as in the low 12 bits of `st_mode`
+  pub mode: u32,
+  pub uid: u32,
+  pub gid: u32,
+  /// nanoseconds since the Unix epoch, as low and high halves since blocks are only
+  /// cached 4 byte aligned
+  atime: [u32; 2],
+  mtime: [u32; 2],
+  ctime: [u32; 2],
+  _reserved: [u32; 23],
+}
+
+fn join_time(time: [u32; 2]) -> u64 {
+  (time[1] as u64) << 32 | time[0] as u64
+}
+
+fn split_time(time: u64) -> [u32; 2] {
+  [time as u32, (time >> 32) as u32]
+}
+
+impl InodeMeta {
+  /// Owned by root with all times `now`
+  pub fn new(mode: u32, now: u64) -> Self {
+    Self {
+      mode,
+      uid: 0,
+      gid: 0,
+      atime: split_time(now),
+      mtime: split_time(now),
+      ctime: split_time(now),
+      _reserved: [0; 23],
+    }
+  }
+
+  /// Last read
+  pub fn atime(&self) -> u64 {
+    join_time(self.atime)
+  }
+
+  /// Last change of the contents
+  pub fn mtime(&self) -> u64 {
+    join_time(self.mtime)
+  }
+
+  /// Last change of the contents or the metadata
+  pub fn ctime(&self) -> u64 {
+    join_time(self.ctime)
+  }
+
+  /// Set the times given, and `ctime` to `now`
+  pub fn set_times(&mut self, atime: Option<u64>, mtime: Option<u64>, now: u64) {
+    if let Some(atime) = atime {
+      self.atime = split_time(atime);
+    }
+    if let Some(mtime) = mtime {
+      self.mtime = split_time(mtime);
+    }
+    self.ctime = split_time(now);
+  }
+}
+
+/// Directory slot of a version 0 image
 #[repr(C)]
 pub struct DirEntry {
-  name: [u8; NAME_LENGTH_LIMIT + 1],
+  name: [u8; FIXED_NAME_LENGTH_LIMIT + 1],
   inode: u32,
 }
 
@@ -392,15 +628,16 @@ pub struct DirEntry {
 pub const DIRENT_SZ: usize = 32;
 
 impl DirEntry {
+  /// An unused slot, as left behind by `Inode::unlink`
   pub fn empty() -> Self {
     Self { 
-      name: [0; NAME_LENGTH_LIMIT + 1], 
+      name: [0; FIXED_NAME_LENGTH_LIMIT + 1], 
       inode: 0
     }
   }
 
   pub fn new(name: &str, inode: u32) -> Self {
-    let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
+    let mut bytes = [0u8; FIXED_NAME_LENGTH_LIMIT + 1];
     bytes[..name.len()].copy_from_slice(name.as_bytes());
     Self { 
       name: bytes,
@@ -422,10 +659,10 @@ impl DirEntry {
     }
   }
 
-  /// Get name of the entry
-  pub fn name(&self) -> &str {
-    let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-    core::str::from_utf8(&self.name[..len]).unwrap()
+  /// Name of the entry, or `None` if a corrupt image left no valid name there
+  pub fn try_name(&self) -> Option<&str> {
+    let len = self.name.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&self.name[..len]).ok()
   }
 
   /// Get inode number of the entry
@@ -434,3 +671,40 @@ impl DirEntry {
   }
 }
 
+/// Header of a directory record of a version 1 image, followed by `name_len` bytes of name
+#[repr(C)]
+pub struct DirRecord {
+  pub inode: u32,
+  /// bytes up to the next record
+  pub rec_len: u16,
+  /// 0 for free space
+  pub name_len: u8,
+  _reserved: u8,
+}
+
+/// size of a directory record header
+pub const DIR_RECORD_SZ: usize = size_of::<DirRecord>();
+
+impl DirRecord {
+  pub fn new(inode: u32, rec_len: usize, name_len: usize) -> Self {
+    Self {
+      inode,
+      rec_len: rec_len as u16,
+      name_len: name_len as u8,
+      _reserved: 0,
+    }
+  }
+
+  /// Header at the start of `bytes`, `None` if they are too few
+  pub fn read(bytes: &[u8]) -> Option<Self> {
+    (bytes.len() >= DIR_RECORD_SZ)
+      .then(|| unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Self) })
+  }
+
+  /// Serialize into bytes
+  pub fn as_bytes(&self) -> &[u8] {
+    unsafe {
+      core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIR_RECORD_SZ)
+    }
+  }
+}
@@ -1,4 +1,6 @@
 #![no_std]
+// `div_ceil` and `is_multiple_of` are newer than the nightly the kernel is built with
+#![allow(clippy::manual_div_ceil, clippy::manual_is_multiple_of)]
 
 extern crate alloc;
 
@@ -7,13 +9,27 @@ mod block_cache;
 mod bitmap;
 mod layout;
 mod fs;
+mod journal;
+mod checksum;
+mod dir;
+mod fsck;
 mod vfs;
 
 pub const BLOCK_SZ: usize = 512;
 
-pub use vfs::Inode;
+pub use vfs::{Inode, RenameError};
+pub use layout::{blocks_for_size, InodeMeta, SuperBlock, FS_VERSION, MAX_FILE_SIZE, NAME_LENGTH_LIMIT, SMALL_MAX_FILE_SIZE};
+pub use dir::dir_size;
 pub use fs::FileSystem;
+pub use checksum::ChecksumPolicy;
+pub use journal::JOURNAL_MAX_BLOCKS;
+pub use fsck::{fsck, FsckProblem, FsckReport};
 pub use block_dev::BlockDevice;
+pub use block_cache::{
+  BlockCacheManager, BlockCacheStats, WritePolicy, DEFAULT_BLOCK_CACHE_SIZE,
+  block_cache_exists, block_cache_release, block_cache_stats, block_cache_stats_all, block_cache_sync,
+  block_cache_sync_all, configure_block_cache,
+};
 
 type DataBlock = [u8; BLOCK_SZ];
 
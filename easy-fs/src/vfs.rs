@@ -1,17 +1,50 @@
 // use std::println;
 
-use alloc::{sync::Arc, vec::Vec, string::{String, ToString}};
+use alloc::{collections::BTreeSet, sync::{Arc, Weak}, vec, vec::Vec, string::String};
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::{Mutex, MutexGuard};
 
-use crate::{fs::FileSystem, block_dev::BlockDevice, layout::{DiskInode, DIRENT_SZ, DirEntry, DiskInodeType}, block_cache::{get_block_cache, block_cache_sync_all}};
+use crate::{fs::FileSystem, block_dev::BlockDevice, block_cache::{get_block_cache, block_cache_sync}, BLOCK_SZ};
+use crate::layout::{DiskInode, DiskInodeType, InodeMeta, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE};
+use crate::dir::{self, DirFormat, Record};
 
+/// Most data blocks one transaction of `write_at` touches, so that it fits in the journal
+/// together with the index and bitmap blocks it modifies
+const WRITE_CHUNK_BLOCKS: usize = 32;
+/// Reads refresh an access time at least this old even if nothing changed since, in nanoseconds
+const ATIME_REFRESH: u64 = 24 * 60 * 60 * 1_000_000_000;
 
-/// Different from `DiskInode`, `Inode` is stored in Memory
+/// Why `Inode::rename` refused
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RenameError {
+  /// there is no entry `old_name`
+  NotFound,
+  /// `new_name` is empty or too long
+  InvalidName,
+  /// the directories are on different filesystems
+  CrossDevice,
+  /// one of the directories is a file, or a directory would replace a file
+  NotDir,
+  /// a file would replace a directory
+  IsDir,
+  /// the directory to replace has entries
+  NotEmpty,
+  /// a directory would move into itself or below it
+  IntoItself,
+  /// the transaction was dropped, e.g. it met a corrupt block, so nothing moved
+  Dropped,
+}
+
+/// Different from `DiskInode`, `Inode` is stored in Memory.
+/// `find_name` and `create` hand out one `Arc<Inode>` per inode; an inode unlinked while
+/// one is alive is freed once the last clone drops.
 pub struct Inode {
   block_id: usize,            // corresponding block id
   block_offset: usize,        // inode's offset within the block
   fs: Arc<Mutex<FileSystem>>, // operations are achieved through `FileSystem`
   block_dev: Arc<dyn BlockDevice>,
+  /// no entry names the inode any more, dropping the handle frees it
+  unlinked: AtomicBool,
 }
 
 impl Inode {
@@ -28,6 +61,7 @@ impl Inode {
       block_offset,
       fs,
       block_dev,
+      unlinked: AtomicBool::new(false),
     }
   }
 
@@ -43,148 +77,519 @@ impl Inode {
       .modify(self.block_offset, f)
   }
 
-  /// find inode by its name
-  pub fn find_name(&self, name: &str) -> Option<Arc<Inode>> {
-    let fs = self.fs.lock();
-    self.read_disk_inode(|disk_inode| {
-      self.find_inode_id(name, disk_inode).map(|inode_id| {
-        let (block_id, inner_block_offset) = fs.get_disk_inode_pos(inode_id as usize);
-        Arc::new(
-          Self::new(
-            block_id,
-            inner_block_offset,
-            self.fs.clone(),
-            self.block_dev.clone(),
-          )
-        )
-      })
+  /// The `InodeMeta` of the inode, if the image has them
+  fn read_meta(&self, fs: &FileSystem) -> Option<InodeMeta> {
+    let offset = self.block_offset + core::mem::size_of::<DiskInode>();
+    fs.has_meta().then(|| {
+      get_block_cache(self.block_id, self.block_dev.clone())
+        .lock()
+        .read(offset, |meta: &InodeMeta| *meta)
     })
   }
 
-  fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
-    assert!(disk_inode.is_dir());
-    let file_count = disk_inode.size as usize / DIRENT_SZ;
+  /// Change the `InodeMeta` of the inode. Returns false if the image has none.
+  fn modify_meta(&self, fs: &FileSystem, f: impl FnOnce(&mut InodeMeta)) -> bool {
+    if !fs.has_meta() {
+      return false;
+    }
+    get_block_cache(self.block_id, self.block_dev.clone())
+      .lock()
+      .modify(self.block_offset + core::mem::size_of::<DiskInode>(), f);
+    true
+  }
 
-    assert!(file_count * DIRENT_SZ == disk_inode.size as usize, 
-      "name = {}, {} {} {}", name, file_count, DIRENT_SZ, disk_inode.size);
+  /// Record that the contents changed
+  fn touch(&self, fs: &FileSystem) {
+    let now = fs.now();
+    self.modify_meta(fs, |meta| meta.set_times(None, Some(now), now));
+  }
 
-    let mut dirent = DirEntry::empty();
-    for i in 0..file_count {
-      let buf_len = disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_dev);
-      assert_eq!(buf_len, DIRENT_SZ);
-      if dirent.name() == name {
-        return Some(dirent.inode_number());
+  /// The inode `inode_id` of the same filesystem
+  fn inode_at(&self, fs: &FileSystem, inode_id: u32) -> Self {
+    let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id as usize);
+    Self::new(block_id, block_offset, self.fs.clone(), self.block_dev.clone())
+  }
+
+  /// Free the inode `inode_id` and its data blocks in the transaction removing its entry.
+  /// Returns false, freeing nothing, if they are too many for one transaction: then
+  /// `release_unlinked` frees them once the removal committed.
+  fn release(&self, fs: &mut FileSystem, inode_id: u32) -> bool {
+    let inode = self.inode_at(fs, inode_id);
+    if inode.read_disk_inode(|disk_inode| disk_inode.data_blocks()) as usize > WRITE_CHUNK_BLOCKS {
+      return false;
+    }
+    inode.modify_disk_inode(|disk_inode| {
+      for block_id in disk_inode.decrease_size(0, &self.block_dev) {
+        fs.dealloc_data(block_id as usize);
       }
+    });
+    fs.dealloc_inode(inode_id as usize);
+    true
+  }
+
+  /// Free the inode `inode_id`, no entry names any more, and its data blocks in as many
+  /// transactions as it takes. A crash, or a dropped transaction, on the way leaves the
+  /// inode allocated but unreachable, which `fsck` repairs.
+  fn release_unlinked(&self, fs: &mut FileSystem, inode_id: u32) {
+    let inode = self.inode_at(fs, inode_id);
+    if inode.cut(fs, 0) {
+      fs.begin();
+      fs.dealloc_inode(inode_id as usize);
+      fs.commit();
     }
-    None
+  }
+
+  /// Shrink to `size` bytes from the end, freeing at most `WRITE_CHUNK_BLOCKS` data blocks
+  /// a transaction so each fits in the journal. Returns false if a transaction was dropped;
+  /// the size stays where the ones before left it.
+  fn cut(&self, fs: &mut FileSystem, size: u64) -> bool {
+    loop {
+      let cur_size = self.read_disk_inode(|disk_inode| disk_inode.size());
+      if cur_size <= size {
+        return true;
+      }
+      let kept_blocks = (DiskInode::_data_blocks(cur_size) as usize).saturating_sub(WRITE_CHUNK_BLOCKS);
+      let step_size = size.max((kept_blocks * BLOCK_SZ) as u64);
+      fs.begin();
+      self.modify_disk_inode(|disk_inode| {
+        for block_id in disk_inode.decrease_size(step_size, &self.block_dev) {
+          fs.dealloc_data(block_id as usize);
+        }
+      });
+      if step_size == size {
+        self.touch(fs);
+      }
+      if !fs.commit() {
+        return false;
+      }
+    }
+  }
+
+  /// The handle of the inode `inode_id`: the one handed out before if it is alive
+  fn handle(&self, fs: &mut FileSystem, inode_id: u32) -> Arc<Self> {
+    if let Some(inode) = fs.handles.get(&inode_id).and_then(Weak::upgrade) {
+      return inode;
+    }
+    let inode = Arc::new(self.inode_at(fs, inode_id));
+    fs.handles.retain(|_, handle| handle.strong_count() > 0);
+    fs.handles.insert(inode_id, Arc::downgrade(&inode));
+    inode
+  }
+
+  /// Is the directory `dir_id` the directory `ancestor_id` or below it?
+  fn is_below(&self, fs: &FileSystem, dir_id: u32, ancestor_id: u32) -> bool {
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![ancestor_id];
+    while let Some(inode_id) = stack.pop() {
+      if inode_id == dir_id {
+        return true;
+      }
+      // a corrupt tree may have loops
+      if !seen.insert(inode_id) {
+        continue;
+      }
+      let inode = self.inode_at(fs, inode_id);
+      inode.read_disk_inode(|disk_inode| {
+        if disk_inode.is_dir() {
+          let records = inode.records(disk_inode, fs.dir_format);
+          stack.extend(records.iter().filter(|record| record.is_entry()).map(|record| record.inode));
+        }
+      });
+    }
+    false
+  }
+
+  /// find inode by its name
+  pub fn find_name(&self, name: &str) -> Option<Arc<Inode>> {
+    let mut fs = self.fs.lock();
+    let format = fs.dir_format;
+    let inode_id = self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode, format))?;
+    Some(self.handle(&mut fs, inode_id))
+  }
+
+  fn find_inode_id(&self, name: &str, disk_inode: &DiskInode, format: DirFormat) -> Option<u32> {
+    let records = self.records(disk_inode, format);
+    Self::find_dirent(name, &records).map(|idx| records[idx].inode)
+  }
+
+  /// Records of the directory, free space and corrupt ones included
+  fn records(&self, disk_inode: &DiskInode, format: DirFormat) -> Vec<Record> {
+    assert!(disk_inode.is_dir());
+    assert!(format.valid_size(disk_inode.size() as usize), "directory of {} bytes", disk_inode.size());
+    dir::records(disk_inode, &self.block_dev, format)
+  }
+
+  /// Index of the record of the entry `name`
+  fn find_dirent(name: &str, records: &[Record]) -> Option<usize> {
+    // empty names mark free space
+    if name.is_empty() {
+      return None;
+    }
+    records.iter().position(|record| record.name.as_deref() == Some(name))
+  }
+
+  /// Does the directory hold anything?
+  fn has_entries(&self, disk_inode: &DiskInode, format: DirFormat) -> bool {
+    self.records(disk_inode, format).iter().any(Record::is_entry)
   }
 
   /// Increase the size of disk inode
   fn increase_size(
     &self, 
-    new_size: u32, 
+    new_size: u64, 
     disk_inode: &mut DiskInode,
     fs: &mut MutexGuard<FileSystem>
   ) {
-    if new_size < disk_inode.size {
+    if new_size < disk_inode.size() {
       return;
     }
     let blocks_needed = disk_inode.blocks_num_needed(new_size);
-    let mut new_blocks: Vec<u32> = Vec::new();
-    for _ in 0..blocks_needed {
-      new_blocks.push(fs.alloc_data());
-    }
+    let new_blocks = fs.alloc_data_blocks(blocks_needed as usize);
     disk_inode.increase_size(new_size, new_blocks, &self.block_dev);
   }
 
-  /// Create an Inode(file) at '/'
+  /// Create a file in this directory.
+  /// Returns `None` if the name is taken or not a valid name, or the transaction was dropped
+  /// (see `FileSystem::commit`).
   pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+    self.create_inode(name, DiskInodeType::File)
+  }
+
+  /// Create an empty directory in this directory, like `create`
+  pub fn create_dir(&self, name: &str) -> Option<Arc<Inode>> {
+    self.create_inode(name, DiskInodeType::Directory)
+  }
+
+  fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
     let mut fs = self.fs.lock();
-    let op = |root_inode: &DiskInode| {
-      assert!(root_inode.is_dir());
-      self.find_inode_id(name, root_inode)
-    };
-    if self.read_disk_inode(op).is_some() {
+    let format = fs.dir_format;
+    // entries of an unlinked directory would be lost with it
+    if name.is_empty() || name.len() > format.name_max() || self.is_unlinked() {
       return None;
     }
+    if self.read_disk_inode(|root_inode| self.find_inode_id(name, root_inode, format)).is_some() {
+      return None;
+    }
+    fs.begin();
     let new_inode_id = fs.alloc_inode();
-    let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id as usize);
-    get_block_cache(block_id, self.block_dev.clone())
-      .lock()
-      .modify(block_offset, |new_inode: &mut DiskInode| {
-        new_inode.initialize(DiskInodeType::File);
-      });
+    fs.initialize_inode(new_inode_id, type_);
     self.modify_disk_inode(|root_inode| {
-      let file_count = (root_inode.size as usize) / DIRENT_SZ;
-      assert_eq!(root_inode.size as usize, file_count * DIRENT_SZ);
-
-      let new_size = (file_count + 1) * DIRENT_SZ;
-      self.increase_size(new_size as u32, root_inode, &mut fs);
-      let dirent = DirEntry::new(name, new_inode_id);
-      root_inode.write_at(file_count * DIRENT_SZ, dirent.as_bytes(), &self.block_dev);
+      let records = self.records(root_inode, format);
+      let record = match dir::find_room(&records, format, name) {
+        Some(record) => record.clone(),
+        None => {
+          let offset = root_inode.size() as usize;
+          self.increase_size((offset + format.grow()) as u64, root_inode, &mut fs);
+          dir::add_free(root_inode, &self.block_dev, format, offset)
+        }
+      };
+      dir::insert(root_inode, &self.block_dev, format, &record, name, new_inode_id);
     });
-    
-    block_cache_sync_all();
-    Some(Arc::new(
-      Self::new(
-        block_id, 
-        block_offset, 
-        self.fs.clone(), 
-        self.block_dev.clone()
-    )))
+    self.touch(&fs);
+    if !fs.commit() {
+      return None;
+    }
+    Some(self.handle(&mut fs, new_inode_id))
   }
 
   /// list inodes under current inode
   pub fn ls(&self) -> Vec<String> {
-    let _fs = self.fs.lock();
+    let fs = self.fs.lock();
     self.read_disk_inode(|disk_inode: &DiskInode| {
-      assert!(disk_inode.is_dir());
-      let file_count = disk_inode.size as usize / DIRENT_SZ;
-      let mut file_name_list = Vec::new();
-      for i in 0..file_count {
-        let mut dirent = DirEntry::empty();
-        let buf_len = disk_inode.read_at(
-          i * DIRENT_SZ, 
-          &mut dirent.as_bytes_mut(), 
-          &self.block_dev
-        );
-        assert_eq!(buf_len, DIRENT_SZ);
-        file_name_list.push(dirent.name().to_string());
+      self.records(disk_inode, fs.dir_format)
+        .into_iter()
+        .filter(Record::is_entry)
+        .filter_map(|record| record.name)
+        .collect()
+    })
+  }
+
+  /// Longest name this directory can hold, in bytes
+  pub fn name_max(&self) -> usize {
+    self.fs.lock().name_max()
+  }
+
+  /// Remove the entry `name` and free its inode and data blocks: after the removal if
+  /// they are too many to free with it, once the last handle drops if it is open.
+  /// Returns false if there is no such entry, it is a directory that is not empty, or the
+  /// transaction removing the entry was dropped.
+  pub fn unlink(&self, name: &str) -> bool {
+    let mut fs = self.fs.lock();
+    let format = fs.dir_format;
+    let records = self.read_disk_inode(|dir| self.records(dir, format));
+    let Some(idx) = Self::find_dirent(name, &records) else {
+      return false;
+    };
+    let inode_id = records[idx].inode;
+    let child = self.inode_at(&fs, inode_id);
+    if child.read_disk_inode(|disk_inode| disk_inode.is_dir() && child.has_entries(disk_inode, format)) {
+      return false;
+    }
+    // an open inode is freed by its last handle instead
+    let open = fs.handles.get(&inode_id).and_then(Weak::upgrade);
+    fs.begin();
+    let released = open.is_some() || self.release(&mut fs, inode_id);
+    self.modify_disk_inode(|dir| dir::remove(dir, &self.block_dev, format, &records, idx));
+    self.touch(&fs);
+    if !fs.commit() {
+      return false;
+    }
+    if !released {
+      self.release_unlinked(&mut fs, inode_id);
+    }
+    if let Some(open) = open {
+      Self::orphan(fs, open);
+    }
+    true
+  }
+
+  /// Leave the inode of `open`, whose last entry was just removed, to be freed by its
+  /// last handle, which may be `open`
+  fn orphan(mut fs: MutexGuard<FileSystem>, open: Arc<Inode>) {
+    let inode_id = fs.get_inode_id(open.block_id, open.block_offset);
+    fs.orphans.insert(inode_id);
+    open.unlinked.store(true, Ordering::Relaxed);
+    // the last handle frees the inode under the lock
+    drop(fs);
+    drop(open);
+  }
+
+  /// Move the entry `old_name` of `old_dir` to `new_name` in `new_dir`, in one transaction.
+  /// An entry `new_name` already there is replaced if it is a file and so is the one moved,
+  /// or both are directories and it is empty; its inode is freed as by `unlink`.
+  pub fn rename(old_dir: &Inode, old_name: &str, new_dir: &Inode, new_name: &str) -> Result<(), RenameError> {
+    if !Arc::ptr_eq(&old_dir.fs, &new_dir.fs) {
+      return Err(RenameError::CrossDevice);
+    }
+    let mut fs = old_dir.fs.lock();
+    let format = fs.dir_format;
+    if !old_dir.is_dir() || !new_dir.is_dir() {
+      return Err(RenameError::NotDir);
+    }
+    if new_name.is_empty() || new_name.len() > format.name_max() {
+      return Err(RenameError::InvalidName);
+    }
+    // entries of an unlinked directory would be lost with it
+    if new_dir.is_unlinked() {
+      return Err(RenameError::NotFound);
+    }
+    let old_records = old_dir.read_disk_inode(|dir| old_dir.records(dir, format));
+    let idx = Self::find_dirent(old_name, &old_records).ok_or(RenameError::NotFound)?;
+    let inode_id = old_records[idx].inode;
+    let old_dir_id = fs.get_inode_id(old_dir.block_id, old_dir.block_offset);
+    let new_dir_id = fs.get_inode_id(new_dir.block_id, new_dir.block_offset);
+    if old_dir_id == new_dir_id && old_name == new_name {
+      return Ok(());
+    }
+    let moving_dir = old_dir.inode_at(&fs, inode_id).is_dir();
+    if moving_dir && new_dir_id != old_dir_id && old_dir.is_below(&fs, new_dir_id, inode_id) {
+      return Err(RenameError::IntoItself);
+    }
+    let new_records = new_dir.read_disk_inode(|dir| new_dir.records(dir, format));
+    let target = Self::find_dirent(new_name, &new_records).map(|idx| &new_records[idx]);
+    if let Some(target) = target {
+      let target_inode = old_dir.inode_at(&fs, target.inode);
+      match (moving_dir, target_inode.is_dir()) {
+        (false, true) => return Err(RenameError::IsDir),
+        (true, false) => return Err(RenameError::NotDir),
+        (true, true) if target_inode.read_disk_inode(|dir| target_inode.has_entries(dir, format)) => {
+          return Err(RenameError::NotEmpty);
+        }
+        _ => {}
+      }
+    }
+    // an open target is freed by its last handle instead
+    let open = target.and_then(|target| fs.handles.get(&target.inode).and_then(Weak::upgrade));
+    fs.begin();
+    new_dir.modify_disk_inode(|dir| match target {
+      // the entry now names the moved inode, so no crash leaves `new_name` missing
+      Some(target) => dir::set_inode(dir, &new_dir.block_dev, format, target, inode_id),
+      None => {
+        let record = match dir::find_room(&new_records, format, new_name) {
+          Some(record) => record.clone(),
+          None => {
+            let offset = dir.size() as usize;
+            new_dir.increase_size((offset + format.grow()) as u64, dir, &mut fs);
+            dir::add_free(dir, &new_dir.block_dev, format, offset)
+          }
+        };
+        dir::insert(dir, &new_dir.block_dev, format, &record, new_name, inode_id);
       }
-      file_name_list
+    });
+    let released = target.map(|target| open.is_some() || old_dir.release(&mut fs, target.inode));
+    // inserting may have split a record of the same directory
+    let old_records = old_dir.read_disk_inode(|dir| old_dir.records(dir, format));
+    let idx = old_records
+      .iter()
+      .position(|record| record.name.as_deref() == Some(old_name) && record.inode == inode_id)
+      .unwrap();
+    old_dir.modify_disk_inode(|dir| dir::remove(dir, &old_dir.block_dev, format, &old_records, idx));
+    old_dir.touch(&fs);
+    new_dir.touch(&fs);
+    let now = fs.now();
+    old_dir.inode_at(&fs, inode_id).modify_meta(&fs, |meta| meta.set_times(None, None, now));
+    if !fs.commit() {
+      return Err(RenameError::Dropped);
+    }
+    if released == Some(false) {
+      old_dir.release_unlinked(&mut fs, target.unwrap().inode);
+    }
+    if let Some(open) = open {
+      Self::orphan(fs, open);
+    }
+    Ok(())
+  }
+
+  /// Has the inode been unlinked while open? It is freed once the last handle drops.
+  pub fn is_unlinked(&self) -> bool {
+    self.unlinked.load(Ordering::Relaxed)
+  }
+
+  /// Number of the inode on its filesystem
+  pub fn inode_id(&self) -> u32 {
+    self.fs.lock().get_inode_id(self.block_id, self.block_offset)
+  }
+
+  pub fn is_dir(&self) -> bool {
+    self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+  }
+
+  /// Size of the file in bytes
+  pub fn size(&self) -> usize {
+    self.read_disk_inode(|disk_inode| disk_inode.size() as usize)
+  }
+
+  /// Largest size the file can have, smaller on images from before triple indirect blocks
+  pub fn max_size(&self) -> usize {
+    self.read_disk_inode(|disk_inode| disk_inode.max_size()) as usize
+  }
+
+  /// Owner, permissions and times, made up from the defaults on images without `InodeMeta`
+  pub fn metadata(&self) -> InodeMeta {
+    let fs = self.fs.lock();
+    self.read_meta(&fs).unwrap_or_else(|| {
+      let mode = if self.is_dir() { DEFAULT_DIR_MODE } else { DEFAULT_FILE_MODE };
+      InodeMeta::new(mode, 0)
     })
   }
 
+  /// Set the permission bits. Returns false if the image has no room for them, or the
+  /// transaction was dropped.
+  pub fn set_mode(&self, mode: u32) -> bool {
+    let mut fs = self.fs.lock();
+    let now = fs.now();
+    fs.begin();
+    let done = self.modify_meta(&fs, |meta| {
+      meta.mode = mode & 0o7777;
+      meta.set_times(None, None, now);
+    });
+    fs.commit() && done
+  }
+
+  /// Set the times given, in nanoseconds since the Unix epoch, like `set_mode`
+  pub fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> bool {
+    let mut fs = self.fs.lock();
+    let now = fs.now();
+    fs.begin();
+    let done = self.modify_meta(&fs, |meta| meta.set_times(atime, mtime, now));
+    fs.commit() && done
+  }
+
   pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-    let _fs = self.fs.lock(); // lock file system (multi-core)
-    self.read_disk_inode(|disk_inode: &DiskInode| {
+    let mut fs = self.fs.lock(); // lock file system (multi-core)
+    let read = self.read_disk_inode(|disk_inode: &DiskInode| {
       disk_inode.read_at(offset, buf, &self.block_dev)
-    })
+    });
+    // like Linux' relatime, record only the first read after a change, or one a day
+    let now = fs.now();
+    let stale = |meta: InodeMeta| {
+      now > meta.atime() && (meta.atime() <= meta.mtime() || meta.atime() <= meta.ctime() || now - meta.atime() >= ATIME_REFRESH)
+    };
+    if self.read_meta(&fs).is_some_and(stale) {
+      fs.begin();
+      self.modify_meta(&fs, |meta| meta.set_times(Some(now), None, meta.ctime()));
+      fs.commit();
+    }
+    read
   }
 
-  /// write `buf` to the file
+  /// write `buf` to the file, up to the largest size it can have. Returns the bytes written,
+  /// fewer if a transaction was dropped (see `FileSystem::commit`). A crash may leave a prefix of a long write behind, but never a broken file.
   pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
     let mut fs = self.fs.lock();
-    let buf_len = self.modify_disk_inode(|disk_inode: &mut DiskInode| {
-      self.increase_size(disk_inode.size + buf.len() as u32, disk_inode, &mut fs);
-      disk_inode.write_at(offset, buf, &self.block_dev)
-    });
-    block_cache_sync_all();
-    buf_len
+    let max_size = self.max_size();
+    if offset > max_size {
+      return 0;
+    }
+    let buf = &buf[..buf.len().min(max_size - offset)];
+    let chunk = WRITE_CHUNK_BLOCKS * BLOCK_SZ;
+    let end = offset + buf.len();
+    let mut pos = offset;
+    loop {
+      let written = pos - offset;
+      fs.begin();
+      let done = self.modify_disk_inode(|disk_inode: &mut DiskInode| {
+        let size = disk_inode.size() as usize;
+        if size < offset {
+          // fill the hole up to `offset` with zeroed blocks first
+          let step_end = offset.min(size - size % BLOCK_SZ + chunk);
+          self.increase_size(step_end as u64, disk_inode, &mut fs);
+          return false;
+        }
+        let step_end = end.min(pos - pos % BLOCK_SZ + chunk);
+        self.increase_size(step_end as u64, disk_inode, &mut fs);
+        disk_inode.write_at(pos, &buf[pos - offset..step_end - offset], &self.block_dev);
+        pos = step_end;
+        pos == end
+      });
+      if done {
+        self.touch(&fs);
+      }
+      if !fs.commit() {
+        // only the chunks before this one made it
+        return written;
+      }
+      if done {
+        return buf.len();
+      }
+    }
+  }
+
+  /// Write back the cached blocks of the device this inode lives on
+  pub fn sync(&self) {
+    block_cache_sync(&self.block_dev);
+  }
+
+  /// See `FileSystem::take_corrupt_blocks`
+  pub fn take_corrupt_blocks(&self) -> Vec<usize> {
+    self.fs.lock().take_corrupt_blocks()
+  }
+
+  /// See `FileSystem::is_read_only`
+  pub fn is_read_only(&self) -> bool {
+    self.fs.lock().is_read_only()
+  }
+
+  /// Shrink the file to `size` bytes, if it is larger, as transactions small enough for
+  /// the journal. Returns false if one was dropped, leaving the file somewhere in between.
+  pub fn shrink(&self, size: u64) -> bool {
+    self.cut(&mut self.fs.lock(), size)
   }
 
   /// Clear the data in current inode but remains the inode
   pub fn clear(&self) {
-    let mut fs = self.fs.lock();
-    self.modify_disk_inode(|disk_inode: &mut DiskInode| {
-      let tot_blks = DiskInode::total_blocks(disk_inode.size);
-      let free_block_ids = disk_inode.clear_size(&self.block_dev);
-      assert_eq!(tot_blks as usize, free_block_ids.len());
-      for block_id in free_block_ids {
-        fs.dealloc_data(block_id as usize);
-      }
-    });
-    block_cache_sync_all();
+    self.shrink(0);
   }
 }
 
+impl Drop for Inode {
+  /// The last handle of an unlinked inode frees it
+  fn drop(&mut self) {
+    if *self.unlinked.get_mut() {
+      let mut fs = self.fs.lock();
+      let inode_id = fs.get_inode_id(self.block_id, self.block_offset);
+      fs.orphans.remove(&inode_id);
+      self.release_unlinked(&mut fs, inode_id);
+    }
+  }
+}
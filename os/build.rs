@@ -1,6 +1,8 @@
 // build script for cargo build
-use std::fs::{read_dir, File};
+use std::env;
+use std::fs::{read_dir, read_to_string, File};
 use std::io::{Result, Write};
+use std::path::Path;
 
 static TARGET_PATH: &str = "../user/target/riscv64gc-unknown-none-elf/release/";
 
@@ -8,6 +10,80 @@ fn main() {
     println!("cargo:rerun-if-changed=../user/src/");
     println!("cargo:rerun-if-changed={}", TARGET_PATH);
     // insert_app_data().unwrap();
+    insert_kernel_symbols().unwrap();
+}
+
+/// Embed the kernel's own function symbols for backtraces.
+///
+/// `KERNEL_SYMS` names an `nm -n -C` listing of a previous build of this kernel
+/// (the Makefile links twice). `.text` comes first in the linker script and the
+/// table lives in `.rodata`, so code addresses do not move between the two links.
+/// Without a listing the table is empty and backtraces print raw addresses.
+fn insert_kernel_symbols() -> Result<()> {
+    println!("cargo:rerun-if-env-changed=KERNEL_SYMS");
+    let mut symbols: Vec<(u64, String)> = Vec::new();
+    if let Ok(path) = env::var("KERNEL_SYMS") {
+        println!("cargo:rerun-if-changed={}", path);
+        if let Ok(listing) = read_to_string(&path) {
+            symbols = parse_nm(&listing);
+        }
+    }
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("ksyms.S");
+    let mut f = File::create(out)?;
+    writeln!(
+        f,
+        r#"
+    .section .rodata.ksyms
+    .align 3
+    .globl _ksyms_num
+_ksyms_num:
+    .quad {}
+    .globl _ksyms_addrs
+_ksyms_addrs:"#,
+        symbols.len()
+    )?;
+    for (addr, _) in symbols.iter() {
+        writeln!(f, "    .quad {:#x}", addr)?;
+    }
+    writeln!(
+        f,
+        r#"
+    .globl _ksyms_names
+_ksyms_names:"#
+    )?;
+    for (_, name) in symbols.iter() {
+        writeln!(f, "    .string \"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))?;
+    }
+    Ok(())
+}
+
+/// Keep text symbols of a `nm -n -C` listing, sorted by address
+fn parse_nm(listing: &str) -> Vec<(u64, String)> {
+    let mut symbols: Vec<(u64, String)> = listing
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            // skip assembler-local labels such as `.Lpcrel_hi0`
+            if !matches!(kind, "T" | "t" | "W" | "w") || name.starts_with(".L") {
+                return None;
+            }
+            // drop the ` (.llvm.1234)` suffix of internalized symbols
+            let name = name.split(" (.llvm.").next().unwrap();
+            // drop the `::h0123456789abcdef` hash of legacy-mangled rust symbols
+            let name = match name.rfind("::h") {
+                Some(pos) if name.len() - pos == 19 => &name[..pos],
+                _ => name,
+            };
+            Some((addr, name.to_string()))
+        })
+        .collect();
+    symbols.sort_by_key(|(addr, _)| *addr);
+    symbols.dedup_by_key(|(addr, _)| *addr);
+    symbols
 }
 
 #[allow(unused)]
@@ -0,0 +1,131 @@
+//! Frame-pointer based kernel backtraces
+//!
+//! The kernel is built with `-Cforce-frame-pointers=yes`, so every frame looks like
+//!
+//! ```text
+//!   fp -  8: return address
+//!   fp - 16: caller's fp
+//! ```
+//!
+//! Addresses are symbolized with the table `build.rs` embeds into `.rodata`.
+
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::{KERNEL_STACK_SIZE, PAGE_SIZE, TRAMPOLINE};
+
+global_asm!(include_str!(concat!(env!("OUT_DIR"), "/ksyms.S")));
+
+const MAX_DEPTH: usize = 32;
+
+/// set while a backtrace is printed, so that a fault inside the walker does not recurse
+static IN_BACKTRACE: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+  fn boot_stack_lower_bound();
+  fn boot_stack_top();
+  fn _ksyms_num();
+  fn _ksyms_addrs();
+  fn _ksyms_names();
+}
+
+/// Return `(function name, offset)` of the symbol containing `pc`
+pub fn lookup_symbol(pc: usize) -> Option<(&'static str, usize)> {
+  let num = unsafe { (_ksyms_num as *const usize).read() };
+  let addrs = unsafe { core::slice::from_raw_parts(_ksyms_addrs as *const usize, num) };
+  // index of the last symbol starting at or before `pc`
+  let idx = match addrs.binary_search(&pc) {
+    Ok(idx) => idx,
+    Err(0) => return None,
+    Err(idx) => idx - 1,
+  };
+  if pc >= etext_addr() {
+    return None;
+  }
+  let mut name = _ksyms_names as *const u8;
+  unsafe {
+    for _ in 0..idx {
+      while name.read() != 0 {
+        name = name.add(1);
+      }
+      name = name.add(1);
+    }
+    let mut len = 0;
+    while name.add(len).read() != 0 {
+      len += 1;
+    }
+    let name = core::str::from_utf8(core::slice::from_raw_parts(name, len)).ok()?;
+    Some((name, pc - addrs[idx]))
+  }
+}
+
+fn etext_addr() -> usize {
+  extern "C" {
+    fn etext();
+  }
+  etext as usize
+}
+
+/// Bounds `[bottom, top)` of the kernel stack containing `sp`:
+/// the boot stack, or one of the per-process stacks below the trampoline.
+fn stack_bounds(sp: usize) -> Option<(usize, usize)> {
+  let (boot_bottom, boot_top) = (boot_stack_lower_bound as usize, boot_stack_top as usize);
+  if (boot_bottom..boot_top).contains(&sp) {
+    return Some((boot_bottom, boot_top));
+  }
+  // see `task::pid::kernel_stack_position`
+  let slot = KERNEL_STACK_SIZE + PAGE_SIZE;
+  let pid = TRAMPOLINE.checked_sub(sp)?.checked_sub(1)? / slot;
+  let top = TRAMPOLINE - pid * slot;
+  let bottom = top - KERNEL_STACK_SIZE;
+  (bottom..top).contains(&sp).then_some((bottom, top))
+}
+
+fn print_frame(depth: usize, pc: usize) {
+  match lookup_symbol(pc) {
+    Some((name, offset)) => { println!("  #{:<2} {:#018x} {}+{:#x}", depth, pc, name, offset); }
+    None => { println!("  #{:<2} {:#018x} <unknown>", depth, pc); }
+  }
+}
+
+/// Print the call chain starting at `pc` whose frame pointer is `fp`
+pub fn print_backtrace_from(pc: usize, mut fp: usize) {
+  if IN_BACKTRACE.swap(true, Ordering::Relaxed) {
+    println!("  <backtrace aborted: fault while unwinding>");
+    return;
+  }
+  println!("backtrace:");
+  print_frame(0, pc);
+  if let Some((bottom, top)) = stack_bounds(fp.wrapping_sub(1)) {
+    // a frame record is two words right below `fp`
+    for depth in 1..MAX_DEPTH {
+      if fp % 8 != 0 || fp < bottom + 16 || fp > top {
+        break;
+      }
+      let (ra, prev_fp) = unsafe {
+        (*((fp - 8) as *const usize), *((fp - 16) as *const usize))
+      };
+      if ra == 0 {
+        break;
+      }
+      // `ra` points after the call, report the call itself
+      print_frame(depth, ra - 4);
+      // the stack grows down, so callers' frames live higher
+      if prev_fp <= fp {
+        break;
+      }
+      fp = prev_fp;
+    }
+  }
+  IN_BACKTRACE.store(false, Ordering::Relaxed);
+}
+
+/// Print the call chain of the caller
+#[inline(never)]
+pub fn print_backtrace() {
+  let (pc, fp): (usize, usize);
+  unsafe {
+    asm!("auipc {}, 0", "mv {}, s0", out(reg) pc, out(reg) fp);
+  }
+  print_backtrace_from(pc, fp);
+}
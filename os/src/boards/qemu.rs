@@ -1,29 +1,155 @@
 //! Constants used in the peaCore for qemu
-const VIRT_TEST: u64 = 0x100000;
+//!
+//! The device layout is not hard-coded: [`init`] reads it from the device tree
+//! the firmware (or qemu's reset vector) passes in `a1`.
+
+use spin::Once;
+
+use crate::fdt::{Fdt, Node};
 
 pub const QEMU_BASE_ADDRESS: usize = 0x8000_0000;
-pub const KERNEL_MAX_ALLOCED_ADDRESS: usize = MEMORY_ENDPOINT;
-pub const UART_BASE_ADDRESS: usize = 0x1000_0000;
-pub const VIRTIO_BASE_ADDRESS: usize = 0x1000_1000;
 
-pub const CLOCK_FREQ: usize = 12500000;
+/// qemu `virt` has eight virtio-mmio transports
+pub const MAX_VIRTIO_SLOTS: usize = 8;
+
+/// A memory-mapped device
+#[derive(Clone, Copy, Debug)]
+pub struct MmioDevice {
+    pub base: usize,
+    pub size: usize,
+    /// PLIC interrupt source, 0 if it has none
+    pub irq: usize,
+}
 
-pub const MMIO: &[(usize, usize)] = &[
-    (0x0010_0000, 0x00_2000), // VIRT_TEST/RTC in virt machine
-    (UART_BASE_ADDRESS, 0x00_1000),
-    (VIRTIO_BASE_ADDRESS, 0x00_1000),
-];
+impl MmioDevice {
+    const fn new(base: usize, size: usize, irq: usize) -> Self {
+        Self { base, size, irq }
+    }
 
-// TODO: RISCV64 what's hifive_test?
-pub const QEMU_EXIT_HANDLE: RISCV64 = RISCV64::new(VIRT_TEST);
+    fn from_node(node: &Node) -> Option<Self> {
+        let (base, size) = node.reg()?;
+        Some(Self::new(base, size, node.interrupt().unwrap_or(0)))
+    }
+}
+
+/// What the kernel needs to know about the machine it boots on
+#[derive(Clone, Copy, Debug)]
+pub struct BoardInfo {
+    /// RAM is `[memory_start, memory_end)`
+    pub memory_start: usize,
+    pub memory_end: usize,
+    /// frequency of `mtime`
+    pub clock_freq: usize,
+    pub uart: MmioDevice,
+    pub clint: MmioDevice,
+    pub plic: MmioDevice,
+    /// sifive_test, used to power off qemu
+    pub test: MmioDevice,
+    /// goldfish RTC, the wall clock
+    pub rtc: MmioDevice,
+    /// virtio-mmio transports sorted by address, i.e. `virtio-mmio-bus.0` first
+    pub virtio: [MmioDevice; MAX_VIRTIO_SLOTS],
+    pub virtio_num: usize,
+    /// false if no device tree was found and `QEMU_VIRT` was assumed
+    pub from_fdt: bool,
+}
+
+/// Layout of qemu `virt` with the 16 MiB the kernel used to assume
+const QEMU_VIRT: BoardInfo = BoardInfo {
+    memory_start: QEMU_BASE_ADDRESS,
+    memory_end: 0x8100_0000,
+    clock_freq: 12_500_000,
+    uart: MmioDevice::new(0x1000_0000, 0x100, 10),
+    clint: MmioDevice::new(0x0200_0000, 0x1_0000, 0),
+    plic: MmioDevice::new(0x0c00_0000, 0x40_0000, 0),
+    test: MmioDevice::new(0x10_0000, 0x1000, 0),
+    rtc: MmioDevice::new(0x10_1000, 0x1000, 11),
+    virtio: [MmioDevice::new(0x1000_1000, 0x1000, 1); MAX_VIRTIO_SLOTS],
+    virtio_num: 1,
+    from_fdt: false,
+};
+
+static BOARD_INFO: Once<BoardInfo> = Once::new();
+
+/// Discover the board from the device tree at `dtb`. Called once, first thing in M-mode.
+///
+/// Everything needed is copied out, so the blob may be overwritten afterwards.
+pub fn init(dtb: usize) {
+    BOARD_INFO.call_once(|| match unsafe { Fdt::from_addr(dtb) } {
+        Ok(fdt) => parse(&fdt),
+        Err(_) => QEMU_VIRT,
+    });
+}
+
+pub fn info() -> &'static BoardInfo {
+    BOARD_INFO.get().expect("board::init has not been called")
+}
+
+fn parse(fdt: &Fdt) -> BoardInfo {
+    let mut info = QEMU_VIRT;
+    info.from_fdt = true;
+    info.virtio_num = 0;
+    let mut found_memory = false;
+    let mut found_clock = false;
+    for node in fdt.nodes() {
+        if !found_clock {
+            if let Some(freq) = node.prop_u32("timebase-frequency") {
+                info.clock_freq = freq as usize;
+                found_clock = true;
+            }
+        }
+        if node.base_name() == "memory" && !found_memory {
+            if let Some((base, size)) = node.reg() {
+                info.memory_start = base;
+                info.memory_end = base + size;
+                found_memory = true;
+            }
+        } else if node.is_compatible("ns16550a") {
+            info.uart = MmioDevice::from_node(&node).unwrap_or(info.uart);
+        } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+            info.clint = MmioDevice::from_node(&node).unwrap_or(info.clint);
+        } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+            info.plic = MmioDevice::from_node(&node).unwrap_or(info.plic);
+        } else if node.is_compatible("sifive,test0") {
+            info.test = MmioDevice::from_node(&node).unwrap_or(info.test);
+        } else if node.is_compatible("google,goldfish-rtc") {
+            info.rtc = MmioDevice::from_node(&node).unwrap_or(info.rtc);
+        } else if node.is_compatible("virtio,mmio") && info.virtio_num < MAX_VIRTIO_SLOTS {
+            if let Some(dev) = MmioDevice::from_node(&node) {
+                info.virtio[info.virtio_num] = dev;
+                info.virtio_num += 1;
+            }
+        }
+    }
+    // qemu lists the transports from the highest address down
+    info.virtio[..info.virtio_num].sort_unstable_by_key(|dev| dev.base);
+    info
+}
+
+impl BoardInfo {
+    pub fn virtio_slots(&self) -> &[MmioDevice] {
+        &self.virtio[..self.virtio_num]
+    }
+
+    /// Every device region the kernel maps, as `(base, size)`
+    pub fn mmio_regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        [self.test, self.rtc, self.plic, self.uart]
+            .into_iter()
+            .chain(self.virtio_slots().iter().copied())
+            .map(|dev| (dev.base, dev.size))
+    }
+}
+
+/// The handle to power off qemu through the discovered test device
+pub fn exit_handle() -> RISCV64 {
+    RISCV64::new(info().test.base as u64)
+}
 
 // use riscv::register::stvec;
 
 //ref:: https://github.com/andre-richter/qemu-exit
 use core::arch::asm;
 
-use crate::config::MEMORY_ENDPOINT;
-
 const EXIT_SUCCESS: u32 = 0x5555; // Equals `exit(0)`. qemu successful exit
 
 const EXIT_FAILURE_FLAG: u32 = 0x3333;
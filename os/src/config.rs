@@ -2,10 +2,18 @@
 pub const USER_STACK_TOP: usize = TRAP_CONTEXT - PAGE_SIZE;
 pub const USER_STACK_SIZE: usize = 4096 * 2;
 pub const USER_STACK_MAX_SIZE: usize = 4096 * 128;
+/// most bytes the arguments of `exec`, argv[] and the strings, may take on the new user
+/// stack; the rest of `USER_STACK_SIZE` is left to the program
+pub const EXEC_ARGS_MAX: usize = USER_STACK_SIZE / 2;
 
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
-pub const MEMORY_ENDPOINT: usize = 0x81000000; // KERNEL_MAX_MEMORY_ALLOCATED
+/// blocks kept by the easy-fs block cache
+pub const BLOCK_CACHE_SIZE: usize = 64;
+/// most milliseconds a dirty easy-fs block stays in the write-back cache
+pub const BLOCK_CACHE_WRITE_BACK_MS: usize = 5000;
+/// what easy-fs does about a block that fails its checksum
+pub const EASY_FS_CHECKSUM_POLICY: easy_fs::ChecksumPolicy = easy_fs::ChecksumPolicy::ReadOnly;
 
 pub const PAGE_SIZE_BITS: usize = 12;
 pub const PAGE_SIZE: usize = 1 << PAGE_SIZE_BITS; // 4k
@@ -13,5 +21,3 @@ pub const PAGE_SIZE: usize = 1 << PAGE_SIZE_BITS; // 4k
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
-
-pub use crate::board::CLOCK_FREQ;
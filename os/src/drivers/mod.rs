@@ -1,17 +1,132 @@
-use alloc::sync::Arc;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
 use easy_fs::BlockDevice;
+use virtio_drivers::{DeviceType, VirtIOHeader};
 
+mod plic;
+pub mod serial;
 mod virtio_blk;
 
-use virtio_blk::VirtIOBlock;
+use plic::Plic;
+pub use virtio_blk::VirtIOBlock;
+
+use crate::board::{self, MmioDevice};
+
+/// the only hart running the kernel
+const BOOT_HART: usize = 0;
+
+/// name of the block device holding the root filesystem
+pub const ROOT_BLOCK_DEVICE: &str = "vda";
+
+fn plic() -> Plic {
+  Plic::new(board::info().plic.base)
+}
+
+pub enum DeviceKind {
+  Block(Arc<VirtIOBlock>),
+  /// found, but we have no driver for it
+  Unsupported(DeviceType),
+}
+
+/// A device behind one of the virtio-mmio transports
+pub struct Device {
+  /// `vda`, `vdb`, ... for block devices in slot order, `virtio<slot>` otherwise
+  pub name: String,
+  pub mmio: MmioDevice,
+  pub kind: DeviceKind,
+}
 
 lazy_static! {
-  pub static ref BLOCK_DEV: Arc<dyn BlockDevice> = Arc::new(VirtIOBlock::new());
+  /// every virtio device found on the board, probed on first use
+  static ref DEVICES: Vec<Device> = probe();
+}
+
+/// Identify the device behind each virtio-mmio transport
+fn probe() -> Vec<Device> {
+  let mut devices = Vec::new();
+  let mut block_num = 0;
+  for (slot, &mmio) in board::info().virtio_slots().iter().enumerate() {
+    let header = unsafe { &mut *(mmio.base as *mut VirtIOHeader) };
+    // an empty transport reports device id 0 and fails verification
+    if !header.verify() {
+      continue;
+    }
+    let (name, kind) = match header.device_type() {
+      DeviceType::Block => {
+        let name = format!("vd{}", (b'a' + block_num) as char);
+        block_num += 1;
+        (name, DeviceKind::Block(Arc::new(VirtIOBlock::new(mmio.base))))
+      }
+      other => (format!("virtio{}", slot), DeviceKind::Unsupported(other)),
+    };
+    devices.push(Device { name, mmio, kind });
+  }
+  devices
+}
+
+pub fn devices() -> &'static [Device] {
+  &DEVICES
+}
+
+/// Look up a block device by name
+pub fn block_device(name: &str) -> Option<Arc<VirtIOBlock>> {
+  DEVICES.iter().find_map(|dev| match &dev.kind {
+    DeviceKind::Block(blk) if dev.name == name => Some(blk.clone()),
+    _ => None,
+  })
+}
+
+/// All block devices with their names
+pub fn block_devices() -> impl Iterator<Item = (&'static str, Arc<VirtIOBlock>)> {
+  DEVICES.iter().filter_map(|dev| match &dev.kind {
+    DeviceKind::Block(blk) => Some((dev.name.as_str(), blk.clone())),
+    _ => None,
+  })
+}
+
+/// Probe devices and route their interrupts to S-mode of the boot hart
+pub fn init() {
+  let plic = plic();
+  for dev in devices() {
+    match &dev.kind {
+      DeviceKind::Block(_) => {
+        println!("[kernel] {}: virtio block device at {:#x}, irq {}", dev.name, dev.mmio.base, dev.mmio.irq);
+        plic.set_priority(dev.mmio.irq, 1);
+        plic.enable(BOOT_HART, dev.mmio.irq);
+      }
+      DeviceKind::Unsupported(ty) => {
+        println!("[kernel] {}: no driver for virtio {:?} device at {:#x}", dev.name, ty, dev.mmio.base);
+      }
+    }
+  }
+  let uart_irq = board::info().uart.irq;
+  plic.set_priority(uart_irq, 1);
+  plic.enable(BOOT_HART, uart_irq);
+  plic.set_threshold(BOOT_HART, 0);
+}
+
+/// Claim and dispatch one external interrupt
+pub fn handle_external_interrupt() {
+  let plic = plic();
+  let irq = plic.claim(BOOT_HART);
+  if irq == 0 {
+    return; // claimed by someone else already
+  }
+  if irq == board::info().uart.irq {
+    serial::handle_irq();
+  } else if let Some(dev) = devices().iter().find(|dev| dev.mmio.irq == irq) {
+    match &dev.kind {
+      DeviceKind::Block(blk) => blk.handle_irq(),
+      DeviceKind::Unsupported(_) => { println!("[kernel] interrupt from {} without a driver", dev.name); }
+    }
+  } else {
+    println!("[kernel] unexpected external interrupt {}", irq);
+  }
+  plic.complete(BOOT_HART, irq);
 }
 
 #[allow(unused)]
 pub fn block_device_test() {
-  let block_device = BLOCK_DEV.clone();
+  let block_device = block_device(ROOT_BLOCK_DEVICE).unwrap();
   let mut write_buffer = [0u8; 512];
   let mut read_buffer: [u8; 512] = [0u8; 512];
   for i in 0..512 {
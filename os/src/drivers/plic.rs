@@ -0,0 +1,60 @@
+//! Platform-Level Interrupt Controller (the SiFive PLIC of qemu `virt`)
+
+/// priority of each interrupt source, one u32 per source
+const PRIORITY: usize = 0x00_0000;
+/// per-context enable bitmaps
+const ENABLE: usize = 0x00_2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// per-context threshold and claim/complete registers
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM_COMPLETE: usize = 0x4;
+
+pub struct Plic {
+  base: usize,
+}
+
+impl Plic {
+  pub const fn new(base: usize) -> Self {
+    Self { base }
+  }
+
+  /// qemu `virt` numbers contexts as (hart 0 M-mode, hart 0 S-mode, hart 1 M-mode, ...)
+  fn s_context(hart_id: usize) -> usize {
+    2 * hart_id + 1
+  }
+
+  fn reg(&self, offset: usize) -> *mut u32 {
+    (self.base + offset) as *mut u32
+  }
+
+  /// priority 0 masks the source, 1 is the lowest real priority
+  pub fn set_priority(&self, irq: usize, priority: u32) {
+    unsafe { self.reg(PRIORITY + irq * 4).write_volatile(priority); }
+  }
+
+  /// Route source `irq` to the S-mode context of `hart_id`
+  pub fn enable(&self, hart_id: usize, irq: usize) {
+    let reg = self.reg(ENABLE + Self::s_context(hart_id) * ENABLE_STRIDE + irq / 32 * 4);
+    unsafe { reg.write_volatile(reg.read_volatile() | 1 << (irq % 32)); }
+  }
+
+  /// Only sources whose priority exceeds `threshold` interrupt `hart_id`
+  pub fn set_threshold(&self, hart_id: usize, threshold: u32) {
+    let ctx = CONTEXT + Self::s_context(hart_id) * CONTEXT_STRIDE;
+    unsafe { self.reg(ctx + THRESHOLD).write_volatile(threshold); }
+  }
+
+  /// Claim the highest-priority pending interrupt, 0 if there is none
+  pub fn claim(&self, hart_id: usize) -> usize {
+    let ctx = CONTEXT + Self::s_context(hart_id) * CONTEXT_STRIDE;
+    unsafe { self.reg(ctx + CLAIM_COMPLETE).read_volatile() as usize }
+  }
+
+  /// Tell the PLIC that we are done with `irq`
+  pub fn complete(&self, hart_id: usize, irq: usize) {
+    let ctx = CONTEXT + Self::s_context(hart_id) * CONTEXT_STRIDE;
+    unsafe { self.reg(ctx + CLAIM_COMPLETE).write_volatile(irq as u32); }
+  }
+}
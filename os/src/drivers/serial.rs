@@ -0,0 +1,50 @@
+//! Interrupt-driven console input
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::{ds::ring_buffer::RingBuffer, sync::SpinNoIrqLock, uart::Console, task::{TaskControlBlock, block_current_and_run_next, wakeup_task, processor::current_task}};
+
+const INPUT_BUFFER_SIZE: usize = 256;
+
+struct SerialInput {
+  buffer: RingBuffer<INPUT_BUFFER_SIZE>,
+  /// tasks blocked in `getchar`
+  waiters: VecDeque<Arc<TaskControlBlock>>,
+}
+
+static SERIAL_INPUT: SpinNoIrqLock<SerialInput> = SpinNoIrqLock::new(SerialInput {
+  buffer: RingBuffer::new(),
+  waiters: VecDeque::new(),
+});
+
+/// UART interrupt: drain the receive FIFO into the input buffer and wake up readers
+pub fn handle_irq() {
+  let mut input = SERIAL_INPUT.lock();
+  while let Some(c) = Console::try_get_char() {
+    // drop input nobody reads instead of blocking in interrupt context
+    input.buffer.push(c);
+  }
+  let waiters = core::mem::take(&mut input.waiters);
+  drop(input);
+  for task in waiters {
+    wakeup_task(task);
+  }
+}
+
+/// Return a buffered byte without blocking
+pub fn try_getchar() -> Option<u8> {
+  SERIAL_INPUT.lock().buffer.pop()
+}
+
+/// Block current task until a byte arrives
+pub fn getchar() -> u8 {
+  loop {
+    let mut input = SERIAL_INPUT.lock();
+    if let Some(c) = input.buffer.pop() {
+      return c;
+    }
+    input.waiters.push_back(current_task().unwrap());
+    drop(input);
+    block_current_and_run_next();
+  }
+}
@@ -1,34 +1,116 @@
-use alloc::vec::Vec;
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc, vec::Vec};
 use easy_fs::BlockDevice;
 
-use virtio_drivers::{Hal, VirtIOBlk, VirtIOHeader};
+use virtio_drivers::{Hal, VirtIOBlk, VirtIOHeader, BlkResp, RespStatus};
 
-use crate::{sync::UPSafeCell, mm::{address::{PhysPageNum, PhysAddr, StepByOne}, frame_alloc, frame_dealloc, PageTable, kernel_token, FrameTracker}, board::VIRTIO_BASE_ADDRESS};
+use crate::{sync::{UPSafeCell, SpinNoIrqLock}, mm::{address::{PhysPageNum, PhysAddr, StepByOne}, frame_alloc, frame_dealloc, PageTable, kernel_token, FrameTracker}, task::{TaskControlBlock, block_current_and_run_next, wakeup_task, processor::current_task}};
 
-/// a simple virtio block device
-pub struct VirtIOBlock(UPSafeCell<VirtIOBlk<'static, VirtioHal>>);
+/// a virtio block device whose requests complete by interrupt
+pub struct VirtIOBlock {
+  inner: SpinNoIrqLock<VirtIOBlockInner>,
+  /// size in 512-byte sectors
+  capacity: usize,
+}
+
+struct VirtIOBlockInner {
+  blk: VirtIOBlk<'static, VirtioHal>,
+  /// requests in flight, keyed by the token (head descriptor) the device gave them
+  in_flight: BTreeMap<u16, Arc<TaskControlBlock>>,
+  /// tasks waiting for free descriptors in the virtqueue
+  queue_waiters: VecDeque<Arc<TaskControlBlock>>,
+}
 
 impl VirtIOBlock {
-  pub fn new() -> Self {
-    Self(unsafe {
-      UPSafeCell::new(
-        VirtIOBlk::<VirtioHal>::new(&mut *(VIRTIO_BASE_ADDRESS as *mut VirtIOHeader)).unwrap()
-      )
-    })
+  /// Drive the virtio-mmio transport at `base`
+  pub fn new(base: usize) -> Self {
+    let blk = unsafe {
+      VirtIOBlk::<VirtioHal>::new(&mut *(base as *mut VirtIOHeader)).unwrap()
+    };
+    // `capacity` leads the device-specific config space at offset 0x100 (virtio 1.1, 5.2.4)
+    let capacity = unsafe { ((base + 0x100) as *const u64).read_volatile() } as usize;
+    Self {
+      inner: SpinNoIrqLock::new(VirtIOBlockInner {
+        blk,
+        in_flight: BTreeMap::new(),
+        queue_waiters: VecDeque::new(),
+      }),
+      capacity,
+    }
+  }
+
+  /// Number of 512-byte blocks on the device
+  pub fn num_blocks(&self) -> usize {
+    self.capacity
+  }
+
+  /// Submit a request and sleep until `handle_irq` sees it completed
+  fn submit_and_wait(
+    &self,
+    mut submit: impl FnMut(&mut VirtIOBlk<'static, VirtioHal>) -> virtio_drivers::Result<u16>
+  ) {
+    loop {
+      // interrupts stay off from submitting to parking, so the completion cannot be missed
+      let mut inner = self.inner.lock();
+      match submit(&mut inner.blk) {
+        Ok(token) => {
+          inner.in_flight.insert(token, current_task().unwrap());
+          drop(inner);
+          block_current_and_run_next();
+          return;
+        }
+        Err(virtio_drivers::Error::BufferTooSmall) => {
+          // virtqueue is full: retry once some request completes
+          inner.queue_waiters.push_back(current_task().unwrap());
+          drop(inner);
+          block_current_and_run_next();
+        }
+        Err(err) => panic!("virtio-blk: cannot submit request: {:?}", err),
+      }
+    }
+  }
+
+  /// Virtio interrupt: wake up the owners of all completed requests
+  pub fn handle_irq(&self) {
+    let mut inner = self.inner.lock();
+    inner.blk.ack_interrupt();
+    let mut completed = false;
+    while let Ok(token) = inner.blk.pop_used() {
+      if let Some(task) = inner.in_flight.remove(&token) {
+        wakeup_task(task);
+      }
+      completed = true;
+    }
+    if completed {
+      while let Some(task) = inner.queue_waiters.pop_front() {
+        wakeup_task(task);
+      }
+    }
   }
 }
 
 impl BlockDevice for VirtIOBlock {
   fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-    self.0.exclusive_access().read_block(block_id, buf).expect("error at reading");
+    if current_task().is_none() {
+      // nobody to put to sleep (still booting): busy-wait
+      self.inner.lock().blk.read_block(block_id, buf).expect("error at reading");
+      return;
+    }
+    let mut resp = BlkResp::default();
+    self.submit_and_wait(|blk| unsafe { blk.read_block_nb(block_id, buf, &mut resp) });
+    assert_eq!(resp.status(), RespStatus::Ok, "error at reading block {}", block_id);
   }
 
   fn write_block(&self, block_id: usize, buf: &[u8]) {
-    self.0.exclusive_access().write_block(block_id, buf).expect("error at writing");
+    if current_task().is_none() {
+      self.inner.lock().blk.write_block(block_id, buf).expect("error at writing");
+      return;
+    }
+    let mut resp = BlkResp::default();
+    self.submit_and_wait(|blk| unsafe { blk.write_block_nb(block_id, buf, &mut resp) });
+    assert_eq!(resp.status(), RespStatus::Ok, "error at writing block {}", block_id);
   }
 }
 
-
 lazy_static! {
   /// to prevent frames being dealloced
   static ref QUEUE_FRAMES: UPSafeCell<Vec<FrameTracker>> = unsafe { UPSafeCell::new(Vec::new()) };
@@ -66,7 +148,9 @@ impl Hal for VirtioHal {
   }
 
   fn virt_to_phys(vaddr: virtio_drivers::VirtAddr) -> virtio_drivers::PhysAddr {
-    // might be data from kernel_stack, which is not identically mapped
+    // might be data from kernel_stack, which is not identically mapped. This runs in
+    // `submit_and_wait` under the device's spin lock, so it must not sleep: `kernel_token`
+    // takes no lock.
     let phyaddr = PageTable::from_token(kernel_token())
       .translate_va(vaddr.into())
       .unwrap().0;
@@ -130,8 +130,23 @@ impl<const ORDER: usize> Heap<ORDER> {
       }
     }
     self.user -= layout.size();
-    self.allocated -= layout.align();
+    self.allocated -= size;
   }
+
+  pub fn stats(&self) -> HeapStats {
+    HeapStats { user: self.user, allocated: self.allocated, total: self.total }
+  }
+}
+
+/// Heap usage in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+  /// requested by callers
+  pub user: usize,
+  /// handed out, i.e. `user` rounded up to power-of-two blocks
+  pub allocated: usize,
+  /// managed by the heap
+  pub total: usize,
 }
 
 
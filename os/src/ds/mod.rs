@@ -4,6 +4,7 @@ use crate::ds::buddy::Heap;
 
 pub mod buddy;
 mod linked_list;
+pub mod ring_buffer;
 
 fn test_linked_list() {
   let mut value1: usize = 0;
@@ -82,11 +83,32 @@ fn test_heap_alloc_and_free() {
   }
 }
 
+fn test_ring_buffer() {
+  let mut ring = ring_buffer::RingBuffer::<4>::new();
+  assert_eq!(ring.pop(), None);
+  for byte in 0..4 {
+    assert!(ring.push(byte));
+  }
+  assert!(ring.is_full());
+  assert!(!ring.push(4));
+
+  // wrap around
+  assert_eq!(ring.pop(), Some(0));
+  assert_eq!(ring.pop(), Some(1));
+  assert!(ring.push(5));
+  assert!(ring.push(6));
+  for byte in [2, 3, 5, 6] {
+    assert_eq!(ring.pop(), Some(byte));
+  }
+  assert!(ring.is_empty());
+}
+
 pub fn test() {
   test_linked_list();
   test_empty_heap();
   test_heap_add();
   test_heap_oom();
   test_heap_alloc_and_free();
+  test_ring_buffer();
   println!("Data Structure test: \x1b[92m[passed!]\x1b[0m");
 }
\ No newline at end of file
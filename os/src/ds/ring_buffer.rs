@@ -0,0 +1,47 @@
+/// Fixed-size FIFO of bytes which never allocates,
+/// so it can be filled from interrupt handlers.
+pub struct RingBuffer<const N: usize> {
+  buf: [u8; N],
+  /// index of the oldest byte
+  head: usize,
+  len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+  pub const fn new() -> Self {
+    Self {
+      buf: [0; N],
+      head: 0,
+      len: 0,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.len == N
+  }
+
+  /// Append a byte, return `false` (and drop it) if the buffer is full
+  pub fn push(&mut self, byte: u8) -> bool {
+    if self.is_full() {
+      return false;
+    }
+    self.buf[(self.head + self.len) % N] = byte;
+    self.len += 1;
+    true
+  }
+
+  /// Remove the oldest byte
+  pub fn pop(&mut self) -> Option<u8> {
+    if self.is_empty() {
+      return None;
+    }
+    let byte = self.buf[self.head];
+    self.head = (self.head + 1) % N;
+    self.len -= 1;
+    Some(byte)
+  }
+}
@@ -0,0 +1,195 @@
+//! Minimal reader of the flattened device tree (devicetree specification v0.3, ch. 5)
+//!
+//! It runs in M-mode before the heap exists, so it never allocates: nodes are
+//! visited in order by [`Fdt::nodes`] and the caller picks what it needs.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// deepest nesting we keep `#address-cells`/`#size-cells` for
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum FdtError {
+  /// no FDT header at the given address
+  BadMagic,
+  /// the blob is shorter than its header claims, or not aligned
+  Truncated,
+}
+
+/// big-endian u32 at byte offset `off`
+fn be32(bytes: &[u8], off: usize) -> Option<u32> {
+  let word = bytes.get(off..off + 4)?;
+  Some(u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+/// NUL-terminated string at the start of `bytes`
+fn cstr(bytes: &[u8]) -> Option<&str> {
+  let len = bytes.iter().position(|&b| b == 0)?;
+  core::str::from_utf8(&bytes[..len]).ok()
+}
+
+const fn align4(n: usize) -> usize {
+  (n + 3) & !3
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+  structs: &'a [u8],
+  strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+  /// Read the blob whose header is at `addr`.
+  ///
+  /// # Safety
+  /// `addr` must be readable for as long as the returned `Fdt` is used.
+  pub unsafe fn from_addr(addr: usize) -> Result<Self, FdtError> {
+    if addr == 0 || addr % 8 != 0 {
+      return Err(FdtError::Truncated);
+    }
+    let header = core::slice::from_raw_parts(addr as *const u8, 40);
+    if be32(header, 0) != Some(FDT_MAGIC) {
+      return Err(FdtError::BadMagic);
+    }
+    let field = |i: usize| be32(header, 4 * i).unwrap() as usize;
+    let (total_size, off_structs, off_strings) = (field(1), field(2), field(3));
+    let (size_strings, size_structs) = (field(8), field(9));
+    if off_structs + size_structs > total_size || off_strings + size_strings > total_size {
+      return Err(FdtError::Truncated);
+    }
+    let blob = core::slice::from_raw_parts(addr as *const u8, total_size);
+    Ok(Self {
+      structs: &blob[off_structs..off_structs + size_structs],
+      strings: &blob[off_strings..off_strings + size_strings],
+    })
+  }
+
+  /// All nodes in depth-first order
+  pub fn nodes(&self) -> NodeIter<'a> {
+    NodeIter {
+      fdt: *self,
+      pos: 0,
+      depth: 0,
+      // the spec's defaults for a parent without the properties
+      cells: [(2, 1); MAX_DEPTH],
+    }
+  }
+}
+
+pub struct NodeIter<'a> {
+  fdt: Fdt<'a>,
+  pos: usize,
+  depth: usize,
+  /// (`#address-cells`, `#size-cells`) declared by the node at each depth
+  cells: [(usize, usize); MAX_DEPTH],
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+  type Item = Node<'a>;
+
+  fn next(&mut self) -> Option<Node<'a>> {
+    let structs = self.fdt.structs;
+    loop {
+      let token = be32(structs, self.pos)?;
+      self.pos += 4;
+      match token {
+        FDT_BEGIN_NODE => {
+          let name = cstr(&structs[self.pos..])?;
+          self.pos = align4(self.pos + name.len() + 1);
+          let (address_cells, size_cells) = match self.depth {
+            0 => (2, 1),
+            depth => self.cells[(depth - 1).min(MAX_DEPTH - 1)],
+          };
+          let node = Node { fdt: self.fdt, name, props_start: self.pos, address_cells, size_cells };
+          // properties precede children, so our children's cells are known now
+          let own = (
+            node.prop_u32("#address-cells").map_or(2, |v| v as usize),
+            node.prop_u32("#size-cells").map_or(1, |v| v as usize),
+          );
+          self.cells[self.depth.min(MAX_DEPTH - 1)] = own;
+          self.depth += 1;
+          return Some(node);
+        }
+        FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+        FDT_PROP => {
+          let len = be32(structs, self.pos)? as usize;
+          self.pos = align4(self.pos + 8 + len);
+        }
+        FDT_NOP => {}
+        FDT_END => return None,
+        _ => return None,
+      }
+    }
+  }
+}
+
+/// A node, with the cell sizes its parent declares for its `reg`
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+  fdt: Fdt<'a>,
+  /// unit name, e.g. `uart@10000000`
+  pub name: &'a str,
+  props_start: usize,
+  address_cells: usize,
+  size_cells: usize,
+}
+
+impl<'a> Node<'a> {
+  /// Raw value of property `name`
+  pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+    let structs = self.fdt.structs;
+    let mut pos = self.props_start;
+    loop {
+      match be32(structs, pos)? {
+        FDT_PROP => {
+          let len = be32(structs, pos + 4)? as usize;
+          let name_off = be32(structs, pos + 8)? as usize;
+          let value = structs.get(pos + 12..pos + 12 + len)?;
+          if cstr(self.fdt.strings.get(name_off..)?)? == name {
+            return Some(value);
+          }
+          pos = align4(pos + 12 + len);
+        }
+        FDT_NOP => pos += 4,
+        _ => return None,
+      }
+    }
+  }
+
+  pub fn prop_u32(&self, name: &str) -> Option<u32> {
+    be32(self.prop(name)?, 0)
+  }
+
+  /// Node name without the unit address
+  pub fn base_name(&self) -> &'a str {
+    self.name.split('@').next().unwrap_or(self.name)
+  }
+
+  /// Does the `compatible` string list contain `compat`?
+  pub fn is_compatible(&self, compat: &str) -> bool {
+    self.prop("compatible").map_or(false, |list| {
+      list.split(|&b| b == 0).any(|s| s == compat.as_bytes())
+    })
+  }
+
+  /// First `(address, size)` pair of `reg`
+  pub fn reg(&self) -> Option<(usize, usize)> {
+    let reg = self.prop("reg")?;
+    let read = |start: usize, cells: usize| -> Option<usize> {
+      (0..cells).try_fold(0usize, |acc, i| {
+        Some((acc << 32) | be32(reg, 4 * (start + i))? as usize)
+      })
+    };
+    Some((read(0, self.address_cells)?, read(self.address_cells, self.size_cells)?))
+  }
+
+  /// First cell of `interrupts`
+  pub fn interrupt(&self) -> Option<usize> {
+    self.prop_u32("interrupts").map(|irq| irq as usize)
+  }
+}
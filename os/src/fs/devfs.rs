@@ -0,0 +1,229 @@
+//! `/dev`: device nodes
+//!
+//! ```text
+//! /dev/null     reads nothing, swallows writes
+//! /dev/zero     reads zeros, swallows writes
+//! /dev/urandom  pseudo-random bytes, seeded from `mtime`
+//! /dev/console  the UART
+//! /dev/vd*      raw block devices
+//! ```
+
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
+use easy_fs::{BlockDevice, BLOCK_SZ};
+
+use crate::{drivers::{self, serial, VirtIOBlock}, sbi::console_putchar, sync::SpinNoIrqLock, timer::get_time};
+
+use super::vfs::{DirEntry, FileSystem, FsError, FsResult, Inode, InodeType, Stat};
+
+#[derive(Clone)]
+enum Device {
+  Null,
+  Zero,
+  Urandom,
+  Console,
+  /// bypasses the block cache, so don't write to a mounted device
+  Block(Arc<VirtIOBlock>),
+}
+
+/// Every device node, in directory order
+fn devices() -> Vec<(String, Device)> {
+  let mut devices: Vec<(String, Device)> = [
+    ("null", Device::Null),
+    ("zero", Device::Zero),
+    ("urandom", Device::Urandom),
+    ("console", Device::Console),
+  ].into_iter().map(|(name, dev)| (name.to_string(), dev)).collect();
+  devices.extend(drivers::block_devices().map(|(name, blk)| (name.to_string(), Device::Block(blk))));
+  devices
+}
+
+const ROOT_INO: u64 = 1;
+
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+  fn root(&self) -> Arc<dyn Inode> {
+    Arc::new(DevDir)
+  }
+
+  fn fs_type(&self) -> &'static str {
+    "devfs"
+  }
+}
+
+struct DevDir;
+
+impl Inode for DevDir {
+  fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+    let (idx, (_, dev)) = devices()
+      .into_iter()
+      .enumerate()
+      .find(|(_, (dev_name, _))| dev_name == name)
+      .ok_or(FsError::NotFound)?;
+    Ok(Arc::new(DevNode { ino: ROOT_INO + 1 + idx as u64, dev }))
+  }
+
+  fn create(&self, _name: &str, _kind: InodeType) -> FsResult<Arc<dyn Inode>> {
+    Err(FsError::NotSupported)
+  }
+
+  fn unlink(&self, _name: &str) -> FsResult {
+    Err(FsError::NotSupported)
+  }
+
+  fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+    Ok(devices()
+      .into_iter()
+      .enumerate()
+      .map(|(idx, (name, _))| DirEntry { name, ino: ROOT_INO + 1 + idx as u64, kind: InodeType::File })
+      .collect())
+  }
+
+  fn stat(&self) -> Stat {
+    Stat::new(ROOT_INO, InodeType::Dir, 0)
+  }
+
+  fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> FsResult<usize> {
+    Err(FsError::IsDir)
+  }
+
+  fn write_at(&self, _offset: usize, _buf: &[u8]) -> FsResult<usize> {
+    Err(FsError::IsDir)
+  }
+
+  fn truncate(&self, _size: usize) -> FsResult {
+    Err(FsError::IsDir)
+  }
+}
+
+struct DevNode {
+  ino: u64,
+  dev: Device,
+}
+
+/// xorshift64* state behind `/dev/urandom`, 0 until first seeded
+static URANDOM: SpinNoIrqLock<u64> = SpinNoIrqLock::new(0);
+
+fn fill_random(buf: &mut [u8]) {
+  let mut state = URANDOM.lock();
+  if *state == 0 {
+    *state = get_time() as u64 | 1;
+  }
+  for chunk in buf.chunks_mut(8) {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    let word = state.wrapping_mul(0x2545_f491_4f6c_dd1d).to_le_bytes();
+    chunk.copy_from_slice(&word[..chunk.len()]);
+  }
+}
+
+/// Block until at least one byte is typed, then return what is buffered
+fn console_read(buf: &mut [u8]) -> usize {
+  if buf.is_empty() {
+    return 0;
+  }
+  buf[0] = serial::getchar();
+  let mut len = 1;
+  while len < buf.len() {
+    match serial::try_getchar() {
+      Some(ch) => buf[len] = ch,
+      None => break,
+    }
+    len += 1;
+  }
+  len
+}
+
+/// Call `f` on each block touched by `len` bytes at `offset`, with the range within the block
+/// and the position in the caller's buffer; stops at the end of the device
+fn for_each_block(blk: &VirtIOBlock, offset: usize, len: usize, mut f: impl FnMut(usize, core::ops::Range<usize>, usize)) -> usize {
+  let end = (offset + len).min(blk.num_blocks() * BLOCK_SZ);
+  let mut pos = offset;
+  while pos < end {
+    let block_id = pos / BLOCK_SZ;
+    let start = pos % BLOCK_SZ;
+    let stop = BLOCK_SZ.min(start + end - pos);
+    f(block_id, start..stop, pos - offset);
+    pos += stop - start;
+  }
+  end.saturating_sub(offset)
+}
+
+impl Inode for DevNode {
+  fn lookup(&self, _name: &str) -> FsResult<Arc<dyn Inode>> {
+    Err(FsError::NotDir)
+  }
+
+  fn create(&self, _name: &str, _kind: InodeType) -> FsResult<Arc<dyn Inode>> {
+    Err(FsError::NotDir)
+  }
+
+  fn unlink(&self, _name: &str) -> FsResult {
+    Err(FsError::NotDir)
+  }
+
+  fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+    Err(FsError::NotDir)
+  }
+
+  fn stat(&self) -> Stat {
+    let size = match &self.dev {
+      Device::Block(blk) => (blk.num_blocks() * BLOCK_SZ) as u64,
+      _ => 0,
+    };
+    Stat::new(self.ino, InodeType::File, size)
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> FsResult<usize> {
+    match &self.dev {
+      Device::Null => Ok(0),
+      Device::Zero => {
+        buf.fill(0);
+        Ok(buf.len())
+      }
+      Device::Urandom => {
+        fill_random(buf);
+        Ok(buf.len())
+      }
+      Device::Console => Ok(console_read(buf)),
+      Device::Block(blk) => {
+        let mut block = [0u8; BLOCK_SZ];
+        Ok(for_each_block(blk, offset, buf.len(), |block_id, range, pos| {
+          blk.read_block(block_id, &mut block);
+          buf[pos..pos + range.len()].copy_from_slice(&block[range]);
+        }))
+      }
+    }
+  }
+
+  fn write_at(&self, offset: usize, buf: &[u8]) -> FsResult<usize> {
+    match &self.dev {
+      Device::Null | Device::Zero | Device::Urandom => Ok(buf.len()),
+      Device::Console => {
+        for &byte in buf {
+          console_putchar(byte as usize);
+        }
+        Ok(buf.len())
+      }
+      Device::Block(blk) => {
+        let mut block = [0u8; BLOCK_SZ];
+        Ok(for_each_block(blk, offset, buf.len(), |block_id, range, pos| {
+          if range.len() < BLOCK_SZ {
+            blk.read_block(block_id, &mut block);
+          }
+          block[range.clone()].copy_from_slice(&buf[pos..pos + range.len()]);
+          blk.write_block(block_id, &block);
+        }))
+      }
+    }
+  }
+
+  /// Lets `open` with `TRUNC` succeed, so output can be sent to e.g. `/dev/null`
+  fn truncate(&self, _size: usize) -> FsResult {
+    match self.dev {
+      Device::Block(_) => Err(FsError::NotSupported),
+      _ => Ok(()),
+    }
+  }
+}
@@ -0,0 +1,99 @@
+//! Open directories, listed with `sys_getdents64`
+
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
+
+use crate::{mm::UserBuffer, sync::SleepLock};
+
+use super::{File, vfs::{DirEntry, FsError, FsResult, Inode, InodeType, Stat}};
+
+/// `d_type` of a `linux_dirent64`
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+/// `d_ino`, `d_off`, `d_reclen` and `d_type`
+const DIRENT64_HEADER: usize = 8 + 8 + 2 + 1;
+
+/// How far a listing got
+#[derive(Default)]
+struct Cursor {
+  /// the entries as of the first `getdents`, so later calls page through the same list
+  /// instead of reading the directory again
+  entries: Option<Vec<DirEntry>>,
+  /// entries returned so far
+  pos: usize,
+}
+
+/// A directory opened for listing
+pub struct DirFile {
+  inode: Arc<dyn Inode>,
+  path: String,
+  cursor: SleepLock<Cursor>,
+}
+
+impl DirFile {
+  pub fn new(inode: Arc<dyn Inode>, path: &str) -> Self {
+    Self { inode, path: path.to_string(), cursor: SleepLock::new(Cursor::default()) }
+  }
+}
+
+impl File for DirFile {
+  fn readable(&self) -> bool {
+    true
+  }
+
+  fn writable(&self) -> bool {
+    false
+  }
+
+  /// Directories are listed, not read
+  fn read(&self, _buf: UserBuffer) -> usize {
+    0
+  }
+
+  fn write(&self, _buf: UserBuffer) -> usize {
+    0
+  }
+
+  fn path(&self) -> String {
+    self.path.clone()
+  }
+
+  fn stat(&self) -> Option<Stat> {
+    Some(self.inode.stat())
+  }
+
+  fn getdents(&self, buf: UserBuffer) -> FsResult<usize> {
+    let mut cursor = self.cursor.lock();
+    let len: usize = buf.buffers.iter().map(|buf| buf.len()).sum();
+    if cursor.entries.is_none() {
+      cursor.entries = Some(self.inode.readdir()?);
+    }
+    let Cursor { entries, pos } = &mut *cursor;
+    let entries = entries.as_ref().unwrap();
+    let mut records = Vec::new();
+    for entry in &entries[*pos..] {
+      // the name is NUL terminated and the record 8 byte aligned
+      let reclen = (DIRENT64_HEADER + entry.name.len() + 1 + 7) & !7;
+      if records.len() + reclen > len {
+        break;
+      }
+      *pos += 1;
+      let d_type = match entry.kind {
+        InodeType::Dir => DT_DIR,
+        InodeType::File => DT_REG,
+      };
+      records.extend_from_slice(&entry.ino.to_ne_bytes());
+      records.extend_from_slice(&(*pos as i64).to_ne_bytes());
+      records.extend_from_slice(&(reclen as u16).to_ne_bytes());
+      records.push(d_type);
+      records.extend_from_slice(entry.name.as_bytes());
+      records.resize(records.len() + reclen - DIRENT64_HEADER - entry.name.len(), 0);
+    }
+    if records.is_empty() && *pos < entries.len() {
+      return Err(FsError::BufferTooSmall);
+    }
+    for (dst, src) in buf.into_iter().zip(&records) {
+      unsafe { *dst = *src };
+    }
+    Ok(records.len())
+  }
+}
@@ -0,0 +1,297 @@
+//! easy-fs behind the VFS
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{mem::ManuallyDrop, sync::atomic::{AtomicUsize, Ordering}};
+use easy_fs::{BlockCacheStats, BLOCK_SZ, BlockDevice, FileSystem as EfsFileSystem, Inode as EfsInode, RenameError, WritePolicy};
+
+use crate::{config::{BLOCK_CACHE_SIZE, BLOCK_CACHE_WRITE_BACK_MS, EASY_FS_CHECKSUM_POLICY}, sync::SleepLock, timer};
+
+use super::vfs::{DirEntry, FileSystem, FsError, FsResult, Inode, InodeType, Stat};
+
+/// Serializes every easy-fs operation issued by the kernel.
+///
+/// easy-fs guards its state with spin locks, and a task may sleep on disk I/O
+/// while holding them; contenders must be parked here instead of spinning.
+static EASY_FS_LOCK: SleepLock<()> = SleepLock::new(());
+
+/// Size the block cache of each easy-fs volume
+pub fn init_block_cache() {
+  let _fs = EASY_FS_LOCK.lock();
+  easy_fs::configure_block_cache(BLOCK_CACHE_SIZE, WritePolicy::WriteBack);
+}
+
+/// When the block caches were last written back, in milliseconds since boot
+static LAST_WRITE_BACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Write back the dirty blocks of every volume if `BLOCK_CACHE_WRITE_BACK_MS` have passed
+/// since the last time. Called on timer ticks taken in user mode, where the task may sleep.
+pub fn write_back_tick() {
+  let now = timer::get_time_ms();
+  if now - LAST_WRITE_BACK.load(Ordering::Relaxed) < BLOCK_CACHE_WRITE_BACK_MS {
+    return;
+  }
+  LAST_WRITE_BACK.store(now, Ordering::Relaxed);
+  let _fs = EASY_FS_LOCK.lock();
+  easy_fs::block_cache_sync_all();
+}
+
+/// Block cache counters summed over all devices
+pub fn block_cache_stats() -> BlockCacheStats {
+  let _fs = EASY_FS_LOCK.lock();
+  easy_fs::block_cache_stats_all()
+}
+
+pub struct EasyFileSystem {
+  block_dev: Arc<dyn BlockDevice>,
+  root: Arc<EfsInode>,
+  /// the block cache was created for this instance, which releases it
+  owns_cache: bool,
+}
+
+impl EasyFileSystem {
+  /// Open the easy-fs image on `block_dev`, `None` if it holds none
+  pub fn open(block_dev: Arc<dyn BlockDevice>) -> Option<Arc<Self>> {
+    let _fs = EASY_FS_LOCK.lock();
+    // another instance may be open on the device, whose cache must outlive this one
+    let owns_cache = !easy_fs::block_cache_exists(&block_dev);
+    if !EfsFileSystem::probe(&block_dev) {
+      if owns_cache {
+        easy_fs::block_cache_release(&block_dev);
+      }
+      return None;
+    }
+    let efs = EfsFileSystem::open(block_dev.clone());
+    efs.lock().set_clock(timer::get_time_ns);
+    efs.lock().set_checksum_policy(EASY_FS_CHECKSUM_POLICY);
+    Some(Arc::new(Self { block_dev, root: Arc::new(EfsFileSystem::root_inode(&efs)), owns_cache }))
+  }
+}
+
+impl Drop for EasyFileSystem {
+  /// Unmounted: write back and free the volume's block cache, unless another instance made it
+  fn drop(&mut self) {
+    let _fs = EASY_FS_LOCK.lock();
+    if self.owns_cache {
+      easy_fs::block_cache_release(&self.block_dev);
+    }
+  }
+}
+
+impl FileSystem for EasyFileSystem {
+  fn root(&self) -> Arc<dyn Inode> {
+    Arc::new(EasyInode::new(self.root.clone()))
+  }
+
+  fn fs_type(&self) -> &'static str {
+    "easyfs"
+  }
+
+  fn sync(&self) {
+    let _fs = EASY_FS_LOCK.lock();
+    self.root.sync();
+  }
+}
+
+/// An unlinked easy-fs inode is freed when its last handle drops, which must happen
+/// under `EASY_FS_LOCK` like any other easy-fs operation
+pub struct EasyInode(ManuallyDrop<Arc<EfsInode>>);
+
+impl EasyInode {
+  fn new(inode: Arc<EfsInode>) -> Self {
+    Self(ManuallyDrop::new(inode))
+  }
+
+  fn kind(&self) -> InodeType {
+    if self.0.is_dir() { InodeType::Dir } else { InodeType::File }
+  }
+
+  fn check_dir(&self) -> FsResult {
+    if self.0.is_dir() { Ok(()) } else { Err(FsError::NotDir) }
+  }
+
+  /// Refuse changes once a corrupt block made the volume read-only
+  fn check_writable(&self) -> FsResult {
+    if self.0.is_read_only() { Err(FsError::ReadOnly) } else { Ok(()) }
+  }
+
+  /// `result` of an operation, unless it came across corrupt blocks and failed
+  fn checked<T>(&self, result: FsResult<T>) -> FsResult<T> {
+    let corrupt = self.0.take_corrupt_blocks();
+    if corrupt.is_empty() {
+      return result;
+    }
+    println!("[kernel] easy-fs: blocks {:?} fail their checksums", corrupt);
+    Err(FsError::Io)
+  }
+
+  /// Can `name` be an entry of this directory? Old images hold shorter names.
+  fn check_name(&self, name: &str) -> FsResult {
+    match name.len() {
+      0 => Err(FsError::InvalidName),
+      len if len > self.0.name_max() => Err(FsError::NameTooLong),
+      _ => Ok(()),
+    }
+  }
+}
+
+impl Drop for EasyInode {
+  fn drop(&mut self) {
+    // the kernel is not preemptible, so nothing unlinks the inode between the check and the drop
+    let _fs = self.0.is_unlinked().then(|| EASY_FS_LOCK.lock());
+    // SAFETY: the inode is not used after this
+    unsafe { ManuallyDrop::drop(&mut self.0) }
+  }
+}
+
+impl Inode for EasyInode {
+  fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+    let _fs = EASY_FS_LOCK.lock();
+    self.check_dir()?;
+    self.check_name(name)?;
+    let found = self.0.find_name(name);
+    self.checked(match found {
+      Some(inode) => Ok(Arc::new(EasyInode::new(inode)) as Arc<dyn Inode>),
+      None => Err(FsError::NotFound),
+    })
+  }
+
+  fn create(&self, name: &str, kind: InodeType) -> FsResult<Arc<dyn Inode>> {
+    let _fs = EASY_FS_LOCK.lock();
+    self.check_dir()?;
+    self.check_name(name)?;
+    self.check_writable()?;
+    let created = match kind {
+      InodeType::File => self.0.create(name),
+      InodeType::Dir => self.0.create_dir(name),
+    };
+    self.checked(match created {
+      Some(inode) => Ok(Arc::new(EasyInode::new(inode)) as Arc<dyn Inode>),
+      None => Err(FsError::AlreadyExists),
+    })
+  }
+
+  fn unlink(&self, name: &str) -> FsResult {
+    let _fs = EASY_FS_LOCK.lock();
+    self.check_dir()?;
+    self.check_writable()?;
+    let unlinked = match self.0.find_name(name) {
+      None => Err(FsError::NotFound),
+      // only a directory with entries left is refused
+      Some(_) if !self.0.unlink(name) => Err(FsError::NotEmpty),
+      Some(_) => Ok(()),
+    };
+    self.checked(unlinked)
+  }
+
+  fn rename(&self, old_name: &str, new_dir: &dyn Inode, new_name: &str, noreplace: bool) -> FsResult {
+    let new_dir = new_dir.as_any().downcast_ref::<EasyInode>().ok_or(FsError::CrossDevice)?;
+    let _fs = EASY_FS_LOCK.lock();
+    self.check_dir()?;
+    new_dir.check_dir()?;
+    new_dir.check_name(new_name)?;
+    self.check_writable()?;
+    if noreplace && new_dir.0.find_name(new_name).is_some() {
+      return Err(FsError::AlreadyExists);
+    }
+    let renamed = EfsInode::rename(&self.0, old_name, &new_dir.0, new_name).map_err(|err| match err {
+      RenameError::NotFound => FsError::NotFound,
+      RenameError::InvalidName => FsError::InvalidName,
+      RenameError::CrossDevice => FsError::CrossDevice,
+      RenameError::NotDir => FsError::NotDir,
+      RenameError::IsDir => FsError::IsDir,
+      RenameError::NotEmpty => FsError::NotEmpty,
+      RenameError::IntoItself => FsError::IntoItself,
+      RenameError::Dropped => FsError::Io,
+    });
+    self.checked(renamed)
+  }
+
+  fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+    let _fs = EASY_FS_LOCK.lock();
+    self.check_dir()?;
+    let entries = self.0.ls().into_iter().filter_map(|name| {
+      let inode = EasyInode::new(self.0.find_name(&name)?);
+      Some(DirEntry { ino: inode.0.inode_id() as u64, kind: inode.kind(), name })
+    }).collect();
+    self.checked(Ok(entries))
+  }
+
+  fn stat(&self) -> Stat {
+    let _fs = EASY_FS_LOCK.lock();
+    let meta = self.0.metadata();
+    Stat {
+      ino: self.0.inode_id() as u64,
+      kind: self.kind(),
+      size: self.0.size() as u64,
+      mode: meta.mode,
+      uid: meta.uid,
+      gid: meta.gid,
+      atime: meta.atime(),
+      mtime: meta.mtime(),
+      ctime: meta.ctime(),
+    }
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> FsResult<usize> {
+    let _fs = EASY_FS_LOCK.lock();
+    if self.0.is_dir() {
+      return Err(FsError::IsDir);
+    }
+    let read = self.0.read_at(offset, buf);
+    self.checked(Ok(read))
+  }
+
+  fn write_at(&self, offset: usize, buf: &[u8]) -> FsResult<usize> {
+    let _fs = EASY_FS_LOCK.lock();
+    if self.0.is_dir() {
+      return Err(FsError::IsDir);
+    }
+    self.check_writable()?;
+    let written = self.0.write_at(offset, buf);
+    self.checked(Ok(written))
+  }
+
+  fn truncate(&self, size: usize) -> FsResult {
+    let _fs = EASY_FS_LOCK.lock();
+    if self.0.is_dir() {
+      return Err(FsError::IsDir);
+    }
+    let old_size = self.0.size();
+    if size == old_size {
+      return Ok(());
+    }
+    self.check_writable()?;
+    let truncated = if size < old_size {
+      if self.0.shrink(size as u64) { Ok(()) } else { Err(FsError::Io) }
+    } else {
+      // grow a block at a time, the kernel heap is small
+      let zeros = [0u8; BLOCK_SZ];
+      (old_size..size).step_by(BLOCK_SZ).try_for_each(|offset| {
+        let len = (size - offset).min(BLOCK_SZ);
+        if self.0.write_at(offset, &zeros[..len]) == len { Ok(()) } else { Err(FsError::NoSpace) }
+      })
+    };
+    self.checked(truncated)
+  }
+
+  /// Images from before easy-fs kept modes and times cannot
+  fn set_mode(&self, mode: u32) -> FsResult {
+    let _fs = EASY_FS_LOCK.lock();
+    self.check_writable()?;
+    let set = if self.0.set_mode(mode) { Ok(()) } else { Err(FsError::NotSupported) };
+    self.checked(set)
+  }
+
+  fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> FsResult {
+    let _fs = EASY_FS_LOCK.lock();
+    self.check_writable()?;
+    let set = if self.0.set_times(atime, mtime) { Ok(()) } else { Err(FsError::NotSupported) };
+    self.checked(set)
+  }
+
+  fn sync(&self) -> FsResult {
+    let _fs = EASY_FS_LOCK.lock();
+    self.0.sync();
+    Ok(())
+  }
+}
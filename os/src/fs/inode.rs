@@ -1,63 +1,54 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
 use bitflags::bitflags;
-use easy_fs::{Inode, FileSystem};
 
-use crate::{sync::UPSafeCell, drivers::BLOCK_DEV};
+use crate::sync::SleepLock;
 
-use super::File;
+use super::{File, dir::DirFile, mount, vfs::{FsError, FsResult, Inode, InodeType, Stat}};
 
+/// An open file: an inode plus the current offset
 pub struct OSInode {
   readable: bool, // immutable info
   writable: bool,
-  inner: UPSafeCell<OSInodeInner>, // mutable info
-}
-
-pub struct OSInodeInner {
-  offset: usize,
-  inode: Arc<Inode>,
+  inode: Arc<dyn Inode>,
+  /// as passed to `open_file`
+  path: String,
+  /// reads and writes may sleep on the disk, hence not a spin lock
+  offset: SleepLock<usize>,
 }
 
 impl OSInode {
-  pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
+  pub fn new(readable: bool, writable: bool, inode: Arc<dyn Inode>, path: &str) -> Self {
     Self {
       readable,
       writable,
-      inner: unsafe {
-        UPSafeCell::new(OSInodeInner { offset: 0, inode })
-      }
+      inode,
+      path: path.to_string(),
+      offset: SleepLock::new(0),
     }
   }
 
   /// Read all data (as bytes) inside a inode into a vector
   pub fn read_all(&self) -> Vec<u8> {
-    let mut inner = self.inner.exclusive_access();
+    let mut offset = self.offset.lock();
     let mut buf = [0u8; 512];
     let mut vec: Vec<_> = Vec::new();
     loop {
-      let len = inner.inode.read_at(inner.offset, &mut buf);
+      let len = self.inode.read_at(*offset, &mut buf).unwrap_or(0);
       if len == 0 {
         break;
       }
-      inner.offset += len;
-      vec.extend_from_slice(&buf);
+      *offset += len;
+      vec.extend_from_slice(&buf[..len]);
     }
     vec
   }  
 }
 
-
-lazy_static! {
-  pub static ref ROOT_INODE: Arc<Inode> = {
-    let fs = FileSystem::open(BLOCK_DEV.clone());
-    Arc::new(FileSystem::root_inode(&fs))
-  };
-}
-
 pub fn list_apps() {
-  let app_list = ROOT_INODE.ls();
+  let app_list = mount::lookup("/").and_then(|root| root.readdir()).unwrap();
   println!("==== BEGIN: APP List ====");
   for app in app_list {
-    println!("{}", app);
+    println!("{}", app.name);
   }
   println!("==== END of List ====");
 }
@@ -88,19 +79,39 @@ impl Flags {
   }
 }
 
-pub fn open_file(name: &str, flags: Flags) -> Option<Arc<OSInode>> {
+/// Find or, with `Flags::CREATE`, create the inode `path` refers to
+fn open_inode(path: &str, flags: Flags) -> FsResult<Arc<dyn Inode>> {
+  match mount::lookup(path) {
+    Ok(inode) => {
+      if flags.contains(Flags::TRUNC) {
+        inode.truncate(0)?;
+      }
+      Ok(inode)
+    }
+    Err(FsError::NotFound) if flags.contains(Flags::CREATE) => {
+      let (dir, name) = mount::lookup_parent(path)?;
+      dir.create(&name, InodeType::File)
+    }
+    Err(err) => Err(err),
+  }
+}
+
+pub fn open_file(path: &str, flags: Flags) -> FsResult<Arc<OSInode>> {
   let (readable, writable) = flags.rdwr_flags();
-  if let Some(inode) = ROOT_INODE.find_name(name) {
-    if flags.contains(Flags::TRUNC) {
-      inode.clear();
+  Ok(Arc::new(OSInode::new(readable, writable, open_inode(path, flags)?, path)))
+}
+
+/// Like `open_file`, but a directory opens as a `DirFile`, which can only be read
+pub fn open(path: &str, flags: Flags) -> FsResult<Arc<dyn File + Send + Sync>> {
+  let inode = open_inode(path, flags)?;
+  let (readable, writable) = flags.rdwr_flags();
+  if inode.stat().kind == InodeType::Dir {
+    if writable {
+      return Err(FsError::IsDir);
     }
-    Some(Arc::new(OSInode::new(readable, writable, inode)))
-  } else if flags.contains(Flags::CREATE) {
-    let inode = ROOT_INODE.create(name).unwrap();
-    Some(Arc::new(OSInode::new(readable, writable, inode)))
-  } else {
-    None
+    return Ok(Arc::new(DirFile::new(inode, path)));
   }
+  Ok(Arc::new(OSInode::new(readable, writable, inode, path)))
 }
 
 impl File for OSInode {
@@ -113,27 +124,40 @@ impl File for OSInode {
   }
 
   fn read(&self, mut buf: crate::mm::UserBuffer) -> usize {
-    let mut inner = self.inner.exclusive_access();
-    let start = inner.offset;
+    let mut offset = self.offset.lock();
+    let start = *offset;
     for buf in buf.buffers.iter_mut() {
-      let size = inner.inode.read_at(inner.offset, buf);
-      inner.offset += size;
-      if size == 0 {
+      let size = self.inode.read_at(*offset, buf).unwrap_or(0);
+      *offset += size;
+      // a short read is the end of the file, or all a device has for now
+      if size < buf.len() {
         break;
       }
     }
-    inner.offset - start
+    *offset - start
   }
 
   fn write(&self, buf: crate::mm::UserBuffer) -> usize {
-    let mut inner = self.inner.exclusive_access();
-    let start = inner.offset;
+    let mut offset = self.offset.lock();
+    let start = *offset;
     for buf in buf.buffers.iter() {
-      let size = inner.inode.write_at(inner.offset, buf);
-      assert_eq!(size, buf.len());
-      inner.offset += size;
+      match self.inode.write_at(*offset, buf) {
+        Ok(size) => *offset += size,
+        Err(_) => break,
+      }
     }
-    inner.offset - start
+    *offset - start
   }
-}
 
+  fn path(&self) -> String {
+    self.path.clone()
+  }
+
+  fn sync(&self) -> bool {
+    self.inode.sync().is_ok()
+  }
+
+  fn stat(&self) -> Option<Stat> {
+    Some(self.inode.stat())
+  }
+}
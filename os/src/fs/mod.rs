@@ -1,14 +1,41 @@
+mod devfs;
+mod dir;
+mod easyfs;
 mod inode;
-mod stdio;
+pub mod mount;
+mod procfs;
+mod tmpfs;
+pub mod vfs;
+
+use alloc::string::String;
 
 use crate::mm::UserBuffer;
 
+use vfs::{FsError, FsResult, Stat};
+
+pub use easyfs::write_back_tick;
 pub use inode::*;
-pub use stdio::{Stdin, Stdout};
+pub use mount::init;
+pub use tmpfs::tmpfs_test;
 
 pub trait File: Send + Sync {
   fn readable(&self) -> bool;
   fn writable(&self) -> bool;
   fn read(&self, buf: UserBuffer) -> usize;
   fn write(&self, buf: UserBuffer) -> usize;
+  /// What the file refers to, e.g. the path it was opened with
+  fn path(&self) -> String;
+  /// Flush written data to the backing device
+  fn sync(&self) -> bool {
+    true
+  }
+  /// Metadata of the inode behind the file, `None` if there is none
+  fn stat(&self) -> Option<Stat> {
+    None
+  }
+  /// Fill `buf` with `linux_dirent64` records of the next entries of this directory,
+  /// returning the bytes filled, 0 once all were listed
+  fn getdents(&self, _buf: UserBuffer) -> FsResult<usize> {
+    Err(FsError::NotDir)
+  }
 }
\ No newline at end of file
@@ -0,0 +1,171 @@
+//! Mount table: which filesystem serves which part of the path tree
+
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
+
+use crate::{drivers::{self, ROOT_BLOCK_DEVICE}, sync::SleepLock};
+
+use super::{devfs::DevFs, easyfs::{self, EasyFileSystem}, procfs::ProcFs, tmpfs::TmpFs, vfs::{FileSystem, FsError, FsResult, Inode, InodeType}};
+
+struct Mount {
+  /// components of the mount point, empty for `/`
+  path: Vec<String>,
+  fs: Arc<dyn FileSystem>,
+}
+
+static MOUNT_TABLE: SleepLock<Vec<Mount>> = SleepLock::new(Vec::new());
+
+/// Split `path` into its components, resolving `.` and `..`.
+/// There is no working directory yet, so every path is taken from `/`.
+fn split_path(path: &str) -> Vec<&str> {
+  let mut components = Vec::new();
+  for component in path.split('/') {
+    match component {
+      "" | "." => {}
+      ".." => { components.pop(); }
+      name => components.push(name),
+    }
+  }
+  components
+}
+
+/// Mount `fs` at `path`, which must be a directory
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> FsResult {
+  let components = split_path(path);
+  if !components.is_empty() && lookup_components(&components)?.stat().kind != InodeType::Dir {
+    return Err(FsError::NotDir);
+  }
+  let path: Vec<String> = components.into_iter().map(String::from).collect();
+  let mut table = MOUNT_TABLE.lock();
+  if table.iter().any(|mount| mount.path == path) {
+    return Err(FsError::Busy);
+  }
+  table.push(Mount { path, fs });
+  Ok(())
+}
+
+/// Detach the filesystem mounted at `path`. Files already open on it stay usable.
+pub fn umount(path: &str) -> FsResult {
+  let path = split_path(path);
+  if path.is_empty() {
+    return Err(FsError::Busy);
+  }
+  let mut table = MOUNT_TABLE.lock();
+  let idx = table.iter().position(|mount| mount.path == path).ok_or(FsError::NotFound)?;
+  table.remove(idx).fs.sync();
+  Ok(())
+}
+
+/// Mount points and the type of filesystem on each
+pub fn mounts() -> Vec<(String, &'static str)> {
+  MOUNT_TABLE
+    .lock()
+    .iter()
+    .map(|mount| (alloc::format!("/{}", mount.path.join("/")), mount.fs.fs_type()))
+    .collect()
+}
+
+/// Write back the cached data of every mounted filesystem
+pub fn sync_all() {
+  let filesystems: Vec<_> = MOUNT_TABLE.lock().iter().map(|mount| mount.fs.clone()).collect();
+  filesystems.iter().for_each(|fs| fs.sync());
+}
+
+/// Find the inode at the already split `components`
+fn lookup_components(components: &[&str]) -> FsResult<Arc<dyn Inode>> {
+  // the mount with the longest matching prefix serves the path
+  let (root, rest) = {
+    let table = MOUNT_TABLE.lock();
+    let mount = table
+      .iter()
+      .filter(|mount| {
+        mount.path.len() <= components.len()
+          && mount.path.iter().zip(components).all(|(a, b)| a == b)
+      })
+      .max_by_key(|mount| mount.path.len())
+      .ok_or(FsError::NotFound)?;
+    (mount.fs.root(), &components[mount.path.len()..])
+  };
+  rest.iter().try_fold(root, |dir, name| dir.lookup(name))
+}
+
+/// Find the inode at `path`
+pub fn lookup(path: &str) -> FsResult<Arc<dyn Inode>> {
+  lookup_components(&split_path(path))
+}
+
+/// Find the directory containing `path`, and the last component of `path`
+pub fn lookup_parent(path: &str) -> FsResult<(Arc<dyn Inode>, String)> {
+  let components = split_path(path);
+  let (name, parent) = components.split_last().ok_or(FsError::InvalidName)?;
+  Ok((lookup_components(parent)?, name.to_string()))
+}
+
+/// Move `old_path` to `new_path`, replacing what is there unless `noreplace`.
+/// Both must be on the same filesystem, neither a mount point, and `old_path` must not
+/// hold one below it.
+pub fn rename(old_path: &str, new_path: &str, noreplace: bool) -> FsResult {
+  let (old_components, new_components) = (split_path(old_path), split_path(new_path));
+  let holds_mount = |mount: &Mount| {
+    mount.path.len() >= old_components.len() && mount.path.iter().zip(&old_components).all(|(a, b)| a == b)
+  };
+  if MOUNT_TABLE.lock().iter().any(|mount| holds_mount(mount) || mount.path == new_components) {
+    return Err(FsError::Busy);
+  }
+  let (old_dir, old_name) = lookup_parent(old_path)?;
+  let (new_dir, new_name) = lookup_parent(new_path)?;
+  old_dir.rename(&old_name, &*new_dir, &new_name, noreplace)
+}
+
+/// Create a filesystem for `sys_mount`
+pub fn new_fs(fs_type: &str, source: &str) -> FsResult<Arc<dyn FileSystem>> {
+  match fs_type {
+    "easyfs" => {
+      let block_dev = drivers::block_device(source.trim_start_matches("/dev/")).ok_or(FsError::NotFound)?;
+      let fs = EasyFileSystem::open(block_dev).ok_or(FsError::NotSupported)?;
+      Ok(fs)
+    }
+    "devfs" => Ok(Arc::new(DevFs)),
+    "procfs" => Ok(Arc::new(ProcFs)),
+    "tmpfs" => Ok(TmpFs::with_default_limit()),
+    _ => Err(FsError::NotSupported),
+  }
+}
+
+/// Mount `fs` at `path`, first creating the directory if the image lacks it
+fn mount_creating(path: &str, fs: Arc<dyn FileSystem>) -> FsResult {
+  if let Err(FsError::NotFound) = lookup(path) {
+    let (parent, name) = lookup_parent(path)?;
+    parent.create(&name, InodeType::Dir)?;
+  }
+  mount(path, fs)
+}
+
+/// Mount the root filesystem, `/dev`, `/proc` and `/tmp`, then the easy-fs image of every other block device at `/<device name>`
+pub fn init() {
+  easyfs::init_block_cache();
+  let root = drivers::block_device(ROOT_BLOCK_DEVICE)
+    .and_then(|block_dev| EasyFileSystem::open(block_dev))
+    .expect("no easy-fs image on the root block device");
+  mount("/", root).unwrap();
+  let builtin: [(&str, Arc<dyn FileSystem>); 3] = [
+    ("/dev", Arc::new(DevFs)),
+    ("/proc", Arc::new(ProcFs)),
+    ("/tmp", TmpFs::with_default_limit()),
+  ];
+  for (path, fs) in builtin {
+    if let Err(err) = mount_creating(path, fs) {
+      println!("[kernel] cannot mount {}: {:?}", path, err);
+    }
+  }
+  for (name, block_dev) in drivers::block_devices() {
+    if name == ROOT_BLOCK_DEVICE {
+      continue;
+    }
+    let path = alloc::format!("/{}", name);
+    match EasyFileSystem::open(block_dev).map(|fs| mount_creating(&path, fs)) {
+      Some(Ok(())) => { println!("[kernel] mounted {} at {}", name, path); }
+      Some(Err(err)) => { println!("[kernel] cannot mount {} at {}: {:?}", name, path, err); }
+      None => { println!("[kernel] {}: no easy-fs image, not mounted", name); }
+    }
+  }
+}
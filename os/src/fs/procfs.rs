@@ -0,0 +1,276 @@
+//! `/proc`: kernel state rendered as text whenever it is read
+//!
+//! ```text
+//! /proc/meminfo       free frames and kernel heap usage
+//! /proc/bcache        block cache hits and misses
+//! /proc/uptime        seconds since boot
+//! /proc/mounts        the mount table
+//! /proc/<pid>/status  state, parent and exit code
+//! /proc/<pid>/maps    memory areas
+//! /proc/<pid>/fds     open file descriptors
+//! ```
+
+use alloc::{format, string::{String, ToString}, sync::Arc, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+  mm::{heap_stats, memory_set::MapPermission, FRAME_ALLOCATOR},
+  task::{all_tasks, find_task, TaskControlBlock},
+  timer::get_time_ms,
+};
+
+use super::{easyfs, mount, vfs::{DirEntry, FileSystem, FsError, FsResult, Inode, InodeType, Stat}};
+
+type GlobalRender = fn() -> String;
+type TaskRender = fn(&TaskControlBlock) -> String;
+
+const GLOBAL_FILES: &[(&str, GlobalRender)] = &[
+  ("meminfo", meminfo),
+  ("bcache", bcache),
+  ("uptime", uptime),
+  ("mounts", mounts),
+];
+
+const TASK_FILES: &[(&str, TaskRender)] = &[
+  ("status", status),
+  ("maps", maps),
+  ("fds", fds),
+];
+
+const ROOT_INO: u64 = 1;
+
+/// inode number of `/proc/<pid>`, its files follow it
+fn pid_ino(pid: usize) -> u64 {
+  ((pid as u64) + 1) << 8
+}
+
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+  fn root(&self) -> Arc<dyn Inode> {
+    Arc::new(ProcDir { pid: None })
+  }
+
+  fn fs_type(&self) -> &'static str {
+    "procfs"
+  }
+}
+
+/// `/proc` itself, or `/proc/<pid>`
+struct ProcDir {
+  pid: Option<usize>,
+}
+
+impl Inode for ProcDir {
+  fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+    match self.pid {
+      None => {
+        if let Some(idx) = GLOBAL_FILES.iter().position(|(file, _)| *file == name) {
+          return Ok(Arc::new(ProcFile::Global(idx)));
+        }
+        let pid = name.parse::<usize>().map_err(|_| FsError::NotFound)?;
+        find_task(pid).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(ProcDir { pid: Some(pid) }))
+      }
+      Some(pid) => {
+        let idx = TASK_FILES.iter().position(|(file, _)| *file == name).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(ProcFile::Task(pid, idx)))
+      }
+    }
+  }
+
+  fn create(&self, _name: &str, _kind: InodeType) -> FsResult<Arc<dyn Inode>> {
+    Err(FsError::NotSupported)
+  }
+
+  fn unlink(&self, _name: &str) -> FsResult {
+    Err(FsError::NotSupported)
+  }
+
+  fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+    let entries = match self.pid {
+      None => GLOBAL_FILES
+        .iter()
+        .enumerate()
+        .map(|(idx, (name, _))| DirEntry { name: name.to_string(), ino: ROOT_INO + 1 + idx as u64, kind: InodeType::File })
+        .chain(all_tasks().iter().map(|task| DirEntry {
+          name: task.getpid().to_string(),
+          ino: pid_ino(task.getpid()),
+          kind: InodeType::Dir,
+        }))
+        .collect(),
+      Some(pid) => TASK_FILES
+        .iter()
+        .enumerate()
+        .map(|(idx, (name, _))| DirEntry { name: name.to_string(), ino: pid_ino(pid) + 1 + idx as u64, kind: InodeType::File })
+        .collect(),
+    };
+    Ok(entries)
+  }
+
+  fn stat(&self) -> Stat {
+    Stat::new(self.pid.map_or(ROOT_INO, pid_ino), InodeType::Dir, 0)
+  }
+
+  fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> FsResult<usize> {
+    Err(FsError::IsDir)
+  }
+
+  fn write_at(&self, _offset: usize, _buf: &[u8]) -> FsResult<usize> {
+    Err(FsError::IsDir)
+  }
+
+  fn truncate(&self, _size: usize) -> FsResult {
+    Err(FsError::IsDir)
+  }
+}
+
+/// A read-only file, indexing `GLOBAL_FILES` or `TASK_FILES`
+enum ProcFile {
+  Global(usize),
+  Task(usize, usize),
+}
+
+impl ProcFile {
+  fn render(&self) -> FsResult<String> {
+    match *self {
+      ProcFile::Global(idx) => Ok((GLOBAL_FILES[idx].1)()),
+      ProcFile::Task(pid, idx) => {
+        let task = find_task(pid).ok_or(FsError::NotFound)?;
+        Ok((TASK_FILES[idx].1)(&task))
+      }
+    }
+  }
+}
+
+impl Inode for ProcFile {
+  fn lookup(&self, _name: &str) -> FsResult<Arc<dyn Inode>> {
+    Err(FsError::NotDir)
+  }
+
+  fn create(&self, _name: &str, _kind: InodeType) -> FsResult<Arc<dyn Inode>> {
+    Err(FsError::NotDir)
+  }
+
+  fn unlink(&self, _name: &str) -> FsResult {
+    Err(FsError::NotDir)
+  }
+
+  fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+    Err(FsError::NotDir)
+  }
+
+  fn stat(&self) -> Stat {
+    let ino = match *self {
+      ProcFile::Global(idx) => ROOT_INO + 1 + idx as u64,
+      ProcFile::Task(pid, idx) => pid_ino(pid) + 1 + idx as u64,
+    };
+    // the size is only known once rendered
+    Stat::new(ino, InodeType::File, 0)
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> FsResult<usize> {
+    let text = self.render()?;
+    let bytes = text.as_bytes();
+    if offset >= bytes.len() {
+      return Ok(0);
+    }
+    let len = buf.len().min(bytes.len() - offset);
+    buf[..len].copy_from_slice(&bytes[offset..offset + len]);
+    Ok(len)
+  }
+
+  fn write_at(&self, _offset: usize, _buf: &[u8]) -> FsResult<usize> {
+    Err(FsError::NotSupported)
+  }
+
+  fn truncate(&self, _size: usize) -> FsResult {
+    Err(FsError::NotSupported)
+  }
+}
+
+fn meminfo() -> String {
+  let (free, total) = {
+    let frames = FRAME_ALLOCATOR.lock();
+    (frames.free_frames(), frames.total_frames())
+  };
+  let heap = heap_stats();
+  let mut text = String::new();
+  writeln!(text, "frames free:    {}", free).unwrap();
+  writeln!(text, "frames total:   {}", total).unwrap();
+  writeln!(text, "heap user:      {} bytes", heap.user).unwrap();
+  writeln!(text, "heap allocated: {} bytes", heap.allocated).unwrap();
+  writeln!(text, "heap total:     {} bytes", heap.total).unwrap();
+  text
+}
+
+fn bcache() -> String {
+  let stats = easyfs::block_cache_stats();
+  let permille = (stats.hits * 1000).checked_div(stats.hits + stats.misses).unwrap_or(0);
+  let mut text = String::new();
+  writeln!(text, "hits:      {}", stats.hits).unwrap();
+  writeln!(text, "misses:    {}", stats.misses).unwrap();
+  writeln!(text, "hit rate:  {}.{}%", permille / 10, permille % 10).unwrap();
+  writeln!(text, "evictions: {}", stats.evictions).unwrap();
+  writeln!(text, "overflows: {}", stats.overflows).unwrap();
+  writeln!(text, "uncached: {}", stats.uncached).unwrap();
+  text
+}
+
+fn uptime() -> String {
+  let ms = get_time_ms();
+  format!("{}.{:03}\n", ms / 1000, ms % 1000)
+}
+
+fn mounts() -> String {
+  mount::mounts().into_iter().map(|(path, fs_type)| format!("{} {}\n", path, fs_type)).collect()
+}
+
+fn status(task: &TaskControlBlock) -> String {
+  let inner = task.inner_exclusive_access();
+  let parent = inner.parent.as_ref().and_then(|parent| parent.upgrade());
+  let mut text = String::new();
+  writeln!(text, "pid:       {}", task.getpid()).unwrap();
+  match parent {
+    Some(parent) => writeln!(text, "parent:    {}", parent.getpid()).unwrap(),
+    None => writeln!(text, "parent:    -").unwrap(),
+  }
+  writeln!(text, "state:     {:?}", inner.task_status).unwrap();
+  writeln!(text, "exit code: {}", inner.exit_code).unwrap();
+  text
+}
+
+fn maps(task: &TaskControlBlock) -> String {
+  let inner = task.inner_exclusive_access();
+  let mut text = String::new();
+  for area in inner.memory_set.areas() {
+    let (start, end) = area.range();
+    let perm = area.map_perm();
+    let flag = |bit: MapPermission, ch: char| if perm.contains(bit) { ch } else { '-' };
+    writeln!(
+      text,
+      "{:#011x}-{:#011x} {}{}{}{} {:?}",
+      usize::from(start), usize::from(end),
+      flag(MapPermission::R, 'r'), flag(MapPermission::W, 'w'), flag(MapPermission::X, 'x'), flag(MapPermission::U, 'u'),
+      area.map_type(),
+    ).unwrap();
+  }
+  text
+}
+
+fn fds(task: &TaskControlBlock) -> String {
+  let inner = task.inner_exclusive_access();
+  let mut text = String::new();
+  for (fd, file) in inner.fd_table.iter().enumerate() {
+    if let Some(file) = file {
+      let rw = match (file.readable(), file.writable()) {
+        (true, true) => "rw",
+        (true, false) => "r-",
+        (false, true) => "-w",
+        (false, false) => "--",
+      };
+      writeln!(text, "{} {} {}", fd, rw, file.path()).unwrap();
+    }
+  }
+  text
+}
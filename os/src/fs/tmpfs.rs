@@ -0,0 +1,276 @@
+//! tmpfs: files kept in physical frames, gone on reboot or `umount`
+
+use alloc::{collections::BTreeMap, string::{String, ToString}, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{config::PAGE_SIZE, mm::{frame_alloc, FrameTracker, FRAME_ALLOCATOR}, sync::SleepLock};
+
+use super::vfs::{DirEntry, FileSystem, FsError, FsResult, Inode, InodeType, Stat};
+
+/// Without an explicit limit a tmpfs may take this share of physical memory
+const DEFAULT_SHARE: usize = 4;
+
+/// State shared by all inodes of one tmpfs
+struct TmpFsShared {
+  /// most frames the file data may occupy
+  max_frames: usize,
+  used_frames: AtomicUsize,
+  next_ino: AtomicU64,
+}
+
+impl TmpFsShared {
+  /// Take a frame for file data, if both the limit and `FRAME_ALLOCATOR` allow it
+  fn alloc_frame(&self) -> FsResult<FrameTracker> {
+    self.used_frames
+      .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| (used < self.max_frames).then_some(used + 1))
+      .map_err(|_| FsError::NoSpace)?;
+    frame_alloc().ok_or_else(|| {
+      self.used_frames.fetch_sub(1, Ordering::Relaxed);
+      FsError::NoSpace
+    })
+  }
+
+  fn release_frames(&self, count: usize) {
+    self.used_frames.fetch_sub(count, Ordering::Relaxed);
+  }
+}
+
+pub struct TmpFs {
+  root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+  /// A tmpfs holding at most `max_frames` frames of data
+  pub fn new(max_frames: usize) -> Arc<Self> {
+    let shared = Arc::new(TmpFsShared {
+      max_frames,
+      used_frames: AtomicUsize::new(0),
+      next_ino: AtomicU64::new(1),
+    });
+    Arc::new(Self { root: TmpInode::new(&shared, InodeType::Dir) })
+  }
+
+  /// A tmpfs limited to a share of the physical memory
+  pub fn with_default_limit() -> Arc<Self> {
+    Self::new(FRAME_ALLOCATOR.lock().total_frames() / DEFAULT_SHARE)
+  }
+}
+
+impl FileSystem for TmpFs {
+  fn root(&self) -> Arc<dyn Inode> {
+    self.root.clone()
+  }
+
+  fn fs_type(&self) -> &'static str {
+    "tmpfs"
+  }
+}
+
+enum TmpData {
+  File {
+    size: usize,
+    /// one frame per `PAGE_SIZE` bytes, including the holes
+    frames: Vec<FrameTracker>,
+  },
+  Dir(BTreeMap<String, Arc<TmpInode>>),
+}
+
+struct TmpInode {
+  ino: u64,
+  fs: Arc<TmpFsShared>,
+  data: SleepLock<TmpData>,
+}
+
+impl TmpInode {
+  fn new(fs: &Arc<TmpFsShared>, kind: InodeType) -> Arc<Self> {
+    let data = match kind {
+      InodeType::File => TmpData::File { size: 0, frames: Vec::new() },
+      InodeType::Dir => TmpData::Dir(BTreeMap::new()),
+    };
+    Arc::new(Self {
+      ino: fs.next_ino.fetch_add(1, Ordering::Relaxed),
+      fs: fs.clone(),
+      data: SleepLock::new(data),
+    })
+  }
+
+  fn kind(data: &TmpData) -> InodeType {
+    match data {
+      TmpData::File { .. } => InodeType::File,
+      TmpData::Dir(_) => InodeType::Dir,
+    }
+  }
+
+  /// Grow or shrink `frames` to cover `size` bytes
+  fn resize(&self, frames: &mut Vec<FrameTracker>, size: usize) -> FsResult {
+    let needed = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    if needed < frames.len() {
+      self.fs.release_frames(frames.len() - needed);
+      frames.truncate(needed);
+    }
+    while frames.len() < needed {
+      frames.push(self.fs.alloc_frame()?);
+    }
+    Ok(())
+  }
+}
+
+impl Drop for TmpInode {
+  fn drop(&mut self) {
+    if let TmpData::File { frames, .. } = &*self.data.lock() {
+      self.fs.release_frames(frames.len());
+    }
+  }
+}
+
+impl Inode for TmpInode {
+  fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+    match &*self.data.lock() {
+      TmpData::Dir(children) => {
+        let child = children.get(name).ok_or(FsError::NotFound)?;
+        Ok(child.clone())
+      }
+      TmpData::File { .. } => Err(FsError::NotDir),
+    }
+  }
+
+  fn create(&self, name: &str, kind: InodeType) -> FsResult<Arc<dyn Inode>> {
+    if name.is_empty() || name.contains('/') {
+      return Err(FsError::InvalidName);
+    }
+    match &mut *self.data.lock() {
+      TmpData::Dir(children) => {
+        if children.contains_key(name) {
+          return Err(FsError::AlreadyExists);
+        }
+        let child = TmpInode::new(&self.fs, kind);
+        children.insert(name.to_string(), child.clone());
+        Ok(child)
+      }
+      TmpData::File { .. } => Err(FsError::NotDir),
+    }
+  }
+
+  fn unlink(&self, name: &str) -> FsResult {
+    match &mut *self.data.lock() {
+      TmpData::Dir(children) => {
+        let child = children.get(name).ok_or(FsError::NotFound)?;
+        if matches!(&*child.data.lock(), TmpData::Dir(grandchildren) if !grandchildren.is_empty()) {
+          return Err(FsError::NotEmpty);
+        }
+        // the frames go back once the last open file drops the inode
+        children.remove(name);
+        Ok(())
+      }
+      TmpData::File { .. } => Err(FsError::NotDir),
+    }
+  }
+
+  fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+    match &*self.data.lock() {
+      TmpData::Dir(children) => Ok(children
+        .iter()
+        .map(|(name, child)| DirEntry {
+          name: name.clone(),
+          ino: child.ino,
+          kind: TmpInode::kind(&child.data.lock()),
+        })
+        .collect()),
+      TmpData::File { .. } => Err(FsError::NotDir),
+    }
+  }
+
+  fn stat(&self) -> Stat {
+    let data = self.data.lock();
+    let size = match &*data {
+      TmpData::File { size, .. } => *size,
+      TmpData::Dir(children) => children.len(),
+    };
+    Stat::new(self.ino, TmpInode::kind(&data), size as u64)
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> FsResult<usize> {
+    match &*self.data.lock() {
+      TmpData::File { size, frames } => {
+        let end = (offset + buf.len()).min(*size);
+        let mut pos = offset;
+        while pos < end {
+          let page = frames[pos / PAGE_SIZE].ppn.get_bytes_array();
+          let len = (PAGE_SIZE - pos % PAGE_SIZE).min(end - pos);
+          buf[pos - offset..pos - offset + len].copy_from_slice(&page[pos % PAGE_SIZE..pos % PAGE_SIZE + len]);
+          pos += len;
+        }
+        Ok(end.saturating_sub(offset))
+      }
+      TmpData::Dir(_) => Err(FsError::IsDir),
+    }
+  }
+
+  fn write_at(&self, offset: usize, buf: &[u8]) -> FsResult<usize> {
+    match &mut *self.data.lock() {
+      TmpData::File { size, frames } => {
+        let end = offset + buf.len();
+        if end > *size {
+          self.resize(frames, end)?;
+          *size = end;
+        }
+        let mut pos = offset;
+        while pos < end {
+          let page = frames[pos / PAGE_SIZE].ppn.get_bytes_array();
+          let len = (PAGE_SIZE - pos % PAGE_SIZE).min(end - pos);
+          page[pos % PAGE_SIZE..pos % PAGE_SIZE + len].copy_from_slice(&buf[pos - offset..pos - offset + len]);
+          pos += len;
+        }
+        Ok(buf.len())
+      }
+      TmpData::Dir(_) => Err(FsError::IsDir),
+    }
+  }
+
+  fn truncate(&self, new_size: usize) -> FsResult {
+    match &mut *self.data.lock() {
+      TmpData::File { size, frames } => {
+        self.resize(frames, new_size)?;
+        // bytes past the new end must read as zero if the file grows again
+        if let Some(last) = frames.last() {
+          let tail = new_size % PAGE_SIZE;
+          if tail != 0 {
+            last.ppn.get_bytes_array()[tail..].fill(0);
+          }
+        }
+        *size = new_size;
+        Ok(())
+      }
+      TmpData::Dir(_) => Err(FsError::IsDir),
+    }
+  }
+}
+
+pub fn tmpfs_test() {
+  let fs = TmpFs::new(3);
+  let root = fs.root();
+  let file = root.create("a", InodeType::File).unwrap();
+  assert_eq!(root.create("a", InodeType::File).err(), Some(FsError::AlreadyExists));
+  // a hole, then data straddling the page boundary
+  assert_eq!(file.write_at(PAGE_SIZE - 2, b"tmpfs").unwrap(), 5);
+  let mut buf = [0xffu8; 8];
+  assert_eq!(file.read_at(PAGE_SIZE - 4, &mut buf).unwrap(), 7);
+  assert_eq!(&buf[..7], b"\0\0tmpfs");
+  // over the three frame limit
+  assert_eq!(file.write_at(3 * PAGE_SIZE, b"x").err(), Some(FsError::NoSpace));
+  file.truncate(PAGE_SIZE).unwrap();
+  file.truncate(PAGE_SIZE + 3).unwrap();
+  assert_eq!(file.read_at(PAGE_SIZE - 2, &mut buf).unwrap(), 5);
+  assert_eq!(&buf[..5], b"tm\0\0\0");
+
+  let dir = root.create("d", InodeType::Dir).unwrap();
+  dir.create("b", InodeType::File).unwrap().write_at(0, b"b").unwrap();
+  assert_eq!(root.unlink("d").err(), Some(FsError::NotEmpty));
+  dir.unlink("b").unwrap();
+  root.unlink("d").unwrap();
+  assert_eq!(root.readdir().unwrap().len(), 1);
+  drop(file);
+  root.unlink("a").unwrap();
+  assert_eq!(fs.root.fs.used_frames.load(Ordering::Relaxed), 0);
+  println!("tmpfs test: \x1b[92m[passed!]\x1b[0m");
+}
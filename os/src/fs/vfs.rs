@@ -0,0 +1,170 @@
+//! Interface every filesystem mounted into the kernel implements
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::any::Any;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InodeType {
+  File,
+  Dir,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FsError {
+  NotFound,
+  AlreadyExists,
+  NotDir,
+  IsDir,
+  /// the filesystem cannot do this, e.g. directories on easy-fs
+  NotSupported,
+  InvalidName,
+  Busy,
+  /// the filesystem or its size limit is full
+  NoSpace,
+  /// removing a directory that still has entries
+  NotEmpty,
+  /// a name longer than the filesystem can hold
+  NameTooLong,
+  /// the caller's buffer cannot hold even one directory entry
+  BufferTooSmall,
+  /// renaming across filesystems
+  CrossDevice,
+  /// moving a directory into itself or below it
+  IntoItself,
+  /// the device returned bad data, e.g. a block failed its checksum
+  Io,
+  /// the filesystem cannot be written, e.g. after finding a corrupt block
+  ReadOnly,
+}
+
+impl FsError {
+  /// Negated Linux errno a syscall returns for this error
+  pub fn errno(self) -> isize {
+    -match self {
+      Self::NotFound => 2,       // ENOENT
+      Self::AlreadyExists => 17, // EEXIST
+      Self::NotDir => 20,        // ENOTDIR
+      Self::IsDir => 21,         // EISDIR
+      Self::NotSupported => 95,  // EOPNOTSUPP
+      Self::InvalidName => 22,   // EINVAL
+      Self::Busy => 16,          // EBUSY
+      Self::NoSpace => 28,       // ENOSPC
+      Self::NotEmpty => 39,      // ENOTEMPTY
+      Self::NameTooLong => 36,   // ENAMETOOLONG
+      Self::BufferTooSmall => 22, // EINVAL
+      Self::CrossDevice => 18,   // EXDEV
+      Self::IntoItself => 22,    // EINVAL
+      Self::Io => 5,             // EIO
+      Self::ReadOnly => 30,      // EROFS
+    }
+  }
+}
+
+pub type FsResult<T = ()> = Result<T, FsError>;
+
+/// Metadata of an inode
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+  /// inode number, unique within its filesystem
+  pub ino: u64,
+  pub kind: InodeType,
+  pub size: u64,
+  /// permission bits, without the file type
+  pub mode: u32,
+  pub uid: u32,
+  pub gid: u32,
+  /// nanoseconds since the Unix epoch, 0 if the filesystem keeps no times
+  pub atime: u64,
+  pub mtime: u64,
+  pub ctime: u64,
+}
+
+impl Stat {
+  /// Metadata of an inode of a filesystem that keeps no modes, owners or times
+  pub fn new(ino: u64, kind: InodeType, size: u64) -> Self {
+    let mode = match kind {
+      InodeType::Dir => 0o755,
+      InodeType::File => 0o644,
+    };
+    Self { ino, kind, size, mode, uid: 0, gid: 0, atime: 0, mtime: 0, ctime: 0 }
+  }
+}
+
+/// One entry of `Inode::readdir`
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+  pub name: String,
+  pub ino: u64,
+  pub kind: InodeType,
+}
+
+/// Lets a filesystem find its own inodes among `dyn Inode`s
+pub trait AsAny {
+  fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+}
+
+/// A file, directory or device node.
+///
+/// Names passed in are single path components; walking paths is the job of the mount table.
+pub trait Inode: AsAny + Send + Sync {
+  /// Find the child `name` of this directory
+  fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>>;
+
+  /// Create the child `name` of this directory
+  fn create(&self, name: &str, kind: InodeType) -> FsResult<Arc<dyn Inode>>;
+
+  /// Remove the child `name` of this directory
+  fn unlink(&self, name: &str) -> FsResult;
+
+  /// Move the child `old_name` of this directory to `new_name` in `new_dir`, replacing
+  /// what is there unless `noreplace`
+  fn rename(&self, _old_name: &str, _new_dir: &dyn Inode, _new_name: &str, _noreplace: bool) -> FsResult {
+    Err(FsError::NotSupported)
+  }
+
+  fn readdir(&self) -> FsResult<Vec<DirEntry>>;
+
+  fn stat(&self) -> Stat;
+
+  /// Read from byte `offset`, returning how many bytes were read (0 at the end)
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> FsResult<usize>;
+
+  /// Write at byte `offset`, growing the file as needed
+  fn write_at(&self, offset: usize, buf: &[u8]) -> FsResult<usize>;
+
+  /// Cut the file down (or extend it with zeros) to `size` bytes
+  fn truncate(&self, size: usize) -> FsResult;
+
+  /// Set the permission bits
+  fn set_mode(&self, _mode: u32) -> FsResult {
+    Err(FsError::NotSupported)
+  }
+
+  /// Set the access and modification times, in nanoseconds since the Unix epoch,
+  /// leaving those that are `None`
+  fn set_times(&self, _atime: Option<u64>, _mtime: Option<u64>) -> FsResult {
+    Err(FsError::NotSupported)
+  }
+
+  /// Flush this file's cached data to the backing device
+  fn sync(&self) -> FsResult {
+    Ok(())
+  }
+}
+
+/// A mounted filesystem instance
+pub trait FileSystem: Send + Sync {
+  fn root(&self) -> Arc<dyn Inode>;
+
+  /// Type name as given to `sys_mount`, e.g. `easyfs`
+  fn fs_type(&self) -> &'static str;
+
+  /// Flush cached data to the backing device
+  fn sync(&self) {}
+}
@@ -13,6 +13,7 @@ fn panic(info: &PanicInfo) -> ! {
 	} else {
 		println!("Panicked: {}", info.message().unwrap());
 	}
+	crate::backtrace::print_backtrace();
 	shutdown()
 }
 
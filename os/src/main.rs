@@ -16,9 +16,11 @@ mod console;
 #[path = "boards/qemu.rs"]
 mod board;
 
+mod backtrace;
 mod config;
 mod ds;
 mod drivers;
+mod fdt;
 mod fs;
 mod start;
 mod sync;
@@ -45,7 +47,10 @@ pub fn rust_main() -> ! {
 	mm::remap_test();
 	// drivers::block_device_test();
 	trap::init();
+	drivers::init();
 
+	fs::init();
+	fs::tmpfs_test();
 	fs::list_apps();
 	task::add_initproc();
 	
@@ -3,7 +3,7 @@
 
 use alloc::vec::Vec;
 
-use crate::{sync::up::UPSafeCell, mm::address::PhysAddr, config::MEMORY_ENDPOINT};
+use crate::{sync::{SpinNoIrqLock, LockRank}, mm::address::PhysAddr, board};
 use core::fmt::{Debug};
 use super::address::PhysPageNum;
 
@@ -43,6 +43,8 @@ trait FrameAllocator {
 }
 
 pub struct StackFrameAllocator {
+  /// first frame managed
+  start: usize,
   // [current, end)
   current: usize,
   end: usize,
@@ -51,15 +53,28 @@ pub struct StackFrameAllocator {
 
 impl StackFrameAllocator {
   pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+    self.start = l.0;
     self.current = l.0;
     self.end = r.0;
     println!("last {}  Frames.", self.end - self.current);
   }
 }
 
+impl StackFrameAllocator {
+  /// number of frames that can still be allocated
+  pub fn free_frames(&self) -> usize {
+    self.end - self.current + self.recycled.len()
+  }
+
+  pub fn total_frames(&self) -> usize {
+    self.end - self.start
+  }
+}
+
 impl FrameAllocator for StackFrameAllocator {
   fn new() -> Self {
     Self {
+      start: 0,
       current: 0,
       end: 0,
       recycled: Vec::new()
@@ -89,27 +104,25 @@ impl FrameAllocator for StackFrameAllocator {
 }
 
 lazy_static! {
-  pub static ref FRAME_ALLOCATOR: UPSafeCell<StackFrameAllocator> =
-    unsafe {
-      UPSafeCell::new(StackFrameAllocator::new())
-    };
+  pub static ref FRAME_ALLOCATOR: SpinNoIrqLock<StackFrameAllocator> =
+    SpinNoIrqLock::ranked(LockRank::FrameAllocator, StackFrameAllocator::new());
 }
 
-/// initiate the frame allocator using `ekernel` and `MEMORY_END`
+/// initiate the frame allocator with the memory between `ekernel` and the end of RAM
 pub fn init_frame_allocator() {
   extern "C" {
     fn ekernel();
   }
-  FRAME_ALLOCATOR.exclusive_access().init(
+  FRAME_ALLOCATOR.lock().init(
     PhysAddr::from(ekernel as usize).ceil(), 
-    PhysAddr::from(MEMORY_ENDPOINT).floor()
+    PhysAddr::from(board::info().memory_end).floor()
   )
 }
 
 /// allocate a frame and **clear** it
 pub fn frame_alloc() -> Option<FrameTracker> {
   FRAME_ALLOCATOR
-    .exclusive_access()
+    .lock()
     .alloc()
     .map(FrameTracker::new)
 }
@@ -117,7 +130,7 @@ pub fn frame_alloc() -> Option<FrameTracker> {
 /// deallocate a frame
 pub fn frame_dealloc(ppn: PhysPageNum) {
   FRAME_ALLOCATOR
-    .exclusive_access()
+    .lock()
     .dealloc(ppn);
 }
 
@@ -1,6 +1,6 @@
 use crate::config::KERNEL_HEAP_SIZE;
 //  use buddy_system_allocator::LockedHeap;
-use crate::ds::buddy::LockedHeap;
+use crate::ds::buddy::{LockedHeap, HeapStats};
 
 #[global_allocator]
 /// heap allocator instance
@@ -24,6 +24,10 @@ pub fn init_heap() {
   }
 }
 
+pub fn heap_stats() -> HeapStats {
+  HEAP_ALLOCATOR.lock().stats()
+}
+
 #[allow(unused)]
 pub fn heap_test() {
   use alloc::boxed::Box;
@@ -1,14 +1,15 @@
 use core::arch::asm;
 use core::cmp::max;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use alloc::{collections::BTreeMap, sync::Arc};
 
 use bitflags::bitflags;
 use alloc::vec::Vec;
 use riscv::register::satp;
-use crate::board::MMIO;
+use crate::board;
 use crate::config::USER_STACK_TOP;
-use crate::{config::{PAGE_SIZE, TRAMPOLINE, MEMORY_ENDPOINT, USER_STACK_SIZE, TRAP_CONTEXT}, mm::address::StepByOne, sync::up::UPSafeCell};
+use crate::{config::{PAGE_SIZE, TRAMPOLINE, USER_STACK_SIZE, TRAP_CONTEXT}, mm::address::StepByOne, sync::SleepLock};
 
 use super::{page_table::{PageTable, PTEFlags, PageTableEntry}, address::{VPNRange, VirtPageNum, VirtAddr, PhysPageNum, PhysAddr}, frame_allocator::{FrameTracker, frame_alloc}};
 
@@ -26,13 +27,26 @@ extern "C" {
 }
 
 lazy_static! {
-  pub static ref KERNEL_SPACE: Arc<UPSafeCell<MemorySet>> = 
-    Arc::new( unsafe { UPSafeCell::new(MemorySet::new_kernel()) } );
+  pub static ref KERNEL_SPACE: Arc<SleepLock<MemorySet>> =
+    Arc::new(SleepLock::new(MemorySet::new_kernel()));
 }
 
-/// get kernel's satp
+/// satp of `KERNEL_SPACE`, which never changes once built
+static KERNEL_TOKEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Switch to the kernel space and remember its satp for `kernel_token`
+pub fn activate_kernel_space() {
+  let kernel_space = KERNEL_SPACE.lock();
+  KERNEL_TOKEN.store(kernel_space.token(), Ordering::Release);
+  kernel_space.activate();
+}
+
+/// get kernel's satp. Takes no lock, so it can be used under spin locks and with
+/// interrupts off, e.g. by the virtio driver.
 pub fn kernel_token() -> usize {
-  KERNEL_SPACE.exclusive_access().token()
+  let token = KERNEL_TOKEN.load(Ordering::Acquire);
+  assert_ne!(token, 0, "the kernel space is not active yet");
+  token
 }
 
 /// All MapAreas shares the same page_table, but their PTE permissions differ.
@@ -53,6 +67,10 @@ impl MemorySet {
     self.page_table.token()
   }
 
+  pub fn areas(&self) -> &[MapArea] {
+    &self.areas
+  }
+
   /// Push map_area into a MemorySet 
   /// If data != None, also write data into map_area
   pub fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
@@ -142,19 +160,20 @@ impl MemorySet {
       None,
     );
 
-    println!("mapping PhyMemory rw- [{:#x}, {:#x})", ekernel as usize, MEMORY_ENDPOINT);
+    let memory_end = board::info().memory_end;
+    println!("mapping PhyMemory rw- [{:#x}, {:#x})", ekernel as usize, memory_end);
     // same as frame_allocator
     memory_set.push(
       MapArea::new(
         (ekernel as usize).into(),
-        MEMORY_ENDPOINT.into(),
+        memory_end.into(),
         MapType::Identical,
         MapPermission::R | MapPermission::W,
       ),
       None,
     );
 
-    for &pair in MMIO {
+    for pair in board::info().mmio_regions() {
       memory_set.push(
         MapArea::new(
           pair.0.into(),
@@ -371,6 +390,19 @@ impl MapArea {
     }
   }
 
+  /// `[start, end)` of the area
+  pub fn range(&self) -> (VirtAddr, VirtAddr) {
+    (self.vpn_range.get_start().into(), self.vpn_range.get_end().into())
+  }
+
+  pub fn map_type(&self) -> MapType {
+    self.map_type
+  }
+
+  pub fn map_perm(&self) -> MapPermission {
+    self.map_perm
+  }
+
   /// Without copy data_frames
   pub fn from_another(another: &Self) -> Self {
     Self {
@@ -472,7 +504,7 @@ impl MapArea {
 
 #[allow(unused)]
 pub fn remap_test() {
-  let mut kernel_space = KERNEL_SPACE.exclusive_access();
+  let mut kernel_space = KERNEL_SPACE.lock();
   let mid_text: VirtAddr = ((stext as usize + etext as usize) / 2).into();
   let mid_rodata: VirtAddr = ((srodata as usize + erodata as usize) / 2).into();
   let mid_data: VirtAddr = ((sdata as usize + edata as usize) / 2).into();
@@ -224,12 +224,6 @@ impl UserBuffer {
   pub fn new(buffers: Vec<&'static mut [u8]>) -> Self {
     Self { buffers }
   }
-
-  pub fn len(&self) -> usize {
-    let mut len = 0;
-    self.buffers.iter().for_each(|seg| len += seg.len());
-    len
-  }
 }
 
 pub struct UserBufferIterator {
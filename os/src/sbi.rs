@@ -30,6 +30,6 @@ pub fn console_getchar() -> usize {
 
 pub fn shutdown() -> ! {
 	use crate::board::QEMUExit;
-	crate::board::QEMU_EXIT_HANDLE.exit_success();
+	crate::board::exit_handle().exit_success();
 	panic!("It should shutdown, but it didn't!");
 }
\ No newline at end of file
@@ -2,9 +2,8 @@ use core::arch::{asm, global_asm};
 
 use csr_riscv::register::{mie, mepc, mstatus::{self, MPP}, mtvec, utvec::TrapMode, mcause, sie, mscratch};
 
-use crate::{board::{QEMU_BASE_ADDRESS, KERNEL_MAX_ALLOCED_ADDRESS, UART_BASE_ADDRESS}, rust_main, uart::Console, config::CLOCK_FREQ};
+use crate::{board, rust_main, uart::Console};
 
-const CLINT: usize = 0x2000000;
 const MTIMER_OFFSET: usize = 0x4000;
 const MTIME_OFFSET: usize = 0xBFF8;
 
@@ -23,8 +22,10 @@ fn hart_id() -> usize {
   riscv::register::mhartid::read()
 }
 
+/// M-mode entry from `entry.asm`, `dtb` is the device tree qemu passes in `a1`
 #[no_mangle]
-pub fn start() {
+pub extern "C" fn start(_hart_id: usize, dtb: usize) {
+  board::init(dtb);
   // set M Exception Program Counter to main, for mret.
   unsafe { 
     mstatus::set_mpp(MPP::Supervisor); 
@@ -43,20 +44,26 @@ pub fn start() {
 
   timer_init();
   set_pmp();
-	Console::console_init(UART_BASE_ADDRESS);
+	Console::console_init(board::info().uart.base);
   
   println!("hart id = {}", hart_id());
+  let info = board::info();
+  if info.from_fdt {
+    println!("memory [{:#x}, {:#x}), {} virtio-mmio slots", info.memory_start, info.memory_end, info.virtio_num);
+  } else {
+    println!("no device tree at {:#x}, assuming the default qemu virt layout", dtb);
+  }
 
   unsafe { asm!("mret"); }
 }
 
 
 fn clint_mtimecmp(id: usize) -> usize {
-  CLINT + MTIMER_OFFSET + 8 * id
+  board::info().clint.base + MTIMER_OFFSET + 8 * id
 }
 
 fn clint_mtime() -> usize {
-  CLINT + MTIME_OFFSET
+  board::info().clint.base + MTIME_OFFSET
 }
 
 static mut SCRATCH: [usize; 5] = [0; 5];
@@ -68,7 +75,7 @@ fn timer_init() {
     fn timervec();
   }
   let id = hart_id();
-  let interval = CLOCK_FREQ / 10;
+  let interval = board::info().clock_freq / 10;
   unsafe {
     *(clint_mtimecmp(id) as *mut usize) = *(clint_mtime() as *const usize) + interval;
   }
@@ -92,12 +99,13 @@ fn set_pmp() {
 	unsafe {
 		pmpcfg0::set_pmp(0, Range::OFF, Permission::NONE, false); // null pointer dereference
 		pmpaddr0::write(0);
+		let info = board::info();
 		// peripherals
 		pmpcfg0::set_pmp(1, Range::TOR, Permission::RW, false);
-		pmpaddr1::write(QEMU_BASE_ADDRESS >> 2);
+		pmpaddr1::write(info.memory_start >> 2);
 		// kernel
 		pmpcfg0::set_pmp(2, Range::TOR, Permission::RWX, false);
-		pmpaddr2::write(KERNEL_MAX_ALLOCED_ADDRESS >> 2);
+		pmpaddr2::write(info.memory_end >> 2);
 		// others
 		pmpcfg0::set_pmp(3, Range::TOR, Permission::RW, false);
 		pmpaddr3::write(1 << (usize :: BITS - 1));
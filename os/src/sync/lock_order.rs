@@ -0,0 +1,86 @@
+//! Lock-ordering checks
+//!
+//! Every spin lock may carry a [`LockRank`]. In debug builds we keep a small
+//! stack of the spin locks held by this hart and panic when
+//!
+//! - a lock is acquired twice (it would spin forever with interrupts off),
+//! - a ranked lock is acquired while a lock of the same or higher rank is held,
+//! - a sleep lock is acquired while any spin lock is held.
+//!
+//! In release builds all checks compile to nothing.
+
+/// Global acquisition order of the kernel's spin locks.
+/// A lock may only be taken while every held ranked lock has a **lower** rank.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LockRank {
+  /// not part of the global order, only checked for re-entrancy
+  Unranked,
+  Processor,
+  TaskManager,
+  PidAllocator,
+  FrameAllocator,
+}
+
+#[cfg(debug_assertions)]
+mod checker {
+  use super::LockRank;
+
+  const MAX_HELD: usize = 16;
+
+  /// (lock address, rank) of every spin lock held by this hart.
+  ///
+  /// Only touched with interrupts disabled, so uniprocessor access is exclusive.
+  static mut HELD: [(usize, LockRank); MAX_HELD] = [(0, LockRank::Unranked); MAX_HELD];
+  static mut DEPTH: usize = 0;
+
+  pub fn on_acquire(addr: usize, rank: LockRank) {
+    unsafe {
+      for &(held_addr, held_rank) in HELD[..DEPTH].iter() {
+        assert_ne!(held_addr, addr, "re-entrant acquisition of {:?} lock {:#x}", rank, addr);
+        assert!(
+          rank == LockRank::Unranked || held_rank == LockRank::Unranked || held_rank < rank,
+          "lock order violation: acquiring {:?} while holding {:?}", rank, held_rank
+        );
+      }
+      assert!(DEPTH < MAX_HELD, "too many spin locks held");
+      HELD[DEPTH] = (addr, rank);
+      DEPTH += 1;
+    }
+  }
+
+  pub fn on_release(addr: usize) {
+    unsafe {
+      // guards may be dropped out of order
+      let idx = HELD[..DEPTH]
+        .iter()
+        .rposition(|&(held_addr, _)| held_addr == addr)
+        .expect("releasing a spin lock that is not held");
+      HELD.copy_within(idx + 1..DEPTH, idx);
+      DEPTH -= 1;
+    }
+  }
+
+  pub fn assert_may_sleep() {
+    unsafe {
+      if DEPTH != 0 {
+        panic!("sleeping while holding {} spin lock(s), innermost {:?}", DEPTH, HELD[DEPTH - 1].1);
+      }
+    }
+  }
+}
+
+#[cfg(not(debug_assertions))]
+mod checker {
+  use super::LockRank;
+
+  #[inline(always)]
+  pub fn on_acquire(_addr: usize, _rank: LockRank) {}
+
+  #[inline(always)]
+  pub fn on_release(_addr: usize) {}
+
+  #[inline(always)]
+  pub fn assert_may_sleep() {}
+}
+
+pub(super) use checker::{on_acquire, on_release, assert_may_sleep};
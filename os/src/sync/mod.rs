@@ -1,3 +1,9 @@
 pub mod up;
+mod lock_order;
+mod spin_lock;
+mod sleep_lock;
 
-pub use up::UPSafeCell;
\ No newline at end of file
+pub use up::UPSafeCell;
+pub use lock_order::LockRank;
+pub use spin_lock::{SpinNoIrqLock, push_off, pop_off};
+pub use sleep_lock::SleepLock;
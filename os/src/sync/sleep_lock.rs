@@ -0,0 +1,90 @@
+//! Blocking lock which parks the waiting task instead of spinning
+
+use core::{cell::UnsafeCell, hint::spin_loop, ops::{Deref, DerefMut}};
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::task::{block_current_and_run_next, wakeup_task, processor::current_task, TaskControlBlock};
+
+use super::{lock_order, SpinNoIrqLock};
+
+struct SleepLockInner {
+  locked: bool,
+  /// tasks parked on this lock, woken in FIFO order
+  wait_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+/// Mutual exclusion for long critical sections in task context.
+///
+/// The holder may be switched out; waiters are `Blocked` instead of spinning.
+/// Before any task is running (early boot) it degrades to a spin lock.
+pub struct SleepLock<T> {
+  inner: SpinNoIrqLock<SleepLockInner>,
+  data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SleepLock<T> {}
+unsafe impl<T: Send> Send for SleepLock<T> {}
+
+pub struct SleepLockGuard<'a, T> {
+  lock: &'a SleepLock<T>,
+}
+
+impl<T> SleepLock<T> {
+  pub const fn new(data: T) -> Self {
+    Self {
+      inner: SpinNoIrqLock::new(SleepLockInner {
+        locked: false,
+        wait_queue: VecDeque::new(),
+      }),
+      data: UnsafeCell::new(data),
+    }
+  }
+
+  pub fn lock(&self) -> SleepLockGuard<'_, T> {
+    lock_order::assert_may_sleep();
+    loop {
+      let mut inner = self.inner.lock();
+      if !inner.locked {
+        inner.locked = true;
+        break;
+      }
+      if let Some(task) = current_task() {
+        inner.wait_queue.push_back(task);
+        drop(inner);
+        block_current_and_run_next();
+      } else {
+        // nobody to park: only the boot flow is running
+        drop(inner);
+        spin_loop();
+      }
+    }
+    SleepLockGuard { lock: self }
+  }
+}
+
+impl<T> Deref for SleepLockGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.data.get() }
+  }
+}
+
+impl<T> DerefMut for SleepLockGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.lock.data.get() }
+  }
+}
+
+impl<T> Drop for SleepLockGuard<'_, T> {
+  fn drop(&mut self) {
+    let mut inner = self.lock.inner.lock();
+    inner.locked = false;
+    let waiter = inner.wait_queue.pop_front();
+    drop(inner);
+    if let Some(task) = waiter {
+      wakeup_task(task);
+    }
+  }
+}
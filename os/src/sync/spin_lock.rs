@@ -0,0 +1,123 @@
+//! Spin lock which keeps supervisor interrupts disabled while held
+
+use core::{cell::UnsafeCell, hint::spin_loop, ops::{Deref, DerefMut}, sync::atomic::{AtomicBool, Ordering}};
+
+use riscv::register::sstatus;
+
+use super::lock_order::{self, LockRank};
+
+/// Interrupt-disable nesting of the hart (xv6's `push_off`/`pop_off`)
+struct IntrNesting {
+  /// depth of `push_off`
+  noff: usize,
+  /// were interrupts enabled before the outermost `push_off`?
+  intena: bool,
+}
+
+static mut INTR_NESTING: IntrNesting = IntrNesting { noff: 0, intena: false };
+
+/// Disable interrupts, remembering whether they were enabled at the outermost level.
+pub fn push_off() {
+  let sie = sstatus::read().sie();
+  unsafe {
+    sstatus::clear_sie();
+    if INTR_NESTING.noff == 0 {
+      INTR_NESTING.intena = sie;
+    }
+    INTR_NESTING.noff += 1;
+  }
+}
+
+/// Undo one `push_off`, re-enabling interrupts once the outermost one is undone.
+pub fn pop_off() {
+  assert!(!sstatus::read().sie(), "pop_off: interrupts are enabled");
+  unsafe {
+    assert!(INTR_NESTING.noff >= 1, "pop_off: unbalanced");
+    INTR_NESTING.noff -= 1;
+    if INTR_NESTING.noff == 0 && INTR_NESTING.intena {
+      sstatus::set_sie();
+    }
+  }
+}
+
+/// Mutual exclusion that is safe against interrupt handlers on the same hart.
+///
+/// Critical sections must be short and **must not** switch tasks.
+pub struct SpinNoIrqLock<T> {
+  locked: AtomicBool,
+  rank: LockRank,
+  data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinNoIrqLock<T> {}
+unsafe impl<T: Send> Send for SpinNoIrqLock<T> {}
+
+pub struct SpinNoIrqLockGuard<'a, T> {
+  lock: &'a SpinNoIrqLock<T>,
+}
+
+impl<T> SpinNoIrqLock<T> {
+  /// A lock outside of the global lock order
+  pub const fn new(data: T) -> Self {
+    Self::ranked(LockRank::Unranked, data)
+  }
+
+  /// A lock taking part in the global lock order, see [`LockRank`]
+  pub const fn ranked(rank: LockRank, data: T) -> Self {
+    Self {
+      locked: AtomicBool::new(false),
+      rank,
+      data: UnsafeCell::new(data),
+    }
+  }
+
+  pub fn lock(&self) -> SpinNoIrqLockGuard<'_, T> {
+    push_off();
+    lock_order::on_acquire(self as *const _ as usize, self.rank);
+    while self
+      .locked
+      .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+      .is_err() {
+      spin_loop();
+    }
+    SpinNoIrqLockGuard { lock: self }
+  }
+
+  /// Acquire the lock only if it is free, e.g. for diagnostics in a trap handler
+  pub fn try_lock(&self) -> Option<SpinNoIrqLockGuard<'_, T>> {
+    push_off();
+    if self
+      .locked
+      .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+      .is_ok() {
+      // cannot deadlock, so it is exempt from the lock order
+      lock_order::on_acquire(self as *const _ as usize, LockRank::Unranked);
+      Some(SpinNoIrqLockGuard { lock: self })
+    } else {
+      pop_off();
+      None
+    }
+  }
+}
+
+impl<T> Deref for SpinNoIrqLockGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.data.get() }
+  }
+}
+
+impl<T> DerefMut for SpinNoIrqLockGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.lock.data.get() }
+  }
+}
+
+impl<T> Drop for SpinNoIrqLockGuard<'_, T> {
+  fn drop(&mut self) {
+    self.lock.locked.store(false, Ordering::Release);
+    lock_order::on_release(self.lock as *const _ as usize);
+    pop_off();
+  }
+}
@@ -1,10 +1,77 @@
 //! File and filesystem-related syscalls
-use crate::{mm::{translated_byte_buffer, UserBuffer, translated_str}, task::processor::{current_user_token, current_task}, fs::{open_file, Flags}};
+use crate::{mm::{translated_byte_buffer, UserBuffer, translated_str}, task::processor::{current_user_token, current_task}, fs::{open, Flags, mount, vfs::{InodeType, Stat}}, timer::get_time_ns};
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+/// `flags` of `sys_renameat2`: fail if the target exists
+const RENAME_NOREPLACE: u32 = 1;
+/// `tv_nsec` of `sys_utimensat`: use the current time, or leave the time as it is
+const UTIME_NOW: i64 = (1 << 30) - 1;
+const UTIME_OMIT: i64 = (1 << 30) - 2;
+
+/// `struct stat` as Linux lays it out on riscv64
+#[repr(C)]
+struct Kstat {
+  dev: u64,
+  ino: u64,
+  mode: u32,
+  nlink: u32,
+  uid: u32,
+  gid: u32,
+  rdev: u64,
+  _pad1: u64,
+  size: i64,
+  blksize: i32,
+  _pad2: i32,
+  blocks: i64,
+  atime_sec: i64,
+  atime_nsec: i64,
+  mtime_sec: i64,
+  mtime_nsec: i64,
+  ctime_sec: i64,
+  ctime_nsec: i64,
+  _unused: [u32; 2],
+}
+
+impl Kstat {
+  fn new(stat: &Stat) -> Self {
+    let kind = match stat.kind {
+      InodeType::Dir => 0o040000,  // S_IFDIR
+      InodeType::File => 0o100000, // S_IFREG
+    };
+    let sec = |nanos: u64| (nanos / NSEC_PER_SEC) as i64;
+    let nsec = |nanos: u64| (nanos % NSEC_PER_SEC) as i64;
+    Self {
+      dev: 0,
+      ino: stat.ino,
+      mode: kind | stat.mode,
+      nlink: 1,
+      uid: stat.uid,
+      gid: stat.gid,
+      rdev: 0,
+      _pad1: 0,
+      size: stat.size as i64,
+      blksize: 512,
+      _pad2: 0,
+      blocks: ((stat.size + 511) / 512) as i64,
+      atime_sec: sec(stat.atime),
+      atime_nsec: nsec(stat.atime),
+      mtime_sec: sec(stat.mtime),
+      mtime_nsec: nsec(stat.mtime),
+      ctime_sec: sec(stat.ctime),
+      ctime_nsec: nsec(stat.ctime),
+      _unused: [0; 2],
+    }
+  }
+
+  fn as_bytes(&self) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>()) }
+  }
+}
 
 /// write buf of length `len` to a file with `fd`
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
   let current_task = current_task().unwrap();
-  let inner = &current_task.inner_exclusive_access();
+  let inner = current_task.inner_exclusive_access();
 
   if fd >= inner.fd_table.len() {
     return -1;
@@ -14,6 +81,8 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
   );
   
   if let Some(file) = &inner.fd_table[fd] {
+    let file = file.clone();
+    // release PCB: `file` may block the current task
     drop(inner);
     if !file.writable() {
       return -1;
@@ -26,7 +95,7 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
 
 pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
   let current_task = current_task().unwrap();
-  let inner = &current_task.inner_exclusive_access();
+  let inner = current_task.inner_exclusive_access();
 
   if fd >= inner.fd_table.len() {
     return -1;
@@ -36,6 +105,8 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
   );
   
   if let Some(file) = &inner.fd_table[fd] {
+    let file = file.clone();
+    // release PCB: `file` may block the current task
     drop(inner);
     if !file.readable() {
       return -1;
@@ -50,14 +121,15 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
   let current_task = current_task().unwrap();
   let token = current_user_token();
   let path = translated_str(token, path);
-  if let Some(inode) = open_file(path.as_str(), Flags::from_bits(flags).unwrap()) {
-    let mut inner = current_task.inner_exclusive_access();
-    let fd = inner.alloc_fd();
-    inner.fd_table[fd] = Some(inode);
-    drop(inner);
-    fd as isize
-  } else {
-    -1
+  match open(path.as_str(), Flags::from_bits(flags).unwrap()) {
+    Ok(file) => {
+      let mut inner = current_task.inner_exclusive_access();
+      let fd = inner.alloc_fd();
+      inner.fd_table[fd] = Some(file);
+      drop(inner);
+      fd as isize
+    }
+    Err(err) => err.errno(),
   }
 }
 
@@ -70,6 +142,146 @@ pub fn sys_close(fd: usize) -> isize {
   if inner.fd_table[fd].is_none() {
     return -1;
   }
-  inner.fd_table[fd].take();
+  let file = inner.fd_table[fd].take();
+  // closing the last handle of an unlinked file frees it, which may sleep
+  drop(inner);
+  drop(file);
+  0
+}
+
+/// Write back the cached data of every filesystem
+pub fn sys_sync() -> isize {
+  mount::sync_all();
+  0
+}
+
+/// Write back the cached data of the file behind `fd`
+pub fn sys_fsync(fd: usize) -> isize {
+  let task = current_task().unwrap();
+  let inner = task.inner_exclusive_access();
+  let file = match inner.fd_table.get(fd) {
+    Some(Some(file)) => file.clone(),
+    _ => return -1,
+  };
+  // release PCB: syncing may block the current task
+  drop(inner);
+  if file.sync() { 0 } else { -1 }
+}
+
+/// Mount a filesystem of type `fs_type` (e.g. `easyfs`) on `source` (e.g. `vdb`) at `target`
+pub fn sys_mount(source: *const u8, target: *const u8, fs_type: *const u8) -> isize {
+  let token = current_user_token();
+  let (source, target, fs_type) = (
+    translated_str(token, source),
+    translated_str(token, target),
+    translated_str(token, fs_type),
+  );
+  match mount::new_fs(&fs_type, &source).and_then(|fs| mount::mount(&target, fs)) {
+    Ok(()) => 0,
+    Err(err) => err.errno(),
+  }
+}
+
+pub fn sys_umount(target: *const u8) -> isize {
+  let target = translated_str(current_user_token(), target);
+  match mount::umount(&target) {
+    Ok(()) => 0,
+    Err(err) => err.errno(),
+  }
+}
+
+/// Move `old_path` to `new_path` on the same filesystem, replacing what is there unless
+/// `flags` has `RENAME_NOREPLACE`
+pub fn sys_renameat2(old_path: *const u8, new_path: *const u8, flags: u32) -> isize {
+  if flags & !RENAME_NOREPLACE != 0 {
+    return -1;
+  }
+  let token = current_user_token();
+  let (old_path, new_path) = (translated_str(token, old_path), translated_str(token, new_path));
+  match mount::rename(&old_path, &new_path, flags & RENAME_NOREPLACE != 0) {
+    Ok(()) => 0,
+    Err(err) => err.errno(),
+  }
+}
+
+/// Fill `buf` with `linux_dirent64` records of the next entries of the directory behind
+/// `fd`, returning the bytes filled, 0 once all were listed
+pub fn sys_getdents64(fd: usize, buf: *const u8, len: usize) -> isize {
+  let task = current_task().unwrap();
+  let inner = task.inner_exclusive_access();
+  let file = match inner.fd_table.get(fd) {
+    Some(Some(file)) => file.clone(),
+    _ => return -1,
+  };
+  let user_buf = UserBuffer::new(translated_byte_buffer(inner.get_user_token(), buf, len));
+  // release PCB: listing may block the current task
+  drop(inner);
+  match file.getdents(user_buf) {
+    Ok(len) => len as isize,
+    Err(err) => err.errno(),
+  }
+}
+
+/// Fill the `struct stat` at `st` with the metadata of the file behind `fd`
+pub fn sys_fstat(fd: usize, st: *mut u8) -> isize {
+  let task = current_task().unwrap();
+  let inner = task.inner_exclusive_access();
+  let file = match inner.fd_table.get(fd) {
+    Some(Some(file)) => file.clone(),
+    _ => return -1,
+  };
+  let token = inner.get_user_token();
+  // release PCB: reading the inode may block the current task
+  drop(inner);
+  let Some(stat) = file.stat() else {
+    return -1;
+  };
+  let kstat = Kstat::new(&stat);
+  let buf = UserBuffer::new(translated_byte_buffer(token, st, kstat.as_bytes().len()));
+  for (dst, src) in buf.into_iter().zip(kstat.as_bytes()) {
+    unsafe { *dst = *src };
+  }
   0
-}
\ No newline at end of file
+}
+
+/// Set the permission bits of `path`
+pub fn sys_chmod(path: *const u8, mode: u32) -> isize {
+  let path = translated_str(current_user_token(), path);
+  match mount::lookup(&path).and_then(|inode| inode.set_mode(mode & 0o7777)) {
+    Ok(()) => 0,
+    Err(err) => err.errno(),
+  }
+}
+
+/// Set the access and modification times of `path` from the two `struct timespec` at
+/// `times`, or to the current time if `times` is null
+pub fn sys_utimensat(path: *const u8, times: *const u8) -> isize {
+  let token = current_user_token();
+  let path = translated_str(token, path);
+  let now = get_time_ns();
+  let (atime, mtime) = if times.is_null() {
+    (Some(now), Some(now))
+  } else {
+    let times = translated_byte_buffer(token, times, 32).concat();
+    let field = |at: usize| i64::from_ne_bytes(times[at..at + 8].try_into().unwrap());
+    let time = |at: usize| match (field(at), field(at + 8)) {
+      (_, UTIME_NOW) => Ok(Some(now)),
+      (_, UTIME_OMIT) => Ok(None),
+      // a time past what the nanosecond count holds is as invalid as a negative one
+      (sec, nsec) if sec >= 0 && (0..NSEC_PER_SEC as i64).contains(&nsec) => (sec as u64)
+        .checked_mul(NSEC_PER_SEC)
+        .and_then(|nanos| nanos.checked_add(nsec as u64))
+        .map(Some)
+        .ok_or(()),
+      _ => Err(()),
+    };
+    match (time(0), time(16)) {
+      (Ok(atime), Ok(mtime)) => (atime, mtime),
+      _ => return -1,
+    }
+  };
+  match mount::lookup(&path).and_then(|inode| inode.set_times(atime, mtime)) {
+    Ok(()) => 0,
+    Err(err) => err.errno(),
+  }
+}
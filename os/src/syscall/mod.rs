@@ -4,10 +4,18 @@ use fs::*;
 mod process;
 mod fs;
 
+const SYSCALL_UMOUNT: usize = 39;
+const SYSCALL_MOUNT: usize = 40;
+const SYSCALL_CHMOD: usize = 53;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
+const SYSCALL_GETDENTS64: usize = 61;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
+const SYSCALL_UTIMENSAT: usize = 88;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_GET_TIME: usize = 169;
@@ -15,20 +23,30 @@ const SYSCALL_GET_PID: usize = 172;
 const SYSCALL_FORK: usize = 220;
 const SYSCALL_EXEC: usize = 221;
 const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_RENAMEAT2: usize = 276;
 
 pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
   match syscall_id {
+    SYSCALL_UMOUNT => sys_umount(args[0] as *const u8),
+    SYSCALL_MOUNT => sys_mount(args[0] as *const u8, args[1] as *const u8, args[2] as *const u8),
+    SYSCALL_CHMOD => sys_chmod(args[0] as *const u8, args[1] as u32),
     SYSCALL_OPEN => sys_open(args[0] as *const u8, args[1] as u32),
     SYSCALL_CLOSE => sys_close(args[0]),
+    SYSCALL_GETDENTS64 => sys_getdents64(args[0], args[1] as *const u8, args[2]),
     SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2]),
     SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+    SYSCALL_FSTAT => sys_fstat(args[0], args[1] as *mut u8),
+    SYSCALL_SYNC => sys_sync(),
+    SYSCALL_FSYNC => sys_fsync(args[0]),
+    SYSCALL_UTIMENSAT => sys_utimensat(args[0] as *const u8, args[1] as *const u8),
     SYSCALL_EXIT => sys_exit(args[0] as i32),
     SYSCALL_YIELD => sys_yield(),
     SYSCALL_GET_TIME => sys_get_time(),
     SYSCALL_GET_PID => sys_getpid(),
     SYSCALL_FORK => sys_fork(),
-    SYSCALL_EXEC => sys_exec(args[0] as *const u8),
+    SYSCALL_EXEC => sys_exec(args[0] as *const u8, args[1] as *const usize),
     SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
+    SYSCALL_RENAMEAT2 => sys_renameat2(args[0] as *const u8, args[1] as *const u8, args[2] as u32),
     _ => panic!("Unsupported syscall: {:#x} ", syscall_id)
   }
 }
\ No newline at end of file
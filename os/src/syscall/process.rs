@@ -1,4 +1,4 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 
 use crate::{task::{exit_current_and_run_next, suspend_current_and_run_next, processor::{current_user_token, current_task}, add_task}, timer::get_time_ms, mm::{translated_str, translated_refmut}, fs::{open_file, Flags}};
 
@@ -34,16 +34,33 @@ pub fn sys_fork() -> isize {
   new_pid as isize
 }
 
-pub fn sys_exec(path_ptr: *const u8) -> isize {
+/// `args` is a NULL terminated array of strings, or NULL for just the path. Fails if they
+/// take more than `EXEC_ARGS_MAX` bytes, argv[] included.
+pub fn sys_exec(path_ptr: *const u8, mut args_ptr: *const usize) -> isize {
   let token = current_user_token();
   let path = translated_str(token, path_ptr);
   // println!("{} {}", current_task().unwrap().pid.0, path);
+  let mut args = Vec::new();
+  if args_ptr.is_null() {
+    args.push(path.clone());
+  } else {
+    loop {
+      let arg = *translated_refmut(token, args_ptr);
+      if arg == 0 {
+        break;
+      }
+      args.push(translated_str(token, arg as *const u8));
+      args_ptr = unsafe { args_ptr.add(1) };
+    }
+  }
 
-  if let Some(file) = open_file(path.as_str(), Flags::RDONLY) {
+  if let Ok(file) = open_file(path.as_str(), Flags::RDONLY) {
     // println!("{} {}", current_task().unwrap().pid.0, path);
     let task = current_task().unwrap();
-    task.exec(file.read_all().as_slice());
-    0
+    let argc = args.len();
+    // arguments too long for the new stack are E2BIG; on success the trap handler puts
+    // the return value in `a0` of the new program, where `_start` wants argc
+    if task.exec(file.read_all().as_slice(), args) { argc as isize } else { -1 }
   } else {
     -1
   }
@@ -80,6 +97,9 @@ pub fn sys_waitpid(pid: isize, exit_status: *mut i32) -> isize {
     let found_pid = child.getpid();
     let exit_code = child.inner_exclusive_access().exit_code;
     *translated_refmut(inner.get_user_token(), exit_status) = exit_code;
+    // dropping the child closes its files, which may sleep
+    drop(inner);
+    drop(child);
     found_pid as isize 
   } else {
     -2 // not a zombie proc
@@ -1,8 +1,8 @@
-use alloc::{sync::Arc};
+use alloc::{sync::Arc, vec::Vec, vec};
 
 use crate::{board::QEMUExit, fs::{open_file, Flags}};
 
-use self::{task::{TaskControlBlock, TaskStatus}, context::TaskContext, processor::{take_current_task, schedule}};
+use self::{context::TaskContext, processor::{take_current_task, schedule}};
 
 mod context;
 mod task_manager;
@@ -13,6 +13,7 @@ mod switch;
 mod task;
 
 pub use task_manager::add_task;
+pub use task::{TaskControlBlock, TaskStatus};
 
 pub fn suspend_current_and_run_next() {
   let task = take_current_task().unwrap();
@@ -30,6 +31,27 @@ pub fn suspend_current_and_run_next() {
   schedule(task_cx_ptr);
 }
 
+/// Park current task: unlike `suspend_current_and_run_next`, it is **not**
+/// put back to the ready queue until someone calls `wakeup_task` on it.
+pub fn block_current_and_run_next() {
+  let task = take_current_task().unwrap();
+
+  let mut task_inner = task.inner_exclusive_access();
+  let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+  task_inner.task_status = TaskStatus::Blocked;
+  drop(task_inner);
+  // the waker holds another reference
+  drop(task);
+
+  schedule(task_cx_ptr);
+}
+
+/// Make a blocked task runnable again
+pub fn wakeup_task(task: Arc<TaskControlBlock>) {
+  task.inner_exclusive_access().task_status = TaskStatus::Ready;
+  add_task(task);
+}
+
 /// pid of usertest
 pub const IDLE_PID: usize = 0;
 
@@ -49,11 +71,12 @@ pub fn exit_current_and_run_next(exit_code: i32) {
       "[kernel] Idle process exit with exit_code {} ...",
       exit_code
     );
+    crate::fs::mount::sync_all();
     // === exit kernel === 
     if exit_code != 0 {
-      crate::board::QEMU_EXIT_HANDLE.exit_failure();
+      crate::board::exit_handle().exit_failure();
     } else {
-      crate::board::QEMU_EXIT_HANDLE.exit_success();
+      crate::board::exit_handle().exit_success();
     }
   }
 
@@ -78,6 +101,22 @@ pub fn exit_current_and_run_next(exit_code: i32) {
   schedule(&mut _unused as *mut TaskContext);
 }
 
+/// `INITPROC` and all its descendants, i.e. every task that has not been reaped
+pub fn all_tasks() -> Vec<Arc<TaskControlBlock>> {
+  let mut tasks = vec![INITPROC.clone()];
+  let mut i = 0;
+  while i < tasks.len() {
+    let children = tasks[i].inner_exclusive_access().children.clone();
+    tasks.extend(children);
+    i += 1;
+  }
+  tasks
+}
+
+pub fn find_task(pid: usize) -> Option<Arc<TaskControlBlock>> {
+  all_tasks().into_iter().find(|task| task.getpid() == pid)
+}
+
 pub fn add_initproc() {
   add_task(INITPROC.clone());
 }
\ No newline at end of file
@@ -1,13 +1,13 @@
 use alloc::vec::Vec;
 
-use crate::{sync::up::UPSafeCell, config::{TRAMPOLINE, KERNEL_STACK_SIZE, PAGE_SIZE}, mm::{memory_set::{KERNEL_SPACE, MapPermission}, address::VirtAddr}};
+use crate::{sync::{SpinNoIrqLock, LockRank}, config::{TRAMPOLINE, KERNEL_STACK_SIZE, PAGE_SIZE}, mm::{memory_set::{KERNEL_SPACE, MapPermission}, address::VirtAddr}};
 
 
 pub struct PidHandler(pub usize);
 
 impl Drop for PidHandler {
   fn drop(&mut self) {
-    PID_ALLOCATOR.exclusive_access().dealloc(self);
+    PID_ALLOCATOR.lock().dealloc(self);
   }
 }
  
@@ -43,13 +43,12 @@ impl PidAllocator {
 }
 
 lazy_static!{
-  pub static ref PID_ALLOCATOR: UPSafeCell<PidAllocator> = unsafe {
-    UPSafeCell::new(PidAllocator::new())
-  };
+  pub static ref PID_ALLOCATOR: SpinNoIrqLock<PidAllocator> =
+    SpinNoIrqLock::ranked(LockRank::PidAllocator, PidAllocator::new());
 }
 
 pub fn pid_alloc() -> PidHandler {
-  PID_ALLOCATOR.exclusive_access().alloc()
+  PID_ALLOCATOR.lock().alloc()
 }
 
 /// return process's kernel stack layout: (bottom, top)
@@ -69,7 +68,7 @@ impl KernelStack {
   pub fn new(pid_handler: &PidHandler) -> Self {
     let pid = pid_handler.0;
     let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(pid);
-    KERNEL_SPACE.exclusive_access().insert_framed_area(
+    KERNEL_SPACE.lock().insert_framed_area(
       kernel_stack_bottom.into(),
       kernel_stack_top.into(), 
       MapPermission::R | MapPermission::W
@@ -89,7 +88,7 @@ impl Drop for KernelStack {
     let (kernel_stack_bottom, _) = kernel_stack_position(self.pid);
     let kernel_stack_bottom_va: VirtAddr = kernel_stack_bottom.into();
     KERNEL_SPACE
-      .exclusive_access()
+      .lock()
       .remove_area_with_start_vpn(kernel_stack_bottom_va.into());
   }
 }
\ No newline at end of file
@@ -1,8 +1,11 @@
 //!Implementation of [`Processor`] and Intersection of control flow
 
+use core::arch::asm;
+
 use alloc::sync::Arc;
+use riscv::register::sstatus;
 
-use crate::{sync::up::UPSafeCell, trap::context::TrapContext};
+use crate::{sync::{SpinNoIrqLock, LockRank}, trap::context::TrapContext};
 
 use super::{task::{TaskControlBlock, TaskStatus}, context::TaskContext, task_manager::fetch_task, switch::__switch};
  
@@ -41,16 +44,15 @@ impl Processor {
 }
 
 lazy_static! {
-  pub static ref PROCESSOR: UPSafeCell<Processor> = unsafe {
-    UPSafeCell::new(Processor::new())
-  };
+  pub static ref PROCESSOR: SpinNoIrqLock<Processor> =
+    SpinNoIrqLock::ranked(LockRank::Processor, Processor::new());
 }
 
 ///The main part of process execution and scheduling
 ///Loop `fetch_task` to get the process that needs to run, and switch the process through `__switch`
 pub fn run_tasks() {
   loop {
-    let mut processor = PROCESSOR.exclusive_access();
+    let mut processor = PROCESSOR.lock();
     if let Some(task) = fetch_task() {
       // find a task ready to run
       let idle_task_cx_ptr = processor.get_idle_task_cx();
@@ -63,18 +65,26 @@ pub fn run_tasks() {
       unsafe {
         __switch(idle_task_cx_ptr, next_task_cx_ptr)
       }
+    } else {
+      // everyone is blocked: wait for an interrupt (e.g. the UART) to wake someone up
+      drop(processor);
+      unsafe {
+        sstatus::set_sie();
+        asm!("wfi");
+        sstatus::clear_sie();
+      }
     }
   }
 }
 
 ///Take the current task,leaving a None in its place
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
-  PROCESSOR.exclusive_access().take_current()
+  PROCESSOR.lock().take_current()
 }
 
 ///Get running task
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
-  PROCESSOR.exclusive_access().current()
+  PROCESSOR.lock().current()
 }
 
 ///Get token of the address space of current task
@@ -90,7 +100,7 @@ pub fn current_trap_cx() -> &'static mut TrapContext {
 }
 
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
-  let mut processor = PROCESSOR.exclusive_access();
+  let mut processor = PROCESSOR.lock();
   let idle_task_cx_ptr = processor.get_idle_task_cx();
   drop(processor); // must drop processor manually before __switch
   unsafe {
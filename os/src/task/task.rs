@@ -1,15 +1,17 @@
 use core::{cell::RefMut};
 
-use alloc::{vec::Vec, vec, sync::{Arc, Weak}};
+use alloc::{vec::Vec, sync::{Arc, Weak}, string::String};
 
-use crate::{mm::{memory_set::{MemorySet, KERNEL_SPACE}, address::{VirtAddr, PhysPageNum, VirtPageNum}}, config::TRAP_CONTEXT, trap::{context::TrapContext, trap_handler}, sync::up::UPSafeCell, fs::{File, Stdin, Stdout}};
+use crate::{mm::{kernel_token, memory_set::MemorySet, address::{VirtAddr, PhysPageNum, VirtPageNum}}, config::{TRAP_CONTEXT, EXEC_ARGS_MAX}, mm::translated_refmut, trap::{context::TrapContext, trap_handler}, sync::up::UPSafeCell, fs::File};
 
 use super::{context::TaskContext, pid::{PidHandler, KernelStack, pid_alloc}};
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum TaskStatus {
   Ready,
   Running,
+  /// parked on a wait queue, see `block_current_and_run_next`
+  Blocked,
   Zombie
 }
 
@@ -89,14 +91,8 @@ impl TaskControlBlock {
             task_cx: TaskContext::goto_trap_return(kernel_stack_top),
             trap_cx_ppn,
             base_size: user_sp,
-            fd_table: vec![
-              // fd 0
-              Some(Arc::new(Stdin)), 
-              // fd 1
-              Some(Arc::new(Stdout)), 
-              // fd 2
-              Some(Arc::new(Stdout))
-            ],
+            // initproc opens its standard streams itself, everyone else inherits them
+            fd_table: Vec::new(),
             parent: None,
             children: Vec::new(),
             exit_code: 0,
@@ -106,7 +102,7 @@ impl TaskControlBlock {
     *trap_cx = TrapContext::app_init_context(
       entry_point, 
       user_sp, 
-      KERNEL_SPACE.exclusive_access().token(), 
+      kernel_token(), 
       kernel_stack_top, 
       trap_handler as usize
     );
@@ -117,12 +113,34 @@ impl TaskControlBlock {
     self.pid.0
   }
 
-  pub fn exec(&self, elf_data: &[u8]) {
+  /// Replace the program, passing `args` as `(argc, argv)` in `a0`/`a1`. Returns false,
+  /// leaving the program as it was, if `args` take more than `EXEC_ARGS_MAX` bytes.
+  pub fn exec(&self, elf_data: &[u8], args: Vec<String>) -> bool {
+    let args_size = (args.len() + 1) * core::mem::size_of::<usize>() + args.iter().map(|arg| arg.len() + 1).sum::<usize>();
+    if args_size > EXEC_ARGS_MAX {
+      return false;
+    }
     let (memory_set, user_sp_bottom, user_sp, entry_point) = MemorySet::from_elf(elf_data);
     let trap_cx_ppn = memory_set
       .translate(VirtAddr::from(TRAP_CONTEXT).into())
       .unwrap()
       .ppn();
+
+    // argv[] (NULL terminated) at the top of the user stack, the strings below it
+    let token = memory_set.token();
+    let argv_base = user_sp - (args.len() + 1) * core::mem::size_of::<usize>();
+    let argv = |i: usize| translated_refmut(token, (argv_base + i * core::mem::size_of::<usize>()) as *mut usize);
+    *argv(args.len()) = 0;
+    let mut sp = argv_base;
+    for (i, arg) in args.iter().enumerate() {
+      sp -= arg.len() + 1;
+      *argv(i) = sp;
+      for (j, &byte) in arg.as_bytes().iter().chain(&[0u8]).enumerate() {
+        *translated_refmut(token, (sp + j) as *mut u8) = byte;
+      }
+    }
+    // the calling convention wants `sp` 16-byte aligned
+    sp &= !0xf;
   
     let mut inner = self.inner_exclusive_access();
     // update PCB's info
@@ -134,12 +152,15 @@ impl TaskControlBlock {
     let trap_cx = inner.get_trap_cx();
     *trap_cx = TrapContext::app_init_context(
       entry_point, 
-      user_sp, 
-      KERNEL_SPACE.exclusive_access().token(),
+      sp, 
+      kernel_token(),
       self.kernel_stack.get_top(), 
       trap_handler as usize
     );
+    trap_cx.x[10] = args.len();
+    trap_cx.x[11] = argv_base;
     // === release inner automaticallly ===
+    true
   }
 
   pub fn fork(self: &Arc<Self>) -> Arc<Self> { 
@@ -2,7 +2,7 @@
 
 use alloc::{sync::Arc, collections::VecDeque};
 
-use crate::sync::up::UPSafeCell;
+use crate::sync::{SpinNoIrqLock, LockRank};
 
 use super::{task::TaskControlBlock};
 
@@ -31,17 +31,16 @@ impl TaskManager {
 }
 
 lazy_static! {
-  pub static ref TASK_MANAGER: UPSafeCell<TaskManager> = unsafe {
-    UPSafeCell::new(TaskManager::new())
-  };
+  pub static ref TASK_MANAGER: SpinNoIrqLock<TaskManager> =
+    SpinNoIrqLock::ranked(LockRank::TaskManager, TaskManager::new());
 }
 
 /// add task to TASK_MANAGER
 pub fn add_task(task: Arc<TaskControlBlock>) {
-  TASK_MANAGER.exclusive_access().add(task)
+  TASK_MANAGER.lock().add(task)
 }
 
 /// fetch task from TASK_MANAGET
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
-  TASK_MANAGER.exclusive_access().fetch()
+  TASK_MANAGER.lock().fetch()
 }
\ No newline at end of file
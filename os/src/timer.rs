@@ -1,12 +1,15 @@
 //! RISC-V timer-related functionality
 
 
-use crate::config::CLOCK_FREQ;
+use crate::board;
 use crate::sbi::set_timer;
 use riscv::register::time;
 
 const TICKS_PER_SEC: usize = 100;
 const MSEC_PER_SEC: usize = 1000;
+/// goldfish RTC registers
+const RTC_TIME_LOW: usize = 0x00;
+const RTC_TIME_HIGH: usize = 0x04;
 
 
 #[allow(unused)]
@@ -18,12 +21,22 @@ pub fn get_time() -> usize {
 
 /// get current time in microseconds
 pub fn get_time_ms() -> usize {
-  time::read() / (CLOCK_FREQ / MSEC_PER_SEC)
+  time::read() / (board::info().clock_freq / MSEC_PER_SEC)
 }
 
+/// Wall-clock time in nanoseconds since the Unix epoch, from the RTC
+pub fn get_time_ns() -> u64 {
+  let base = board::info().rtc.base;
+  // reading TIME_LOW latches TIME_HIGH
+  unsafe {
+    let low = ((base + RTC_TIME_LOW) as *const u32).read_volatile();
+    let high = ((base + RTC_TIME_HIGH) as *const u32).read_volatile();
+    (high as u64) << 32 | low as u64
+  }
+}
 
 #[allow(unused)]
 /// set the next timer interrupt
 pub fn set_next_trigger() {
-  set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
+  set_timer(get_time() + board::info().clock_freq / TICKS_PER_SEC);
 }
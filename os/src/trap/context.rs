@@ -46,3 +46,13 @@ impl TrapContext {
   }
 }
 
+
+/// Registers saved by `__kernel_trap` on the current kernel stack
+/// when a trap is taken while already in S-mode.
+#[repr(C)]
+pub struct KernelTrapContext {
+  /// general regs[0..31], x2 (sp) is the value before the trap
+  pub x: [usize; 32],
+  pub sstatus: usize,
+  pub sepc: usize,
+}
@@ -8,22 +8,28 @@ use crate::config::USER_STACK_MAX_SIZE;
 use crate::config::USER_STACK_TOP;
 use crate::mm::address::VirtAddr;
 use crate::syscall::syscall;
-use crate::task::processor::current_task;
+use crate::task::processor::{current_task, PROCESSOR};
 use crate::task::processor::current_trap_cx;
 use crate::task::processor::current_user_token;
 use crate::{config::{TRAP_CONTEXT, TRAMPOLINE}, task::{exit_current_and_run_next, suspend_current_and_run_next}};
 
 pub mod context;
 
+use context::KernelTrapContext;
+
 global_asm!(include_str!("trap.S"));
+global_asm!(include_str!("kernel_trap.S"));
 
 pub fn init() {
   set_kernel_trap_entry();
 }
 
 fn set_kernel_trap_entry() {
+  extern "C" {
+    fn __kernel_trap();
+  }
   unsafe {
-    stvec::write(trap_from_kernel as usize, TrapMode::Direct);
+    stvec::write(__kernel_trap as usize, TrapMode::Direct);
   }
 }
 
@@ -33,12 +39,66 @@ fn set_user_trap_entry() {
   }
 }
 
+/// clear SSIP: soft interruption pending bit, raised by `timervec` on every tick
+fn clear_soft_interrupt() {
+  use csr_riscv::register::sip;
+  unsafe { asm!("csrw sip,    {}", in(reg)sip::read().bits() & !2); }
+}
+
+/// Called by `__kernel_trap` with the registers it saved on the kernel stack
 #[no_mangle]
-pub fn trap_from_kernel() -> ! {
-  use riscv::register::mcause;
-  let cause = mcause::read();
-  println!("{:?}", cause);
-  panic!("kernel's trap to do...")
+pub fn trap_from_kernel(cx: &mut KernelTrapContext) {
+  let scause = scause::read();
+  match scause.cause() {
+    Trap::Interrupt(Interrupt::SupervisorSoft) => {
+      // the kernel is not preemptible: just acknowledge the tick
+      clear_soft_interrupt();
+    }
+    Trap::Interrupt(Interrupt::SupervisorExternal) => {
+      crate::drivers::handle_external_interrupt();
+    }
+    Trap::Interrupt(_) => {
+      kernel_fatal(cx, "unexpected interrupt");
+    }
+    Trap::Exception(_) => {
+      kernel_fatal(cx, "exception");
+    }
+  }
+}
+
+const REG_NAMES: [&str; 32] = [
+  "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+  "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+  "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+  "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// Print everything we know about a trap the kernel cannot recover from, then halt.
+fn kernel_fatal(cx: &KernelTrapContext, reason: &str) -> ! {
+  use crate::board::QEMUExit;
+  // the trap may have hit while the console was being written
+  unsafe { crate::uart::UART.force_unlock(); }
+
+  let scause = scause::read();
+  println!("[kernel] fatal trap in S-mode: {} ({:?})", reason, scause.cause());
+  println!("  scause = {:#x}, stval = {:#x}, sepc = {:#x}, sstatus = {:#x}",
+    scause.bits(), stval::read(), cx.sepc, cx.sstatus);
+  // never spin on PROCESSOR here, the trap might come from inside it
+  match PROCESSOR.try_lock().map(|mut processor| processor.current()) {
+    Some(Some(task)) => { println!("  task: pid = {}", task.getpid()); }
+    Some(None) => { println!("  task: none (idle control flow)"); }
+    None => { println!("  task: unknown (PROCESSOR is locked)"); }
+  }
+  for row in 0..8 {
+    for col in 0..4 {
+      let i = row * 4 + col;
+      print!("  {:>4} = {:#018x}", REG_NAMES[i], cx.x[i]);
+    }
+    println!("");
+  }
+  // s0 is the frame pointer of the interrupted function
+  crate::backtrace::print_backtrace_from(cx.sepc, cx.x[8]);
+  crate::board::exit_handle().exit_failure();
 }
 
 #[no_mangle]
@@ -85,10 +145,13 @@ pub fn trap_handler() -> ! {
       panic!("timer interrupt is not implemented this way!");
     }
     Trap::Interrupt(Interrupt::SupervisorSoft) => {
-      use csr_riscv::register::sip;
-      unsafe { asm!("csrw sip,    {}", in(reg)sip::read().bits() & !2); } // clear SSIP: soft interruption pending bit
+      clear_soft_interrupt();
+      crate::fs::write_back_tick();
       suspend_current_and_run_next();
     }
+    Trap::Interrupt(Interrupt::SupervisorExternal) => {
+      crate::drivers::handle_external_interrupt();
+    }
     _ => {
       panic!(
         "Unsupported trap {:?}, stval = {:#x}!",
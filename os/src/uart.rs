@@ -1,4 +1,4 @@
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, sync::atomic::{AtomicUsize, Ordering}};
 
 use spin::Mutex;
 use uart_16550::MmioSerialPort;
@@ -14,13 +14,40 @@ pub trait ConsoleTrait: Sync {
   fn get_char(&self) -> usize;
 }
 
+/// FIFO control register (write only)
+const FCR: usize = 2;
+/// line status register
+const LSR: usize = 5;
+const LSR_DATA_READY: u8 = 1;
+
 pub (crate) struct Console;
 pub static UART: Mutex<MaybeUninit<MmioSerialPort>> = Mutex::new(MaybeUninit::uninit());
+static UART_BASE: AtomicUsize = AtomicUsize::new(0);
 
 impl Console {
-  /// initialize UART
+  /// initialize UART, with the receive interrupt enabled
   pub fn console_init(addr: usize) { 
-    *UART.lock() = MaybeUninit::new(unsafe { MmioSerialPort::new(addr) });
+    let mut port = unsafe { MmioSerialPort::new(addr) };
+    port.init();
+    // FIFO on and cleared, but interrupt on every byte instead of every 14
+    unsafe { ((addr + FCR) as *mut u8).write_volatile(0x07); }
+    UART_BASE.store(addr, Ordering::Relaxed);
+    *UART.lock() = MaybeUninit::new(port);
+  }
+
+  /// Fetch a received byte if there is one.
+  ///
+  /// Bypasses `UART`, so it is safe in the interrupt handler even if
+  /// the interrupted code is printing.
+  pub fn try_get_char() -> Option<u8> {
+    let base = UART_BASE.load(Ordering::Relaxed);
+    unsafe {
+      if ((base + LSR) as *const u8).read_volatile() & LSR_DATA_READY != 0 {
+        Some((base as *const u8).read_volatile())
+      } else {
+        None
+      }
+    }
   }
 }
 
@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::args;
+
+/// Run by usertests as `argv first` and `argv first second third`
+#[no_mangle]
+pub fn main() -> i32 {
+    let args = args();
+    println!("argc = {}, argv = {:?}", args.len(), args);
+    assert!(args.len() == 2 || args.len() == 4);
+    assert_eq!(args[0], "argv");
+    let expected = ["first", "second", "third"];
+    assert_eq!(&args[1..], &expected[..args.len() - 1]);
+    0
+}
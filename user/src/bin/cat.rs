@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::{args, open, read, write, close, OpenFlags};
+
+/// Copy each file named on the command line to stdout
+#[no_mangle]
+pub fn main() -> i32 {
+  let args = args();
+  if args.len() < 2 {
+    println!("usage: cat <file>...");
+    return -1;
+  }
+  let mut buf = [0u8; 256];
+  for path in &args[1..] {
+    let mut path = String::from(*path);
+    path.push('\0');
+    let fd = open(path.as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+      println!("cat: cannot open {}", path.trim_end_matches('\0'));
+      return -1;
+    }
+    loop {
+      let len = read(fd as usize, &mut buf);
+      if len <= 0 {
+        break;
+      }
+      write(1, &buf[..len as usize]);
+    }
+    close(fd as usize);
+  }
+  0
+}
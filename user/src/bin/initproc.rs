@@ -4,10 +4,14 @@
 #[macro_use]
 extern crate user_lib;
 
-use user_lib::{fork, exec, wait, yield_};
+use user_lib::{fork, exec, wait, yield_, open, OpenFlags};
 
 #[no_mangle]
 fn main() -> i32 {  
+  // stdin, stdout and stderr, inherited by every process forked from here
+  assert_eq!(open("/dev/console\0", OpenFlags::RDONLY), 0);
+  assert_eq!(open("/dev/console\0", OpenFlags::WRONLY), 1);
+  assert_eq!(open("/dev/console\0", OpenFlags::WRONLY), 2);
   if fork() == 0 {
     exec("user_shell\0");
   } else {
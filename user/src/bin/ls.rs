@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::{format, string::String, vec::Vec};
+use user_lib::{args, close, dirents, fstat, getdents64, open, OpenFlags, Stat, DT_DIR, S_IFDIR, S_IFMT};
+
+/// `drwxr-xr-x` for the mode of `st`
+fn mode_string(st: &Stat) -> String {
+  let mut text = String::from(if st.mode & S_IFMT == S_IFDIR { "d" } else { "-" });
+  for shift in [6, 3, 0] {
+    for (bit, ch) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+      text.push(if st.mode >> shift & bit != 0 { ch } else { '-' });
+    }
+  }
+  text
+}
+
+/// `ls [-l] [dir]...`: list the entries of each directory, `/` by default, directories
+/// with a trailing `/`; `-l` adds the mode and size
+#[no_mangle]
+pub fn main() -> i32 {
+  let args = &args()[1..];
+  let long = args.contains(&"-l");
+  let mut dirs: Vec<&str> = args.iter().copied().filter(|arg| *arg != "-l").collect();
+  if dirs.is_empty() {
+    dirs.push("/");
+  }
+  let mut ret = 0;
+  let mut buf = [0u8; 512];
+  for dir in dirs {
+    let fd = open(format!("{}\0", dir).as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+      println!("ls: cannot open {}", dir);
+      ret = -1;
+      continue;
+    }
+    loop {
+      let len = getdents64(fd as usize, &mut buf);
+      if len < 0 {
+        println!("ls: {}: not a directory", dir);
+        ret = -1;
+      }
+      if len <= 0 {
+        break;
+      }
+      for entry in dirents(&buf[..len as usize]) {
+        let slash = if entry.kind == DT_DIR { "/" } else { "" };
+        if !long {
+          println!("{}{}", entry.name, slash);
+          continue;
+        }
+        let mut st = Stat::default();
+        let path = format!("{}/{}\0", dir.trim_end_matches('/'), entry.name);
+        let child = open(path.as_str(), OpenFlags::RDONLY);
+        if child >= 0 {
+          fstat(child as usize, &mut st);
+          close(child as usize);
+        }
+        println!("{} {:>8} {}{}", mode_string(&st), st.size, entry.name, slash);
+      }
+    }
+    close(fd as usize);
+  }
+  ret
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::{format, vec::Vec};
+use user_lib::{args, close, fstat, open, renameat2, OpenFlags, Stat, RENAME_NOREPLACE, S_IFDIR, S_IFMT};
+
+/// Is there a directory at `path`, which ends with `\0`?
+fn is_dir(path: &str) -> bool {
+  let fd = open(path, OpenFlags::RDONLY);
+  if fd < 0 {
+    return false;
+  }
+  let mut st = Stat::default();
+  let ret = fstat(fd as usize, &mut st);
+  close(fd as usize);
+  ret == 0 && st.mode & S_IFMT == S_IFDIR
+}
+
+/// `mv [-n] <source> <target>`: rename `source` to `target`, or move it into `target` if
+/// that is a directory; `-n` never replaces an existing file
+#[no_mangle]
+pub fn main() -> i32 {
+  let args = &args()[1..];
+  let noreplace = args.contains(&"-n");
+  let paths: Vec<&str> = args.iter().copied().filter(|arg| *arg != "-n").collect();
+  let [source, target] = paths[..] else {
+    println!("usage: mv [-n] <source> <target>");
+    return -1;
+  };
+  let mut target = format!("{}\0", target);
+  if is_dir(&target) {
+    let name = source.trim_end_matches('/').rsplit('/').next().unwrap_or(source);
+    target = format!("{}/{}\0", target.trim_end_matches('\0').trim_end_matches('/'), name);
+  }
+  let flags = if noreplace { RENAME_NOREPLACE } else { 0 };
+  let ret = renameat2(format!("{}\0", source).as_str(), &target, flags);
+  if ret < 0 {
+    println!("mv: cannot move {} to {}: error {}", source, target.trim_end_matches('\0'), ret);
+    return -1;
+  }
+  0
+}
@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::{args, close, fstat, open, OpenFlags, Stat, S_IFDIR, S_IFMT};
+
+/// Print the inode number, mode, size and times of each file named on the command line
+#[no_mangle]
+pub fn main() -> i32 {
+  let args = args();
+  if args.len() < 2 {
+    println!("usage: stat <file>...");
+    return -1;
+  }
+  for path in &args[1..] {
+    let mut path = String::from(*path);
+    path.push('\0');
+    let fd = open(path.as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+      println!("stat: cannot open {}", path.trim_end_matches('\0'));
+      return -1;
+    }
+    let mut st = Stat::default();
+    let ret = fstat(fd as usize, &mut st);
+    close(fd as usize);
+    if ret < 0 {
+      println!("stat: cannot stat {}", path.trim_end_matches('\0'));
+      return -1;
+    }
+    let kind = if st.mode & S_IFMT == S_IFDIR { "directory" } else { "file" };
+    println!("{}: {} {} mode {:o} size {}", path.trim_end_matches('\0'), kind, st.ino, st.mode & 0o7777, st.size);
+    println!("  atime {}.{:09}", st.atime_sec, st.atime_nsec);
+    println!("  mtime {}.{:09}", st.mtime_sec, st.mtime_nsec);
+    println!("  ctime {}.{:09}", st.ctime_sec, st.ctime_nsec);
+  }
+  0
+}
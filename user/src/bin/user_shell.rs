@@ -1,8 +1,8 @@
 #![no_std]
 #![no_main]
 
-use alloc::string::String;
-use user_lib::{console::getchar, fork, exec, waitpid}; 
+use alloc::{string::String, vec::Vec};
+use user_lib::{console::getchar, fork, execv, waitpid}; 
 
 extern crate alloc;
 
@@ -24,12 +24,18 @@ pub fn main() -> i32 {
     match c {
       LF | CR => {
         println!("");
-        if !line.is_empty() {
-          line.push('\0');
+        if !line.trim().is_empty() {
+          // `\0` terminated words, the first one is the program
+          let args: Vec<String> = line
+            .split_whitespace()
+            .map(|arg| { let mut arg = String::from(arg); arg.push('\0'); arg })
+            .collect();
+          let mut argv: Vec<*const u8> = args.iter().map(|arg| arg.as_ptr()).collect();
+          argv.push(core::ptr::null());
           let pid = fork();
           if pid == 0 {
-            if exec(line.as_str()) == -1 {
-              println!("Error when execve(\"{}\")", line);
+            if execv(args[0].as_str(), argv.as_slice()) == -1 {
+              println!("Error when execve(\"{}\")", args[0].trim_end_matches('\0'));
               return -4;
             }
             unreachable!();
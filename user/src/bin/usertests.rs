@@ -20,17 +20,21 @@ static SUCC_TESTS: &[(&str, &str, &str, &str, i32)] = &[
     ("sleep_simple\0", "\0", "\0", "\0", 0),
     ("sleep\0", "\0", "\0", "\0", 0),
     ("yield\0", "\0", "\0", "\0", 0),
+    ("argv\0", "first\0", "\0", "\0", 0),
+    ("argv\0", "first\0", "second\0", "third\0", 0),
 ];
 
 static FAIL_TESTS: &[(&str, &str, &str, &str, i32)] = &[
     ("stack_overflow\0", "\0", "\0", "\0", -2),
 ];
 
-use user_lib::{exec, fork, waitpid};
+use user_lib::{execv, fork, waitpid};
 
 fn run_tests(tests: &[(&str, &str, &str, &str, i32)]) -> i32 {
     let mut pass_num = 0;
-    let mut arr: [*const u8; 4] = [
+    // argv[], NULL terminated
+    let mut arr: [*const u8; 5] = [
+        core::ptr::null::<u8>(),
         core::ptr::null::<u8>(),
         core::ptr::null::<u8>(),
         core::ptr::null::<u8>(),
@@ -64,7 +68,7 @@ fn run_tests(tests: &[(&str, &str, &str, &str, i32)]) -> i32 {
 
         let pid = fork();
         if pid == 0 {
-            exec(test.0);
+            execv(test.0, &arr);
             panic!("unreachable!");
         } else {
             let mut exit_code: i32 = Default::default();
@@ -10,6 +10,10 @@ mod ds;
 mod lang_items;
 mod syscall;
 
+extern crate alloc;
+
+use alloc::vec::Vec;
+
 use riscv::register::fcsr::Flags;
 use syscall::*;
 
@@ -30,15 +34,27 @@ pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
 
 #[no_mangle]
 #[link_section = ".text.entry"]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
   unsafe {
     HEAP.lock()
       .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
+    for i in 0..argc {
+      let arg = *(argv as *const usize).add(i) as *const u8;
+      let len = (0usize..).find(|&j| *arg.add(j) == 0).unwrap();
+      ARGS.push(core::str::from_utf8(core::slice::from_raw_parts(arg, len)).unwrap());
+    }
   }
   exit(main());
   panic!("unreachable after sys_exit!");
 }
 
+static mut ARGS: Vec<&'static str> = Vec::new();
+
+/// Command line arguments, the first one being the program's path
+pub fn args() -> &'static [&'static str] {
+  unsafe { &ARGS }
+}
+
 #[linkage = "weak"]
 #[no_mangle]
 fn main() -> i32 {
@@ -63,6 +79,132 @@ pub fn open(path: &str, flags: OpenFlags) -> isize {
   sys_open(path, flags.bits)
 }
 
+/// Mount a filesystem of type `fs_type` on device `source` at `target`; strings end with `\0`
+pub fn mount(source: &str, target: &str, fs_type: &str) -> isize {
+  sys_mount(source, target, fs_type)
+}
+
+pub fn umount(target: &str) -> isize {
+  sys_umount(target)
+}
+
+/// Write cached file data of all filesystems to disk
+pub fn sync() -> isize {
+  sys_sync()
+}
+
+pub fn fsync(fd: usize) -> isize {
+  sys_fsync(fd)
+}
+
+/// `Dirent::kind` of a directory
+pub const DT_DIR: u8 = 4;
+/// `Dirent::kind` of a regular file
+pub const DT_REG: u8 = 8;
+
+/// One directory entry filled in by `getdents64`
+#[derive(Clone, Copy, Debug)]
+pub struct Dirent<'a> {
+  pub ino: u64,
+  /// `DT_DIR` or `DT_REG`
+  pub kind: u8,
+  pub name: &'a str,
+}
+
+/// Fill `buf` with the next entries of the directory open at `fd`. Returns the bytes
+/// filled, to be walked with `dirents`, 0 once all entries were listed.
+pub fn getdents64(fd: usize, buf: &mut [u8]) -> isize {
+  sys_getdents64(fd, buf)
+}
+
+/// The entries in `buf`, as filled by `getdents64`
+pub fn dirents(buf: &[u8]) -> impl Iterator<Item = Dirent<'_>> {
+  let mut pos = 0;
+  core::iter::from_fn(move || {
+    // `d_ino`, `d_off`, `d_reclen`, `d_type` and the NUL terminated name
+    let record = buf.get(pos..pos + 19)?;
+    let reclen = u16::from_ne_bytes([record[16], record[17]]) as usize;
+    if reclen < record.len() || pos + reclen > buf.len() {
+      return None;
+    }
+    let name = &buf[pos + 19..pos + reclen];
+    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+    let dirent = Dirent {
+      ino: u64::from_ne_bytes(record[..8].try_into().unwrap()),
+      kind: record[18],
+      name: core::str::from_utf8(name).unwrap_or("?"),
+    };
+    pos += reclen;
+    Some(dirent)
+  })
+}
+
+/// `struct stat` as Linux lays it out on riscv64; times are in seconds plus nanoseconds
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stat {
+  pub dev: u64,
+  pub ino: u64,
+  /// file type and permission bits
+  pub mode: u32,
+  pub nlink: u32,
+  pub uid: u32,
+  pub gid: u32,
+  pub rdev: u64,
+  _pad1: u64,
+  pub size: i64,
+  pub blksize: i32,
+  _pad2: i32,
+  pub blocks: i64,
+  pub atime_sec: i64,
+  pub atime_nsec: i64,
+  pub mtime_sec: i64,
+  pub mtime_nsec: i64,
+  pub ctime_sec: i64,
+  pub ctime_nsec: i64,
+  _unused: [u32; 2],
+}
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+
+/// A time for `utimensat`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TimeSpec {
+  pub sec: i64,
+  pub nsec: i64,
+}
+
+/// `TimeSpec::nsec` meaning the current time
+pub const UTIME_NOW: i64 = (1 << 30) - 1;
+/// `TimeSpec::nsec` meaning the time is left as it is
+pub const UTIME_OMIT: i64 = (1 << 30) - 2;
+
+pub fn fstat(fd: usize, st: &mut Stat) -> isize {
+  sys_fstat(fd, st as *mut Stat as *mut u8)
+}
+
+/// Set the permission bits of `path`, which ends with `\0`
+pub fn chmod(path: &str, mode: u32) -> isize {
+  sys_chmod(path, mode)
+}
+
+/// Set the access and modification times of `path`, which ends with `\0`, to `times`, or
+/// both to the current time if it is `None`
+pub fn utimensat(path: &str, times: Option<&[TimeSpec; 2]>) -> isize {
+  sys_utimensat(path, times.map_or(core::ptr::null(), |times| times.as_ptr() as *const u8))
+}
+
+/// `renameat2` flag: fail if `new_path` exists
+pub const RENAME_NOREPLACE: u32 = 1;
+
+/// Move `old_path` to `new_path` on the same filesystem; both end with `\0`
+pub fn renameat2(old_path: &str, new_path: &str, flags: u32) -> isize {
+  sys_renameat2(old_path, new_path, flags)
+}
+
 pub fn close(fd: usize) -> isize {
   sys_close(fd)
 }
@@ -84,7 +226,13 @@ pub fn fork() -> isize {
 }
 
 pub fn exec(path: &str) -> isize {
-  sys_exec(path)
+  sys_exec(path, &[])
+}
+
+/// Run `path` with `args`, a NULL terminated array of `\0` terminated strings. Returns -1
+/// if `path` cannot be opened or `args` take more than 4 KiB, the array included.
+pub fn execv(path: &str, args: &[*const u8]) -> isize {
+  sys_exec(path, args)
 }
 
 pub fn yield_() -> isize {
@@ -1,9 +1,17 @@
 use core::arch::asm;
 
+const SYSCALL_UMOUNT: usize = 39;
+const SYSCALL_MOUNT: usize = 40;
+const SYSCALL_CHMOD: usize = 53;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
+const SYSCALL_GETDENTS64: usize = 61;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
+const SYSCALL_UTIMENSAT: usize = 88;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_GET_TIME: usize = 169;
@@ -11,6 +19,7 @@ const SYSCALL_GETPID: usize = 172;
 const SYSCALL_FORK: usize = 220;
 const SYSCALL_EXEC: usize = 221;
 const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_RENAMEAT2: usize = 276;
 const SYSCALL_SBRK: usize = 214;
 
 fn syscall(id: usize, args: [usize; 3]) -> isize {
@@ -31,6 +40,42 @@ pub fn sys_open(fd: &str, flags: u32) -> isize {
   syscall(SYSCALL_OPEN, [fd.as_ptr() as usize, flags as usize, 0])
 }
 
+pub fn sys_mount(source: &str, target: &str, fs_type: &str) -> isize {
+  syscall(SYSCALL_MOUNT, [source.as_ptr() as usize, target.as_ptr() as usize, fs_type.as_ptr() as usize])
+}
+
+pub fn sys_umount(target: &str) -> isize {
+  syscall(SYSCALL_UMOUNT, [target.as_ptr() as usize, 0, 0])
+}
+
+pub fn sys_sync() -> isize {
+  syscall(SYSCALL_SYNC, [0, 0, 0])
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+  syscall(SYSCALL_FSYNC, [fd, 0, 0])
+}
+
+pub fn sys_getdents64(fd: usize, buf: &mut [u8]) -> isize {
+  syscall(SYSCALL_GETDENTS64, [fd, buf.as_mut_ptr() as usize, buf.len()])
+}
+
+pub fn sys_fstat(fd: usize, st: *mut u8) -> isize {
+  syscall(SYSCALL_FSTAT, [fd, st as usize, 0])
+}
+
+pub fn sys_chmod(path: &str, mode: u32) -> isize {
+  syscall(SYSCALL_CHMOD, [path.as_ptr() as usize, mode as usize, 0])
+}
+
+pub fn sys_utimensat(path: &str, times: *const u8) -> isize {
+  syscall(SYSCALL_UTIMENSAT, [path.as_ptr() as usize, times as usize, 0])
+}
+
+pub fn sys_renameat2(old_path: &str, new_path: &str, flags: u32) -> isize {
+  syscall(SYSCALL_RENAMEAT2, [old_path.as_ptr() as usize, new_path.as_ptr() as usize, flags as usize])
+}
+
 pub fn sys_close(fd: usize) -> isize {
   syscall(SYSCALL_CLOSE, [fd, 0, 0])
 }
@@ -64,8 +109,9 @@ pub fn sys_get_time() -> isize {
   syscall(SYSCALL_GET_TIME, [0, 0, 0])
 }
 
-pub fn sys_exec(path: &str) -> isize {
-  syscall(SYSCALL_EXEC, [path.as_ptr() as usize, 0, 0])
+pub fn sys_exec(path: &str, args: &[*const u8]) -> isize {
+  let args = if args.is_empty() { 0 } else { args.as_ptr() as usize };
+  syscall(SYSCALL_EXEC, [path.as_ptr() as usize, args, 0])
 }
 
 pub fn sys_fork() -> isize {